@@ -0,0 +1,127 @@
+//! Other spellings of workspace roots in the URIs a client sends
+//!
+//! Instances are keyed by the canonical workspace root. A client which spells
+//! its root differently, through a symlink or in another case, gets the URIs
+//! in its messages rewritten to the canonical spelling on the way to the
+//! instances and back on the way to the client, so its documents are inside
+//! the workspace the server knows.
+
+use serde_json::Value;
+
+use crate::lsp::jsonrpc::Message;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client's to the canonical spelling
+    ToServer,
+    /// From the canonical to the client's spelling
+    ToClient,
+}
+
+/// URI prefixes of a client with a different canonical spelling
+#[derive(Debug, Default)]
+pub struct UriAliases {
+    /// The client's spelling and the canonical one, without trailing slashes
+    aliases: Vec<(String, String)>,
+}
+
+impl UriAliases {
+    /// Spell URIs starting with `client` with `canonical` instead
+    pub fn add(&mut self, client: &str, canonical: &str) {
+        let client = client.trim_end_matches('/');
+        let canonical = canonical.trim_end_matches('/');
+        if client == canonical || self.aliases.iter().any(|(other, _)| other == client) {
+            return;
+        }
+        self.aliases.push((client.to_owned(), canonical.to_owned()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// Rewrite the URIs in the params or result of `message`
+    pub fn rewrite_message(&self, message: &mut Message, direction: Direction) {
+        if self.is_empty() {
+            return;
+        }
+        match message {
+            Message::Request(req) => self.rewrite(&mut req.params, direction),
+            Message::Notification(notif) => self.rewrite(&mut notif.params, direction),
+            Message::ResponseSuccess(res) => self.rewrite(&mut res.result, direction),
+            Message::ResponseError(_) => {}
+        }
+    }
+
+    /// Rewrite the URIs in all strings and object keys of `value`, URIs are
+    /// keys in a `WorkspaceEdit`'s `changes`
+    pub fn rewrite(&self, value: &mut Value, direction: Direction) {
+        match value {
+            Value::String(string) => {
+                if let Some(uri) = self.replace(string, direction) {
+                    *string = uri;
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.rewrite(value, direction);
+                }
+            }
+            Value::Object(map) => {
+                if map.keys().any(|key| self.replace(key, direction).is_some()) {
+                    *map = std::mem::take(map)
+                        .into_iter()
+                        .map(|(key, value)| (self.replace(&key, direction).unwrap_or(key), value))
+                        .collect();
+                }
+                for value in map.values_mut() {
+                    self.rewrite(value, direction);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `uri` in the other spelling, `None` if it's not inside any alias
+    fn replace(&self, uri: &str, direction: Direction) -> Option<String> {
+        self.aliases.iter().find_map(|(client, canonical)| {
+            let (from, to) = match direction {
+                Direction::ToServer => (client, canonical),
+                Direction::ToClient => (canonical, client),
+            };
+            let rest = uri.strip_prefix(from.as_str())?;
+            (rest.is_empty() || rest.starts_with('/')).then(|| format!("{to}{rest}"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn rewrite_uris() {
+        let mut aliases = UriAliases::default();
+        aliases.add("file:///home/me/link/", "file:///data/proj");
+        aliases.add("file:///data/proj", "file:///data/proj");
+        assert_eq!(aliases.aliases.len(), 1);
+
+        let client = json!({
+            "textDocument": { "uri": "file:///home/me/link/src/a.rs" },
+            "changes": { "file:///home/me/link": [], "file:///home/me/linked/b.rs": [] },
+            "path": "/home/me/link/src/a.rs",
+        });
+        let canonical = json!({
+            "textDocument": { "uri": "file:///data/proj/src/a.rs" },
+            "changes": { "file:///data/proj": [], "file:///home/me/linked/b.rs": [] },
+            "path": "/home/me/link/src/a.rs",
+        });
+        let mut value = client.clone();
+        aliases.rewrite(&mut value, Direction::ToServer);
+        assert_eq!(value, canonical);
+        aliases.rewrite(&mut value, Direction::ToClient);
+        assert_eq!(value, client);
+    }
+}
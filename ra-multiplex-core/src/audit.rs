@@ -0,0 +1,259 @@
+//! Audit log of what clients do through the shared servers
+//!
+//! With `audit_log` enabled every connection, the instances clients connect
+//! to, the requests they send and the workspace edits they're asked to apply
+//! are appended to `audit.log` in the data directory as JSON lines. Teams
+//! sharing a daemon on a common host can tell who ran which command. Document
+//! contents and request params other than the command are never recorded.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::warn;
+
+use crate::instance::Instance;
+use crate::lsp::ext::ClientMode;
+use crate::lsp::jsonrpc::Request;
+use crate::lsp::ApplyWorkspaceEditResult;
+use crate::paths;
+use crate::socketwrapper::{SocketAddr, Stream};
+
+/// Audit log of one server, records nothing unless it's opened
+#[derive(Default)]
+pub struct AuditLog {
+    /// Lines for the task writing the audit log
+    lines: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl AuditLog {
+    /// Open the audit log for appending, start the task writing it and
+    /// return its path
+    pub async fn open() -> Result<(AuditLog, PathBuf)> {
+        let path = paths::audit_log()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating {dir:?}"))?;
+        }
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options
+            .open(&path)
+            .await
+            .with_context(|| format!("opening {path:?}"))?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        task::spawn(write_lines(file, rx));
+        Ok((AuditLog { lines: Some(tx) }, path))
+    }
+
+    /// A client connected from `addr`
+    pub fn accept(&self, client_id: usize, addr: &SocketAddr, socket: &Stream) {
+        let (uid, pid) = socket.peer_credentials().unzip();
+        self.record(
+            "accept",
+            client_id,
+            json!({
+                "address": addr.to_string(),
+                "uid": uid,
+                "pid": pid.flatten(),
+            }),
+        );
+    }
+
+    /// A client connected in-process through the embedding API
+    pub fn in_process(&self, client_id: usize) {
+        self.record("accept", client_id, json!({ "address": "in-process" }));
+    }
+
+    /// A client was opened as a session of the multiplexed connection
+    /// `connection_id`, its peer is the peer of the connection
+    pub fn channel(&self, client_id: usize, connection_id: usize) {
+        self.record("channel", client_id, json!({ "connection": connection_id }));
+    }
+
+    /// A client connected to language server instances
+    pub fn connect(&self, client_id: usize, mode: ClientMode, instances: &[Arc<Instance>]) {
+        let instances = instances
+            .iter()
+            .map(|instance| {
+                let key = instance.key();
+                json!({
+                    "pid": instance.pid(),
+                    "server": key.server,
+                    "workspace_root": key.workspace_root,
+                    "tag": key.tag,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.record(
+            "connect",
+            client_id,
+            json!({ "mode": mode, "instances": instances }),
+        );
+    }
+
+    /// A client sent a request
+    pub fn request(&self, client_id: usize, req: &Request) {
+        let mut fields = json!({ "method": req.method, "id": req.id });
+        if req.method == "workspace/executeCommand" {
+            fields["command"] = req.params.get("command").cloned().unwrap_or_default();
+        }
+        self.record("request", client_id, fields);
+    }
+
+    /// A client was asked to apply a `workspace/applyEdit` request from the
+    /// instance with `pid`
+    pub fn apply_edit(&self, client_id: usize, pid: u32, req: &Request) {
+        let files = edited_files(&req.params);
+        self.record(
+            "apply_edit",
+            client_id,
+            json!({ "instance": pid, "label": req.params.get("label"), "files": files }),
+        );
+    }
+
+    /// A client answered a `workspace/applyEdit` request from the instance with
+    /// `pid`
+    pub fn apply_edit_answered(
+        &self,
+        client_id: usize,
+        pid: u32,
+        result: &Result<ApplyWorkspaceEditResult, String>,
+    ) {
+        let (applied, failure_reason) = match result {
+            Ok(result) => (result.applied, result.failure_reason.as_deref()),
+            Err(err) => (false, Some(err.as_str())),
+        };
+        self.record(
+            "apply_edit_answered",
+            client_id,
+            json!({ "instance": pid, "applied": applied, "failure_reason": failure_reason }),
+        );
+    }
+
+    /// A client disconnected
+    pub fn disconnect(&self, client_id: usize) {
+        self.record("disconnect", client_id, json!({}));
+    }
+
+    fn record(&self, event: &str, client_id: usize, fields: Value) {
+        if let Some(lines) = &self.lines {
+            let _ = lines.send(entry(event, client_id, fields));
+        }
+    }
+}
+
+/// Append the lines from `rx` to `file` until all senders are gone
+///
+/// Lines are written at once so concurrent servers appending to the same
+/// file don't interleave them.
+async fn write_lines(mut file: File, mut rx: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = rx.recv().await {
+        let res = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        };
+        if let Err(err) = res.await {
+            warn!(?err, "writing audit log");
+        }
+    }
+}
+
+/// JSON line of an `event` of a client with extra `fields`
+fn entry(event: &str, client_id: usize, fields: Value) -> String {
+    let mut entry = json!({
+        "time": time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000,
+        "event": event,
+        "client": client_id,
+    });
+    if let (Some(entry), Value::Object(fields)) = (entry.as_object_mut(), fields) {
+        entry.extend(fields);
+    }
+    format!("{entry}\n")
+}
+
+/// URIs of the files a `WorkspaceEdit` in `workspace/applyEdit` params changes,
+/// creates, renames or deletes
+fn edited_files(params: &Value) -> Vec<&str> {
+    let edit = &params["edit"];
+    let changes = edit["changes"].as_object().into_iter().flatten();
+    let mut files = changes.map(|(uri, _)| uri.as_str()).collect::<Vec<_>>();
+    for change in edit["documentChanges"].as_array().into_iter().flatten() {
+        let uris = ["/textDocument/uri", "/uri", "/oldUri", "/newUri"]
+            .into_iter()
+            .filter_map(|pointer| change.pointer(pointer)?.as_str());
+        files.extend(uris);
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_edited_files() {
+        let params = json!({
+            "label": "rename",
+            "edit": {
+                "changes": { "file:///a.rs": [] },
+                "documentChanges": [
+                    { "textDocument": { "uri": "file:///b.rs", "version": 1 }, "edits": [] },
+                    { "kind": "create", "uri": "file:///c.rs" },
+                    { "kind": "rename", "oldUri": "file:///d.rs", "newUri": "file:///e.rs" },
+                ],
+            },
+        });
+        assert_eq!(
+            edited_files(&params),
+            [
+                "file:///a.rs",
+                "file:///b.rs",
+                "file:///c.rs",
+                "file:///d.rs",
+                "file:///e.rs",
+            ],
+        );
+        assert!(edited_files(&json!({})).is_empty());
+    }
+
+    #[tokio::test]
+    async fn append_entries() {
+        let path = std::env::temp_dir().join(format!("ra-mux-audit-{}", std::process::id()));
+        std::fs::write(&path, "{}\n").unwrap();
+        let file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = task::spawn(write_lines(file, rx));
+        tx.send(entry(
+            "request",
+            1,
+            json!({ "method": "shutdown", "id": 2 }),
+        ))
+        .unwrap();
+        tx.send(entry("disconnect", 1, json!({}))).unwrap();
+        drop(tx);
+        writer.await.unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries = log
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 3, "{log}");
+        assert_eq!(entries[1]["event"], "request");
+        assert_eq!(entries[1]["client"], 1);
+        assert_eq!(entries[1]["method"], "shutdown");
+        assert_eq!(entries[1]["id"], 2);
+        assert!(entries[1]["time"].is_i64());
+        assert_eq!(entries[2]["event"], "disconnect");
+    }
+}
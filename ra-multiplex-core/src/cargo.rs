@@ -0,0 +1,149 @@
+//! Keying rust-analyzer instances by the Cargo workspace
+//!
+//! Editors opened on a member crate report the crate's directory as the
+//! workspace root while rust-analyzer loads the whole Cargo workspace anyway.
+//! Keying the instance by the Cargo workspace root lets editors opened on
+//! different members share it.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tokio::fs;
+use tokio::process::Command;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// How long to wait for `cargo locate-project`, it may have to install the
+/// toolchain the workspace is pinned to first
+const LOCATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the workspace root found for a directory is reused, members are
+/// rarely moved between workspaces
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Workspace roots by directory and when they were found
+static CACHE: Mutex<BTreeMap<String, (Instant, Option<String>)>> = Mutex::new(BTreeMap::new());
+
+/// Find the root of the Cargo workspace containing `dir`
+///
+/// Asks `cargo locate-project --workspace` and looks for the workspace
+/// manifest itself if that fails. Returns `None` if `dir` isn't inside a
+/// Cargo package. The result is reused for [`CACHE_TTL`] so every client
+/// connecting doesn't wait for cargo.
+pub async fn workspace_root(dir: &str) -> Option<String> {
+    let now = Instant::now();
+    if let Some((found, root)) = CACHE.lock().unwrap().get(dir) {
+        if now.duration_since(*found) < CACHE_TTL {
+            return root.clone();
+        }
+    }
+    let root = match time::timeout(LOCATE_TIMEOUT, locate_project(dir)).await {
+        Ok(Ok(root)) => Some(root),
+        Ok(Err(err)) => {
+            debug!(?err, ?dir, "cargo locate-project failed");
+            find_workspace_root(Path::new(dir)).await
+        }
+        Err(_) => {
+            warn!(?dir, "cargo locate-project timed out");
+            find_workspace_root(Path::new(dir)).await
+        }
+    };
+    let mut cache = CACHE.lock().unwrap();
+    cache.retain(|_, (found, _)| now.duration_since(*found) < CACHE_TTL);
+    cache.insert(dir.to_owned(), (now, root.clone()));
+    root
+}
+
+async fn locate_project(dir: &str) -> Result<String> {
+    let output = Command::new("cargo")
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("running cargo")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "cargo failed: {}",
+            stderr.lines().next().unwrap_or_default()
+        );
+    }
+    let manifest = String::from_utf8(output.stdout).context("cargo output is not utf-8")?;
+    let root = Path::new(manifest.trim())
+        .parent()
+        .context("manifest path has no parent")?;
+    Ok(root.display().to_string())
+}
+
+/// Find the closest ancestor of `dir` with a workspace manifest, or else the
+/// closest one with a package manifest
+async fn find_workspace_root(dir: &Path) -> Option<String> {
+    let mut package = None;
+    for dir in dir.ancestors() {
+        let Ok(text) = fs::read_to_string(dir.join("Cargo.toml")).await else {
+            continue;
+        };
+        if is_workspace_manifest(&text) {
+            return Some(dir.display().to_string());
+        }
+        package.get_or_insert_with(|| dir.display().to_string());
+    }
+    package
+}
+
+fn is_workspace_manifest(text: &str) -> bool {
+    toml::from_str::<toml::Value>(text).is_ok_and(|manifest| manifest.get("workspace").is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_workspace_manifest() {
+        assert!(is_workspace_manifest(
+            "[workspace]\nmembers = [\"crates/*\"]\n"
+        ));
+        assert!(is_workspace_manifest(
+            "[package]\nname = \"a\"\n\n[workspace]\n"
+        ));
+        assert!(!is_workspace_manifest("[package]\nname = \"a\"\n"));
+        assert!(!is_workspace_manifest("[workspace"));
+    }
+
+    #[tokio::test]
+    async fn find_manifests() {
+        let dir = std::env::temp_dir().join(format!("ra-mux-cargo-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("ws/crates/a/src")).unwrap();
+        std::fs::create_dir_all(dir.join("single/src")).unwrap();
+        std::fs::write(
+            dir.join("ws/Cargo.toml"),
+            "[workspace]
+",
+        )
+        .unwrap();
+        let package = "[package]\nname = \"a\"\n";
+        std::fs::write(dir.join("ws/crates/a/Cargo.toml"), package).unwrap();
+        std::fs::write(dir.join("single/Cargo.toml"), package).unwrap();
+        let path = |path: &str| dir.join(path).display().to_string();
+
+        let member = dir.join("ws/crates/a/src");
+        assert_eq!(find_workspace_root(&member).await, Some(path("ws")));
+        let package = dir.join("single/src");
+        assert_eq!(find_workspace_root(&package).await, Some(path("single")));
+        assert_eq!(find_workspace_root(&dir).await, None);
+
+        // Later lookups don't see the manifest changing.
+        let member = path("ws/crates/a");
+        let root = workspace_root(&member).await;
+        std::fs::remove_file(dir.join("ws/Cargo.toml")).unwrap();
+        assert_eq!(workspace_root(&member).await, root);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
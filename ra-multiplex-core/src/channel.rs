@@ -0,0 +1,408 @@
+//! Multiplexed connections carrying many client sessions
+//!
+//! An editor with many workspace folders can run a session for each of them
+//! over one connection instead of connecting once per session. Every message
+//! is wrapped in a [`ext::CHANNEL_MESSAGE`] notification with the ID of its
+//! session and every session is served as if it had its own connection, over
+//! an in-memory pipe.
+//!
+//! [`Multiplexer`] is the client side, the daemon serves the connection with
+//! `serve`. An error reading the connection ends it with all its sessions,
+//! there is no telling which session the lost message belonged to.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::io::{self, AsyncBufRead, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::{select, task};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::client;
+use crate::config::Config;
+use crate::ext::connect_session;
+use crate::instance::InstanceMap;
+use crate::lsp::ext::{self, ChannelClosed, ChannelMessage};
+use crate::lsp::jsonrpc::{Message, Notification, RequestId, ResponseSuccess, Version};
+use crate::lsp::redact::{Logged, Redaction};
+use crate::lsp::transport::{LspReader, LspWriter};
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
+use crate::state::ServerState;
+
+/// Size of the in-memory pipe of a session, a session which doesn't keep up
+/// with reading its messages holds up the other sessions
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Writes the messages for a session into its pipe
+type SessionWriter = LspWriter<WriteHalf<DuplexStream>>;
+
+/// Client side of a multiplexed connection
+///
+/// Every session opened is used like a separate connection to the server.
+pub struct Multiplexer {
+    /// Wrapped messages of the sessions to write into the connection
+    output: mpsc::Sender<Message>,
+
+    /// Sessions opened for `demultiplex` to deliver their messages to
+    opened: mpsc::UnboundedSender<(u64, SessionWriter)>,
+
+    next_channel: AtomicU64,
+
+    /// What is hidden in the logged messages of the sessions
+    redaction: Arc<Redaction>,
+}
+
+impl Multiplexer {
+    /// Connect to the server at the `connect` address and ask it to multiplex
+    /// the connection
+    pub async fn connect(config: &Config) -> Result<Multiplexer> {
+        let (_, mut reader, mut writer) =
+            connect_session(config, ext::Request::Multiplex {}).await?;
+        let redaction = Arc::new(Redaction::new(config));
+        reader.set_redaction(redaction.clone());
+        writer.set_redaction(redaction.clone());
+        let (output, rx) = mpsc::channel(64);
+        task::spawn(write_output(rx, writer).in_current_span());
+        let (opened, opened_rx) = mpsc::unbounded_channel();
+        task::spawn(demultiplex(reader, opened_rx, redaction.clone()).in_current_span());
+        Ok(Multiplexer {
+            output,
+            opened,
+            next_channel: AtomicU64::new(0),
+            redaction,
+        })
+    }
+
+    /// Start a new session
+    ///
+    /// Returns the client's end of the session. It's used like a connection
+    /// to the server, the first message must be the `initialize` request with
+    /// `lspMux` initialization options. Dropping it ends the session, it also
+    /// ends when the server ends it or the connection is closed.
+    pub fn open(&self) -> DuplexStream {
+        let channel = self.next_channel.fetch_add(1, Ordering::Relaxed);
+        let (ours, theirs) = io::duplex(PIPE_SIZE);
+        let (read, write) = io::split(ours);
+        let mut reader = LspReader::new(BufReader::new(read), "channel");
+        reader.set_redaction(self.redaction.clone());
+        task::spawn(read_session(channel, reader, self.output.clone()).in_current_span());
+        let mut writer = LspWriter::new(write, "channel");
+        writer.set_redaction(self.redaction.clone());
+        let _ = self.opened.send((channel, writer));
+        theirs
+    }
+}
+
+/// Deliver the messages of the server to the sessions they belong to until
+/// the connection is closed
+async fn demultiplex<R>(
+    mut reader: LspReader<R>,
+    mut opened: mpsc::UnboundedReceiver<(u64, SessionWriter)>,
+    redaction: Arc<Redaction>,
+) where
+    R: AsyncBufRead + Unpin,
+{
+    let mut sessions = HashMap::<u64, SessionWriter>::new();
+    loop {
+        let notif = select! {
+            // A session is registered before the server can answer it.
+            biased;
+            Some((channel, session)) = opened.recv() => {
+                sessions.insert(channel, session);
+                continue;
+            }
+            notif = read_notification(&mut reader, &redaction) => notif,
+        };
+        let Some(notif) = notif else {
+            break;
+        };
+        match incoming(notif) {
+            Some(Incoming::Message(channel, message)) => {
+                if sessions.contains_key(&channel) {
+                    deliver(&mut sessions, channel, message).await;
+                } else {
+                    debug!(channel, "ignoring message of unknown channel");
+                }
+            }
+            Some(Incoming::Closed(channel)) => close(&mut sessions, channel).await,
+            None => {}
+        }
+    }
+    for session in sessions.values_mut() {
+        let _ = session.shutdown().await;
+    }
+}
+
+/// Serve the sessions of a multiplexed connection until it's closed
+pub(crate) async fn serve(
+    connection_id: usize,
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    shutdown: Arc<Notify>,
+) -> Result<()> {
+    writer
+        .write_message(&ResponseSuccess::null(RequestId::Number(0)).into())
+        .await
+        .context("writing response")?;
+
+    let (output, rx) = mpsc::channel(64);
+    let write_task = task::spawn(write_output(rx, writer).in_current_span());
+
+    let mut sessions = HashMap::<u64, SessionWriter>::new();
+    while let Some(notif) = read_notification(&mut reader, &state.redaction).await {
+        match incoming(notif) {
+            Some(Incoming::Message(channel, message)) => {
+                if let Entry::Vacant(e) = sessions.entry(channel) {
+                    e.insert(open_session(
+                        connection_id,
+                        channel,
+                        output.clone(),
+                        instance_map.clone(),
+                        config.clone(),
+                        state.clone(),
+                        shutdown.clone(),
+                    ));
+                }
+                deliver(&mut sessions, channel, message).await;
+            }
+            Some(Incoming::Closed(channel)) => close(&mut sessions, channel).await,
+            None => {}
+        }
+    }
+
+    for session in sessions.values_mut() {
+        let _ = session.shutdown().await;
+    }
+    drop(output);
+    let _ = write_task.await;
+    Ok(())
+}
+
+/// Message of a multiplexed connection
+enum Incoming {
+    Message(u64, Message),
+    Closed(u64),
+}
+
+/// Read the next notification of a multiplexed connection, `None` once it's
+/// closed or failed
+async fn read_notification<R>(
+    reader: &mut LspReader<R>,
+    redaction: &Redaction,
+) -> Option<Notification>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        match reader.read_message().await {
+            Ok(Some(Message::Notification(notif))) => return Some(notif),
+            Ok(Some(message)) => {
+                warn!(message = ?Logged(&message, redaction), "ignoring message outside a channel");
+            }
+            Ok(None) => {
+                debug!("multiplexed connection closed");
+                return None;
+            }
+            Err(err) => {
+                error!(?err, "error reading multiplexed connection");
+                return None;
+            }
+        }
+    }
+}
+
+fn incoming(notif: Notification) -> Option<Incoming> {
+    match notif.method.as_str() {
+        ext::CHANNEL_MESSAGE => match serde_json::from_value::<ChannelMessage>(notif.params) {
+            Ok(ChannelMessage { channel, message }) => Some(Incoming::Message(channel, message)),
+            Err(err) => {
+                warn!(?err, "invalid channel message");
+                None
+            }
+        },
+        ext::CHANNEL_CLOSED => match serde_json::from_value::<ChannelClosed>(notif.params) {
+            Ok(ChannelClosed { channel }) => Some(Incoming::Closed(channel)),
+            Err(err) => {
+                warn!(?err, "invalid channel closed notification");
+                None
+            }
+        },
+        method => {
+            warn!(?method, "ignoring message outside a channel");
+            None
+        }
+    }
+}
+
+/// Write a message into the pipe of its session
+async fn deliver(sessions: &mut HashMap<u64, SessionWriter>, channel: u64, message: Message) {
+    let Some(session) = sessions.get_mut(&channel) else {
+        return;
+    };
+    if session.write_message(&message).await.is_err() {
+        // The session ended, the other side is told by `read_session`.
+        sessions.remove(&channel);
+    }
+}
+
+/// End a session the other side closed
+async fn close(sessions: &mut HashMap<u64, SessionWriter>, channel: u64) {
+    debug!(channel, "channel closed by peer");
+    if let Some(mut session) = sessions.remove(&channel) {
+        let _ = session.shutdown().await;
+    }
+}
+
+/// Start serving a new session, returns the writer of its client messages
+fn open_session(
+    connection_id: usize,
+    channel: u64,
+    output: mpsc::Sender<Message>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    shutdown: Arc<Notify>,
+) -> SessionWriter {
+    let (ours, theirs) = io::duplex(PIPE_SIZE);
+    let client_id = client::next_client_id();
+    info!(channel, client_id, "channel opened");
+    state.audit.channel(client_id, connection_id);
+
+    let redaction = state.redaction.clone();
+    task::spawn(
+        async move {
+            let socket = Stream::Channel { channel: theirs };
+            let res = client::process(
+                socket,
+                client_id,
+                instance_map,
+                config,
+                state,
+                shutdown,
+                None,
+            )
+            .await;
+            if let Err(err) = res {
+                error!("client error: {err:?}");
+            }
+        }
+        .instrument(info_span!("client", %client_id)),
+    );
+
+    let (read, write) = io::split(ours);
+    let mut reader = LspReader::new(BufReader::new(read), "channel");
+    reader.set_redaction(redaction.clone());
+    task::spawn(read_session(channel, reader, output).in_current_span());
+    let mut writer = LspWriter::new(write, "channel");
+    writer.set_redaction(redaction);
+    writer
+}
+
+/// Wrap the messages of a session for the multiplexed connection and tell the
+/// other side when the session ends
+async fn read_session(
+    channel: u64,
+    mut reader: LspReader<BufReader<ReadHalf<DuplexStream>>>,
+    output: mpsc::Sender<Message>,
+) {
+    loop {
+        match reader.read_message().await {
+            Ok(Some(message)) => {
+                if output.send(wrap(channel, message)).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                error!(?err, channel, "error reading channel");
+                break;
+            }
+        }
+    }
+    debug!(channel, "channel closed");
+    let notif = Notification {
+        jsonrpc: Version,
+        method: ext::CHANNEL_CLOSED.into(),
+        params: serde_json::to_value(ChannelClosed { channel }).unwrap(),
+    };
+    let _ = output.send(notif.into()).await;
+}
+
+/// Write the wrapped messages of all sessions into the connection
+async fn write_output(mut rx: mpsc::Receiver<Message>, mut writer: LspWriter<OwnedWriteHalf>) {
+    while let Some(message) = rx.recv().await {
+        if let Err(err) = writer.write_message(&message).await {
+            error!(?err, "error writing multiplexed connection");
+            break;
+        }
+    }
+}
+
+fn wrap(channel: u64, message: Message) -> Message {
+    Notification {
+        jsonrpc: Version,
+        method: ext::CHANNEL_MESSAGE.into(),
+        params: serde_json::to_value(ChannelMessage { channel, message }).unwrap(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{self, Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn end_sessions_after_read_error() {
+        let (mut connection, theirs) = io::duplex(PIPE_SIZE);
+        let reader = LspReader::new(BufReader::new(theirs), "multiplexed");
+        let (opened, opened_rx) = mpsc::unbounded_channel();
+        let (session, mut client) = io::duplex(PIPE_SIZE);
+        let (_, write) = io::split(session);
+        opened.send((0, LspWriter::new(write, "channel"))).unwrap();
+        let demultiplex = task::spawn(demultiplex(reader, opened_rx, Arc::default()));
+
+        connection
+            .write_all(b"Content-Length: x\r\n\r\n")
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(10), demultiplex)
+            .await
+            .expect("connection wasn't torn down")
+            .unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(client.read_to_end(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn wrap_channel_message() {
+        let message = ResponseSuccess {
+            jsonrpc: Version,
+            result: json!({ "capabilities": {} }),
+            id: RequestId::Number(1),
+        };
+        let Message::Notification(notif) = wrap(7, message.into()) else {
+            panic!("not wrapped in a notification");
+        };
+        assert_eq!(notif.method, ext::CHANNEL_MESSAGE);
+        assert_eq!(
+            notif.params,
+            json!({
+                "channel": 7,
+                "message": { "jsonrpc": "2.0", "result": { "capabilities": {} }, "id": 1 },
+            }),
+        );
+        let ChannelMessage { channel, message } = serde_json::from_value(notif.params).unwrap();
+        assert_eq!(channel, 7);
+        assert!(matches!(message, Message::ResponseSuccess(res) if res.id == RequestId::Number(1)));
+    }
+}
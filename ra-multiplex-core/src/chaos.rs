@@ -0,0 +1,246 @@
+//! Fault injection for testing clients behind ra-multiplex
+//!
+//! The hidden `ra-multiplex server --chaos` flag makes the server misbehave
+//! on the hop between itself and the clients: messages are delayed,
+//! notifications to clients are reordered and notifications clients can do
+//! without are dropped. Editor plugin authors can check their client copes
+//! with a slow or lossy connection. The flag takes comma separated settings,
+//! for example `--chaos delay=200,reorder=0.2,drop=0.1,seed=1`.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::lsp::jsonrpc::Message;
+use crate::lsp::redact::{Logged, Redaction};
+
+/// How long a reordered notification is held back at most when no other
+/// message follows it
+const MAX_HOLD: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    /// Upper bound of the random delay before each message
+    pub delay: Duration,
+    /// Probability a notification is sent after the message following it
+    pub reorder: f64,
+    /// Probability a droppable notification is dropped
+    pub drop: f64,
+    /// Seed making the injected faults repeatable, random if unset
+    pub seed: Option<u64>,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos {
+            delay: Duration::from_millis(100),
+            reorder: 0.1,
+            drop: 0.05,
+            seed: None,
+        }
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos::default();
+        for setting in s.split(',').filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected `key=value`, found {setting:?}"))?;
+            let invalid = || format!("invalid value for `{key}`: {value:?}");
+            match key {
+                "delay" => {
+                    chaos.delay = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "reorder" => chaos.reorder = probability(value).ok_or_else(invalid)?,
+                "drop" => chaos.drop = probability(value).ok_or_else(invalid)?,
+                "seed" => chaos.seed = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("unknown setting `{key}`")),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+fn probability(value: &str) -> Option<f64> {
+    let p = value.parse().ok()?;
+    (0.0..=1.0).contains(&p).then_some(p)
+}
+
+/// Check whether a notification can be lost without breaking the client
+///
+/// Progress reports are superseded by the next one, logs and telemetry are
+/// informational. The begin and end of progress must arrive.
+fn droppable(message: &Message) -> bool {
+    let Message::Notification(notif) = message else {
+        return false;
+    };
+    match notif.method.as_str() {
+        "$/progress" => notif.params.pointer("/value/kind") == Some(&"report".into()),
+        "window/logMessage" | "$/logTrace" | "telemetry/event" => true,
+        _ => false,
+    }
+}
+
+/// Injects faults into the messages of one client connection
+pub struct Injector {
+    chaos: Chaos,
+    rng: Rng,
+    redaction: Arc<Redaction>,
+
+    /// Reordered notification and when it's sent if no message follows it
+    held: Option<(Instant, Message)>,
+}
+
+impl Injector {
+    pub fn new(chaos: Chaos, redaction: Arc<Redaction>) -> Self {
+        let seed = chaos.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        Injector {
+            chaos,
+            rng: Rng(seed),
+            redaction,
+            held: None,
+        }
+    }
+
+    /// Random delay to wait before the next message
+    pub fn delay(&mut self) -> Duration {
+        let max = self.chaos.delay.as_millis() as u64;
+        Duration::from_millis(self.rng.below(max + 1))
+    }
+
+    /// Returns the messages to send to the client in place of `message`
+    ///
+    /// The message may be dropped or held back, a held back notification is
+    /// sent after the next message.
+    pub fn inject(&mut self, message: Message, now: Instant) -> Vec<Message> {
+        if droppable(&message) && self.rng.chance(self.chaos.drop) {
+            debug!(message = ?Logged(&message, &self.redaction), "chaos: dropped");
+            return Vec::new();
+        }
+        if matches!(message, Message::Notification(_))
+            && self.held.is_none()
+            && self.rng.chance(self.chaos.reorder)
+        {
+            self.held = Some((now + MAX_HOLD, message));
+            return Vec::new();
+        }
+        let mut messages = vec![message];
+        if let Some((_, held)) = self.held.take() {
+            debug!(message = ?Logged(&held, &self.redaction), "chaos: reordered");
+            messages.push(held);
+        }
+        messages
+    }
+
+    /// When the held back notification is due if no message follows it
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.held.as_ref().map(|(deadline, _)| *deadline)
+    }
+
+    /// Take the held back notification if it's due at `now`
+    pub fn take_due(&mut self, now: Instant) -> Option<Message> {
+        match &self.held {
+            Some((deadline, _)) if *deadline <= now => self.held.take().map(|(_, held)| held),
+            _ => None,
+        }
+    }
+}
+
+/// SplitMix64, faults don't need good randomness only a repeatable one
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{Notification, RequestId, ResponseSuccess, Version};
+
+    fn progress(kind: &str) -> Message {
+        Notification {
+            jsonrpc: Version,
+            method: "$/progress".into(),
+            params: json!({ "token": "t", "value": { "kind": kind } }),
+        }
+        .into()
+    }
+
+    fn seeded(chaos: &str) -> Injector {
+        Injector {
+            chaos: chaos.parse().unwrap(),
+            rng: Rng(1),
+            redaction: Arc::default(),
+            held: None,
+        }
+    }
+
+    #[test]
+    fn parse_settings() {
+        assert_eq!("".parse::<Chaos>(), Ok(Chaos::default()));
+        assert_eq!(
+            "delay=5,drop=1,seed=3".parse::<Chaos>(),
+            Ok(Chaos {
+                delay: Duration::from_millis(5),
+                drop: 1.0,
+                seed: Some(3),
+                ..Chaos::default()
+            }),
+        );
+        assert!("drop=2".parse::<Chaos>().is_err());
+        assert!("jitter=1".parse::<Chaos>().is_err());
+        assert!("delay".parse::<Chaos>().is_err());
+    }
+
+    #[test]
+    fn drop_and_reorder() {
+        let now = Instant::now();
+        let mut injector = seeded("drop=1,reorder=0");
+        assert!(injector.inject(progress("report"), now).is_empty());
+        assert_eq!(injector.inject(progress("begin"), now).len(), 1);
+
+        let mut injector = seeded("drop=0,reorder=1");
+        assert!(injector.inject(progress("begin"), now).is_empty());
+        assert_eq!(injector.next_deadline(), Some(now + MAX_HOLD));
+        assert!(injector.take_due(now).is_none());
+        let res = ResponseSuccess::null(RequestId::Number(1));
+        let messages = injector.inject(res.into(), now);
+        assert!(matches!(
+            messages.as_slice(),
+            [Message::ResponseSuccess(_), Message::Notification(_)],
+        ));
+
+        assert!(injector.inject(progress("end"), now).is_empty());
+        assert!(injector.take_due(now + MAX_HOLD).is_some());
+        assert_eq!(injector.next_deadline(), None);
+    }
+}
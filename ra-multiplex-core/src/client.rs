@@ -0,0 +1,2630 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use tokio::time::{self, Instant};
+use tokio::{fs, select, task};
+use tracing::{debug, error, info, trace, warn, Instrument};
+use uriparse::URI;
+
+use crate::alias::{Direction, UriAliases};
+use crate::cargo;
+use crate::channel;
+use crate::chaos::Injector;
+use crate::config::{CompanionServer, Config, OptionsMode, WorkspaceKeying};
+use crate::download;
+use crate::events;
+use crate::git;
+use crate::hooks::{self, Event};
+use crate::instance::{self, Instance, InstanceKey, InstanceMap};
+use crate::lsp::ext::{self, ClientMode, ErrorCode, EventKind, InstanceRole, LspMuxOptions, Tag};
+use crate::lsp::jsonrpc::{
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+};
+use crate::lsp::redact::{self, Logged};
+use crate::lsp::transport::{Compression, LspReader, LspWriter};
+use crate::lsp::{ApplyWorkspaceEditResult, InitializeParams, WorkspaceFolder};
+use crate::merge::{self, merge_patch, Merges};
+use crate::ratelimit::{NotificationLimiter, RequestQuota};
+use crate::resolve;
+use crate::resume::{self, Rebind, Replay, Resumable};
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
+use crate::state::ServerState;
+use crate::toolchain;
+use crate::watcher;
+
+/// Read first client message and dispatch lsp mux commands
+///
+/// Clients connected in-process send `initialize` without `lspMux` options,
+/// the `preset` ones are used instead.
+pub async fn process(
+    socket: Stream,
+    client_id: usize,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    shutdown: Arc<Notify>,
+    preset: Option<LspMuxOptions>,
+) -> Result<()> {
+    let uid = socket.peer_credentials().map(|(uid, _)| uid);
+    let (socket_read, mut socket_write) = socket.into_split();
+    let mut socket_read = BufReader::new(socket_read);
+
+    // Something probing the port with HTTP gets an answer it can show.
+    if matches!(socket_read.fill_buf().await, Ok(buf) if is_http(buf)) {
+        let _ = socket_write.write_all(HTTP_REJECTION.as_bytes()).await;
+        bail!("rejected HTTP request, expected LSP");
+    }
+
+    let mut reader = LspReader::new(socket_read, "client");
+    reader.set_limits(config.message_limits());
+    reader.set_redaction(state.redaction.clone());
+    let mut writer = LspWriter::new(socket_write, "client");
+    writer.set_redaction(state.redaction.clone());
+
+    // Read the first client message, this must be `initialize` request. Peers
+    // failing the handshake are told why before the connection is closed.
+    let req = match reader.read_message().await {
+        Ok(Some(Message::Request(req))) if req.method == "initialize" => req,
+        Ok(Some(message)) => {
+            let id = match message {
+                Message::Request(req) => Some(req.id),
+                _ => None,
+            };
+            // ServerNotInitialized
+            reject_handshake(&mut writer, id, -32002, "expected `initialize` request").await;
+            bail!("first client message was not `initialize` request");
+        }
+        Ok(None) => bail!("channel closed"),
+        Err(err) => {
+            // ParseError
+            reject_handshake(&mut writer, None, -32700, &format!("{err:#}")).await;
+            return Err(err.context("receive `initialize` request"));
+        }
+    };
+    let mut init_params = match serde_json::from_value::<InitializeParams>(req.params.clone()) {
+        Ok(init_params) => init_params,
+        Err(err) => {
+            // InvalidParams
+            reject_handshake(&mut writer, Some(req.id), -32602, &err.to_string()).await;
+            return Err(err).context("parse `initialize` request params");
+        }
+    };
+
+    // Remove `lspMux` from `initializationOptions`, it's ra-multiplex extension
+    // and we don't want to forward it to the real language server.
+    let lsp_mux = init_params
+        .initialization_options
+        .as_mut()
+        .and_then(|options| options.lsp_mux.take());
+    let options = match (lsp_mux, preset) {
+        (Some(options), _) | (None, Some(options)) => options,
+        (None, None) => {
+            // InvalidRequest
+            reject_handshake(&mut writer, Some(req.id), -32600, NOT_LSP_MUX).await;
+            ensure!(
+                init_params.initialization_options.is_some(),
+                "missing `initializationOptions` in `initialize` request",
+            );
+            bail!("missing `lspMux` in `initializationOptions` in `initialize` request");
+        }
+    };
+    reader.set_encoding(options.encoding);
+    writer.set_encoding(options.encoding);
+    // Messages are compressed from the `initialize` response on, the proxy
+    // only compresses once it sees we accepted so ours are read with it.
+    let compression = options.compression.accepted();
+    reader.set_compression(compression);
+    if options.version != LspMuxOptions::PROTOCOL_VERSION {
+        let message = format!(
+            "{}: unsupported protocol version {:?}, expected {:?}",
+            ErrorCode::VersionMismatch,
+            &options.version,
+            LspMuxOptions::PROTOCOL_VERSION,
+        );
+        let code = ErrorCode::VersionMismatch.code();
+        reject_handshake(&mut writer, Some(req.id), code, &message).await;
+        bail!(message);
+    }
+
+    // Keep the members the config lists as sensitive from the server too.
+    if let Some(init_options) = &mut init_params.initialization_options {
+        for path in &config.strip_initialization_options {
+            if redact::strip(&mut init_options.other_options, path) {
+                debug!(?path, "stripped initialization option");
+            }
+        }
+    }
+
+    debug!(?options, "lspmux initialization");
+    if let Some(token) = options.reconnect_token {
+        let received = options.received.unwrap_or(0);
+        return reconnect(
+            &state,
+            &token,
+            req.id,
+            received,
+            compression,
+            reader,
+            writer,
+        )
+        .await;
+    }
+    match options.method {
+        ext::Request::Connect {
+            server,
+            args,
+            env,
+            cwd,
+            tag,
+        } => {
+            connect(
+                (client_id, uid),
+                instance_map,
+                (server, args, env, cwd),
+                (options.mode, options.follow, tag, options.resumable),
+                compression,
+                req,
+                init_params,
+                config,
+                state,
+                reader,
+                writer,
+            )
+            .await
+        }
+        ext::Request::Status {} => status(instance_map, writer).await,
+        ext::Request::Reload { pid, cwd } => reload(pid, cwd, instance_map, &config, writer).await,
+        ext::Request::Pin { pid, cwd, pinned } => pin(pid, cwd, pinned, instance_map, writer).await,
+        ext::Request::Restart { pid, cwd } => restart(pid, cwd, instance_map, writer).await,
+        ext::Request::Rollover { pid, cwd } => {
+            rollover(pid, cwd, instance_map, &state, writer).await
+        }
+        ext::Request::Merge {
+            pid,
+            cwd,
+            into_pid,
+            into_cwd,
+        } => merge((pid, cwd), (into_pid, into_cwd), instance_map, writer).await,
+        ext::Request::Attach { pid, cwd, tag } => {
+            attach(
+                (client_id, uid),
+                pid,
+                cwd,
+                tag,
+                instance_map,
+                &state,
+                reader,
+                writer,
+            )
+            .await
+        }
+        ext::Request::Notify {
+            pid,
+            cwd,
+            notification,
+            params,
+        } => notify(pid, cwd, (notification, params), instance_map, writer).await,
+        ext::Request::Queue { pid, cwd, cancel } => {
+            queue(pid, cwd, cancel, instance_map, writer).await
+        }
+        ext::Request::Shutdown { detach } => stop(detach, instance_map, shutdown, writer).await,
+        ext::Request::Statusline { pid, cwd } => statusline(pid, cwd, instance_map, writer).await,
+        ext::Request::Doctor { servers, env } => doctor(servers, env, &config, writer).await,
+        ext::Request::Subscribe {} => subscribe(&state, reader, writer).await,
+        ext::Request::LogFilter {
+            filter,
+            reset,
+            revert_after,
+        } => log_level(&state, filter, reset, revert_after, writer).await,
+        ext::Request::Multiplex {} => {
+            channel::serve(
+                client_id,
+                reader,
+                writer,
+                instance_map,
+                config,
+                state,
+                shutdown,
+            )
+            .await
+        }
+    }
+}
+
+/// Error message for LSP clients connecting without `lspMux` options
+const NOT_LSP_MUX: &str = "this is an ra-multiplex server, editors connect to it \
+    through `ra-multiplex client` used as their language server command, only \
+    clients sending `lspMux` in `initializationOptions` can connect directly";
+
+/// Response to HTTP requests on the daemon's port
+const HTTP_REJECTION: &str = "HTTP/1.1 400 Bad Request\r\n\
+    Content-Type: text/plain\r\n\
+    Content-Length: 146\r\n\
+    Connection: close\r\n\
+    \r\n\
+    This is an ra-multiplex server speaking LSP, not HTTP. Editors connect to \
+    it through `ra-multiplex client` used as their language server command.\n";
+
+/// Check if the first bytes a peer sent start an HTTP request
+fn is_http(buf: &[u8]) -> bool {
+    const METHODS: [&[u8]; 9] = [
+        b"GET ",
+        b"HEAD ",
+        b"POST ",
+        b"PUT ",
+        b"DELETE ",
+        b"CONNECT ",
+        b"OPTIONS ",
+        b"TRACE ",
+        b"PATCH ",
+    ];
+    METHODS.iter().any(|method| buf.starts_with(method))
+}
+
+/// Answer a peer which failed the handshake with an error response to its
+/// request `id` or with a `null` ID if it didn't send a request
+async fn reject_handshake(
+    writer: &mut LspWriter<OwnedWriteHalf>,
+    id: Option<RequestId>,
+    code: i64,
+    message: &str,
+) {
+    if let Some(id) = id {
+        let res = ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code,
+                message: message.into(),
+                data: None,
+            },
+            id,
+        };
+        let _ = writer.write_message(&res.into()).await;
+        return;
+    }
+    // Written raw, `ResponseError` can't have a `null` ID.
+    let body = json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+    .to_string();
+    let message = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+    let _ = writer.get_mut().write_all(message.as_bytes()).await;
+}
+
+/// Allocate the ID of a newly connected client
+pub fn next_client_id() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone)]
+pub struct Client {
+    id: usize,
+
+    /// User ID of the peer of a Unix socket connection
+    uid: Option<u32>,
+    sender: mpsc::Sender<Message>,
+
+    /// Merge state if the client is connected to more than one instance
+    merges: Option<Merges>,
+
+    mode: ClientMode,
+
+    /// ID of the client whose opened documents this one is notified about
+    follow: Option<usize>,
+
+    /// Workspace folders the client asked for in `initialize` or added later
+    workspace_folders: Arc<std::sync::Mutex<Vec<ext::ClientFolder>>>,
+
+    /// `initializationOptions` the client sent, its own settings
+    settings: Arc<Map<String, Value>>,
+
+    /// The client's spellings of the canonical workspace roots
+    aliases: Arc<UriAliases>,
+
+    /// Notified when an instance forgets one of the client's requests
+    responded: Arc<Notify>,
+
+    /// Tag an attached session was opened with
+    tag: Option<String>,
+
+    /// Client announced `window.workDoneProgress` support in `initialize`
+    work_done_progress: bool,
+}
+
+impl Client {
+    fn new(
+        (id, uid): (usize, Option<u32>),
+        multiple_instances: bool,
+        mode: ClientMode,
+        follow: Option<usize>,
+    ) -> (Client, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(16);
+        let merges = multiple_instances.then(Merges::default);
+        let client = Client {
+            id,
+            uid,
+            sender,
+            merges,
+            mode,
+            follow,
+            workspace_folders: Arc::default(),
+            settings: Arc::default(),
+            aliases: Arc::default(),
+            responded: Arc::default(),
+            tag: None,
+            work_done_progress: false,
+        };
+        (client, receiver)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// User ID of the peer, `None` unless it's connected over a Unix socket
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// Tag an attached session was opened with
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// Client can be sent `window/workDoneProgress/create` requests
+    pub fn supports_work_done_progress(&self) -> bool {
+        self.work_done_progress
+    }
+
+    /// Wake up the client if it's waiting for the responses to its requests
+    pub fn responded(&self) {
+        self.responded.notify_waiters();
+    }
+
+    /// Client is an observer whose edits are dropped
+    pub fn is_observer(&self) -> bool {
+        self.mode == ClientMode::Observer
+    }
+
+    /// Check whether the client follows the client with `client_id`
+    pub fn follows(&self, client_id: usize) -> bool {
+        self.follow == Some(client_id)
+    }
+
+    pub fn workspace_folders(&self) -> Vec<ext::ClientFolder> {
+        self.workspace_folders.lock().unwrap().clone()
+    }
+
+    pub fn settings(&self) -> &Map<String, Value> {
+        &self.settings
+    }
+
+    /// Remember the workspace folders the client asked for in `initialize`
+    fn init_workspace_folders(&self, init_params: &InitializeParams) {
+        let uris = if init_params.workspace_folders.is_empty() {
+            let root = init_params
+                .root_uri
+                .as_ref()
+                .or(init_params.root_path.as_ref());
+            root.into_iter().cloned().collect()
+        } else {
+            init_params
+                .workspace_folders
+                .iter()
+                .map(|folder| folder.uri.clone())
+                .collect::<Vec<_>>()
+        };
+        *self.workspace_folders.lock().unwrap() = uris
+            .into_iter()
+            .map(|uri| ext::ClientFolder { uri, added: false })
+            .collect();
+    }
+
+    /// Track folders added and removed with `workspace/didChangeWorkspaceFolders`
+    fn change_workspace_folders(&self, params: &Value) {
+        let uris = |pointer| {
+            params
+                .pointer(pointer)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|folder| folder.get("uri")?.as_str())
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        let removed = uris("/event/removed");
+        let mut folders = self.workspace_folders.lock().unwrap();
+        folders.retain(|folder| !removed.contains(&folder.uri));
+        for uri in uris("/event/added") {
+            if !folders.iter().any(|folder| folder.uri == uri) {
+                folders.push(ext::ClientFolder { uri, added: true });
+            }
+        }
+    }
+
+    /// Send a message to the client channel
+    pub async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
+        self.sender.send(message).await
+    }
+
+    /// Send `textDocument/publishDiagnostics` notification from instance
+    /// `pid` to the client channel
+    ///
+    /// If the client is connected to more than one instance the diagnostics
+    /// are merged with diagnostics other instances published for the same file.
+    pub async fn publish_diagnostics(
+        &self,
+        pid: u32,
+        notif: Notification,
+    ) -> Result<(), SendError<Message>> {
+        let notif = match &self.merges {
+            Some(merges) => merges.diagnostics(pid, notif),
+            None => notif,
+        };
+        self.send_message(notif.into()).await
+    }
+
+    /// Send `experimental/serverStatus` notification from instance `pid` to
+    /// the client channel
+    ///
+    /// If the client is connected to more than one instance the status is
+    /// combined with the last status of the other instances.
+    pub async fn publish_server_status(
+        &self,
+        pid: u32,
+        notif: Notification,
+    ) -> Result<(), SendError<Message>> {
+        let notif = match &self.merges {
+            Some(merges) => merges.server_status(pid, notif),
+            None => notif,
+        };
+        self.send_message(notif.into()).await
+    }
+}
+
+async fn status(
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let status = task::spawn_blocking(move || instance_map.blocking_lock().get_status())
+        .await
+        .unwrap();
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(status).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+/// Make an instance reload its workspace for `ra-multiplex reload-workspace`
+async fn reload(
+    pid: Option<u32>,
+    cwd: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    config: &Config,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    let server = &instance.key().server;
+    let res = match reload_method(config, server) {
+        Some(method) => {
+            info!(pid = instance.pid(), ?method, "reloading workspace");
+            instance.internal_request(method, Value::Null).await
+        }
+        None => Err(anyhow!(
+            "no reload method known for {server:?}, set `reload_method` in its server settings"
+        )),
+    };
+    let res = match res {
+        Ok(_) => Message::ResponseSuccess(ResponseSuccess::null(RequestId::Number(0))),
+        Err(err) => Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: format!("{err:#}"),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        }),
+    };
+    writer.write_message(&res).await.context("writing response")
+}
+
+/// Request making `server` reload its workspace
+fn reload_method<'a>(config: &'a Config, server: &str) -> Option<&'a str> {
+    let configured = config
+        .server_settings(server)
+        .and_then(|settings| settings.reload_method.as_deref());
+    configured.or(is_rust_analyzer(server).then_some("rust-analyzer/reloadWorkspace"))
+}
+
+/// Find the instance with language server `pid` or the instance for `cwd` if
+/// no PID is given
+async fn find_instance(
+    instance_map: &Mutex<InstanceMap>,
+    pid: Option<u32>,
+    cwd: &str,
+) -> Option<Arc<Instance>> {
+    let instance_map = instance_map.lock().await;
+    let instance = match pid {
+        Some(pid) => instance_map.get_by_pid(pid),
+        None => instance_map.get_by_cwd(cwd),
+    };
+    if instance.is_none() {
+        debug!(?pid, ?cwd, "no instance found");
+    }
+    instance.cloned()
+}
+
+/// Response to a request with the same ID as one the client is still waiting
+/// for, its response couldn't be routed
+pub fn id_in_use(id: RequestId) -> ResponseError {
+    ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            code: -32600, // InvalidRequest
+            message: "request ID is already used by a pending request".into(),
+            data: None,
+        },
+        id,
+    }
+}
+
+fn no_instance_found() -> Message {
+    Message::ResponseError(ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            code: 0,
+            message: "no instance found".into(),
+            data: None,
+        },
+        id: RequestId::Number(0),
+    })
+}
+
+/// Pin or unpin an instance for `ra-multiplex pin` and `unpin`
+async fn pin(
+    pid: Option<u32>,
+    cwd: String,
+    pinned: bool,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    info!(pid = instance.pid(), pinned, "pinning instance");
+    instance.pin(pinned);
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess::null(
+            RequestId::Number(0),
+        )))
+        .await
+        .context("writing response")
+}
+
+async fn restart(
+    pid: Option<u32>,
+    cwd: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    instance.restart().await;
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess::null(
+            RequestId::Number(0),
+        )))
+        .await
+        .context("writing response")
+}
+
+/// Replace the language server of an instance with a new one once it
+/// finished indexing for `ra-multiplex rollover`
+async fn rollover(
+    pid: Option<u32>,
+    cwd: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    state: &ServerState,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    // Keep going when `ra-multiplex rollover` is interrupted, killing the new
+    // server halfway through would waste the indexing done so far. The new
+    // server runs on the instances' runtime like the old one.
+    let rollover = state
+        .runtime
+        .spawn(async move { instance.rollover().await }.in_current_span());
+    let res = rollover
+        .await
+        .context("rollover failed")
+        .and_then(|res| res);
+    let res = match res {
+        Ok(pid) => Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: pid.into(),
+            id: RequestId::Number(0),
+        }),
+        Err(err) => Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: format!("{err:#}"),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        }),
+    };
+    writer.write_message(&res).await.context("writing response")
+}
+
+/// Replace instances merged into another one by `ra-multiplex merge` with the
+/// instance the client was moved to
+///
+/// Removing the duplicates shifts the instance indices of merged requests,
+/// the ones sent before are answered by the instance they were sent to or
+/// failed by the merge so the pending merges don't wait for an index which
+/// is gone.
+fn follow_merges(instances: &mut Vec<Arc<Instance>>) {
+    let mut merged = false;
+    for instance in instances.iter_mut() {
+        while let Some(target) = instance.merged_into() {
+            *instance = target;
+            merged = true;
+        }
+    }
+    if merged {
+        let mut seen = BTreeSet::new();
+        instances.retain(|instance| seen.insert(instance.pid()));
+    }
+}
+
+/// Move the clients of an instance onto another one running the same server
+/// for `ra-multiplex merge`
+async fn merge(
+    (pid, cwd): (Option<u32>, String),
+    (into_pid, into_cwd): (Option<u32>, String),
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let instance = find_instance(&instance_map, pid, &cwd).await;
+    let target = find_instance(&instance_map, into_pid, &into_cwd).await;
+    let (Some(instance), Some(target)) = (instance, target) else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    // Like rollover keep going when `ra-multiplex merge` is interrupted,
+    // clients moved halfway would be stuck between the instances.
+    let merge = task::spawn(async move { instance.merge_into(&target).await }.in_current_span());
+    let res = merge.await.context("merge failed").and_then(|res| res);
+    let res = match res {
+        Ok(moved) => Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: moved.into(),
+            id: RequestId::Number(0),
+        }),
+        Err(err) => Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: format!("{err:#}"),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        }),
+    };
+    writer.write_message(&res).await.context("writing response")
+}
+
+/// Send a notification from `ra-multiplex notify` to an instance
+async fn notify(
+    pid: Option<u32>,
+    cwd: String,
+    (method, params): (String, Value),
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    // These are tracked by ra-multiplex, sending them behind its back would
+    // break the server for all clients.
+    if matches!(method.as_str(), "initialized" | "exit") || method.starts_with("textDocument/did") {
+        let res = ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                // InvalidRequest
+                code: -32600,
+                message: format!("`{method}` can't be sent to a shared instance"),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        };
+        return writer
+            .write_message(&res.into())
+            .await
+            .context("writing response");
+    }
+    info!(pid = instance.pid(), ?method, "sending notification");
+    let notif = Notification {
+        jsonrpc: Version,
+        method,
+        params,
+    };
+    if instance.send_message(notif.into()).await.is_err() {
+        bail!("instance closed");
+    }
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess::null(
+            RequestId::Number(0),
+        )))
+        .await
+        .context("writing response")
+}
+
+/// List or cancel pending requests of an instance for `ra-multiplex queue`
+async fn queue(
+    pid: Option<u32>,
+    cwd: String,
+    cancel: Option<String>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    let result = match cancel {
+        Some(id) => {
+            if !instance.cancel_request(RequestId::String(id.clone())).await {
+                let res = ResponseError {
+                    jsonrpc: Version,
+                    error: jsonrpc::Error {
+                        code: 0,
+                        message: format!("no pending request with ID {id:?}"),
+                        data: None,
+                    },
+                    id: RequestId::Number(0),
+                };
+                return writer
+                    .write_message(&res.into())
+                    .await
+                    .context("writing response");
+            }
+            Value::Null
+        }
+        None => serde_json::to_value(instance.pending_requests().await).unwrap(),
+    };
+
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result,
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+/// Forward requests from an `ra-multiplex attach` session to an instance
+#[allow(clippy::too_many_arguments)]
+async fn attach(
+    (client_id, uid): (usize, Option<u32>),
+    pid: Option<u32>,
+    cwd: String,
+    tag: Option<String>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    state: &ServerState,
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let Some(instance) = find_instance(&instance_map, pid, &cwd).await else {
+        return writer
+            .write_message(&no_instance_found())
+            .await
+            .context("writing response");
+    };
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess::null(
+            RequestId::Number(0),
+        )))
+        .await
+        .context("writing response")?;
+    info!(pid = instance.pid(), "attached to instance");
+
+    let (mut client, client_rx) = Client::new((client_id, uid), false, ClientMode::Normal, None);
+    client.tag = tag;
+    let limiter = NotificationLimiter::new(&BTreeMap::new());
+    let aliases = client.aliases.clone();
+    let chaos = injector(state);
+    task::spawn(
+        input_task(client_rx, writer, limiter, None, None, aliases, chaos).in_current_span(),
+    );
+    instance.attach_client(client.clone()).await;
+
+    loop {
+        let message = match reader.read_message().await {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                debug!("attached session closed");
+                break;
+            }
+            Err(err) => {
+                error!(?err, "error reading attached session");
+                continue;
+            }
+        };
+        match message {
+            // These would break the server for all other clients.
+            Message::Request(req) if matches!(req.method.as_str(), "initialize" | "shutdown") => {
+                let res = ResponseError {
+                    jsonrpc: Version,
+                    error: jsonrpc::Error {
+                        // InvalidRequest
+                        code: -32600,
+                        message: format!("`{}` can't be sent to a shared instance", req.method),
+                        data: None,
+                    },
+                    id: req.id,
+                };
+                if client.send_message(res.into()).await.is_err() {
+                    break;
+                }
+            }
+            Message::Request(req) => {
+                if instance.send_request(client.id, req).await.is_err() {
+                    break;
+                }
+            }
+            message => {
+                let message = Logged(&message, &state.redaction);
+                debug!(?message, "ignoring attached session message");
+            }
+        }
+    }
+
+    if let Err(err) = instance.cleanup_client(client).await {
+        warn!(?err, "error cleaning up after an attached session");
+    }
+    Ok(())
+}
+
+async fn statusline(
+    pid: Option<u32>,
+    cwd: String,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let result = match find_instance(&instance_map, pid, &cwd).await {
+        Some(instance) => serde_json::to_value(instance.statusline().await).unwrap(),
+        None => Value::Null,
+    };
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result,
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+async fn doctor(
+    servers: Vec<String>,
+    env: BTreeMap<String, String>,
+    config: &Config,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let path = env
+        .get("PATH")
+        .cloned()
+        .or_else(|| std::env::var("PATH").ok());
+    let mut lookups = Vec::new();
+    for server in servers {
+        let resolved = resolve::resolve_server(config, &server, &env).await;
+        lookups.push(ext::ServerLookup {
+            path: resolve::find_executable(&resolved, path.as_deref())
+                .map(|path| path.display().to_string()),
+            server,
+        });
+    }
+    let res = ext::DoctorResponse {
+        version: env!("CARGO_PKG_VERSION").into(),
+        path,
+        servers: lookups,
+    };
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(res).unwrap(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response")
+}
+
+/// Stream lifecycle events to the client until it disconnects
+async fn subscribe(
+    state: &ServerState,
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let mut events = state.events.subscribe();
+    writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess::null(
+            RequestId::Number(0),
+        )))
+        .await
+        .context("writing response")?;
+    info!("client subscribed to events");
+
+    loop {
+        let event = select! {
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => ext::Event {
+                    missed: Some(missed),
+                    ..events::event(EventKind::Lagged)
+                },
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            // Subscribers send nothing, reading only notices them disconnect.
+            message = reader.read_message() => match message {
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => {
+                    debug!("subscriber disconnected");
+                    return Ok(());
+                }
+            },
+        };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: ext::EVENT.into(),
+            params: serde_json::to_value(event).unwrap(),
+        };
+        if let Err(err) = writer.write_message(&notif.into()).await {
+            debug!(?err, "subscriber disconnected");
+            return Ok(());
+        }
+    }
+}
+
+async fn stop(
+    detach: bool,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    shutdown: Arc<Notify>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    info!(detach, "shutdown requested");
+    let count = InstanceMap::shutdown(&instance_map, detach).await;
+    let res = writer
+        .write_message(&Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: count.into(),
+            id: RequestId::Number(0),
+        }))
+        .await
+        .context("writing response");
+    let _ = writer.get_mut().close().await;
+    // Stop the server even if the requesting client is gone already.
+    shutdown.notify_one();
+    res
+}
+
+/// Change the log filter for `ra-multiplex log-level`
+async fn log_level(
+    state: &ServerState,
+    filter: Option<String>,
+    reset: bool,
+    revert_after: Option<u32>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let revert_after = revert_after.map(|secs| Duration::from_secs(secs.into()));
+    let res = match (&state.log_filter, filter, reset) {
+        (None, ..) => Err(anyhow!("logging is not set up by the server")),
+        (Some(log_filter), None, false) => Ok(log_filter.get()),
+        (Some(log_filter), filter, _) => log_filter.set(filter.as_deref(), revert_after),
+    };
+    let res = match res {
+        Ok(filter) => Message::ResponseSuccess(ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(filter).unwrap(),
+            id: RequestId::Number(0),
+        }),
+        Err(err) => Message::ResponseError(ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: 0,
+                message: format!("{err:#}"),
+                data: None,
+            },
+            id: RequestId::Number(0),
+        }),
+    };
+    writer.write_message(&res).await.context("writing response")
+}
+
+/// Re-bind a client reconnecting with `token` to its session
+async fn reconnect(
+    state: &ServerState,
+    token: &str,
+    id: RequestId,
+    received: u64,
+    compression: Compression,
+    reader: LspReader<BufReader<OwnedReadHalf>>,
+    writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    let reconnect = resume::Reconnect {
+        id,
+        received,
+        compression,
+        reader,
+        writer,
+    };
+    let Err(reconnect) = state.sessions.reconnect(token, reconnect).await else {
+        return Ok(());
+    };
+    info!("client reconnected with an expired token");
+    let resume::Reconnect { id, mut writer, .. } = reconnect;
+    let res = ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            code: -32803, // RequestFailed
+            message: "reconnect token expired".into(),
+            data: None,
+        },
+        id,
+    };
+    writer
+        .write_message(&res.into())
+        .await
+        .context("writing response")
+}
+
+/// Find or spawn language server instances and connect the client to them
+#[allow(clippy::too_many_arguments)]
+async fn connect(
+    (client_id, uid): (usize, Option<u32>),
+    instance_map: Arc<Mutex<InstanceMap>>,
+    (server, args, env, cwd): (
+        String,
+        Vec<String>,
+        BTreeMap<String, String>,
+        Option<String>,
+    ),
+    (mode, follow, tag, resumable): (ClientMode, Option<usize>, Option<String>, bool),
+    compression: Compression,
+    req: Request,
+    init_params: InitializeParams,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut writer: LspWriter<OwnedWriteHalf>,
+) -> Result<()> {
+    // Failures to start the instances are reported in the response to the
+    // `initialize` request, with an lspMux error code plugins can act on.
+    let mut aliases = UriAliases::default();
+    let prepared = async {
+        // Select the workspace root directories.
+        let workspace_roots =
+            select_workspace_roots(&init_params, cwd.as_deref(), &config, &mut aliases)
+                .await
+                .context(ErrorCode::NoWorkspaceRoot)?;
+        let workspace_roots = key_workspace_roots(&config, &server, workspace_roots).await;
+
+        // Select language server instances for this client, for every workspace
+        // root one for the requested server followed by its companions, then
+        // its replica and background instance if any methods are assigned to
+        // them. The first one is the primary which answers the `initialize`
+        // request.
+        let companions = companion_servers(&config, &server);
+        let mut keys = Vec::new();
+        for (workspace_root, group, init_params) in workspace_roots {
+            let start = keys.len();
+            let servers = [(&server, &args)]
+                .into_iter()
+                .chain(companions.iter().map(|c| (&c.server, &c.args)));
+            for (server, args) in servers {
+                let server = if let Some(spec) = server.strip_prefix(download::MANAGED_PREFIX) {
+                    // The editor only sees the `initialize` request hang
+                    // otherwise, downloads can take a while.
+                    if download::is_missing(&config, spec).await {
+                        let notif = Notification {
+                            jsonrpc: Version,
+                            method: "window/showMessage".into(),
+                            params: json!({
+                                // Info
+                                "type": 3,
+                                "message": format!("ra-multiplex: downloading {spec}"),
+                            }),
+                        };
+                        let _ = writer.write_message(&notif.into()).await;
+                    }
+                    download::resolve(&config, spec).await?
+                } else if config.rust_toolchain {
+                    toolchain::resolve_server(server, &workspace_root).await
+                } else {
+                    server.clone()
+                };
+                let server = resolve::resolve_server(&config, &server, &env).await;
+                let mut init_params = init_params.clone();
+                configure_initialize_params(&config, &server, &mut init_params);
+                let key = InstanceKey {
+                    server,
+                    args: args.clone(),
+                    env: env.clone(),
+                    workspace_root: workspace_root.clone(),
+                    role: InstanceRole::Primary,
+                    group: group.clone(),
+                    tag: tag.clone(),
+                };
+                keys.push((key, init_params));
+            }
+            // Companions answer their requests with the primary instance.
+            for role in config.secondary_roles() {
+                let (key, init_params) = &keys[start];
+                let key = InstanceKey {
+                    role,
+                    ..key.clone()
+                };
+                keys.push((key, init_params.clone()));
+            }
+        }
+
+        // With `lazy_spawn` the instances are spawned only once the client sends
+        // something after initialization, until then it's answered with the
+        // result of the same server spawned earlier.
+        let lazy_init_result = match config.lazy_spawn {
+            true => instance_map.lock().await.lazy_init_result(&keys[0].0),
+            false => None,
+        };
+        let (instances, init_result) = match lazy_init_result {
+            Some(init_result) => (Vec::new(), init_result),
+            None => {
+                let instances = spawn_instances(&instance_map, &keys).await?;
+                let init_result = instances[0].initialize_result();
+                (instances, init_result)
+            }
+        };
+        Ok::<_, anyhow::Error>((keys, instances, init_result))
+    }
+    .await;
+    let (keys, mut instances, init_result) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            let code = err.downcast_ref::<ErrorCode>().copied();
+            let code = code.unwrap_or(ErrorCode::SpawnFailed);
+            let mut message = format!("{err:#}");
+            let mut data = None;
+            // The editor would only show a failed initialization otherwise,
+            // tell the user what we tried to execute and where we looked.
+            if let Some(spawn) = err.downcast_ref::<instance::SpawnError>() {
+                message = format!("{code}: {spawn}");
+                data = Some(json!({
+                    "program": spawn.program,
+                    "path": spawn.path,
+                    "error": spawn.source.to_string(),
+                }));
+                let _ = writer.write_message(&show_error(&message).into()).await;
+            }
+            let res = ResponseError {
+                jsonrpc: Version,
+                error: jsonrpc::Error {
+                    code: code.code(),
+                    message,
+                    data,
+                },
+                id: req.id,
+            };
+            let _ = writer.write_message(&res.into()).await;
+            return Err(err);
+        }
+    };
+
+    // Respond to client's `initialize` request using a response result from
+    // the first time this server instance was initialized, it might not be
+    // a response directly to our previous request but it should be hopefully
+    // similar if it comes from another instance of the same client.
+    let mut result = serde_json::to_value(init_result).unwrap();
+    let settings = config.server_settings(&keys[0].0.server);
+    if let Some(patch) = settings.and_then(|settings| settings.server_capabilities.as_ref()) {
+        merge_patch(&mut result["capabilities"], patch);
+    }
+    // Issue a reconnect token if the client asks for one and we keep sessions.
+    let resumable = match (resumable, config.reconnect_timeout) {
+        (true, Some(secs)) => Some(Resumable::register(
+            &state.sessions,
+            client_id,
+            Duration::from_secs(secs.into()),
+        )),
+        _ => None,
+    };
+    if let Some((resumable, _)) = &resumable {
+        result["lspMux"] = serde_json::to_value(resumable.session()).unwrap();
+    }
+    // Tell the proxy the compression we settled on.
+    if !compression.is_off() {
+        result["lspMux"]["compression"] = serde_json::to_value(compression).unwrap();
+    }
+    let (resumable, rebinds) = resumable.unzip();
+    let res = ResponseSuccess {
+        jsonrpc: Version,
+        result,
+        id: req.id,
+    };
+    writer
+        .write_message(&res.into())
+        .await
+        .context("send `initialize` request response")?;
+    writer.set_compression(compression);
+
+    // Wait for the client to send `initialized` notification. We don't want to
+    // forward it since the server only expects one and we already sent a fake
+    // one during the server handshake. Heartbeats the proxy sent while we
+    // were spawning the instance are answered in the meantime.
+    loop {
+        match reader
+            .read_message()
+            .await
+            .context("receive `initialized` notification")?
+            .context("channel closed")?
+        {
+            Message::Notification(notif) if notif.method == ext::HEARTBEAT_PING => {
+                writer
+                    .write_message(&heartbeat_pong().into())
+                    .await
+                    .context("send heartbeat pong")?;
+            }
+            Message::Notification(notif) if notif.method == "initialized" => {
+                // Discard the notification.
+                break;
+            }
+            _ => bail!("second client message was not `initialized` notification"),
+        }
+    }
+    info!("initialized client");
+
+    let mut first_message = None;
+    if instances.is_empty() {
+        let Some(message) = wait_for_first_message(&mut reader, &mut writer).await? else {
+            info!("client disconnected before spawning the server");
+            return Ok(());
+        };
+        instances = match spawn_instances(&instance_map, &keys).await {
+            Ok(instances) => instances,
+            Err(err) => {
+                // The client already got its `initialize` response, tell the
+                // user why nothing works and fail the request waiting for it.
+                let code = err.downcast_ref::<ErrorCode>().copied();
+                let code = code.unwrap_or(ErrorCode::SpawnFailed);
+                let error = match err.downcast_ref::<instance::SpawnError>() {
+                    Some(spawn) => format!("{code}: {spawn}"),
+                    None => format!("{code}: {err:#}"),
+                };
+                let _ = writer.write_message(&show_error(&error).into()).await;
+                if let Message::Request(req) = message {
+                    let res = ResponseError {
+                        jsonrpc: Version,
+                        error: jsonrpc::Error {
+                            code: code.code(),
+                            message: error,
+                            data: None,
+                        },
+                        id: req.id,
+                    };
+                    let _ = writer.write_message(&res.into()).await;
+                }
+                return Err(err);
+            }
+        };
+        first_message = Some(message);
+    }
+
+    let mut hook_vars = instances[0].hook_vars();
+    hook_vars.push(("LSPMUX_CLIENT_ID", client_id.to_string()));
+    hooks::run(&config.hooks, Event::ClientConnect, &hook_vars);
+    for instance in &instances {
+        let attached = events::client(EventKind::ClientAttached, client_id, instance);
+        state.events.publish(attached);
+    }
+
+    let (mut client, client_rx) = Client::new((client_id, uid), instances.len() > 1, mode, follow);
+    client.init_workspace_folders(&init_params);
+    client.work_done_progress = init_params
+        .capabilities
+        .as_ref()
+        .is_some_and(|capabilities| capabilities["window"]["workDoneProgress"] == true);
+    if let Some(options) = &init_params.initialization_options {
+        client.settings = Arc::new(options.other_options.clone());
+    }
+    client.aliases = Arc::new(aliases);
+    let limiter = NotificationLimiter::new(&config.notification_rate_limits);
+    let merges = client.merges.clone();
+    let aliases = client.aliases.clone();
+    let chaos = injector(&state);
+    // The proxy doesn't count the `initialize` response.
+    let resume = rebinds.map(|rebinds| {
+        let replay = Replay::new(writer.messages() - 1, config.reconnect_buffer);
+        (rebinds, replay)
+    });
+    task::spawn(
+        input_task(client_rx, writer, limiter, merges, resume, aliases, chaos).in_current_span(),
+    );
+    for instance in &instances {
+        instance.add_client(client.clone()).await;
+    }
+    state.audit.connect(client_id, mode, &instances);
+
+    // Catch up with the documents the followed client already has open.
+    if let Some(followed) = follow {
+        let mut files = BTreeSet::new();
+        for instance in &instances {
+            files.extend(instance.client_files(followed).await);
+        }
+        for uri in files {
+            let notif = followed_document(ext::DOCUMENT_OPENED, followed, uri);
+            let _ = client.send_message(notif.into()).await;
+        }
+    }
+
+    task::spawn(
+        output_task(
+            reader,
+            first_message,
+            client,
+            instances,
+            config,
+            state,
+            resumable,
+        )
+        .in_current_span(),
+    );
+
+    Ok(())
+}
+
+/// `window/showMessage` notification showing an error to the user
+fn show_error(message: &str) -> Notification {
+    Notification {
+        jsonrpc: Version,
+        method: "window/showMessage".into(),
+        params: json!({
+            // Error
+            "type": 1,
+            "message": format!("ra-multiplex: {message}"),
+        }),
+    }
+}
+
+/// Get or spawn the instances for `keys`
+async fn spawn_instances(
+    instance_map: &Arc<Mutex<InstanceMap>>,
+    keys: &[(InstanceKey, InitializeParams)],
+) -> Result<Vec<Arc<Instance>>> {
+    let mut instances = Vec::new();
+    for (key, init_params) in keys {
+        let instance =
+            instance::get_or_spawn(instance_map.clone(), key.clone(), init_params.clone()).await?;
+        instances.push(instance);
+    }
+    Ok(instances)
+}
+
+/// Wait for the first client message after initialization before the
+/// instances are spawned for it with `lazy_spawn`
+///
+/// Heartbeats are answered in the meantime and a `shutdown` request right
+/// away. Returns `None` if the client disconnects first.
+async fn wait_for_first_message(
+    reader: &mut LspReader<BufReader<OwnedReadHalf>>,
+    writer: &mut LspWriter<OwnedWriteHalf>,
+) -> Result<Option<Message>> {
+    loop {
+        let message = match reader.read_message().await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                // Only rejected messages leave the stream in a state where
+                // the next one can be read.
+                let Some(res) = rejected_response(&err) else {
+                    return Err(err.context("reading the client's first message"));
+                };
+                let _ = writer.write_message(&res.into()).await;
+                continue;
+            }
+        };
+        match message {
+            Message::Notification(notif) if notif.method == ext::HEARTBEAT_PING => {
+                writer
+                    .write_message(&heartbeat_pong().into())
+                    .await
+                    .context("send heartbeat pong")?;
+            }
+            Message::Request(req) if req.method == "shutdown" => {
+                let res = ResponseSuccess::null(req.id);
+                let _ = writer.write_message(&res.into()).await;
+                return Ok(None);
+            }
+            message => return Ok(Some(message)),
+        }
+    }
+}
+
+/// Select workspace roots and `initialize` params for the instances the client
+/// connects to
+///
+/// With `meta_instances` enabled there is an instance for every workspace
+/// folder, each one only knowing about its own folder. Otherwise there is a
+/// single instance.
+///
+/// Roots are canonicalized, `aliases` get the client's spelling of those which
+/// differ.
+async fn select_workspace_roots(
+    init_params: &InitializeParams,
+    proxy_cwd: Option<&str>,
+    config: &Config,
+    aliases: &mut UriAliases,
+) -> Result<Vec<(String, InitializeParams)>> {
+    if config.meta_instances && init_params.workspace_folders.len() > 1 {
+        let mut roots = Vec::new();
+        for (index, folder) in init_params.workspace_folders.iter().enumerate() {
+            let root = parse_file_uri(&folder.uri)
+                .with_context(|| format!("parse initParams.workspaceFolders[{index}].uri"))?;
+            let mut init_params = init_params.clone();
+            init_params.workspace_folders = vec![folder.clone()];
+            init_params.root_uri = Some(folder.uri.clone());
+            init_params.root_path = Some(root.clone());
+            roots.push(canonicalize_root(root, init_params, aliases).await);
+        }
+        return Ok(roots);
+    }
+
+    let root = select_workspace_root(init_params, proxy_cwd)?;
+    Ok(vec![
+        canonicalize_root(root, init_params.clone(), aliases).await,
+    ])
+}
+
+/// Canonicalize a workspace `root` and the URIs inside it in `init_params`
+///
+/// The client's spellings of the root are added to `aliases`, the server only
+/// ever sees the canonical one.
+async fn canonicalize_root(
+    root: String,
+    mut init_params: InitializeParams,
+    aliases: &mut UriAliases,
+) -> (String, InitializeParams) {
+    let canonical = canonical_workspace_root(root.clone()).await;
+    let Some(canonical_uri) = watcher::file_uri(Path::new(&canonical)) else {
+        return (canonical, init_params);
+    };
+    // Clients don't always percent-encode like we do.
+    let spellings = init_params
+        .workspace_folders
+        .iter()
+        .map(|folder| folder.uri.clone())
+        .chain(init_params.root_uri.clone())
+        .filter(|uri| parse_file_uri(uri).is_ok_and(|path| path == root))
+        .chain(watcher::file_uri(Path::new(&root)));
+    for uri in spellings {
+        aliases.add(&uri, &canonical_uri);
+    }
+    if !aliases.is_empty() {
+        let mut params = serde_json::to_value(&init_params).expect("BUG: invalid data");
+        aliases.rewrite(&mut params, Direction::ToServer);
+        init_params = serde_json::from_value(params).expect("BUG: invalid data");
+        if init_params.root_path.is_some() {
+            init_params.root_path = Some(canonical.clone());
+        }
+    }
+    (canonical, init_params)
+}
+
+/// Replace workspace roots with the directories instances are keyed by
+/// according to `instance_groups` and `workspace_keying`, together with the
+/// name of the instance group
+///
+/// The server is initialized with the directory it's keyed by as its only
+/// workspace folder. Roots which end up the same are merged.
+async fn key_workspace_roots(
+    config: &Config,
+    server: &str,
+    workspace_roots: Vec<(String, InitializeParams)>,
+) -> Vec<(String, Option<String>, InitializeParams)> {
+    let mut keyed = Vec::<(String, Option<String>, InitializeParams)>::new();
+    for (root, mut init_params) in workspace_roots {
+        let group = config.instance_group(Path::new(&root));
+        let key_root = match (group.as_ref(), config.workspace_keying) {
+            (Some((_, base)), _) => Some(base.display().to_string()),
+            (None, keying) => key_root(keying, server, &root).await,
+        };
+        let group = group.map(|(name, _)| name.to_owned());
+        let root = match key_root {
+            Some(key_root) if key_root != root => {
+                debug!(?root, ?key_root, "keying instance by another directory");
+                let uri = watcher::file_uri(Path::new(&key_root)).unwrap_or_default();
+                let name = Path::new(&key_root)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                init_params.root_uri = Some(uri.clone());
+                init_params.root_path = Some(key_root.clone());
+                init_params.workspace_folders = vec![WorkspaceFolder { uri, name }];
+                key_root
+            }
+            _ => root,
+        };
+        if keyed
+            .iter()
+            .any(|(other, other_group, _)| *other == root && *other_group == group)
+        {
+            debug!(?root, "workspace root already selected");
+            continue;
+        }
+        keyed.push((root, group, init_params));
+    }
+    keyed
+}
+
+/// Directory the instances for a workspace `root` are keyed by according to
+/// `keying`, `None` if that's the root itself
+async fn key_root(keying: WorkspaceKeying, server: &str, root: &str) -> Option<String> {
+    match keying {
+        WorkspaceKeying::Cargo if is_rust_analyzer(server) => {
+            Some(canonical_workspace_root(cargo::workspace_root(root).await?).await)
+        }
+        WorkspaceKeying::Git => Some(canonical_workspace_root(git::toplevel(root).await?).await),
+        WorkspaceKeying::Client | WorkspaceKeying::Cargo => None,
+    }
+}
+
+/// Check if `server` as requested by the client is rust-analyzer, possibly a
+/// managed one
+fn is_rust_analyzer(server: &str) -> bool {
+    let server = server
+        .strip_prefix(download::MANAGED_PREFIX)
+        .unwrap_or(server);
+    let name = Path::new(server).file_stem().and_then(OsStr::to_str);
+    name.is_some_and(|name| name.split('@').next() == Some("rust-analyzer"))
+}
+
+/// Normalize a workspace root so different spellings of the same directory
+/// select the same instance
+///
+/// Trailing slashes and `.` components are removed and symlinks resolved. On
+/// macOS, whose file systems usually ignore case, the case of every component
+/// is taken from its directory. A root which doesn't exist is only cleaned up.
+async fn canonical_workspace_root(root: String) -> String {
+    let path = Path::new(&root).components().collect::<PathBuf>();
+    let canonical = match fs::canonicalize(&path).await {
+        Ok(canonical) => canonical,
+        Err(err) => {
+            debug!(?err, ?path, "cannot canonicalize workspace root");
+            return path.into_os_string().into_string().unwrap_or(root);
+        }
+    };
+    #[cfg(target_os = "macos")]
+    let canonical = on_disk_case(&canonical).await;
+    let Ok(canonical) = canonical.into_os_string().into_string() else {
+        return root;
+    };
+    // Windows returns verbatim paths which not every server understands.
+    match canonical.strip_prefix(r"\\?\") {
+        Some(path) if !path.starts_with(r"UNC\") => path.to_owned(),
+        _ => canonical,
+    }
+}
+
+/// Replace each component of an existing path with the name it has in its
+/// directory, which differs in case if the file system is case-insensitive
+#[cfg(target_os = "macos")]
+async fn on_disk_case(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        let name = component.as_os_str();
+        let mut entries = Vec::new();
+        if let Ok(mut dir) = fs::read_dir(&result).await {
+            while let Ok(Some(entry)) = dir.next_entry().await {
+                entries.push(entry.file_name());
+            }
+        }
+        let on_disk = entries.iter().find(|entry| *entry == name).or_else(|| {
+            entries
+                .iter()
+                .find(|entry| entry.eq_ignore_ascii_case(name))
+        });
+        result.push(on_disk.map_or(name, |entry| entry.as_os_str()));
+    }
+    result
+}
+
+fn select_workspace_root<'a>(
+    init_params: &'a InitializeParams,
+    proxy_cwd: Option<&'a str>,
+) -> Result<String> {
+    if init_params.workspace_folders.len() > 1 {
+        // TODO Ideally we'd be looking up any server which has a superset of
+        // workspace folders active possibly adding transforming the `initialize`
+        // request into a few requests for adding workspace folders if the
+        // server supports it. Buuut let's just run with supporting single-folder
+        // workspaces only at first, it's probably the most common use-case anyway.
+        warn!(
+            "initialize request with multiple workspace folders isn't supported \
+            without `meta_instances`, using the first one"
+        );
+        debug!(workspace_folders = ?init_params.workspace_folders);
+    }
+
+    if let Some(folder) = init_params.workspace_folders.first() {
+        return parse_file_uri(&folder.uri).context("parse initParams.workspaceFolders[0].uri");
+    }
+
+    // Using the deprecated LSP fields `rootPath` or `rootUri` as fallback
+    if let Some(root_uri) = &init_params.root_uri {
+        return parse_file_uri(root_uri).context("parse initParams.rootUri");
+    }
+    if let Some(root_path) = &init_params.root_path {
+        return Ok(root_path.to_owned());
+    }
+
+    // Using the proxy `cwd` as fallback
+    if let Some(proxy_cwd) = proxy_cwd {
+        return Ok(proxy_cwd.to_owned());
+    }
+
+    bail!("could not determine a suitable workspace_root");
+}
+
+/// Parse a file path as String out of a LSP `URI` type.
+pub fn parse_file_uri(uri: &str) -> Result<String> {
+    let (scheme, _, mut path, _, _) = URI::try_from(uri)
+        .context("failed to parse URI")?
+        .into_parts();
+
+    if scheme != uriparse::Scheme::File {
+        bail!("only `file://` URIs are supported");
+    }
+
+    path.normalize(false);
+
+    let path = percent_decode_str(&path.to_string())
+        .decode_utf8()
+        .context("decoded URI was not valid utf-8")?
+        .to_string();
+
+    Ok(path)
+}
+
+/// Companion servers configured for the client's `server`, matched either by
+/// the full path or only by the executable name
+fn companion_servers<'a>(config: &'a Config, server: &str) -> &'a [CompanionServer] {
+    let name = Path::new(server).file_name().and_then(OsStr::to_str);
+    config
+        .companion_servers
+        .get(server)
+        .or_else(|| config.companion_servers.get(name?))
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+/// Combine the `initializationOptions` sent to `server` with the ones
+/// configured for it and patch the client capabilities
+fn configure_initialize_params(config: &Config, server: &str, init_params: &mut InitializeParams) {
+    let Some(settings) = config.server_settings(server) else {
+        return;
+    };
+    if let Some(patch) = &settings.client_capabilities {
+        let capabilities = init_params.capabilities.get_or_insert_with(|| json!({}));
+        merge_patch(capabilities, patch);
+    }
+    let Some(options) = &settings.initialization_options else {
+        return;
+    };
+    let init_options = init_params
+        .initialization_options
+        .get_or_insert_with(Default::default);
+    match settings.initialization_options_mode {
+        OptionsMode::Merge => {
+            let mut merged = Value::Object(mem::take(&mut init_options.other_options));
+            merge_patch(&mut merged, &Value::Object(options.clone()));
+            if let Value::Object(merged) = merged {
+                init_options.other_options = merged;
+            }
+        }
+        OptionsMode::Replace => init_options.other_options = options.clone(),
+    }
+    debug!(?server, "configured initialization options");
+}
+
+/// Instances answering requests sent to instances of `role`
+///
+/// Only the requested server gets replica and background instances, its
+/// companions answer with their primary instance.
+fn answering_instances(instances: &[Arc<Instance>], role: InstanceRole) -> Vec<Arc<Instance>> {
+    let has_role = |instance: &Instance| {
+        instances.iter().any(|other| {
+            other.role() == role
+                && InstanceKey {
+                    role: instance.role(),
+                    ..other.key().clone()
+                } == *instance.key()
+        })
+    };
+    instances
+        .iter()
+        .filter(|instance| {
+            instance.role() == role
+                || (instance.role() == InstanceRole::Primary && !has_role(instance))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Select the instances responsible for the document in a message `params`
+///
+/// Those are the ones with the longest workspace root containing the document,
+/// the main server's instance first followed by its companions. Messages
+/// without a document or documents outside of any workspace root go to the
+/// primary instance and its companions.
+fn instances_for_document<'a>(
+    instances: &'a [Arc<Instance>],
+    params: &Value,
+) -> Vec<&'a Arc<Instance>> {
+    let Some(primary) = instances.first() else {
+        return Vec::new();
+    };
+    let primary_root = primary.workspace_root();
+    let path = params
+        .pointer("/textDocument/uri")
+        .and_then(Value::as_str)
+        .and_then(|uri| parse_file_uri(uri).ok());
+    let root = path
+        .and_then(|path| {
+            instances
+                .iter()
+                .map(|instance| instance.workspace_root())
+                .filter(|root| Path::new(&path).starts_with(root))
+                .max_by_key(|root| root.len())
+        })
+        .unwrap_or(primary_root);
+    instances
+        .iter()
+        .filter(|instance| instance.workspace_root() == root)
+        .collect()
+}
+
+/// Receive messages from channel and write them to the client input socket
+///
+/// Notifications are throttled by the `limiter` before they're written. If
+/// the client is connected to multiple instances responses are collected in
+/// `merges` until all instances respond.
+///
+/// Messages to a resumable client are kept in its `Replay` for resending,
+/// they're buffered while its connection is lost until one of the `rebinds`
+/// replaces it.
+async fn input_task(
+    mut rx: mpsc::Receiver<Message>,
+    writer: LspWriter<OwnedWriteHalf>,
+    mut limiter: NotificationLimiter,
+    merges: Option<Merges>,
+    resume: Option<(mpsc::Receiver<Rebind>, Replay)>,
+    aliases: Arc<UriAliases>,
+    mut chaos: Option<Injector>,
+) {
+    let (mut rebinds, mut replay) = resume.unzip();
+    let mut writer = Some(writer);
+    // The other end of this channel is held by the `output_task` _and_ in the
+    // `Instance` itself, this task depends on the `output_task` to detect a
+    // client disconnect and call `Instance::cleanup_client`, otherwise we're
+    // going to hang forever here.
+    'recv: loop {
+        let deadline = limiter.next_deadline();
+        let chaos_deadline = chaos.as_ref().and_then(Injector::next_deadline);
+        let mut released = false;
+        let mut messages = select! {
+            message = rx.recv() => match message {
+                Some(Message::Notification(notif)) => {
+                    limiter.check(notif, Instant::now()).map(Message::from).into_iter().collect()
+                }
+                Some(message) => match &merges {
+                    Some(merges) => merges.response(message).into_iter().collect(),
+                    None => vec![message],
+                },
+                None => {
+                    // Flush what was written and let the client see the end of
+                    // the stream.
+                    if let Some(writer) = &mut writer {
+                        let _ = writer.shutdown().await;
+                    }
+                    break;
+                }
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let due = limiter.take_due(Instant::now());
+                due.into_iter().map(Message::from).collect::<Vec<_>>()
+            }
+            _ = time::sleep_until(chaos_deadline.unwrap_or_else(Instant::now)), if chaos_deadline.is_some() => {
+                released = true;
+                let chaos = chaos.as_mut().unwrap();
+                chaos.take_due(Instant::now()).into_iter().collect()
+            }
+            rebind = next_rebind(rebinds.as_mut()) => {
+                match rebind {
+                    Some(rebind) => {
+                        let replay = replay.as_ref().expect("BUG: rebind without replay");
+                        writer = resend(rebind, replay).await;
+                    }
+                    // The client is gone for good.
+                    None if writer.is_none() => break,
+                    None => rebinds = None,
+                }
+                continue;
+            }
+        };
+
+        // Faults are injected last so they hit the messages as the client
+        // would receive them, released notifications were injected already.
+        if let Some(chaos) = chaos.as_mut().filter(|_| !released) {
+            let now = Instant::now();
+            messages = messages
+                .into_iter()
+                .flat_map(|message| chaos.inject(message, now))
+                .collect();
+            if !messages.is_empty() {
+                time::sleep(chaos.delay()).await;
+            }
+        }
+        for mut message in messages {
+            aliases.rewrite_message(&mut message, Direction::ToClient);
+            if let Some(connection) = &mut writer {
+                if let Err(err) = connection.write_message(&message).await {
+                    match err.kind() {
+                        // ignore benign errors, treat as socket close
+                        ErrorKind::BrokenPipe => {}
+                        // report fatal errors
+                        _ => error!(?err, "error writing client input: {err}"),
+                    }
+                    if rebinds.is_none() {
+                        break 'recv; // break on any error
+                    }
+                    debug!("client connection lost, buffering messages until it reconnects");
+                    writer = None;
+                }
+            }
+            if let Some(replay) = replay.as_mut().filter(|_| rebinds.is_some()) {
+                replay.push(message);
+            }
+        }
+    }
+    debug!("client input closed");
+    info!("client disconnected");
+}
+
+/// Injector of the faults the server was started with, `None` if disabled
+fn injector(state: &ServerState) -> Option<Injector> {
+    let chaos = state.chaos?;
+    Some(Injector::new(chaos, state.redaction.clone()))
+}
+
+/// Receive the next connection of a resumable client, never finishes for
+/// other clients
+async fn next_rebind(rebinds: Option<&mut mpsc::Receiver<Rebind>>) -> Option<Rebind> {
+    match rebinds {
+        Some(rebinds) => rebinds.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Answer the handshake of a reconnected client and resend the messages it
+/// missed, returns the new writer
+async fn resend(rebind: Rebind, replay: &Replay) -> Option<LspWriter<OwnedWriteHalf>> {
+    let Rebind {
+        id,
+        session,
+        received,
+        compression,
+        mut writer,
+    } = rebind;
+    let Some(missed) = replay.since(received) else {
+        warn!(
+            received,
+            kept = replay.start(),
+            "messages the client missed aren't kept anymore"
+        );
+        let res = ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: -32803, // RequestFailed
+                message: "missed messages aren't kept anymore".into(),
+                data: None,
+            },
+            id,
+        };
+        let _ = writer.write_message(&res.into()).await;
+        return None;
+    };
+    let mut result = json!({ "lspMux": session });
+    if !compression.is_off() {
+        result["lspMux"]["compression"] = serde_json::to_value(compression).unwrap();
+    }
+    let res = ResponseSuccess {
+        jsonrpc: Version,
+        result,
+        id,
+    };
+    writer.write_message(&res.into()).await.ok()?;
+    writer.set_compression(compression);
+    for message in missed {
+        writer.write_message(message).await.ok()?;
+    }
+    info!(received, "client reconnected");
+    Some(writer)
+}
+
+async fn broadcast_answered(
+    instances: &[Arc<Instance>],
+    pid: u32,
+    client_id: usize,
+    id: &RequestId,
+) {
+    match instances.iter().find(|instance| instance.pid() == pid) {
+        Some(instance) => instance.broadcast_answered(client_id, Some(id)).await,
+        None => debug!(?id, pid, "no matching instance"),
+    }
+}
+
+async fn apply_edit_answered(
+    instances: &[Arc<Instance>],
+    pid: u32,
+    client_id: usize,
+    id: &RequestId,
+    result: Result<ApplyWorkspaceEditResult, String>,
+) {
+    match instances.iter().find(|instance| instance.pid() == pid) {
+        Some(instance) => {
+            instance
+                .apply_edit_answered(client_id, Some(id), result)
+                .await
+        }
+        None => debug!(?id, pid, "no matching instance"),
+    }
+}
+
+fn followed_document(method: &str, client_id: usize, uri: String) -> Notification {
+    Notification {
+        jsonrpc: Version,
+        method: method.into(),
+        params: serde_json::to_value(ext::FollowedDocument { client_id, uri }).unwrap(),
+    }
+}
+
+/// Notify clients following `client_id` it opened or closed documents
+///
+/// Followers connected to more than one of the instances are notified once.
+async fn notify_followers(
+    instances: &[Arc<Instance>],
+    client_id: usize,
+    method: &str,
+    uris: &[String],
+) {
+    let mut followers = BTreeMap::new();
+    for instance in instances {
+        for follower in instance.followers(client_id).await {
+            followers.insert(follower.id(), follower);
+        }
+    }
+    for follower in followers.values() {
+        for uri in uris {
+            let notif = followed_document(method, client_id, uri.clone());
+            let _ = follower.send_message(notif.into()).await;
+        }
+    }
+}
+
+/// Log a client output reading error, returns the error response for a
+/// request rejected for exceeding the message limits
+fn rejected_response(err: &anyhow::Error) -> Option<ResponseError> {
+    let Some(rejected) = err.downcast_ref::<jsonrpc::Rejected>() else {
+        error!(?err, "error reading client output");
+        return None;
+    };
+    warn!(reason = rejected.reason, id = ?rejected.id, "rejected client message");
+    Some(ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            code: -32600, // InvalidRequest
+            message: rejected.to_string(),
+            data: None,
+        },
+        id: rejected.id.clone()?,
+    })
+}
+
+/// Wait for responses to the requests of a client which closed only its
+/// sending side, unless its receiving side is closed too
+async fn wait_for_responses(client: &Client, instances: &[Arc<Instance>]) {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        // Listen before counting so a response in between isn't missed.
+        let responded = client.responded.notified();
+        tokio::pin!(responded);
+        responded.as_mut().enable();
+        let mut pending = 0;
+        for instance in instances {
+            pending += instance.client_requests(client.id).await;
+        }
+        if pending == 0 {
+            return;
+        }
+        select! {
+            _ = responded => {}
+            _ = client.sender.closed() => break,
+            _ = time::sleep_until(deadline) => break,
+        }
+    }
+    debug!("gave up waiting for responses to a closed client");
+}
+
+fn heartbeat_pong() -> Notification {
+    Notification {
+        jsonrpc: Version,
+        method: ext::HEARTBEAT_PONG.into(),
+        params: Value::Null,
+    }
+}
+
+enum Read {
+    Message(Result<Option<Message>>),
+    /// The connection was lost and the client didn't reconnect
+    Lost,
+}
+
+/// Cancel request `id` of `client` on the instances it was sent to
+///
+/// The instances only know the request by its tagged ID, a merged request
+/// was sent to each one with the instance index in it.
+async fn cancel_request(client: &Client, instances: &[Arc<Instance>], id: RequestId) {
+    let merged = client
+        .merges
+        .as_ref()
+        .and_then(|merges| merges.instances(&id));
+    let ids = match merged {
+        Some(count) => (0..count).map(|index| id.tag(Tag::Merge(index))).collect(),
+        None => vec![id],
+    };
+    for instance in instances {
+        for id in &ids {
+            instance
+                .cancel_request(id.tag(Tag::ClientId(client.id)))
+                .await;
+        }
+    }
+}
+
+/// Read the next client message, `heartbeat` is the timeout once the client
+/// sent a heartbeat ping
+///
+/// A resumable client which loses its connection is waited for to reconnect.
+/// It can also reconnect before we notice, the `reader` is replaced by the one
+/// of the new connection either way.
+async fn read_message(
+    reader: &mut LspReader<BufReader<OwnedReadHalf>>,
+    heartbeat: &mut Option<Duration>,
+    mut resumable: Option<&mut Resumable>,
+) -> Read {
+    loop {
+        let timeout = *heartbeat;
+        let read = async {
+            match timeout {
+                Some(timeout) => time::timeout(timeout, reader.read_message()).await.ok(),
+                None => Some(reader.read_message().await),
+            }
+        };
+        let reconnect = async {
+            match resumable.as_deref_mut() {
+                Some(resumable) => resumable.next().await,
+                None => std::future::pending().await,
+            }
+        };
+        let reconnect = select! {
+            message = read => {
+                let lost = match message {
+                    Some(Ok(None)) => Read::Message(Ok(None)),
+                    Some(message) => return Read::Message(message),
+                    None => {
+                        warn!(?timeout, "client heartbeat timed out");
+                        Read::Lost
+                    }
+                };
+                let Some(resumable) = resumable.as_deref_mut() else {
+                    return lost;
+                };
+                match resumable.wait().await {
+                    Some(reconnect) => reconnect,
+                    None => {
+                        info!("client didn't reconnect in time");
+                        return Read::Lost;
+                    }
+                }
+            }
+            reconnect = reconnect => reconnect,
+        };
+        let resumable = resumable.as_deref_mut().unwrap();
+        match resumable.rebind(reader, reconnect).await {
+            Some(new) => *reader = new,
+            None => return Read::Lost,
+        }
+        // The new connection starts heartbeats anew.
+        *heartbeat = None;
+    }
+}
+
+/// Read messages from client output socket and send them to the server channel
+///
+/// Once the client sends its first heartbeat ping the client is disconnected
+/// if it doesn't send anything for `heartbeat_timeout`. Clients with disabled
+/// heartbeats are never timed out. A `first_message` read while waiting to
+/// spawn the instances is handled before anything else.
+async fn output_task(
+    mut reader: LspReader<BufReader<OwnedReadHalf>>,
+    mut first_message: Option<Message>,
+    client: Client,
+    mut instances: Vec<Arc<Instance>>,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    mut resumable: Option<Resumable>,
+) {
+    let heartbeat_timeout = Duration::from_secs(config.heartbeat_timeout.into());
+    let mut heartbeat = None;
+    let mut quota = RequestQuota::new(&config, Instant::now());
+    let mut chaos = injector(&state);
+    'read: loop {
+        let message = if let Some(message) = first_message.take() {
+            Ok(Some(message))
+        } else {
+            match read_message(&mut reader, &mut heartbeat, resumable.as_mut()).await {
+                Read::Message(message) => message,
+                Read::Lost => break,
+            }
+        };
+        let message = match message {
+            Ok(Some(mut message)) => {
+                client
+                    .aliases
+                    .rewrite_message(&mut message, Direction::ToServer);
+                message
+            }
+            Ok(None) => {
+                debug!("client output closed");
+                wait_for_responses(&client, &instances).await;
+                break;
+            }
+            Err(err) => {
+                if let Some(res) = rejected_response(&err) {
+                    if client.send_message(res.into()).await.is_err() {
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+        if let Some(chaos) = &mut chaos {
+            time::sleep(chaos.delay()).await;
+        }
+        follow_merges(&mut instances);
+        for instance in &instances {
+            instance.keep_alive();
+        }
+
+        match message {
+            Message::Notification(notif) if notif.method == ext::HEARTBEAT_PING => {
+                heartbeat = Some(heartbeat_timeout);
+                if client.send_message(heartbeat_pong().into()).await.is_err() {
+                    break;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == ext::END_SESSION => {
+                // The client is closing the connection, it won't come back.
+                resumable = None;
+            }
+
+            Message::Request(req) if req.method == "shutdown" => {
+                // Client requested the server to shut down but other clients might still be connected.
+                // Instead we disconnect this client to prevent the editor hanging
+                // see <https://github.com/pr2502/ra-multiplex/issues/5>.
+                info!("client sent shutdown request, sending a response and closing connection");
+
+                // <https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#shutdown>
+                let res = ResponseSuccess::null(req.id);
+                // Ignoring error because we would've closed the connection regardless
+                let _ = client.send_message(res.into()).await;
+                break;
+            }
+
+            Message::Request(req) => {
+                let mut in_flight = 0;
+                if quota.limits_in_flight() {
+                    for instance in &instances {
+                        in_flight += instance.client_requests(client.id).await;
+                    }
+                }
+                if let Err(reason) = quota.check(in_flight, Instant::now()) {
+                    debug!(?req.id, reason, "client exceeded its request quota");
+                    let res = ResponseError {
+                        jsonrpc: Version,
+                        error: jsonrpc::Error {
+                            code: -32803, // RequestFailed
+                            message: format!("server busy: {reason}"),
+                            data: None,
+                        },
+                        id: req.id,
+                    };
+                    if client.send_message(res.into()).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                state.audit.request(client.id, &req);
+                state.stats.request();
+
+                let answering = answering_instances(&instances, config.request_role(&req.method));
+                // Unlike document notifications requests only go to the main
+                // server, companions only answer requests which are merged.
+                let strategy = merge::strategy(&config, &req.method);
+                let strategy = strategy.filter(|_| answering.len() > 1);
+                if let (Some(strategy), Some(merges)) = (strategy, &client.merges) {
+                    // Ask all instances and merge their responses.
+                    if !merges.start(req.id.clone(), strategy, answering.len()) {
+                        warn!(?req.id, "client reused the ID of a pending request");
+                        if client.send_message(id_in_use(req.id).into()).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    for (index, instance) in answering.iter().enumerate() {
+                        let mut req = req.clone();
+                        req.id = req.id.tag(Tag::Merge(index));
+                        if instance.send_request(client.id, req).await.is_err() {
+                            break 'read;
+                        }
+                    }
+                } else if let Some(instance) =
+                    instances_for_document(&answering, &req.params).first()
+                {
+                    if instance.send_request(client.id, req).await.is_err() {
+                        break;
+                    }
+                } else {
+                    warn!(?req.id, "no instance to answer the request");
+                    let res = ResponseError {
+                        jsonrpc: Version,
+                        error: jsonrpc::Error {
+                            code: -32803, // RequestFailed
+                            message: "no language server instance".into(),
+                            data: None,
+                        },
+                        id: req.id,
+                    };
+                    if client.send_message(res.into()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            Message::ResponseSuccess(mut res) => match res.id.untag() {
+                (Some(Tag::Forward(pid)), id) => {
+                    res.id = id;
+                    let Some(instance) = instances.iter().find(|instance| instance.pid() == pid)
+                    else {
+                        debug!(res = ?Logged(&res, &state.redaction), pid, "no matching instance");
+                        continue;
+                    };
+                    if instance.forward_response(res).await.is_err() {
+                        break;
+                    }
+                }
+                (Some(Tag::Broadcast(pid, _)), id) => {
+                    broadcast_answered(&instances, pid, client.id, &id).await;
+                }
+                (Some(Tag::ApplyEdit(pid, _)), id) => {
+                    let result = serde_json::from_value(res.result)
+                        .map_err(|err| format!("invalid response: {err}"));
+                    state.audit.apply_edit_answered(client.id, pid, &result);
+                    apply_edit_answered(&instances, pid, client.id, &id, result).await;
+                }
+                (Some(Tag::Drop), _) => {
+                    // Drop the message
+                }
+                _ => {
+                    debug!(res = ?Logged(&res, &state.redaction), "unexpected client response");
+                }
+            },
+
+            Message::ResponseError(mut res) => {
+                warn!(res = ?Logged(&res, &state.redaction), "client responded with error");
+                match res.id.untag() {
+                    (Some(Tag::Forward(pid)), id) => {
+                        // The server is waiting for the answer of this client
+                        // only, it needs the error.
+                        res.id = id;
+                        let Some(instance) =
+                            instances.iter().find(|instance| instance.pid() == pid)
+                        else {
+                            continue;
+                        };
+                        if instance.forward_error(res).await.is_err() {
+                            break;
+                        }
+                    }
+                    (Some(Tag::Broadcast(pid, _)), id) => {
+                        // The server still needs a response, errors of
+                        // broadcast requests don't matter to it.
+                        broadcast_answered(&instances, pid, client.id, &id).await;
+                    }
+                    (Some(Tag::ApplyEdit(pid, _)), id) => {
+                        let result = Err(res.error.message);
+                        state.audit.apply_edit_answered(client.id, pid, &result);
+                        apply_edit_answered(&instances, pid, client.id, &id, result).await;
+                    }
+                    _ => {}
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "textDocument/didOpen" => {
+                for instance in instances_for_document(&instances, &notif.params) {
+                    if client.is_observer() && instance.document_diverges(&notif.params).await {
+                        debug!("dropping observer didOpen with diverging content");
+                        continue;
+                    }
+                    if let Err(err) = instance.open_file(client.id, notif.params.clone()).await {
+                        warn!(?err, "error opening file");
+                    }
+                }
+                let uri = notif.params.pointer("/textDocument/uri");
+                if let Some(uri) = uri.and_then(Value::as_str) {
+                    let uris = [uri.to_owned()];
+                    notify_followers(&instances, client.id, ext::DOCUMENT_OPENED, &uris).await;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "textDocument/didClose" => {
+                for instance in instances_for_document(&instances, &notif.params) {
+                    if let Err(err) = instance.close_file(client.id, notif.params.clone()).await {
+                        warn!(?err, "error closing file");
+                    }
+                }
+                let uri = notif.params.pointer("/textDocument/uri");
+                if let Some(uri) = uri.and_then(Value::as_str) {
+                    let uris = [uri.to_owned()];
+                    notify_followers(&instances, client.id, ext::DOCUMENT_CLOSED, &uris).await;
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "textDocument/didChange" => {
+                if client.is_observer() {
+                    debug!("dropping observer didChange");
+                    continue;
+                }
+                for instance in instances_for_document(&instances, &notif.params) {
+                    if instance
+                        .change_file(client.id, notif.clone())
+                        .await
+                        .is_err()
+                    {
+                        break 'read;
+                    }
+                }
+            }
+
+            Message::Notification(notif) if notif.method == "$/cancelRequest" => {
+                let Ok(id) = serde_json::from_value(notif.params["id"].clone()) else {
+                    debug!(params = ?notif.params, "invalid cancel request");
+                    continue;
+                };
+                cancel_request(&client, &instances, id).await;
+            }
+
+            Message::Notification(notif)
+                if config.watch_files && notif.method == watcher::DID_CHANGE_WATCHED_FILES =>
+            {
+                // The daemon watches files for the server itself.
+                trace!("dropping client didChangeWatchedFiles");
+            }
+
+            Message::Notification(notif) if notif.params.get("textDocument").is_some() => {
+                for instance in instances_for_document(&instances, &notif.params) {
+                    if instance.send_message(notif.clone().into()).await.is_err() {
+                        break 'read;
+                    }
+                }
+            }
+
+            Message::Notification(notif) => {
+                if notif.method == "workspace/didChangeWorkspaceFolders" {
+                    client.change_workspace_folders(&notif.params);
+                }
+                // Notifications not concerning a single document are relevant
+                // to all instances.
+                for instance in &instances {
+                    if instance.send_message(notif.clone().into()).await.is_err() {
+                        break 'read;
+                    }
+                }
+            }
+        }
+    }
+
+    follow_merges(&mut instances);
+    let mut files = BTreeSet::new();
+    for instance in &instances {
+        files.extend(instance.client_files(client.id).await);
+    }
+    let files = files.into_iter().collect::<Vec<_>>();
+    notify_followers(&instances, client.id, ext::DOCUMENT_CLOSED, &files).await;
+
+    for instance in &instances {
+        if let Err(err) = instance.cleanup_client(client.clone()).await {
+            warn!(?err, "error cleaning up after a client");
+        }
+    }
+
+    let mut hook_vars = instances[0].hook_vars();
+    hook_vars.push(("LSPMUX_CLIENT_ID", client.id.to_string()));
+    hooks::run(&config.hooks, Event::ClientDisconnect, &hook_vars);
+    state.audit.disconnect(client.id);
+    for instance in &instances {
+        let detached = events::client(EventKind::ClientDetached, client.id, instance);
+        state.events.publish(detached);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn canonicalize_workspace_root() {
+        let dir = std::env::temp_dir().join(format!("ra-mux-root-{}", std::process::id()));
+        fs::create_dir_all(dir.join("proj")).await.unwrap();
+        let dir = fs::canonicalize(&dir).await.unwrap();
+        let proj = dir.join("proj").to_str().unwrap().to_owned();
+
+        assert_eq!(canonical_workspace_root(proj.clone()).await, proj);
+        assert_eq!(canonical_workspace_root(format!("{proj}/")).await, proj);
+        assert_eq!(canonical_workspace_root(format!("{proj}/./")).await, proj);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&proj, dir.join("link")).unwrap();
+            let link = dir.join("link").to_str().unwrap().to_owned();
+            assert_eq!(canonical_workspace_root(link.clone()).await, proj);
+            assert_eq!(
+                canonical_workspace_root("/does/not/exist/".into()).await,
+                "/does/not/exist",
+            );
+
+            // The server only sees the canonical root.
+            let uri = format!("file://{link}/");
+            let init_params = serde_json::from_value(json!({
+                "capabilities": {},
+                "rootUri": uri,
+                "rootPath": link,
+                "workspaceFolders": [{ "uri": uri, "name": "link" }],
+            }))
+            .unwrap();
+            let mut aliases = UriAliases::default();
+            let (root, init_params) =
+                canonicalize_root(link.clone(), init_params, &mut aliases).await;
+            assert_eq!(root, proj);
+            assert_eq!(init_params.root_uri, Some(format!("file://{proj}/")));
+            assert_eq!(init_params.root_path, Some(proj.clone()));
+            assert_eq!(
+                init_params.workspace_folders[0].uri,
+                format!("file://{proj}/")
+            );
+            let mut message = Message::Notification(Notification {
+                jsonrpc: Version,
+                method: "textDocument/didOpen".into(),
+                params: json!({ "textDocument": { "uri": format!("file://{link}/a.rs") } }),
+            });
+            aliases.rewrite_message(&mut message, Direction::ToServer);
+            let Message::Notification(notif) = message else {
+                unreachable!()
+            };
+            assert_eq!(
+                notif.params["textDocument"]["uri"],
+                format!("file://{proj}/a.rs")
+            );
+        }
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn detect_http() {
+        assert!(is_http(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert!(is_http(b"POST /api HTTP/1.1\r\n"));
+        assert!(!is_http(b"Content-Length: 2\r\n\r\n{}"));
+        assert!(!is_http(b"GETTER"));
+
+        let (head, body) = HTTP_REJECTION.split_once("\r\n\r\n").unwrap();
+        let length = format!("Content-Length: {}\r\n", body.len());
+        assert!(head.contains(&length), "{head:?} should contain {length:?}");
+    }
+
+    #[test]
+    fn reload_methods() {
+        let config =
+            toml::from_str::<Config>("[server.taplo]\nreload_method = \"taplo/reload\"").unwrap();
+        assert_eq!(
+            reload_method(&config, "/usr/bin/rust-analyzer"),
+            Some("rust-analyzer/reloadWorkspace"),
+        );
+        assert_eq!(reload_method(&config, "taplo"), Some("taplo/reload"));
+        assert_eq!(reload_method(&config, "clangd"), None);
+    }
+
+    #[tokio::test]
+    async fn stop_on_first_message_error() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let (read, write) = Stream::Channel { channel: ours }.into_split();
+        let mut reader = LspReader::new(BufReader::new(read), "client");
+        let mut writer = LspWriter::new(write, "client");
+
+        // The connection stays open but nothing after the error can be read.
+        theirs
+            .write_all(b"Content-Length: x\r\n\r\n")
+            .await
+            .unwrap();
+        let first = wait_for_first_message(&mut reader, &mut writer);
+        let res = time::timeout(Duration::from_secs(5), first).await;
+        assert!(res.expect("kept reading").is_err());
+    }
+}
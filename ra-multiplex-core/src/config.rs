@@ -0,0 +1,2125 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::time::Duration;
+use std::{fmt, fs, io, thread};
+
+use anyhow::{Context, Result};
+use directories::BaseDirs;
+use globset::GlobBuilder;
+use serde::de::{Error, Unexpected};
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::runtime::{self, Runtime};
+use tracing::warn;
+
+use crate::download;
+use crate::lsp::ext::InstanceRole;
+use crate::lsp::jsonrpc::Limits;
+use crate::lsp::transport::{Compression, WireEncoding};
+use crate::paths;
+
+mod default {
+    use super::*;
+
+    pub fn instance_timeout() -> Option<u32> {
+        // 5 minutes
+        Some(5 * 60)
+    }
+
+    pub fn keep_alive() -> Option<KeepAlive> {
+        // use `instance_timeout`
+        None
+    }
+
+    pub fn gc_interval() -> u32 {
+        // 10 seconds
+        10
+    }
+
+    pub fn listen() -> Address {
+        // localhost & some random unprivileged port
+        Address::Tcp(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 27_631)
+    }
+
+    pub fn allowed_ips() -> Vec<IpNetwork> {
+        Vec::new()
+    }
+
+    pub fn connect() -> Address {
+        listen()
+    }
+
+    pub fn connect_rules() -> Vec<ConnectRule> {
+        Vec::new()
+    }
+
+    pub fn connect_retry() -> Option<u32> {
+        // 5 seconds
+        Some(5)
+    }
+
+    pub fn heartbeat_interval() -> Option<u32> {
+        // 10 seconds
+        Some(10)
+    }
+
+    pub fn heartbeat_timeout() -> u32 {
+        // 30 seconds
+        30
+    }
+
+    pub fn reconnect_timeout() -> Option<u32> {
+        // disabled
+        None
+    }
+
+    pub fn reconnect_buffer() -> u32 {
+        1024
+    }
+
+    pub fn wire_encoding() -> WireEncoding {
+        WireEncoding::Lsp
+    }
+
+    pub fn compression() -> Compression {
+        Compression::Off
+    }
+
+    pub fn stream_message_size() -> Option<u32> {
+        // 1 MiB
+        Some(1024 * 1024)
+    }
+
+    pub fn max_message_size() -> u32 {
+        64 * 1024 * 1024
+    }
+
+    pub fn max_json_depth() -> u32 {
+        64
+    }
+
+    pub fn max_json_array_length() -> u32 {
+        1_000_000
+    }
+
+    pub fn max_concurrent_requests() -> Option<u32> {
+        // unlimited
+        None
+    }
+
+    pub fn max_client_requests() -> Option<u32> {
+        // unlimited
+        None
+    }
+
+    pub fn max_client_requests_per_second() -> Option<u32> {
+        // unlimited
+        None
+    }
+
+    pub fn stuck_request_timeout() -> Option<u32> {
+        // 1 minute
+        Some(60)
+    }
+
+    pub fn stuck_request_notify() -> bool {
+        false
+    }
+
+    pub fn restart_after_errors() -> Option<u32> {
+        // disabled
+        None
+    }
+
+    pub fn crash_report_messages() -> Option<u32> {
+        // disabled
+        None
+    }
+
+    pub fn memory_usage_interval() -> Option<u32> {
+        // disabled
+        None
+    }
+
+    pub fn meta_instances() -> bool {
+        false
+    }
+
+    pub fn rust_toolchain() -> bool {
+        false
+    }
+
+    pub fn workspace_keying() -> WorkspaceKeying {
+        WorkspaceKeying::Client
+    }
+
+    pub fn instance_groups() -> Vec<InstanceGroup> {
+        Vec::new()
+    }
+
+    pub fn log_filters() -> String {
+        "info".to_owned()
+    }
+
+    pub fn audit_log() -> bool {
+        false
+    }
+
+    pub fn record_stats() -> bool {
+        false
+    }
+
+    pub fn pass_environment() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn passthrough_methods() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn strip_initialization_options() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn redact_initialization_options() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn privacy() -> Privacy {
+        Privacy::Off
+    }
+
+    pub fn server() -> ServerOptions {
+        ServerOptions::default()
+    }
+
+    pub fn supersede_requests() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn replica_methods() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn background_methods() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn adopt_responses() -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    pub fn apply_edit() -> ApplyEditTarget {
+        ApplyEditTarget::Originator
+    }
+
+    pub fn show_document() -> ShowDocumentFallback {
+        ShowDocumentFallback::FirstClient
+    }
+
+    pub fn log_messages() -> Route {
+        Route::Broadcast
+    }
+
+    pub fn lazy_spawn() -> bool {
+        false
+    }
+
+    pub fn watch_files() -> bool {
+        false
+    }
+
+    pub fn adopt_instances() -> bool {
+        false
+    }
+
+    pub fn trust_project_config() -> bool {
+        false
+    }
+
+    pub fn notification_rate_limits() -> BTreeMap<String, NonZeroU32> {
+        BTreeMap::new()
+    }
+
+    pub fn routing() -> BTreeMap<String, Route> {
+        BTreeMap::new()
+    }
+
+    pub fn companion_servers() -> BTreeMap<String, Vec<CompanionServer>> {
+        BTreeMap::new()
+    }
+
+    pub fn did_change_debounce() -> BTreeMap<String, NonZeroU32> {
+        BTreeMap::new()
+    }
+
+    pub fn merge_strategies() -> BTreeMap<String, MergeStrategy> {
+        BTreeMap::new()
+    }
+
+    pub fn timeouts() -> BTreeMap<String, NonZeroU32> {
+        BTreeMap::new()
+    }
+
+    pub fn download() -> BTreeMap<String, BTreeMap<String, Download>> {
+        BTreeMap::new()
+    }
+
+    pub fn hooks() -> Hooks {
+        Hooks::default()
+    }
+
+    pub fn runtime() -> RuntimeOptions {
+        RuntimeOptions::default()
+    }
+}
+
+mod de {
+    use super::*;
+
+    /// parse either bool(false) or u32
+    pub fn u32_or_false<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOf {
+            Bool(bool),
+            U32(u32),
+        }
+
+        match OneOf::deserialize(deserializer) {
+            Ok(OneOf::U32(value)) => Ok(Some(value)),
+            Ok(OneOf::Bool(false)) => Ok(None),
+            Ok(OneOf::Bool(true)) => Err(Error::invalid_value(
+                Unexpected::Bool(true),
+                &"a non-negative integer or false",
+            )),
+            Err(_) => Err(Error::custom(
+                "invalid type: expected a non-negative integer or false",
+            )),
+        }
+    }
+
+    /// parse either bool(false) or u32 greater than 0
+    pub fn non_zero_u32_or_false<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u32_or_false(deserializer)? {
+            Some(0) => Err(Error::invalid_value(
+                Unexpected::Unsigned(0),
+                &"an integer 1 or greater or false",
+            )),
+            value => Ok(value),
+        }
+    }
+
+    /// parse either bool(false), u32 or string "never"
+    pub fn keep_alive_or_false<'de, D>(deserializer: D) -> Result<Option<KeepAlive>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOf {
+            Bool(bool),
+            U32(u32),
+            String(String),
+        }
+
+        match OneOf::deserialize(deserializer) {
+            Ok(OneOf::U32(value)) => Ok(Some(KeepAlive::Seconds(value))),
+            Ok(OneOf::String(value)) if value == "never" => Ok(Some(KeepAlive::Never)),
+            Ok(OneOf::Bool(false)) => Ok(None),
+            Ok(OneOf::Bool(true)) => Err(Error::invalid_value(
+                Unexpected::Bool(true),
+                &"a non-negative integer, \"never\" or false",
+            )),
+            Ok(OneOf::String(value)) => Err(Error::invalid_value(
+                Unexpected::Str(&value),
+                &"a non-negative integer, \"never\" or false",
+            )),
+            Err(_) => Err(Error::custom(
+                "invalid type: expected a non-negative integer, \"never\" or false",
+            )),
+        }
+    }
+
+    /// parse either u8 from 0 to 7 or string "idle"
+    pub fn io_priority<'de, D>(deserializer: D) -> Result<Option<IoPriority>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOf {
+            U8(u8),
+            String(String),
+        }
+
+        let priority = match OneOf::deserialize(deserializer) {
+            Ok(OneOf::U8(value @ 0..=7)) => Ok(IoPriority::BestEffort(value)),
+            Ok(OneOf::String(value)) if value == "idle" => Ok(IoPriority::Idle),
+            Ok(OneOf::U8(value)) => Err(Error::invalid_value(
+                Unexpected::Unsigned(value.into()),
+                &"an integer from 0 to 7 or \"idle\"",
+            )),
+            Ok(OneOf::String(value)) => Err(Error::invalid_value(
+                Unexpected::Str(&value),
+                &"an integer from 0 to 7 or \"idle\"",
+            )),
+            Err(_) => Err(Error::custom(
+                "invalid type: expected an integer from 0 to 7 or \"idle\"",
+            )),
+        }?;
+        linux_only("io_priority")?;
+        Ok(Some(priority))
+    }
+
+    /// parse a list of CPU indices
+    pub fn cpu_affinity<'de, D>(deserializer: D) -> Result<Option<Vec<usize>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let cpus = Vec::deserialize(deserializer)?;
+        linux_only("cpu_affinity")?;
+        Ok(Some(cpus))
+    }
+
+    /// reject a setting which can't be applied on this platform, instead of
+    /// failing every server spawn later
+    fn linux_only<E: Error>(setting: &str) -> Result<(), E> {
+        match cfg!(target_os = "linux") {
+            true => Ok(()),
+            false => Err(E::custom(format!("{setting} is only supported on linux"))),
+        }
+    }
+
+    /// parse either a table or a string with a JSON object, which unlike a
+    /// table can contain `null`
+    pub fn json_patch<'de, D>(deserializer: D) -> Result<Option<Value>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOf {
+            Table(Map<String, Value>),
+            String(String),
+        }
+
+        match OneOf::deserialize(deserializer) {
+            Ok(OneOf::Table(table)) => Ok(Some(Value::Object(table))),
+            Ok(OneOf::String(json)) => match serde_json::from_str(&json) {
+                Ok(patch @ Value::Object(_)) => Ok(Some(patch)),
+                _ => Err(Error::invalid_value(
+                    Unexpected::Str(&json),
+                    &"a string with a JSON object",
+                )),
+            },
+            Err(_) => Err(Error::custom(
+                "invalid type: expected a table or a string with a JSON object",
+            )),
+        }
+    }
+
+    /// parse the tables of `[server]` not matching one of its fields as
+    /// server settings, other values are unknown fields
+    ///
+    /// `deny_unknown_fields` doesn't work together with `flatten`.
+    pub fn server_settings<'de, D>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, ServerSettings>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        const FIELDS: &[&str] = &[
+            "wrapper",
+            "nice",
+            "io_priority",
+            "cpu_affinity",
+            "search_path",
+            "login_shell",
+            "shell",
+        ];
+
+        BTreeMap::<String, Value>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| {
+                if !value.is_object() {
+                    return Err(Error::unknown_field(&key, FIELDS));
+                }
+                let settings = ServerSettings::deserialize(value)
+                    .map_err(|err| Error::custom(format!("server {key:?}: {err}")))?;
+                Ok((key, settings))
+            })
+            .collect()
+    }
+
+    /// make sure the value is greater than 0 to giver users feedback on invalid configuration
+    pub fn non_zero_u32<'de, D>(deserializer: D) -> Result<u32, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u32::deserialize(deserializer)? {
+            0 => Err(Error::invalid_value(
+                Unexpected::Unsigned(0),
+                &"an integer 1 or greater",
+            )),
+            value => Ok(value),
+        }
+    }
+}
+
+mod ser {
+    use super::*;
+
+    /// serialize `None` as bool(false), the inverse of [`de::u32_or_false`]
+    pub fn u32_or_false<S>(value: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(value) => serializer.serialize_u32(*value),
+            None => serializer.serialize_bool(false),
+        }
+    }
+
+    /// serialize `None` as bool(false), the inverse of [`de::keep_alive_or_false`]
+    pub fn keep_alive_or_false<S>(
+        value: &Option<KeepAlive>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(KeepAlive::Seconds(value)) => serializer.serialize_u32(*value),
+            Some(KeepAlive::Never) => serializer.serialize_str("never"),
+            None => serializer.serialize_bool(false),
+        }
+    }
+
+    /// the inverse of [`de::io_priority`]
+    pub fn io_priority<S>(value: &Option<IoPriority>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match value {
+            Some(IoPriority::BestEffort(level)) => serializer.serialize_u8(*level),
+            Some(IoPriority::Idle) => serializer.serialize_str("idle"),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "RawAddress", into = "RawAddress")]
+pub enum Address {
+    Tcp(IpAddr, u16),
+    /// Written as `"quic:<ip>:<port>[#<fingerprint>]"`, the client trusts the
+    /// certificate with the fingerprint or the one of the server running on
+    /// its own machine
+    Quic(SocketAddr, Option<Fingerprint>),
+    /// Written as `"vsock:<cid>:<port>"`
+    #[cfg(target_os = "linux")]
+    Vsock(u32, u32),
+    /// Written as `"ssh://[user@]host[:port][/<ip>:<port>]"`
+    Ssh(SshAddress),
+    #[cfg(target_family = "unix")]
+    Unix(PathBuf),
+}
+
+/// How an [`Address`] is written in the config file
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawAddress {
+    Tcp(IpAddr, u16),
+    String(String),
+}
+
+impl TryFrom<RawAddress> for Address {
+    type Error = String;
+
+    fn try_from(raw: RawAddress) -> Result<Self, Self::Error> {
+        let value = match raw {
+            RawAddress::Tcp(ip_addr, port) => return Ok(Address::Tcp(ip_addr, port)),
+            RawAddress::String(value) => value,
+        };
+        if let Some(addr) = value.strip_prefix("quic:") {
+            let (addr, fingerprint) = match addr.split_once('#') {
+                Some((addr, fingerprint)) => (addr, Some(fingerprint.parse()?)),
+                None => (addr, None),
+            };
+            return addr
+                .parse()
+                .map(|addr| Address::Quic(addr, fingerprint))
+                .map_err(|_| format!("invalid quic address {addr:?}, expected <ip>:<port>"));
+        }
+        if let Some(url) = value.strip_prefix("ssh://") {
+            return SshAddress::parse(url).map(Address::Ssh);
+        }
+        if let Some(addr) = value.strip_prefix("vsock:") {
+            let invalid = || format!("invalid vsock address {addr:?}, expected <cid>:<port>");
+            let (cid, port) = addr.split_once(':').ok_or_else(invalid)?;
+            let cid = cid.parse::<u32>().map_err(|_| invalid())?;
+            let port = port.parse::<u32>().map_err(|_| invalid())?;
+            #[cfg(target_os = "linux")]
+            return Ok(Address::Vsock(cid, port));
+            #[cfg(not(target_os = "linux"))]
+            return Err(format!(
+                "vsock address {cid}:{port} is only supported on linux"
+            ));
+        }
+        #[cfg(target_family = "unix")]
+        return Ok(Address::Unix(PathBuf::from(value)));
+        #[cfg(not(target_family = "unix"))]
+        Err(format!("unsupported address {value:?}"))
+    }
+}
+
+impl From<Address> for RawAddress {
+    fn from(addr: Address) -> Self {
+        match addr {
+            Address::Tcp(ip_addr, port) => RawAddress::Tcp(ip_addr, port),
+            Address::Quic(addr, None) => RawAddress::String(format!("quic:{addr}")),
+            Address::Quic(addr, Some(fingerprint)) => {
+                RawAddress::String(format!("quic:{addr}#{fingerprint}"))
+            }
+            #[cfg(target_os = "linux")]
+            Address::Vsock(cid, port) => RawAddress::String(format!("vsock:{cid}:{port}")),
+            Address::Ssh(addr) => RawAddress::String(addr.to_string()),
+            #[cfg(target_family = "unix")]
+            Address::Unix(path) => RawAddress::String(path.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+impl Address {
+    /// IP address listened on or connected to, `None` for non-IP transports
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::Tcp(ip_addr, _) => Some(*ip_addr),
+            Address::Quic(addr, _) => Some(addr.ip()),
+            _ => None,
+        }
+    }
+
+    /// Address is on this machine, compressing messages sent to it only costs
+    /// CPU time
+    pub fn is_local(&self) -> bool {
+        match self {
+            Address::Tcp(..) | Address::Quic(..) => self.ip().is_some_and(|ip| ip.is_loopback()),
+            #[cfg(target_os = "linux")]
+            Address::Vsock(..) => true,
+            Address::Ssh(_) => false,
+            #[cfg(target_family = "unix")]
+            Address::Unix(_) => true,
+        }
+    }
+}
+
+/// Network in `allowed_ips`, written as "<ip>/<prefix length>" or as a single
+/// IP address
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6.
+        let bits = |ip: IpAddr| match ip.to_canonical() {
+            IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => (u128::from(ip), 128),
+        };
+        let (network, width) = bits(self.addr);
+        let (ip, ip_width) = bits(ip);
+        if width != ip_width {
+            return false;
+        }
+        let shift = width - u32::from(self.prefix);
+        shift == width || network >> shift == ip >> shift
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid network {value:?}, expected <ip>[/<prefix length>]");
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.as_str(), None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => width,
+        };
+        if prefix > width {
+            return Err(invalid());
+        }
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        format!("{}/{}", network.addr, network.prefix)
+    }
+}
+
+/// Server on another machine reached through an SSH connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshAddress {
+    /// `[user@]host` passed to ssh
+    pub destination: String,
+
+    /// Port of the SSH server
+    pub port: Option<u16>,
+
+    /// Address the server listens on as seen from the other machine
+    pub forward: String,
+}
+
+impl SshAddress {
+    /// Parse an `ssh://` URL without the scheme
+    fn parse(url: &str) -> Result<SshAddress, String> {
+        let (authority, forward) = match url.split_once('/') {
+            Some((authority, forward)) => (authority, forward.to_owned()),
+            // the default `listen` address
+            None => (url, "127.0.0.1:27631".to_owned()),
+        };
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user), host),
+            None => (None, authority),
+        };
+        // IPv6 hosts are written in brackets, ssh takes them without.
+        let (host, port) = match host.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("missing `]` in ssh address {url:?}"))?;
+                match rest {
+                    "" => (host, None),
+                    _ => match rest.strip_prefix(':') {
+                        Some(port) => (host, Some(port)),
+                        None => return Err(format!("invalid ssh address {url:?}")),
+                    },
+                }
+            }
+            None => match host.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host, None),
+            },
+        };
+        let port = port
+            .map(|port| port.parse())
+            .transpose()
+            .map_err(|_| format!("invalid ssh port in {url:?}"))?;
+        if host.is_empty() {
+            return Err(format!("missing host in ssh address {url:?}"));
+        }
+        let destination = match user {
+            Some(user) => format!("{user}@{host}"),
+            None => host.to_owned(),
+        };
+        if !forward.contains(':') {
+            return Err(format!(
+                "invalid server address {forward:?} in ssh address, expected <ip>:<port>"
+            ));
+        }
+        Ok(SshAddress {
+            destination,
+            port,
+            forward,
+        })
+    }
+}
+
+/// SHA-256 digest of a QUIC server's certificate, written as hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint(pub [u8; 32]);
+
+impl std::str::FromStr for Fingerprint {
+    type Err = String;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid certificate fingerprint {hex:?}, expected 64 hex digits");
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut digest = [0; 32];
+        for (byte, digits) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).unwrap();
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Fingerprint(digest))
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl fmt::Display for SshAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.destination.rsplit_once('@') {
+            Some((user, host)) if host.contains(':') => write!(f, "ssh://{user}@[{host}]")?,
+            None if self.destination.contains(':') => write!(f, "ssh://[{}]", self.destination)?,
+            _ => write!(f, "ssh://{}", self.destination)?,
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        write!(f, "/{}", self.forward)
+    }
+}
+
+/// How long to keep an instance running after its last client disconnected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAlive {
+    /// Close the instance after this many seconds
+    Seconds(u32),
+    /// Keep the instance running until it's closed by other means
+    Never,
+}
+
+/// How language server processes are spawned
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Command the server and its arguments are appended to
+    #[serde(default)]
+    pub wrapper: Vec<String>,
+
+    /// Niceness of the server process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+
+    /// IO scheduling priority of the server process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "de::io_priority")]
+    #[serde(serialize_with = "ser::io_priority")]
+    pub io_priority: Option<IoPriority>,
+
+    /// CPUs the server process is allowed to run on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "de::cpu_affinity")]
+    pub cpu_affinity: Option<Vec<usize>>,
+
+    /// Directories searched for servers requested by a plain name before
+    /// `PATH`
+    #[serde(default)]
+    pub search_path: Vec<String>,
+
+    /// Look up servers not found otherwise with the user's login shell
+    #[serde(default)]
+    pub login_shell: bool,
+
+    /// Run the server command with the system shell instead of passing the
+    /// arguments verbatim
+    #[serde(default)]
+    pub shell: bool,
+
+    /// Settings of individual servers by their path or file name
+    #[serde(flatten, deserialize_with = "de::server_settings")]
+    pub servers: BTreeMap<String, ServerSettings>,
+}
+
+/// Settings of a language server
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Options combined with the `initializationOptions` clients send
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initialization_options: Option<Map<String, Value>>,
+
+    #[serde(default)]
+    pub initialization_options_mode: OptionsMode,
+
+    /// JSON merge patch applied to the client capabilities sent to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "de::json_patch")]
+    pub client_capabilities: Option<Value>,
+
+    /// JSON merge patch applied to the server capabilities sent to clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "de::json_patch")]
+    pub server_capabilities: Option<Value>,
+
+    /// JSON or TOML file answering the server's `workspace/configuration`
+    /// requests instead of clients, may contain placeholders like
+    /// `{workspace}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings_file: Option<String>,
+
+    /// `workspace/configuration` section the `initializationOptions` of
+    /// clients correspond to, they're applied to the results for scopes in
+    /// their workspace folders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_settings_section: Option<String>,
+
+    /// Unix socket all messages exchanged with the server are mirrored to as
+    /// JSON lines, can contain the same placeholders as `settings_file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror_socket: Option<String>,
+
+    /// Command started with the instance getting the mirrored messages on its
+    /// stdin instead of `mirror_socket`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror_command: Option<Vec<String>>,
+
+    /// Executable used for the server when it's requested by a plain name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    /// Request sent by `ra-multiplex reload-workspace`, rust-analyzer's
+    /// `rust-analyzer/reloadWorkspace` is known already
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reload_method: Option<String>,
+
+    /// Instances of the server are never evicted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+/// How configured options are combined with the ones sent by clients
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OptionsMode {
+    /// Merge objects recursively, configured values override the client's
+    #[default]
+    Merge,
+    /// Use the configured options instead of the client's
+    Replace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Best-effort class with level from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+    /// Only gets disk time when no other process needs it
+    Idle,
+}
+
+/// Strategy for delivering server requests and notifications to clients
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Route {
+    /// Send to the client which sent the last request to the server
+    Originator,
+    /// Send to all clients
+    Broadcast,
+    /// Send to the client which is connected for the longest time
+    FirstClient,
+    /// Don't send to any client
+    Drop,
+}
+
+/// Clients asked to apply a `workspace/applyEdit` request from the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApplyEditTarget {
+    /// The client whose `workspace/executeCommand` request the server is
+    /// handling, or the client which sent the last request
+    Originator,
+    /// The client connected most recently
+    NewestClient,
+    /// All clients, the first client which applied the edit answers
+    All,
+}
+
+/// Client asked to show a document for a `window/showDocument` request from
+/// the server when it's unclear which client the request is meant for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShowDocumentFallback {
+    /// The client connected for the longest time
+    FirstClient,
+    /// The client connected most recently
+    NewestClient,
+    /// No client, the server is told the document wasn't shown
+    Drop,
+}
+
+/// How string values of messages are hidden in logs, crash reports and
+/// mirrored traffic
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Privacy {
+    /// Messages are shown as they are
+    Off,
+    /// Strings are replaced with a hash, equal strings like the same URI can
+    /// still be matched up
+    Hash,
+    /// Strings are replaced with their length
+    Elide,
+}
+
+/// Directory instances are keyed by, selected from the workspace root the
+/// client asked for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkspaceKeying {
+    /// The client's workspace root
+    Client,
+    /// The root of the Cargo workspace for rust-analyzer, the client's
+    /// workspace root for other servers
+    Cargo,
+    /// The top-level directory of the git working tree
+    Git,
+}
+
+/// Workspaces matching a glob deliberately keyed by a named instance instead
+/// of `workspace_keying`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceGroup {
+    /// Glob matched against the workspace root and its parent directories, a
+    /// leading `~/` is the home directory
+    pub path: String,
+    pub instance: String,
+}
+
+/// Servers used for the workspaces under a directory instead of `connect`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectRule {
+    pub path: PathBuf,
+
+    /// Tried in order until one of them is reachable
+    pub connect: Vec<Address>,
+}
+
+/// Additional language server started next to the one requested by a client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CompanionServer {
+    pub server: String,
+
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// How to merge responses to a request sent to multiple instances
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// Concatenate array results, `null` results are treated as empty arrays
+    Concat,
+    /// Use the first result which isn't `null`
+    FirstNonNull,
+}
+
+/// Commands executed on lifecycle events, each one is a program followed by
+/// its arguments
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_instance_start: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_instance_exit: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_instance_crash: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_client_connect: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_client_disconnect: Option<Vec<String>>,
+}
+
+/// Tokio runtime ra-multiplex runs on
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeOptions {
+    #[serde(default)]
+    pub flavor: RuntimeFlavor,
+
+    /// Threads running tasks with the multi-thread flavor, defaults to the
+    /// number of CPUs up to [`RuntimeOptions::MAX_DEFAULT_WORKER_THREADS`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// Maximum number of threads for blocking work like reading stdin, files
+    /// and collecting `status`, defaults to tokio's 512
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocking_threads: Option<NonZeroUsize>,
+
+    /// Run the server's client connections on a current-thread runtime of
+    /// their own, the language servers stay on the runtime of `flavor`
+    #[serde(default)]
+    pub transport_thread: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuntimeFlavor {
+    /// Tasks are spread over `worker_threads` threads
+    #[default]
+    MultiThread,
+    /// All tasks run on the main thread, messages aren't handed between
+    /// threads which is faster on small machines
+    CurrentThread,
+}
+
+impl RuntimeOptions {
+    /// More threads than this only sit idle forwarding messages, the language
+    /// servers do the actual work
+    pub const MAX_DEFAULT_WORKER_THREADS: usize = 8;
+
+    /// Build the runtime
+    pub fn build(&self) -> io::Result<Runtime> {
+        let mut builder = match self.flavor {
+            RuntimeFlavor::MultiThread => {
+                let mut builder = runtime::Builder::new_multi_thread();
+                let worker_threads = self.worker_threads.map_or_else(
+                    || {
+                        let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+                        cpus.min(Self::MAX_DEFAULT_WORKER_THREADS)
+                    },
+                    NonZeroUsize::get,
+                );
+                builder.worker_threads(worker_threads);
+                builder
+            }
+            RuntimeFlavor::CurrentThread => runtime::Builder::new_current_thread(),
+        };
+        if let Some(blocking_threads) = self.blocking_threads {
+            builder.max_blocking_threads(blocking_threads.get());
+        }
+        builder.enable_all().build()
+    }
+}
+
+/// Language server release downloaded by ra-multiplex
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Download {
+    /// URL of the binary or archive
+    pub url: String,
+
+    /// Expected SHA-256 checksum of the downloaded file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// Path of the server binary inside the archive, defaults to the server
+    /// name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<String>,
+
+    /// Seconds after which the download is refreshed, never if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_interval: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default::instance_timeout")]
+    #[serde(deserialize_with = "de::u32_or_false")]
+    pub instance_timeout: Option<u32>,
+
+    #[serde(default = "default::keep_alive")]
+    #[serde(deserialize_with = "de::keep_alive_or_false")]
+    #[serde(serialize_with = "ser::keep_alive_or_false")]
+    pub keep_alive: Option<KeepAlive>,
+
+    #[serde(default = "default::gc_interval")]
+    #[serde(deserialize_with = "de::non_zero_u32")]
+    pub gc_interval: u32,
+
+    #[serde(default = "default::listen")]
+    pub listen: Address,
+
+    #[serde(default = "default::allowed_ips")]
+    pub allowed_ips: Vec<IpNetwork>,
+
+    #[serde(default = "default::connect")]
+    pub connect: Address,
+
+    #[serde(default = "default::connect_rules")]
+    pub connect_rules: Vec<ConnectRule>,
+
+    #[serde(default = "default::connect_retry")]
+    #[serde(deserialize_with = "de::u32_or_false")]
+    pub connect_retry: Option<u32>,
+
+    #[serde(default = "default::heartbeat_interval")]
+    #[serde(deserialize_with = "de::u32_or_false")]
+    pub heartbeat_interval: Option<u32>,
+
+    #[serde(default = "default::heartbeat_timeout")]
+    #[serde(deserialize_with = "de::non_zero_u32")]
+    pub heartbeat_timeout: u32,
+
+    #[serde(default = "default::reconnect_timeout")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub reconnect_timeout: Option<u32>,
+
+    #[serde(default = "default::reconnect_buffer")]
+    #[serde(deserialize_with = "de::non_zero_u32")]
+    pub reconnect_buffer: u32,
+
+    #[serde(default = "default::wire_encoding")]
+    pub wire_encoding: WireEncoding,
+
+    #[serde(default = "default::compression")]
+    pub compression: Compression,
+
+    #[serde(default = "default::stream_message_size")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub stream_message_size: Option<u32>,
+
+    #[serde(default = "default::max_message_size")]
+    #[serde(deserialize_with = "de::non_zero_u32")]
+    pub max_message_size: u32,
+
+    #[serde(default = "default::max_json_depth")]
+    #[serde(deserialize_with = "de::non_zero_u32")]
+    pub max_json_depth: u32,
+
+    #[serde(default = "default::max_json_array_length")]
+    #[serde(deserialize_with = "de::non_zero_u32")]
+    pub max_json_array_length: u32,
+
+    #[serde(default = "default::max_concurrent_requests")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub max_concurrent_requests: Option<u32>,
+
+    #[serde(default = "default::max_client_requests")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub max_client_requests: Option<u32>,
+
+    #[serde(default = "default::max_client_requests_per_second")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub max_client_requests_per_second: Option<u32>,
+
+    #[serde(default = "default::stuck_request_timeout")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub stuck_request_timeout: Option<u32>,
+
+    #[serde(default = "default::stuck_request_notify")]
+    pub stuck_request_notify: bool,
+
+    #[serde(default = "default::restart_after_errors")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub restart_after_errors: Option<u32>,
+
+    #[serde(default = "default::crash_report_messages")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub crash_report_messages: Option<u32>,
+
+    #[serde(default = "default::memory_usage_interval")]
+    #[serde(deserialize_with = "de::non_zero_u32_or_false")]
+    #[serde(serialize_with = "ser::u32_or_false")]
+    pub memory_usage_interval: Option<u32>,
+
+    #[serde(default = "default::meta_instances")]
+    pub meta_instances: bool,
+
+    #[serde(default = "default::rust_toolchain")]
+    pub rust_toolchain: bool,
+
+    #[serde(default = "default::workspace_keying")]
+    pub workspace_keying: WorkspaceKeying,
+
+    #[serde(default = "default::instance_groups")]
+    pub instance_groups: Vec<InstanceGroup>,
+
+    #[serde(default = "default::log_filters")]
+    pub log_filters: String,
+
+    #[serde(default = "default::audit_log")]
+    pub audit_log: bool,
+
+    #[serde(default = "default::record_stats")]
+    pub record_stats: bool,
+
+    #[serde(default = "default::pass_environment")]
+    pub pass_environment: BTreeSet<String>,
+
+    #[serde(default = "default::passthrough_methods")]
+    pub passthrough_methods: BTreeSet<String>,
+
+    #[serde(default = "default::strip_initialization_options")]
+    pub strip_initialization_options: BTreeSet<String>,
+
+    #[serde(default = "default::redact_initialization_options")]
+    pub redact_initialization_options: BTreeSet<String>,
+
+    #[serde(default = "default::privacy")]
+    pub privacy: Privacy,
+
+    #[serde(default = "default::supersede_requests")]
+    pub supersede_requests: BTreeSet<String>,
+
+    #[serde(default = "default::replica_methods")]
+    pub replica_methods: BTreeSet<String>,
+
+    #[serde(default = "default::background_methods")]
+    pub background_methods: BTreeSet<String>,
+
+    #[serde(default = "default::adopt_responses")]
+    pub adopt_responses: BTreeSet<String>,
+
+    #[serde(default = "default::apply_edit")]
+    pub apply_edit: ApplyEditTarget,
+
+    #[serde(default = "default::show_document")]
+    pub show_document: ShowDocumentFallback,
+
+    #[serde(default = "default::log_messages")]
+    pub log_messages: Route,
+
+    #[serde(default = "default::lazy_spawn")]
+    pub lazy_spawn: bool,
+
+    #[serde(default = "default::watch_files")]
+    pub watch_files: bool,
+
+    #[serde(default = "default::adopt_instances")]
+    pub adopt_instances: bool,
+
+    #[serde(default = "default::trust_project_config")]
+    pub trust_project_config: bool,
+
+    #[serde(default = "default::server")]
+    pub server: ServerOptions,
+
+    #[serde(default = "default::notification_rate_limits")]
+    pub notification_rate_limits: BTreeMap<String, NonZeroU32>,
+
+    #[serde(default = "default::routing")]
+    pub routing: BTreeMap<String, Route>,
+
+    #[serde(default = "default::companion_servers")]
+    pub companion_servers: BTreeMap<String, Vec<CompanionServer>>,
+
+    #[serde(default = "default::did_change_debounce")]
+    pub did_change_debounce: BTreeMap<String, NonZeroU32>,
+
+    #[serde(default = "default::merge_strategies")]
+    pub merge_strategies: BTreeMap<String, MergeStrategy>,
+
+    #[serde(default = "default::timeouts")]
+    pub timeouts: BTreeMap<String, NonZeroU32>,
+
+    #[serde(default = "default::hooks")]
+    pub hooks: Hooks,
+
+    #[serde(default = "default::download")]
+    pub download: BTreeMap<String, BTreeMap<String, Download>>,
+
+    #[serde(default = "default::runtime")]
+    pub runtime: RuntimeOptions,
+
+    /// Settings of an untrusted project config which were ignored, see
+    /// [`PROJECT_SETTINGS`]
+    #[serde(skip)]
+    pub ignored_settings: Vec<String>,
+}
+
+/// Settings an untrusted project config may change
+///
+/// Anyone able to write to a directory can put a project config into it, the
+/// settings running commands, listening, connecting, downloading or deciding
+/// what is logged and shared are only read from the system and user config
+/// unless it sets `trust_project_config`.
+pub const PROJECT_SETTINGS: &[&str] = &[
+    "instance_timeout",
+    "keep_alive",
+    "max_concurrent_requests",
+    "stuck_request_timeout",
+    "stuck_request_notify",
+    "meta_instances",
+    "rust_toolchain",
+    "passthrough_methods",
+    "strip_initialization_options",
+    "supersede_requests",
+    "replica_methods",
+    "background_methods",
+    "apply_edit",
+    "show_document",
+    "log_messages",
+    "lazy_spawn",
+    "watch_files",
+    "notification_rate_limits",
+    "routing",
+    "did_change_debounce",
+    "merge_strategies",
+    "timeouts",
+];
+
+#[cfg(test)]
+#[test]
+fn generate_default_and_check_it_matches_commited_defaults() {
+    use std::fs;
+    use std::path::Path;
+
+    let generated_defaults = Config::default();
+    let generated_defaults = toml::to_string(&generated_defaults).expect("failed serialize");
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../defaults.toml");
+    let saved_defaults = fs::read_to_string(path).expect("failed reading defaults.toml file");
+
+    assert_eq!(generated_defaults, saved_defaults);
+}
+
+#[cfg(test)]
+#[test]
+fn layered_config_files() {
+    let dir = std::env::temp_dir().join(format!("ra-mux-layers-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let user = dir.join("config.toml");
+    let project = dir.join(paths::PROJECT_CONFIG);
+    fs::write(
+        &user,
+        "gc_interval = 5\ninstance_timeout = 60\ntrust_project_config = true\n\
+         [server.clangd]\npath = \"/opt/clangd\"\n",
+    )
+    .unwrap();
+    fs::write(
+        &project,
+        "instance_timeout = 120\n[server.clangd]\nsettings_file = \"clangd.json\"\n",
+    )
+    .unwrap();
+
+    let config = Config::load_layers(&[user, project.clone()]).unwrap();
+    assert_eq!(config.gc_interval, 5);
+    assert_eq!(config.instance_timeout, Some(120));
+    let clangd = config.server_settings("clangd").unwrap();
+    assert_eq!(clangd.path.as_deref(), Some("/opt/clangd"));
+    assert_eq!(clangd.settings_file.as_deref(), Some("clangd.json"));
+
+    fs::write(&project, "instance_timeout = \"never\"\n").unwrap();
+    let err = Config::load_layers(&[project]).unwrap_err();
+    assert!(
+        format!("{err:#}").contains(paths::PROJECT_CONFIG),
+        "{err:#}"
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn untrusted_project_config() {
+    let dir = std::env::temp_dir().join(format!("ra-mux-untrusted-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let project = dir.join(paths::PROJECT_CONFIG);
+    fs::write(
+        &project,
+        "instance_timeout = 120\ntrust_project_config = true\n\
+         [server.clangd]\npath = \"/tmp/clangd\"\n",
+    )
+    .unwrap();
+
+    // Without the system and user config nothing trusts the project config.
+    let config = Config::load_files(slice::from_ref(&project)).unwrap();
+    assert_eq!(config.instance_timeout, Some(120));
+    assert!(!config.trust_project_config);
+    assert!(config.server_settings("clangd").is_none());
+    assert_eq!(
+        config.ignored_settings.len(),
+        2,
+        "{:?}",
+        config.ignored_settings
+    );
+
+    let err = Config::load_files(&[]).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(io::ErrorKind::NotFound)
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn runtime_options() {
+    let config = toml::from_str::<Config>(
+        r#"
+        [runtime]
+        flavor = "current-thread"
+        blocking_threads = 2
+        "#,
+    )
+    .unwrap();
+    let runtime = config.runtime.build().unwrap();
+    assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+
+    let config = toml::from_str::<Config>("[runtime]\nworker_threads = 3\n").unwrap();
+    assert_eq!(config.runtime.worker_threads, NonZeroUsize::new(3));
+    let runtime = config.runtime.build().unwrap();
+    let spawned = runtime.spawn(async { 1 + 1 });
+    assert_eq!(runtime.block_on(spawned).unwrap(), 2);
+
+    assert!(toml::from_str::<Config>("[runtime]\nworker_threads = 0\n").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn per_method_timeouts() {
+    let config = toml::from_str::<Config>(
+        r#"
+        stuck_request_timeout = 30
+
+        [timeouts]
+        "textDocument/completion" = 2
+        "workspace/symbol" = 60
+        "#,
+    )
+    .unwrap();
+    let secs = Duration::from_secs;
+    let timeout = |method| config.request_timeout(method);
+    assert_eq!(timeout("textDocument/completion"), Some(secs(2)));
+    assert_eq!(timeout("workspace/symbol"), Some(secs(60)));
+    assert_eq!(timeout("textDocument/hover"), Some(secs(30)));
+    assert_eq!(config.min_request_timeout(), Some(secs(2)));
+
+    let config = toml::from_str::<Config>("stuck_request_timeout = false").unwrap();
+    assert_eq!(config.request_timeout("textDocument/hover"), None);
+    assert_eq!(config.min_request_timeout(), None);
+    assert!(toml::from_str::<Config>("[timeouts]\nfoo = 0").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn parse_server_settings() {
+    let config = toml::from_str::<Config>(
+        r#"
+        [server]
+        nice = 10
+
+        [server."rust-analyzer".initialization_options]
+        cargo.targetDir = "target/ra"
+
+        [server."/opt/clangd"]
+        initialization_options_mode = "replace"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.server.nice, Some(10));
+    let rust_analyzer = &config.server.servers["rust-analyzer"];
+    assert_eq!(
+        Value::from(rust_analyzer.initialization_options.clone().unwrap()),
+        serde_json::json!({ "cargo": { "targetDir": "target/ra" } }),
+    );
+    assert_eq!(
+        rust_analyzer.initialization_options_mode,
+        OptionsMode::Merge
+    );
+    let clangd = &config.server.servers["/opt/clangd"];
+    assert_eq!(clangd.initialization_options_mode, OptionsMode::Replace);
+    for server in [
+        "/usr/bin/rust-analyzer",
+        "rust-analyzer@nightly",
+        "managed:rust-analyzer@2024-05-06",
+    ] {
+        assert!(config.server_settings(server).is_some(), "{server}");
+    }
+    assert!(config
+        .server_settings("rust-analyzer-proc-macro-srv")
+        .is_none());
+
+    let config = toml::from_str::<Config>(
+        r#"
+        [server."rust-analyzer"]
+        client_capabilities = '{ "workspace": { "workspaceEdit": null } }'
+        server_capabilities.semanticTokensProvider.full = false
+        "#,
+    )
+    .unwrap();
+    let rust_analyzer = &config.server.servers["rust-analyzer"];
+    assert_eq!(
+        rust_analyzer.client_capabilities,
+        Some(serde_json::json!({ "workspace": { "workspaceEdit": null } })),
+    );
+    assert_eq!(
+        rust_analyzer.server_capabilities,
+        Some(serde_json::json!({ "semanticTokensProvider": { "full": false } })),
+    );
+
+    let parse = |text: &str| toml::from_str::<Config>(text);
+    assert!(parse("[server.\"rust-analyzer\"]\nunknown = 1").is_err());
+    let err = parse("[server]\nnise = 10").unwrap_err().to_string();
+    assert!(err.contains("unknown field `nise`"), "{err}");
+    assert!(parse("[server]\nwrapper = 1").is_err());
+    assert!(parse("[server.a]\nclient_capabilities = '[]'").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn parse_io_priority() {
+    let parse = |value: &str| toml::from_str::<Config>(&format!("[server]\nio_priority = {value}"));
+
+    assert!(parse("8").is_err());
+    assert!(parse("\"realtime\"").is_err());
+    assert_eq!(Config::default().server.io_priority, None);
+    let cpu_affinity = toml::from_str::<Config>("[server]\ncpu_affinity = [0, 2]");
+    if !cfg!(target_os = "linux") {
+        assert!(parse("7").is_err());
+        assert!(cpu_affinity.is_err());
+        return;
+    }
+    assert_eq!(cpu_affinity.unwrap().server.cpu_affinity, Some(vec![0, 2]));
+    assert_eq!(
+        parse("7").unwrap().server.io_priority,
+        Some(IoPriority::BestEffort(7))
+    );
+    assert_eq!(
+        parse("\"idle\"").unwrap().server.io_priority,
+        Some(IoPriority::Idle)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn parse_address() {
+    let parse = |value: &str| toml::from_str::<Config>(&format!("listen = {value}"));
+
+    assert_eq!(
+        parse(r#"["127.0.0.1", 27631]"#).unwrap().listen,
+        Address::Tcp(IpAddr::V4(Ipv4Addr::LOCALHOST), 27631)
+    );
+    assert_eq!(
+        parse(r#""quic:127.0.0.1:27631""#).unwrap().listen,
+        Address::Quic(SocketAddr::from((Ipv4Addr::LOCALHOST, 27631)), None)
+    );
+    assert_eq!(
+        parse(r#""quic:[::1]:27631""#).unwrap().listen,
+        Address::Quic(
+            SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 27631)),
+            None
+        )
+    );
+    let fingerprint = "0f".repeat(32);
+    assert_eq!(
+        parse(&format!(r#""quic:127.0.0.1:27631#{fingerprint}""#))
+            .unwrap()
+            .listen,
+        Address::Quic(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 27631)),
+            Some(Fingerprint([15; 32]))
+        )
+    );
+    assert_eq!(Fingerprint([15; 32]).to_string(), fingerprint);
+    assert!(parse(r#""quic:127.0.0.1:27631#0f""#).is_err());
+    assert!(parse(r#""quic:localhost""#).is_err());
+    #[cfg(target_os = "linux")]
+    assert_eq!(
+        parse(r#""vsock:3:27631""#).unwrap().listen,
+        Address::Vsock(3, 27631)
+    );
+    assert!(parse(r#""vsock:3""#).is_err());
+    assert_eq!(
+        parse(r#""ssh://dev@build:2222""#).unwrap().listen,
+        Address::Ssh(SshAddress {
+            destination: "dev@build".into(),
+            port: Some(2222),
+            forward: "127.0.0.1:27631".into(),
+        })
+    );
+    assert_eq!(
+        parse(r#""ssh://build/127.0.0.1:4000""#).unwrap().listen,
+        Address::Ssh(SshAddress {
+            destination: "build".into(),
+            port: None,
+            forward: "127.0.0.1:4000".into(),
+        })
+    );
+    assert_eq!(
+        parse(r#""ssh://dev@[fe80::1]:2222""#).unwrap().listen,
+        Address::Ssh(SshAddress {
+            destination: "dev@fe80::1".into(),
+            port: Some(2222),
+            forward: "127.0.0.1:27631".into(),
+        })
+    );
+    let ipv6 = SshAddress {
+        destination: "::1".into(),
+        port: None,
+        forward: "[::1]:4000".into(),
+    };
+    assert_eq!(
+        parse(r#""ssh://[::1]/[::1]:4000""#).unwrap().listen,
+        Address::Ssh(ipv6.clone())
+    );
+    assert_eq!(ipv6.to_string(), "ssh://[::1]/[::1]:4000");
+    assert!(parse(r#""ssh://dev@""#).is_err());
+    assert!(parse(r#""ssh://dev@[::1""#).is_err());
+    assert!(parse(r#""ssh://build/localhost""#).is_err());
+    #[cfg(target_family = "unix")]
+    assert_eq!(
+        parse(r#""/run/ra-mux.sock""#).unwrap().listen,
+        Address::Unix(PathBuf::from("/run/ra-mux.sock"))
+    );
+
+    let config = Config {
+        listen: Address::Quic(SocketAddr::from((Ipv4Addr::LOCALHOST, 27631)), None),
+        ..Config::default()
+    };
+    let toml = toml::to_string(&config).unwrap();
+    assert_eq!(
+        toml::from_str::<Config>(&toml).unwrap().listen,
+        config.listen
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn match_allowed_ips() {
+    let network = |value: &str| IpNetwork::try_from(value.to_owned());
+    let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+
+    let lan = network("192.168.1.0/24").unwrap();
+    assert!(lan.contains(ip("192.168.1.7")));
+    assert!(lan.contains(ip("::ffff:192.168.1.7")));
+    assert!(!lan.contains(ip("192.168.2.7")));
+    assert!(!lan.contains(ip("fd00::1")));
+    let host = network("10.0.0.5").unwrap();
+    assert!(host.contains(ip("10.0.0.5")));
+    assert!(!host.contains(ip("10.0.0.6")));
+    assert!(network("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+    assert!(network("fd00::/8").unwrap().contains(ip("fd12::1")));
+    assert!(network("10.0.0.0/33").is_err());
+    assert!(network("localhost").is_err());
+    assert_eq!(String::from(host), "10.0.0.5/32");
+}
+
+#[cfg(test)]
+#[test]
+fn match_connect_rules() {
+    let config = toml::from_str::<Config>(
+        r#"
+        connect = ["127.0.0.1", 27631]
+
+        [[connect_rules]]
+        path = "/work/remote"
+        connect = ["ssh://dev@build", ["127.0.0.1", 27632]]
+        "#,
+    )
+    .unwrap();
+    let addresses = |path: &str| config.connect_addresses(Some(Path::new(path)));
+
+    assert_eq!(addresses("/work/remote/app").len(), 2);
+    assert!(matches!(addresses("/work/remote")[0], Address::Ssh(_)));
+    assert_eq!(
+        addresses("/work/remote-2"),
+        slice::from_ref(&config.connect)
+    );
+    assert_eq!(
+        config.connect_addresses(None),
+        slice::from_ref(&config.connect)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn match_instance_groups() {
+    let config = toml::from_str::<Config>(
+        r#"
+        [[instance_groups]]
+        path = "/work/mono/services/huge"
+        instance = "huge"
+
+        [[instance_groups]]
+        path = "/work/mono/services/*"
+        instance = "mono-services"
+        "#,
+    )
+    .unwrap();
+    let group = |path: &str| config.instance_group(Path::new(path));
+
+    assert_eq!(
+        group("/work/mono/services/a"),
+        Some(("mono-services", PathBuf::from("/work/mono/services"))),
+    );
+    assert_eq!(
+        group("/work/mono/services/b/src"),
+        Some(("mono-services", PathBuf::from("/work/mono/services"))),
+    );
+    assert_eq!(
+        group("/work/mono/services/huge"),
+        Some(("huge", PathBuf::from("/work/mono/services/huge"))),
+    );
+    assert_eq!(group("/work/mono/tools"), None);
+}
+
+#[cfg(test)]
+#[test]
+fn classify_request_methods() {
+    let config = toml::from_str::<Config>(
+        r#"
+        replica_methods = ["workspace/symbol", "workspace/diagnostic"]
+        background_methods = ["rust-analyzer/expandMacro", "workspace/diagnostic"]
+        "#,
+    )
+    .unwrap();
+    let role = |method: &str| config.request_role(method);
+
+    assert_eq!(role("textDocument/hover"), InstanceRole::Primary);
+    assert_eq!(role("workspace/symbol"), InstanceRole::Replica);
+    assert_eq!(role("rust-analyzer/expandMacro"), InstanceRole::Background);
+    assert_eq!(role("workspace/diagnostic"), InstanceRole::Background);
+    assert_eq!(
+        config.secondary_roles(),
+        [InstanceRole::Replica, InstanceRole::Background],
+    );
+    assert!(Config::default().secondary_roles().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn parse_keep_alive() {
+    let parse = |value: &str| toml::from_str::<Config>(&format!("keep_alive = {value}"));
+
+    assert_eq!(parse("false").unwrap().keep_alive, None);
+    assert_eq!(
+        parse("60").unwrap().keep_alive,
+        Some(KeepAlive::Seconds(60))
+    );
+    assert_eq!(
+        parse("\"never\"").unwrap().keep_alive,
+        Some(KeepAlive::Never)
+    );
+    assert!(parse("true").is_err());
+    assert!(parse("\"always\"").is_err());
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            instance_timeout: default::instance_timeout(),
+            keep_alive: default::keep_alive(),
+            gc_interval: default::gc_interval(),
+            listen: default::listen(),
+            allowed_ips: default::allowed_ips(),
+            connect: default::connect(),
+            connect_rules: default::connect_rules(),
+            connect_retry: default::connect_retry(),
+            heartbeat_interval: default::heartbeat_interval(),
+            heartbeat_timeout: default::heartbeat_timeout(),
+            reconnect_timeout: default::reconnect_timeout(),
+            reconnect_buffer: default::reconnect_buffer(),
+            wire_encoding: default::wire_encoding(),
+            compression: default::compression(),
+            stream_message_size: default::stream_message_size(),
+            max_message_size: default::max_message_size(),
+            max_json_depth: default::max_json_depth(),
+            max_json_array_length: default::max_json_array_length(),
+            max_concurrent_requests: default::max_concurrent_requests(),
+            max_client_requests: default::max_client_requests(),
+            max_client_requests_per_second: default::max_client_requests_per_second(),
+            stuck_request_timeout: default::stuck_request_timeout(),
+            stuck_request_notify: default::stuck_request_notify(),
+            restart_after_errors: default::restart_after_errors(),
+            crash_report_messages: default::crash_report_messages(),
+            memory_usage_interval: default::memory_usage_interval(),
+            meta_instances: default::meta_instances(),
+            rust_toolchain: default::rust_toolchain(),
+            workspace_keying: default::workspace_keying(),
+            instance_groups: default::instance_groups(),
+            log_filters: default::log_filters(),
+            audit_log: default::audit_log(),
+            record_stats: default::record_stats(),
+            pass_environment: default::pass_environment(),
+            passthrough_methods: default::passthrough_methods(),
+            strip_initialization_options: default::strip_initialization_options(),
+            redact_initialization_options: default::redact_initialization_options(),
+            privacy: default::privacy(),
+            supersede_requests: default::supersede_requests(),
+            replica_methods: default::replica_methods(),
+            background_methods: default::background_methods(),
+            adopt_responses: default::adopt_responses(),
+            apply_edit: default::apply_edit(),
+            show_document: default::show_document(),
+            log_messages: default::log_messages(),
+            lazy_spawn: default::lazy_spawn(),
+            watch_files: default::watch_files(),
+            adopt_instances: default::adopt_instances(),
+            trust_project_config: default::trust_project_config(),
+            server: default::server(),
+            notification_rate_limits: default::notification_rate_limits(),
+            routing: default::routing(),
+            companion_servers: default::companion_servers(),
+            did_change_debounce: default::did_change_debounce(),
+            merge_strategies: default::merge_strategies(),
+            timeouts: default::timeouts(),
+            hooks: default::hooks(),
+            download: default::download(),
+            runtime: default::runtime(),
+            ignored_settings: Vec::new(),
+        }
+    }
+}
+
+/// Server notifications routed according to the `log_messages` option
+const LOG_MESSAGES: &[&str] = &["telemetry/event", "window/logMessage", "$/logTrace"];
+
+/// Check if server notifications with `method` are log messages or telemetry
+/// routed according to `log_messages`
+pub fn is_log_message(method: &str) -> bool {
+    LOG_MESSAGES.contains(&method)
+}
+
+/// rust-analyzer extension methods which are always passed through
+///
+/// See <https://github.com/rust-lang/rust-analyzer/blob/master/docs/dev/lsp-extensions.md>.
+const RUST_ANALYZER_EXTENSIONS: &[&str] = &[
+    "experimental/externalDocs",
+    "experimental/joinLines",
+    "experimental/matchingBrace",
+    "experimental/moveItem",
+    "experimental/onEnter",
+    "experimental/openCargoToml",
+    "experimental/parentModule",
+    "experimental/runnables",
+    "experimental/ssr",
+    "rust-analyzer/analyzerStatus",
+    "rust-analyzer/expandMacro",
+    "rust-analyzer/fetchDependencyList",
+    "rust-analyzer/interpretFunction",
+    "rust-analyzer/memoryUsage",
+    "rust-analyzer/rebuildProcMacros",
+    "rust-analyzer/relatedTests",
+    "rust-analyzer/reloadWorkspace",
+    "rust-analyzer/runFlycheck",
+    "rust-analyzer/viewCrateGraph",
+    "rust-analyzer/viewFileText",
+    "rust-analyzer/viewHir",
+    "rust-analyzer/viewItemTree",
+    "rust-analyzer/viewMir",
+    "rust-analyzer/viewSyntaxTree",
+];
+
+impl Config {
+    /// Check if requests with `method` are passed through between the client
+    /// and the server which is responsible for them
+    ///
+    /// Client requests go only to the main server of the document's workspace
+    /// and are never merged. Server requests go to the client which sent the
+    /// last request and its response is forwarded back.
+    pub fn is_passthrough(&self, method: &str) -> bool {
+        RUST_ANALYZER_EXTENSIONS.contains(&method) || self.passthrough_methods.contains(method)
+    }
+
+    /// Time after which a request with `method` the server didn't respond to
+    /// is stuck, `None` if it's never
+    pub fn request_timeout(&self, method: &str) -> Option<Duration> {
+        let secs = match self.timeouts.get(method) {
+            Some(secs) => secs.get(),
+            None => self.stuck_request_timeout?,
+        };
+        Some(Duration::from_secs(secs.into()))
+    }
+
+    /// Shortest time after which any request is stuck, `None` if they never are
+    pub fn min_request_timeout(&self) -> Option<Duration> {
+        let secs = self.timeouts.values().map(|secs| secs.get());
+        let secs = secs.chain(self.stuck_request_timeout).min()?;
+        Some(Duration::from_secs(secs.into()))
+    }
+
+    /// Role of the instances answering client requests with `method`
+    pub fn request_role(&self, method: &str) -> InstanceRole {
+        if self.background_methods.contains(method) {
+            InstanceRole::Background
+        } else if self.replica_methods.contains(method) {
+            InstanceRole::Replica
+        } else {
+            InstanceRole::Primary
+        }
+    }
+
+    /// Roles of the instances spawned for every server besides the primary
+    pub fn secondary_roles(&self) -> Vec<InstanceRole> {
+        let mut roles = Vec::new();
+        if !self.replica_methods.is_empty() {
+            roles.push(InstanceRole::Replica);
+        }
+        if !self.background_methods.is_empty() {
+            roles.push(InstanceRole::Background);
+        }
+        roles
+    }
+
+    /// Limits on messages read from clients
+    pub fn message_limits(&self) -> Limits {
+        Limits {
+            max_message_size: self.max_message_size as usize,
+            max_depth: self.max_json_depth as usize,
+            max_array_length: self.max_json_array_length as usize,
+        }
+    }
+
+    /// Servers to connect to for a workspace, in order of preference
+    ///
+    /// The first of `connect_rules` containing `workspace` applies, `connect`
+    /// is used when none of them do.
+    pub fn connect_addresses(&self, workspace: Option<&Path>) -> &[Address] {
+        workspace
+            .and_then(|workspace| {
+                self.connect_rules
+                    .iter()
+                    .find(|rule| workspace.starts_with(&rule.path) && !rule.connect.is_empty())
+            })
+            .map_or(slice::from_ref(&self.connect), |rule| &rule.connect)
+    }
+
+    /// Settings of `server` by its path or file name, managed servers and
+    /// ones with a version like `rust-analyzer@nightly` by their name
+    pub fn server_settings(&self, server: &str) -> Option<&ServerSettings> {
+        let servers = &self.server.servers;
+        let unmanaged = server
+            .strip_prefix(download::MANAGED_PREFIX)
+            .unwrap_or(server);
+        let name = Path::new(unmanaged)
+            .file_name()
+            .and_then(|name| name.to_str());
+        servers
+            .get(server)
+            .or_else(|| servers.get(name?))
+            .or_else(|| servers.get(name?.split_once('@')?.0))
+    }
+
+    /// Find the first of `instance_groups` matching `workspace` or one of its
+    /// parents, returns its name and the directory its instance is keyed by
+    ///
+    /// That's the part of the glob before the first component with a
+    /// wildcard, so all workspaces matching it share the instance.
+    pub fn instance_group(&self, workspace: &Path) -> Option<(&str, PathBuf)> {
+        self.instance_groups.iter().find_map(|group| {
+            let path = match group.path.strip_prefix("~/") {
+                Some(rest) => BaseDirs::new()?.home_dir().join(rest),
+                None => PathBuf::from(&group.path),
+            };
+            let glob = match GlobBuilder::new(path.to_str()?)
+                .literal_separator(true)
+                .build()
+            {
+                Ok(glob) => glob.compile_matcher(),
+                Err(err) => {
+                    warn!(?err, path = group.path, "invalid instance group glob");
+                    return None;
+                }
+            };
+            if !workspace.ancestors().any(|dir| glob.is_match(dir)) {
+                return None;
+            }
+            let base = path
+                .components()
+                .take_while(|component| {
+                    !component
+                        .as_os_str()
+                        .to_string_lossy()
+                        .contains(['*', '?', '[', '{'])
+                })
+                .collect();
+            Some((group.instance.as_str(), base))
+        })
+    }
+
+    /// Path of the user config file in the platform config directory
+    pub fn path() -> Result<PathBuf> {
+        paths::user_config()
+    }
+
+    /// Try loading the system, user and project config files layered on each
+    /// other, see [`paths`]
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if none of them exists.
+    pub fn try_load() -> Result<Self> {
+        Config::load_files(&paths::config_files())
+    }
+
+    fn load_files(files: &[PathBuf]) -> Result<Self> {
+        if files.is_empty() {
+            let err = io::Error::from(io::ErrorKind::NotFound);
+            return Err(err).context("no config file found");
+        }
+        Config::load_layers(files)
+    }
+
+    /// Load the config `files`, settings in later ones override the same
+    /// settings in earlier ones and tables are merged
+    ///
+    /// Only the [`PROJECT_SETTINGS`] are read from a project config unless an
+    /// earlier file sets `trust_project_config`, the others end up in
+    /// `ignored_settings`.
+    pub fn load_layers(files: &[PathBuf]) -> Result<Self> {
+        let mut config = toml::value::Table::new();
+        let mut ignored = Vec::new();
+        for file in files {
+            let path = file.display();
+            let data =
+                fs::read(file).with_context(|| format!("cannot read config file `{path}`"))?;
+            let mut layer: toml::value::Table = toml::from_slice(&data)
+                .with_context(|| format!("cannot parse config file `{path}`"))?;
+            let trusted = config.get("trust_project_config") == Some(&toml::Value::Boolean(true));
+            if file.ends_with(paths::PROJECT_CONFIG) && !trusted {
+                layer = layer
+                    .into_iter()
+                    .filter(|(key, _)| {
+                        let allowed = PROJECT_SETTINGS.contains(&key.as_str());
+                        if !allowed {
+                            ignored.push(format!("`{key}` in `{path}`"));
+                        }
+                        allowed
+                    })
+                    .collect();
+            }
+            merge_layer(&mut config, layer);
+        }
+        let paths = files.iter().map(|file| format!("`{}`", file.display()));
+        let paths = paths.collect::<Vec<_>>().join(", ");
+        let mut config: Config = toml::Value::Table(config)
+            .try_into()
+            .with_context(|| format!("cannot parse config files {paths}"))?;
+        config.ignored_settings = ignored;
+        Ok(config)
+    }
+}
+
+/// Merge the settings of a config file `layer` into `config`, nested tables
+/// are merged and other values replaced
+fn merge_layer(config: &mut toml::value::Table, layer: toml::value::Table) {
+    for (key, value) in layer {
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(layer)) => {
+                merge_layer(table, layer);
+            }
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
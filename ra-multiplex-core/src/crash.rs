@@ -0,0 +1,124 @@
+//! Crash reports of language servers
+//!
+//! The last messages sent to a server and the last lines it wrote to stderr
+//! are kept around, when the server exits abnormally they're saved into a
+//! crash report directory together with its exit status so there is something
+//! to attach to an upstream bug report.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use tokio::fs;
+
+use crate::lsp::jsonrpc::Message;
+use crate::lsp::redact::Redaction;
+use crate::paths;
+
+/// Number of stderr lines kept for a crash report
+const STDERR_LINES: usize = 200;
+
+pub struct CrashRecorder {
+    /// Number of messages kept
+    limit: usize,
+
+    /// What is hidden in the recorded messages
+    redaction: Arc<Redaction>,
+
+    /// Last messages sent to the server serialized as JSON
+    messages: Mutex<VecDeque<String>>,
+
+    /// Last lines the server wrote to stderr
+    stderr: Mutex<VecDeque<String>>,
+}
+
+impl CrashRecorder {
+    pub fn new(limit: usize, redaction: Arc<Redaction>) -> CrashRecorder {
+        CrashRecorder {
+            limit,
+            redaction,
+            messages: Mutex::default(),
+            stderr: Mutex::default(),
+        }
+    }
+
+    /// Record a message written to the server
+    pub fn sent(&self, message: &Message) {
+        let json = serde_json::to_string(&self.redaction.redacted(message)).unwrap();
+        push(&mut self.messages.lock().unwrap(), json, self.limit);
+    }
+
+    /// Record a line the server wrote to stderr
+    pub fn stderr(&self, line: &str) {
+        push(
+            &mut self.stderr.lock().unwrap(),
+            line.to_owned(),
+            STDERR_LINES,
+        );
+    }
+
+    /// Save a crash report into a new directory named `name` and return its
+    /// path
+    ///
+    /// `summary` describes the server and how it exited.
+    pub async fn save(&self, name: &str, summary: &str) -> Result<PathBuf> {
+        let dir = paths::crash_dir()?.join(name);
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("creating {dir:?}"))?;
+
+        let messages = lines(&self.messages.lock().unwrap());
+        let stderr = lines(&self.stderr.lock().unwrap());
+        for (file, contents) in [
+            ("summary.txt", summary),
+            ("messages.jsonl", &messages),
+            ("stderr.log", &stderr),
+        ] {
+            let path = dir.join(file);
+            fs::write(&path, contents)
+                .await
+                .with_context(|| format!("writing {path:?}"))?;
+        }
+        Ok(dir)
+    }
+}
+
+fn push(buffer: &mut VecDeque<String>, line: String, limit: usize) {
+    if buffer.len() == limit {
+        buffer.pop_front();
+    }
+    buffer.push_back(line);
+}
+
+fn lines(buffer: &VecDeque<String>) -> String {
+    buffer.iter().map(|line| format!("{line}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{Notification, Version};
+
+    #[test]
+    fn keep_last_messages() {
+        let recorder = CrashRecorder::new(2, Arc::default());
+        for method in ["a", "b", "c"] {
+            let notif = Notification {
+                jsonrpc: Version,
+                method: method.into(),
+                params: Value::Null,
+            };
+            recorder.sent(&notif.into());
+        }
+        recorder.stderr("panicked");
+
+        let messages = lines(&recorder.messages.lock().unwrap());
+        assert_eq!(messages.lines().count(), 2);
+        assert!(messages.contains(r#""method":"b""#));
+        assert!(messages.contains(r#""method":"c""#));
+        assert_eq!(lines(&recorder.stderr.lock().unwrap()), "panicked\n");
+    }
+}
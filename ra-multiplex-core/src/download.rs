@@ -0,0 +1,406 @@
+//! Language servers downloaded and cached by ra-multiplex
+//!
+//! Clients can request a server like `managed:rust-analyzer@nightly` which
+//! refers to an entry in the `[download]` config section. The release is
+//! downloaded with `curl`, verified, unpacked into the cache directory and
+//! reused until its `update_interval` passes. Outdated downloads are updated
+//! in the background while clients keep using the cached one.
+//!
+//! Checksums and archives are handled by the system tools, `ra-multiplex
+//! doctor` reports the ones missing for the configured downloads.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::config::{Config, Download};
+use crate::lsp::ext::ErrorCode;
+use crate::paths;
+use crate::resolve::find_executable;
+
+/// Prefix of server names referring to managed downloads
+pub const MANAGED_PREFIX: &str = "managed:";
+
+/// Server name and version of a download
+type DownloadKey = (String, String);
+
+/// Locks of the downloads by server name and version, clients requesting
+/// the same server don't race each other but different ones don't wait
+static LOCKS: std::sync::Mutex<BTreeMap<DownloadKey, Arc<Mutex<()>>>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+fn lock(name: &str, version: &str) -> Arc<Mutex<()>> {
+    let mut locks = LOCKS.lock().unwrap();
+    let key = (name.to_owned(), version.to_owned());
+    Arc::clone(locks.entry(key).or_default())
+}
+
+/// Resolve a managed server spec like `rust-analyzer@nightly` to the path of
+/// the downloaded binary
+///
+/// A missing download is fetched before returning, an outdated one is
+/// updated in the background. The version may be omitted if only one is
+/// configured.
+pub async fn resolve(config: &Config, spec: &str) -> Result<String> {
+    let (name, version, download) = lookup(config, spec).context(ErrorCode::ServerNotAllowed)?;
+    let dir = paths::cache_dir()?.join("servers").join(name).join(version);
+    let binary = dir.join(download.binary.as_deref().unwrap_or(name));
+
+    let lock = lock(name, version);
+    let guard = lock.lock().await;
+    match age(&dir).await {
+        Some(age) if !is_outdated(download, age) => {}
+        Some(_) => {
+            drop(guard);
+            let (name, version) = (name.to_owned(), version.to_owned());
+            let (download, dir) = (download.clone(), dir.clone());
+            tokio::spawn(async move { update(&download, &name, &version, &dir).await });
+        }
+        None => {
+            info!(?name, ?version, url = ?download.url, "downloading language server");
+            fetch(download, name, &dir)
+                .await
+                .context(ErrorCode::DownloadFailed)?;
+        }
+    }
+    ensure!(
+        fs::try_exists(&binary).await?,
+        "downloaded archive doesn't contain {binary:?}"
+    );
+    Ok(binary.display().to_string())
+}
+
+/// Check if the server of a managed server spec has to be downloaded before
+/// it can be started
+pub async fn is_missing(config: &Config, spec: &str) -> bool {
+    let Ok((name, version, _)) = lookup(config, spec) else {
+        return false;
+    };
+    let Ok(cache) = paths::cache_dir() else {
+        return false;
+    };
+    age(&cache.join("servers").join(name).join(version))
+        .await
+        .is_none()
+}
+
+/// Update an outdated download, unless another client did already
+async fn update(download: &Download, name: &str, version: &str, dir: &Path) {
+    let lock = lock(name, version);
+    let _guard = lock.lock().await;
+    if !age(dir).await.is_some_and(|age| is_outdated(download, age)) {
+        return;
+    }
+    info!(?name, ?version, url = ?download.url, "updating language server");
+    match fetch(download, name, dir).await {
+        Ok(()) => info!(?name, ?version, "language server updated"),
+        Err(err) => warn!(
+            ?err,
+            ?name,
+            ?version,
+            "update failed, using cached download"
+        ),
+    }
+}
+
+/// Find the download configured for a managed server spec, returns it with
+/// the name and version
+fn lookup<'a>(config: &'a Config, spec: &'a str) -> Result<(&'a str, &'a str, &'a Download)> {
+    let (name, version) = parse_spec(spec);
+    let versions = config
+        .download
+        .get(name)
+        .with_context(|| format!("no download configured for {name:?}"))?;
+    let (version, download) = match version {
+        Some(version) => versions
+            .get_key_value(version)
+            .with_context(|| format!("no download configured for {name:?} version {version:?}"))?,
+        None => {
+            let mut versions = versions.iter();
+            match (versions.next(), versions.next()) {
+                (Some(only), None) => only,
+                _ => bail!("{name:?} has multiple versions configured, specify one"),
+            }
+        }
+    };
+    ensure!(
+        is_path_component(name) && is_path_component(version),
+        "invalid managed server name {name:?} version {version:?}",
+    );
+    Ok((name, version, download))
+}
+
+fn parse_spec(spec: &str) -> (&str, Option<&str>) {
+    match spec.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    }
+}
+
+fn is_path_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+fn is_outdated(download: &Download, age: Duration) -> bool {
+    download
+        .update_interval
+        .is_some_and(|interval| age > Duration::from_secs(interval.into()))
+}
+
+/// Time since the download in `dir` was completed
+async fn age(dir: &Path) -> Option<Duration> {
+    let modified = fs::metadata(dir).await.ok()?.modified().ok()?;
+    Some(
+        SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default(),
+    )
+}
+
+/// How the downloaded file is unpacked
+#[derive(Debug, PartialEq, Eq)]
+enum Format {
+    Tar,
+    Zip,
+    Gzip,
+    Binary,
+}
+
+impl Format {
+    fn from_url(url: &str) -> Format {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let tar_suffixes = [
+            ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.bz2", ".tar.zst",
+        ];
+        if tar_suffixes.iter().any(|suffix| path.ends_with(suffix)) {
+            Format::Tar
+        } else if path.ends_with(".zip") {
+            Format::Zip
+        } else if path.ends_with(".gz") {
+            Format::Gzip
+        } else {
+            Format::Binary
+        }
+    }
+}
+
+/// Download, verify and unpack into a temporary directory and then replace
+/// `dir` with it, so a failed download never leaves a broken server behind
+async fn fetch(download: &Download, name: &str, dir: &Path) -> Result<()> {
+    let mut tmp = dir.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    if fs::try_exists(&tmp).await? {
+        fs::remove_dir_all(&tmp).await?;
+    }
+    fs::create_dir_all(&tmp)
+        .await
+        .with_context(|| format!("creating {tmp:?}"))?;
+
+    if let Err(err) = unpack(download, name, &tmp).await {
+        let _ = fs::remove_dir_all(&tmp).await;
+        return Err(err);
+    }
+
+    // Move the old download aside first, so a server being started from it
+    // isn't missing for longer than the two renames take.
+    let mut old = dir.as_os_str().to_owned();
+    old.push(".old");
+    let old = PathBuf::from(old);
+    if fs::try_exists(&old).await? {
+        fs::remove_dir_all(&old).await?;
+    }
+    if fs::try_exists(dir).await? {
+        fs::rename(dir, &old).await?;
+    }
+    fs::rename(&tmp, dir)
+        .await
+        .with_context(|| format!("moving download to {dir:?}"))?;
+    if fs::try_exists(&old).await? {
+        fs::remove_dir_all(&old).await?;
+    }
+    Ok(())
+}
+
+/// Download and verify the file and unpack the server into `tmp`
+async fn unpack(download: &Download, name: &str, tmp: &Path) -> Result<()> {
+    let format = Format::from_url(&download.url);
+    // gzip refuses to decompress files without the suffix
+    let file = tmp.join(match format {
+        Format::Gzip => "download.gz",
+        _ => "download",
+    });
+    run(Command::new("curl")
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(&file)
+        .arg(&download.url))
+    .await?;
+
+    if let Some(expected) = &download.sha256 {
+        let output = run(sha256_command()?.arg(&file)).await?;
+        let actual = output.split_whitespace().next().unwrap_or_default();
+        ensure!(
+            actual.eq_ignore_ascii_case(expected),
+            "sha256 mismatch, expected {expected} found {actual}"
+        );
+    }
+
+    let binary = tmp.join(download.binary.as_deref().unwrap_or(name));
+    match format {
+        Format::Tar => {
+            run(Command::new("tar").arg("-xf").arg(&file).arg("-C").arg(tmp)).await?;
+        }
+        Format::Zip => {
+            run(Command::new("unzip")
+                .arg("-q")
+                .arg(&file)
+                .arg("-d")
+                .arg(tmp))
+            .await?;
+        }
+        Format::Gzip => {
+            run(Command::new("gzip").arg("-d").arg(&file)).await?;
+            fs::rename(tmp.join("download"), &binary).await?;
+        }
+        Format::Binary => {
+            fs::rename(&file, &binary).await?;
+        }
+    }
+    if fs::try_exists(&file).await? {
+        fs::remove_file(&file).await?;
+    }
+    set_executable(&binary).await
+}
+
+/// Programs which compute SHA-256 checksums, `sha256sum` is missing on macOS
+/// and the BSDs which have `shasum` instead
+const SHA256_TOOLS: &[&str] = &["sha256sum", "shasum"];
+
+fn sha256_command() -> Result<Command> {
+    let path = env::var("PATH").ok();
+    let tool = SHA256_TOOLS
+        .iter()
+        .find(|tool| find_executable(tool, path.as_deref()).is_some())
+        .with_context(|| format!("none of {SHA256_TOOLS:?} found to verify the checksum"))?;
+    let mut command = Command::new(tool);
+    if *tool == "shasum" {
+        command.args(["--algorithm", "256"]);
+    }
+    Ok(command)
+}
+
+/// Programs needed to fetch `download`, one of each of the returned groups
+pub fn required_tools(download: &Download) -> Vec<&'static [&'static str]> {
+    let mut tools: Vec<&'static [&'static str]> = vec![&["curl"]];
+    if download.sha256.is_some() {
+        tools.push(SHA256_TOOLS);
+    }
+    match Format::from_url(&download.url) {
+        Format::Tar => tools.push(&["tar"]),
+        Format::Zip => tools.push(&["unzip"]),
+        Format::Gzip => tools.push(&["gzip"]),
+        Format::Binary => {}
+    }
+    tools
+}
+
+/// Run a command and return its stdout
+async fn run(command: &mut Command) -> Result<String> {
+    let program = command.as_std().get_program().to_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("running {program:?}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{program:?} failed: {}", stderr.trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(unix)]
+async fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if fs::try_exists(path).await? {
+        let mut permissions = fs::metadata(path).await?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        fs::set_permissions(path, permissions).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn managed_server_spec() {
+        assert_eq!(
+            parse_spec("rust-analyzer@nightly"),
+            ("rust-analyzer", Some("nightly"))
+        );
+        assert_eq!(parse_spec("clangd"), ("clangd", None));
+        assert!(!is_path_component(".."));
+        assert!(!is_path_component("a/b"));
+    }
+
+    #[test]
+    fn download_format_from_url() {
+        let format = Format::from_url;
+        assert_eq!(format("https://x/clangd-linux-18.1.3.zip"), Format::Zip);
+        assert_eq!(format("https://x/clangd.tar.xz"), Format::Tar);
+        assert_eq!(
+            format("https://x/rust-analyzer-x86_64-unknown-linux-gnu.gz"),
+            Format::Gzip
+        );
+        assert_eq!(format("https://x/server?archive=.zip#x"), Format::Binary);
+    }
+
+    #[test]
+    fn lock_per_version() {
+        let nightly = lock("rust-analyzer", "nightly");
+        assert!(Arc::ptr_eq(&nightly, &lock("rust-analyzer", "nightly")));
+        assert!(!Arc::ptr_eq(&nightly, &lock("rust-analyzer", "stable")));
+        assert!(!Arc::ptr_eq(&nightly, &lock("clangd", "nightly")));
+    }
+
+    #[test]
+    fn tools_for_download() {
+        let download = |url: &str, sha256: Option<&str>| Download {
+            url: url.into(),
+            sha256: sha256.map(Into::into),
+            binary: None,
+            update_interval: None,
+        };
+        assert_eq!(
+            required_tools(&download("https://x/clangd.zip", None)),
+            [&["curl"][..], &["unzip"]]
+        );
+        assert_eq!(
+            required_tools(&download("https://x/rust-analyzer.gz", Some("00"))),
+            [&["curl"][..], SHA256_TOOLS, &["gzip"]]
+        );
+    }
+}
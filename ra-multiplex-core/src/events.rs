@@ -0,0 +1,93 @@
+//! Lifecycle events streamed to subscribed clients
+//!
+//! Instances starting, exiting, crashing and being evicted and clients
+//! attaching to and detaching from them are published to every client
+//! connected with the `subscribe` method. Supervisors and dashboards react to
+//! them instead of polling `status`. Events published while nobody is
+//! subscribed are dropped.
+
+use tokio::sync::broadcast;
+
+use crate::instance::Instance;
+use crate::lsp::ext::{Event, EventKind};
+
+/// Events buffered for each subscriber, a slower one misses the oldest
+const CAPACITY: usize = 256;
+
+/// Events of one server, its subscribers don't see the events of other
+/// servers in the same process
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Events {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+
+    /// Receive all events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Event without any details
+pub fn event(kind: EventKind) -> Event {
+    Event {
+        kind,
+        time: (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64,
+        pid: None,
+        server: None,
+        workspace_root: None,
+        client_id: None,
+        exit_code: None,
+        reason: None,
+        missed: None,
+    }
+}
+
+/// Event about `instance`
+pub fn instance(kind: EventKind, instance: &Instance) -> Event {
+    let key = instance.key();
+    Event {
+        pid: Some(instance.pid()),
+        server: Some(key.server.clone()),
+        workspace_root: Some(key.workspace_root.clone()),
+        ..event(kind)
+    }
+}
+
+/// Event about a client attaching to or detaching from `instance`
+pub fn client(kind: EventKind, client_id: usize, instance: &Instance) -> Event {
+    Event {
+        client_id: Some(client_id),
+        ..self::instance(kind, instance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_to_subscribers() {
+        let events = Events::new();
+        let other = Events::new();
+        let mut unrelated = other.subscribe();
+        events.publish(event(EventKind::InstanceStarted));
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        events.publish(event(EventKind::ClientAttached));
+        for subscriber in [&mut first, &mut second] {
+            let received = subscriber.recv().await.unwrap();
+            assert_eq!(received.kind, EventKind::ClientAttached);
+        }
+        assert!(unrelated.try_recv().is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Sending the server `lspMux` requests like `ra-multiplex status` does
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::io::{AsyncBufRead, AsyncWrite, BufReader};
+
+use crate::config::Config;
+use crate::lsp::ext::{self, ClientMode, LspMuxOptions};
+use crate::lsp::jsonrpc::{Message, Request, RequestId, Version};
+use crate::lsp::transport::{Compression, LspReader, LspWriter, WireEncoding};
+use crate::lsp::{InitializationOptions, InitializeParams};
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
+
+/// Send an lspmux request and parse the response result
+pub async fn ext_request<T>(config: &Config, method: ext::Request) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let (result, _, _) = connect_session(config, method).await?;
+    serde_json::from_value(result).context("parse response result")
+}
+
+/// Send an lspmux request, returns the response result and the connection
+/// for requests which keep it open like `attach` and `subscribe`
+pub async fn ext_session(
+    config: &Config,
+    method: ext::Request,
+) -> Result<(
+    Value,
+    LspReader<impl AsyncBufRead + Unpin>,
+    LspWriter<impl AsyncWrite + Unpin>,
+)> {
+    connect_session(config, method).await
+}
+
+/// [`ext_session`] over a socket
+pub(crate) async fn connect_session(
+    config: &Config,
+    method: ext::Request,
+) -> Result<(
+    Value,
+    LspReader<BufReader<OwnedReadHalf>>,
+    LspWriter<OwnedWriteHalf>,
+)> {
+    let (reader, writer) = Stream::connect(&config.connect)
+        .await
+        .context("connect")?
+        .into_split();
+    let mut writer = LspWriter::new(writer, "lspmux");
+    let mut reader = LspReader::new(BufReader::new(reader), "lspmux");
+
+    writer
+        .write_message(
+            &Request {
+                jsonrpc: Version,
+                method: "initialize".into(),
+                params: serde_json::to_value(InitializeParams {
+                    initialization_options: Some(InitializationOptions {
+                        lsp_mux: Some(LspMuxOptions {
+                            version: LspMuxOptions::PROTOCOL_VERSION.into(),
+                            mode: ClientMode::Normal,
+                            follow: None,
+                            encoding: WireEncoding::Lsp,
+                            compression: Compression::Off,
+                            resumable: false,
+                            reconnect_token: None,
+                            received: None,
+                            method,
+                        }),
+                        other_options: serde_json::Map::default(),
+                    }),
+                    process_id: None,
+                    client_info: None,
+                    locale: None,
+                    root_path: None,
+                    root_uri: None,
+                    capabilities: None,
+                    trace: None,
+                    workspace_folders: Vec::new(),
+                })
+                .unwrap(),
+                id: RequestId::Number(0),
+            }
+            .into(),
+        )
+        .await
+        .context("send lspmux request")?;
+
+    match reader
+        .read_message()
+        .await
+        .context("read lspmux response")?
+        .context("stream ended")?
+        .into_response()
+        .context("received message was not a response")?
+    {
+        Ok(success) => Ok((success.result, reader, writer)),
+        Err(error) => bail!(
+            "received error response: {msg:?}",
+            msg = Message::ResponseError(error),
+        ),
+    }
+}
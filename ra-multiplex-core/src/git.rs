@@ -0,0 +1,50 @@
+//! Keying instances by the git repository
+//!
+//! Editors opened on different subdirectories of one repository can share an
+//! instance keyed by the repository's top-level directory while instances of
+//! different repositories stay separate.
+
+use std::path::Path;
+
+use tokio::fs;
+
+/// Find the top-level directory of the git working tree containing `dir`
+///
+/// That's the closest ancestor with a `.git` directory, or a `.git` file in
+/// linked worktrees and submodules which are working trees of their own.
+/// Returns `None` outside a git working tree.
+pub async fn toplevel(dir: &str) -> Option<String> {
+    for dir in Path::new(dir).ancestors() {
+        if fs::try_exists(dir.join(".git")).await.unwrap_or(false) {
+            return Some(dir.display().to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn find_toplevel() {
+        let dir = std::env::temp_dir().join(format!("ra-mux-git-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("repo/.git")).unwrap();
+        std::fs::create_dir_all(dir.join("repo/crates/a")).unwrap();
+        std::fs::create_dir_all(dir.join("repo/vendor/sub")).unwrap();
+        std::fs::write(
+            dir.join("repo/vendor/sub/.git"),
+            "gitdir: ../../.git/modules/sub",
+        )
+        .unwrap();
+        let path = |path: &str| dir.join(path).display().to_string();
+
+        assert_eq!(toplevel(&path("repo/crates/a")).await, Some(path("repo")));
+        assert_eq!(toplevel(&path("repo")).await, Some(path("repo")));
+        assert_eq!(
+            toplevel(&path("repo/vendor/sub")).await,
+            Some(path("repo/vendor/sub"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
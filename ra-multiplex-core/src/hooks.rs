@@ -0,0 +1,81 @@
+//! User commands executed on lifecycle events
+//!
+//! The commands run in the background with environment variables describing
+//! the event, their output is discarded and failures are only logged.
+
+use std::process::Stdio;
+
+use tokio::process::Command;
+use tokio::task;
+use tracing::{debug, warn, Instrument};
+
+use crate::config::Hooks;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    InstanceStart,
+    InstanceExit,
+    InstanceCrash,
+    ClientConnect,
+    ClientDisconnect,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::InstanceStart => "instance_start",
+            Event::InstanceExit => "instance_exit",
+            Event::InstanceCrash => "instance_crash",
+            Event::ClientConnect => "client_connect",
+            Event::ClientDisconnect => "client_disconnect",
+        }
+    }
+
+    fn command(self, hooks: &Hooks) -> Option<&[String]> {
+        let command = match self {
+            Event::InstanceStart => &hooks.on_instance_start,
+            Event::InstanceExit => &hooks.on_instance_exit,
+            Event::InstanceCrash => &hooks.on_instance_crash,
+            Event::ClientConnect => &hooks.on_client_connect,
+            Event::ClientDisconnect => &hooks.on_client_disconnect,
+        };
+        command.as_deref()
+    }
+}
+
+/// Run the command configured for `event` if there is one
+///
+/// `LSPMUX_EVENT` is set to the event name, `vars` are passed as additional
+/// environment variables.
+pub fn run(hooks: &Hooks, event: Event, vars: &[(&str, String)]) {
+    let Some((program, args)) = event.command(hooks).and_then(<[_]>::split_first) else {
+        return;
+    };
+    debug!(?event, ?program, ?args, "running hook");
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env("LSPMUX_EVENT", event.name())
+        .envs(vars.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            warn!(?err, ?event, ?program, "error running hook");
+            return;
+        }
+    };
+    task::spawn(
+        async move {
+            match child.wait().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!(?event, code = status.code(), "hook failed"),
+                Err(err) => warn!(?err, ?event, "error waiting for hook"),
+            }
+        }
+        .in_current_span(),
+    );
+}
@@ -0,0 +1,5006 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use std::{env, fmt};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::time::MissedTickBehavior;
+use tokio::{select, task};
+use tracing::{debug, error, field, info, instrument, trace, warn, Instrument};
+
+use crate::client::{self, Client};
+use crate::config::{self, ApplyEditTarget, Config, KeepAlive, Route, ShowDocumentFallback};
+use crate::crash::CrashRecorder;
+use crate::events;
+use crate::hooks::{self, Event};
+use crate::lsp::ext::{ErrorCode, EventKind, InstanceRole, Tag};
+use crate::lsp::jsonrpc::{
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+};
+use crate::lsp::redact::Logged;
+use crate::lsp::transport::{LspReader, LspWriter};
+use crate::lsp::{self, ext};
+use crate::merge::merge_patch;
+use crate::mirror::{Mirror, Sink};
+use crate::paths;
+use crate::queue::{self, RequestQueue};
+use crate::scheduling;
+#[cfg(unix)]
+use crate::shim;
+use crate::state::ServerState;
+use crate::traffic::TrafficStats;
+use crate::watcher::{self, FileWatcher};
+
+/// Specifies server configuration
+///
+/// If another server with the same configuration is requested we can reuse it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceKey {
+    pub server: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub workspace_root: String,
+    #[serde(default)]
+    pub role: InstanceRole,
+    /// Name of the `instance_groups` entry the workspace matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Tag the client asked for with `RA_MUX_TAG`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+impl InstanceKey {
+    /// Build the command spawning the server, `wrapper` is prepended to the
+    /// server and its arguments and `launcher` to all of them
+    ///
+    /// Placeholders `{workspace}`, `{workspace_name}`, `{user}` and
+    /// `{config_dir}` are substituted in every part of the command and in the
+    /// environment variable values, anything else is left as is. The parts are
+    /// passed as separate arguments without a shell, on Windows they're quoted
+    /// so the server gets them verbatim. Batch files like the `.cmd` shims npm
+    /// installs run through cmd.exe, std escapes their arguments for it and
+    /// refuses to spawn them with an argument it can't escape. With `shell`
+    /// the parts are joined with spaces and run by `/bin/sh -c` or
+    /// `cmd.exe /c` instead.
+    fn command(&self, launcher: &[String], wrapper: &[String], shell: bool) -> Command {
+        let placeholders = self.placeholders();
+        let placeholders = placeholders
+            .each_ref()
+            .map(|(name, value)| (*name, value.as_str()));
+        let expand = |text: &String| expand_placeholders(text, &placeholders);
+
+        let parts = wrapper
+            .iter()
+            .chain([&self.server])
+            .chain(&self.args)
+            .map(expand)
+            .collect::<Vec<_>>();
+        let mut command = match shell {
+            true => shell_command(launcher, &parts.join(" ")),
+            false => {
+                let mut parts = launcher.iter().cloned().chain(parts);
+                let mut command = Command::new(parts.next().unwrap());
+                command.args(parts);
+                command
+            }
+        };
+        command
+            .envs(self.env.iter().map(|(key, value)| (key, expand(value))))
+            .current_dir(&self.workspace_root);
+        command
+    }
+
+    /// Substitute the placeholders in a path from the server settings
+    fn expand(&self, text: &str) -> String {
+        let placeholders = self.placeholders();
+        let placeholders = placeholders
+            .each_ref()
+            .map(|(name, value)| (*name, value.as_str()));
+        expand_placeholders(text, &placeholders)
+    }
+
+    /// Values of the placeholders substituted in commands and settings file
+    /// paths
+    fn placeholders(&self) -> [(&'static str, String); 4] {
+        let workspace_name = Path::new(&self.workspace_root)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let user = env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_default();
+        let config_dir = paths::config_dir()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+        [
+            ("{workspace}", self.workspace_root.clone()),
+            ("{workspace_name}", workspace_name),
+            ("{user}", user),
+            ("{config_dir}", config_dir),
+        ]
+    }
+}
+
+/// Command running `line` with the system shell
+#[cfg(not(windows))]
+fn shell_command(launcher: &[String], line: &str) -> Command {
+    let mut parts = launcher
+        .iter()
+        .map(String::as_str)
+        .chain(["/bin/sh", "-c", line]);
+    let mut command = Command::new(parts.next().unwrap());
+    command.args(parts);
+    command
+}
+
+/// Command running `line` with the system shell
+#[cfg(windows)]
+fn shell_command(_launcher: &[String], line: &str) -> Command {
+    // cmd.exe doesn't follow the usual quoting rules, with `/s` it strips the
+    // outer quotes and runs the rest as is.
+    let mut command = Command::new("cmd.exe");
+    command.raw_arg(format!("/d /s /c \"{line}\""));
+    command
+}
+
+fn expand_placeholders(text: &str, placeholders: &[(&str, &str)]) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match placeholders
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder))
+        {
+            Some((placeholder, value)) => {
+                expanded.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Language server instance
+pub struct Instance {
+    key: InstanceKey,
+
+    /// Language server child process id, changes when the server is restarted
+    pid: AtomicU32,
+
+    /// Params of the `initialize` request the server was started with
+    init_params: lsp::InitializeParams,
+
+    /// Server's response to `initialize` request
+    init_result: lsp::InitializeResult,
+
+    /// Handle for sending messages to the language server instance
+    server: mpsc::Sender<Message>,
+
+    /// Data of associated clients
+    clients: Mutex<HashMap<usize, ClientData>>,
+
+    /// Dynamic capabilities registered by the server
+    dynamic_capabilities: Mutex<HashMap<String, lsp::Registration>>,
+
+    /// Last `experimental/serverStatus` notification sent by the server
+    server_status: Mutex<Option<Notification>>,
+
+    /// Work done progress the server began and didn't end yet by token
+    progress: Mutex<HashMap<String, ActiveProgress>>,
+
+    /// Documents opened in the server by URI
+    documents: Mutex<HashMap<String, Document>>,
+
+    /// Delay for merging `textDocument/didChange` notifications
+    did_change_debounce: Option<Duration>,
+
+    /// `textDocument/didChange` notification held back to be merged with
+    /// following changes, must be locked after `documents`
+    debounced_change: Mutex<DebouncedChange>,
+
+    /// Server requests broadcast to clients by the server's request ID with
+    /// the clients which didn't answer yet, must be locked after `clients`
+    broadcasts: Mutex<HashMap<RequestId, HashSet<usize>>>,
+
+    /// `workspace/applyEdit` requests by the server's request ID waiting for
+    /// clients to apply them, must be locked after `clients`
+    apply_edits: Mutex<HashMap<RequestId, PendingEdit>>,
+
+    /// `window/showDocument` requests by the server's request ID with the
+    /// client asked to show the document, must be locked after `clients`
+    show_documents: Mutex<HashMap<RequestId, usize>>,
+
+    /// Requests of disconnected clients whose responses go to an attached
+    /// session of the same user, by the client ID and the client's request ID
+    orphans: Mutex<HashMap<(usize, RequestId), Orphan>>,
+
+    /// Params of `workspace/configuration` requests forwarded to a client by
+    /// the server's request ID, the client's result gets the settings
+    /// overlays of other clients and is remembered in `configuration`
+    configuration_requests: Mutex<HashMap<RequestId, Value>>,
+
+    /// Values of the last `workspace/configuration` results by their item,
+    /// they answer the standby server's requests during [`Instance::rollover`]
+    configuration: Mutex<HashMap<String, Value>>,
+
+    /// Requests sent by ra-multiplex itself waiting for a response, keyed by
+    /// the tagged request ID
+    internal_requests: Mutex<HashMap<RequestId, InternalResponse>>,
+
+    /// ID of the next request sent by ra-multiplex itself
+    next_internal_id: AtomicI64,
+
+    /// Last `rust-analyzer/memoryUsage` breakdown
+    memory_usage: Mutex<Option<Vec<ext::MemoryUsage>>>,
+
+    /// Messages exchanged with the server
+    traffic: Arc<TrafficStats>,
+
+    /// Watcher serving the server's `workspace/didChangeWatchedFiles`
+    /// registrations, `None` if clients watch files
+    file_watcher: Option<FileWatcher>,
+
+    /// Last messages and stderr lines of the server saved when it crashes,
+    /// `None` if crash reports are disabled
+    crashes: Option<Arc<CrashRecorder>>,
+
+    /// Sink all messages exchanged with the server are mirrored to
+    mirror: Option<Arc<Mirror>>,
+
+    /// The server is being restarted, `wait_task` should start it again once
+    /// it exits
+    restarting: AtomicBool,
+
+    /// A replacement server is being started by [`Instance::rollover`]
+    rolling_over: AtomicBool,
+
+    /// The instance is never evicted, set by the `pinned` server setting and
+    /// `ra-multiplex pin`
+    pinned: AtomicBool,
+
+    /// The instance was evicted and is shutting down, clients can't connect
+    /// to it anymore
+    evicted: AtomicBool,
+
+    /// Instance the clients were moved to by [`Instance::merge_into`], new
+    /// clients connect to it instead
+    merged_into: std::sync::Mutex<Option<Arc<Instance>>>,
+
+    /// Initialized server `wait_task` replaces the exited one with instead of
+    /// starting it again, see [`Instance::rollover`]
+    standby: Mutex<Option<Standby>>,
+
+    /// Number of server errors in a row without a successful response in
+    /// between, see [`is_server_error`]
+    consecutive_errors: AtomicU32,
+
+    /// Server configuration
+    config: Arc<Config>,
+
+    /// State of the server the instance belongs to
+    state: Arc<ServerState>,
+
+    /// ID of the client which sent the last request
+    originator: AtomicUsize,
+
+    /// Limits the number of client requests forwarded to the server at once,
+    /// `None` if there is no limit
+    request_permits: Option<RequestQueue>,
+
+    /// Permits held by forwarded requests until the server responds, keyed
+    /// by the tagged request ID
+    pending_requests: Mutex<HashMap<RequestId, queue::Permit>>,
+
+    /// Client requests `stdin_task` wrote to the server which it didn't
+    /// respond to yet, a restarted server only gets the ones it didn't write
+    written_requests: Arc<std::sync::Mutex<HashSet<RequestId>>>,
+
+    /// Wakes up `wait_task` and asks it to send SIGKILL to the instance.
+    close: Notify,
+
+    /// Notified by `wait_task` when the instance exits
+    exited: Notify,
+
+    /// The server exited and wasn't started again yet
+    has_exited: AtomicBool,
+
+    /// Last time a message was sent to this instance
+    ///
+    /// Uses UTC unix timestamp ([utc_now] function)
+    last_used: AtomicI64,
+
+    /// Last time a client disconnected from this instance
+    ///
+    /// Uses UTC unix timestamp ([utc_now] function)
+    last_client_left: AtomicI64,
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        // Make sure we're not leaking anything
+        debug!("instance dropped");
+    }
+}
+
+/// Wrapper around client handle with additional data only the server instance
+/// knows about
+struct ClientData {
+    /// Handle for sending messages to clients
+    client: Client,
+
+    /// URIs of files currently opened by this client
+    files: HashSet<String>,
+
+    /// Hash of the last `textDocument/publishDiagnostics` params sent to this
+    /// client for each URI
+    diagnostics: HashMap<String, u64>,
+
+    /// Requests sent by this client which the server didn't respond to yet
+    /// by their tagged IDs
+    requests: HashMap<RequestId, PendingRequest>,
+
+    /// Client is an `ra-multiplex attach` session which only receives
+    /// responses to its own requests
+    attached: bool,
+}
+
+/// Request of a disconnected client adopted by `adopt_responses`
+struct Orphan {
+    method: String,
+
+    /// User ID of the disconnected client
+    uid: Option<u32>,
+}
+
+/// `workspace/applyEdit` request waiting for clients to apply it
+struct PendingEdit {
+    /// Clients which didn't answer yet
+    clients: HashSet<usize>,
+
+    /// Reasons clients which answered gave for not applying the edit
+    failures: Vec<String>,
+}
+
+impl PendingEdit {
+    /// Record the answer of a client, returns the response for the server
+    /// once it's known
+    ///
+    /// The first client which applied the edit answers for all of them, if
+    /// none did their failures are combined.
+    fn answer(
+        &mut self,
+        client_id: usize,
+        result: Result<lsp::ApplyWorkspaceEditResult, String>,
+    ) -> Option<lsp::ApplyWorkspaceEditResult> {
+        if !self.clients.remove(&client_id) {
+            return None;
+        }
+        match result {
+            Ok(result) if result.applied => return Some(result),
+            Ok(result) => self.failures.push(
+                result
+                    .failure_reason
+                    .unwrap_or_else(|| format!("client {client_id} didn't apply the edit")),
+            ),
+            Err(reason) => self.failures.push(reason),
+        }
+        self.clients
+            .is_empty()
+            .then(|| edit_not_applied(self.failures.join("; ")))
+    }
+}
+
+fn edit_not_applied(reason: String) -> lsp::ApplyWorkspaceEditResult {
+    lsp::ApplyWorkspaceEditResult {
+        applied: false,
+        failure_reason: Some(reason),
+        failed_change: None,
+    }
+}
+
+/// Client request waiting for a server response
+struct PendingRequest {
+    method: String,
+    sent: Instant,
+
+    /// When the request was forwarded to the server, `None` while it waits
+    /// for a request limit permit
+    forwarded: Option<Instant>,
+
+    /// Document and line of a request which is cancelled when the client
+    /// sends another one with the same method for the same line
+    supersede_key: Option<(String, Option<u64>)>,
+
+    /// The request was already reported as stuck
+    reported: bool,
+
+    /// Cancels the request while it waits for a request limit permit, closed
+    /// once it's sent to the server
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+impl ClientData {
+    fn new(client: Client, attached: bool) -> Self {
+        ClientData {
+            client,
+            files: HashSet::new(),
+            diagnostics: HashMap::new(),
+            requests: HashMap::new(),
+            attached,
+        }
+    }
+
+    /// Remember the diagnostics sent for `uri`, returns `false` if they're the
+    /// same as the last ones and don't need to be sent again
+    fn update_diagnostics(&mut self, uri: &str, hash: u64) -> bool {
+        self.diagnostics.insert(uri.to_owned(), hash) != Some(hash)
+    }
+
+    fn get_status(&self) -> ext::Client {
+        ext::Client {
+            id: self.client.id(),
+            files: self.files.iter().cloned().collect(),
+            workspace_folders: self.client.workspace_folders(),
+        }
+    }
+}
+
+type InternalResponse = oneshot::Sender<Result<Value, jsonrpc::Error>>;
+
+/// Change of a document by a client which is not forwarded yet
+#[derive(Default)]
+struct DebouncedChange {
+    pending: Option<(usize, lsp::DidChangeTextDocumentParams)>,
+
+    /// Incremented for every new pending change so a flush timer doesn't
+    /// flush a later change early
+    generation: u64,
+}
+
+/// Work done progress the server began and didn't end yet
+struct ActiveProgress {
+    token: Value,
+    /// Value of the `begin` notification
+    begin: Value,
+    /// Values of the `report` notifications combined
+    report: Option<Value>,
+}
+
+impl ActiveProgress {
+    fn summary(&self) -> ext::Progress {
+        let percentage = |value: &Value| {
+            value["percentage"]
+                .as_u64()
+                .map(|percentage| percentage as u32)
+        };
+        ext::Progress {
+            title: self.begin["title"].as_str().unwrap_or_default().to_owned(),
+            percentage: self
+                .report
+                .as_ref()
+                .and_then(percentage)
+                .or_else(|| percentage(&self.begin)),
+        }
+    }
+}
+
+/// Document opened in the server with all changes it received since
+///
+/// Used for opening the document again in a restarted server.
+struct Document {
+    open: lsp::DidOpenTextDocumentParams,
+    changes: Vec<lsp::DidChangeTextDocumentParams>,
+
+    /// Number of changes forwarded to the server
+    revision: u64,
+
+    /// Revision each client last knew the content at by client ID
+    synced: HashMap<usize, u64>,
+
+    /// Client which sent the last change
+    last_client: Option<usize>,
+}
+
+impl Document {
+    fn new(client_id: usize, open: lsp::DidOpenTextDocumentParams) -> Self {
+        Document {
+            open,
+            changes: Vec::new(),
+            revision: 0,
+            synced: HashMap::from([(client_id, 0)]),
+            last_client: None,
+        }
+    }
+
+    /// Check whether an incremental change by `client_id` was made without
+    /// knowing about changes other clients made since
+    ///
+    /// Changes replacing the whole document never are.
+    fn is_stale(&self, client_id: usize, params: &lsp::DidChangeTextDocumentParams) -> bool {
+        let full = params
+            .content_changes
+            .iter()
+            .any(|change| change.range.is_none());
+        let synced = self.synced.get(&client_id);
+        !full && synced.is_some_and(|&revision| revision != self.revision)
+    }
+
+    /// Check whether `text` differs from the current content of the document
+    ///
+    /// The content isn't known after incremental changes, then it's assumed
+    /// to differ.
+    fn diverges(&self, text: &str) -> bool {
+        !self.changes.is_empty() || self.open.text_document.text != text
+    }
+
+    /// Remember a client opened the document and knows its current content
+    fn sync(&mut self, client_id: usize) {
+        self.synced.insert(client_id, self.revision);
+    }
+
+    fn change(&mut self, client_id: usize, mut params: lsp::DidChangeTextDocumentParams) {
+        self.revision += 1;
+        self.sync(client_id);
+        self.last_client = Some(client_id);
+
+        // Changes without a range replace the whole document, there's no need
+        // to remember anything before them.
+        let full = params
+            .content_changes
+            .iter()
+            .rposition(|change| change.range.is_none());
+        if let Some(index) = full {
+            let mut changes = params.content_changes.split_off(index);
+            self.open.text_document.text = changes.remove(0).text;
+            self.open.text_document.version = params.text_document.version;
+            self.changes.clear();
+            params.content_changes = changes;
+        }
+        if !params.content_changes.is_empty() {
+            self.changes.push(params);
+        }
+    }
+
+    /// Notifications bringing a server to the current state of the document
+    fn replay(&self) -> impl Iterator<Item = Notification> + '_ {
+        let open = Notification {
+            jsonrpc: Version,
+            method: "textDocument/didOpen".into(),
+            params: serde_json::to_value(&self.open).unwrap(),
+        };
+        let changes = self.changes.iter().map(|params| Notification {
+            jsonrpc: Version,
+            method: "textDocument/didChange".into(),
+            params: serde_json::to_value(params).unwrap(),
+        });
+        std::iter::once(open).chain(changes)
+    }
+}
+
+impl Deref for ClientData {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+// Current unix timestamp with second precission
+fn utc_now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+impl Instance {
+    /// Mark the instance as used
+    pub fn keep_alive(&self) {
+        self.last_used.store(utc_now(), Ordering::Relaxed);
+    }
+
+    /// How many seconds is the instance idle for
+    pub fn idle(&self) -> i64 {
+        i64::max(0, utc_now() - self.last_used.load(Ordering::Relaxed))
+    }
+
+    /// How many seconds ago did the last client disconnect
+    fn detached(&self) -> i64 {
+        i64::max(0, utc_now() - self.last_client_left.load(Ordering::Relaxed))
+    }
+
+    /// Whether the server runs in a shim a later daemon can adopt
+    fn is_adoptable(&self) -> bool {
+        cfg!(unix) && self.config.adopt_instances
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid.load(Ordering::Relaxed)
+    }
+
+    pub fn key(&self) -> &InstanceKey {
+        &self.key
+    }
+
+    pub fn workspace_root(&self) -> &str {
+        &self.key.workspace_root
+    }
+
+    pub fn role(&self) -> InstanceRole {
+        self.key.role
+    }
+
+    /// Server requests and notifications of replica and background instances
+    /// duplicate the primary's, they're kept from clients
+    fn is_secondary(&self) -> bool {
+        self.key.role != InstanceRole::Primary
+    }
+
+    /// Environment variables describing the instance for hooks
+    pub fn hook_vars(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("LSPMUX_PID", self.pid().to_string()),
+            ("LSPMUX_SERVER", self.key.server.clone()),
+            ("LSPMUX_WORKSPACE_ROOT", self.key.workspace_root.clone()),
+        ]
+    }
+
+    pub fn initialize_result(&self) -> lsp::InitializeResult {
+        self.init_result.clone()
+    }
+
+    /// Add client to the instance so it can receive traffic from it
+    ///
+    /// It replays all registered dynamic capabilities and the last server
+    /// status to it.
+    pub async fn add_client(&self, client: Client) {
+        let mut clients = self.clients.lock().await;
+        let dyn_capabilities = self.dynamic_capabilities.lock().await;
+
+        if !dyn_capabilities.is_empty() && !self.is_secondary() {
+            // Register all currently cached dynamic capabilities if there are
+            // any. We will drop the client response and we need to make sure
+            // the request ID is unique.
+            let id = RequestId::String("replay:registerCapabilities".into()).tag(Tag::Drop);
+            let params = lsp::RegistrationParams {
+                registrations: dyn_capabilities.values().cloned().collect(),
+            };
+            let req = Request {
+                id,
+                method: "client/registerCapability".into(),
+                params: serde_json::to_value(params).unwrap(),
+                jsonrpc: Version,
+            };
+            debug!(req = ?Logged(&req, &self.state.redaction), "replaying server request");
+            let _ = client.send_message(req.into()).await;
+        }
+
+        if let Some(notif) = self.server_status.lock().await.clone() {
+            debug!(notif = ?Logged(&notif, &self.state.redaction), "replaying server status");
+            let _ = client.publish_server_status(self.pid(), notif).await;
+        }
+
+        // Only clients which support it may be asked to create a token.
+        if !self.is_secondary() && client.supports_work_done_progress() {
+            self.replay_progress(&client).await;
+        }
+
+        let client = ClientData::new(client, false);
+        if clients.insert(client.id(), client).is_some() {
+            unreachable!("BUG: added two clients with the same ID");
+        }
+    }
+
+    /// Add an `ra-multiplex attach` session to the instance
+    ///
+    /// It can send requests like any other client but it doesn't receive any
+    /// server requests or notifications.
+    pub async fn attach_client(&self, client: Client) {
+        let mut clients = self.clients.lock().await;
+        let client = ClientData::new(client, true);
+        if clients.insert(client.id(), client).is_some() {
+            unreachable!("BUG: added two clients with the same ID");
+        }
+    }
+
+    /// Send cleanup messages and remove remove client for client map
+    pub async fn cleanup_client(&self, client: Client) -> Result<()> {
+        debug!("cleaning up client");
+
+        let mut clients = self.clients.lock().await;
+
+        let Some(client) = clients.remove(&client.id()) else {
+            // TODO This happens for example when the language server died while
+            // client was still connected, and the client cleanup is attempted
+            // with the instance being gone already. We should try notifying
+            // these clients immediately and handling the cleanup separately.
+            bail!("client was not connected");
+        };
+        self.last_client_left.store(utc_now(), Ordering::Relaxed);
+        self.broadcast_answered(client.id(), None).await;
+        let disconnected = Err("client disconnected".to_owned());
+        self.apply_edit_answered(client.id(), None, disconnected)
+            .await;
+        self.show_document_abandoned(client.id()).await;
+
+        // Nobody is going to receive the responses, don't let the server waste
+        // time computing them. Unless an attached session of the same user
+        // adopts them, requests still waiting for a permit are cancelled with
+        // the client anyway.
+        let uid = client.uid();
+        let adopter = clients.values().any(|other| self.adopts(other, uid));
+        let mut cancelled = HashSet::new();
+        let mut orphans = self.orphans.lock().await;
+        for (id, pending) in client.requests {
+            let adopt = adopter
+                && pending.forwarded.is_some()
+                && self.config.adopt_responses.contains(&pending.method);
+            match id.untag() {
+                (Some(Tag::ClientId(client_id)), client_req_id) if adopt => {
+                    debug!(?id, method = pending.method, "adopting request");
+                    let orphan = Orphan {
+                        method: pending.method,
+                        uid,
+                    };
+                    orphans.insert((client_id, client_req_id), orphan);
+                }
+                _ => {
+                    cancelled.insert(id);
+                }
+            }
+        }
+        drop(orphans);
+        self.cancel_requests(cancelled).await;
+
+        let files = client.files.into_iter().collect::<Vec<_>>();
+        self.close_all_files(&clients, files)
+            .await
+            .context("error closing files")?;
+
+        Ok(())
+    }
+
+    /// Send a server request to all clients and respond to the server once
+    /// every one of them answered or after [`BROADCAST_TIMEOUT`]
+    async fn broadcast_request(
+        self: &Arc<Self>,
+        clients: &HashMap<usize, ClientData>,
+        mut req: Request,
+    ) {
+        let id = req.id.clone();
+        let targets = self.target_clients(clients, Route::Broadcast);
+        if targets.is_empty() {
+            let _ = self.send_message(ResponseSuccess::null(id).into()).await;
+            return;
+        }
+
+        let pending = targets.iter().copied().collect();
+        self.broadcasts.lock().await.insert(id.clone(), pending);
+        for client_id in targets {
+            req.id = id.tag(Tag::Broadcast(self.pid(), client_id));
+            let _ = clients[&client_id].send_message(req.clone().into()).await;
+        }
+
+        let instance = Arc::downgrade(self);
+        task::spawn(
+            async move {
+                tokio::time::sleep(BROADCAST_TIMEOUT).await;
+                let Some(instance) = instance.upgrade() else {
+                    return;
+                };
+                let Some(pending) = instance.broadcasts.lock().await.remove(&id) else {
+                    return;
+                };
+                warn!(?id, clients = ?pending, "clients didn't answer broadcast server request");
+                let _ = instance
+                    .send_message(ResponseSuccess::null(id).into())
+                    .await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Record the client answered the broadcast server request `id`, or all
+    /// of them if `id` is `None`, and respond to the server requests all
+    /// clients answered
+    pub async fn broadcast_answered(&self, client_id: usize, id: Option<&RequestId>) {
+        let mut answered = Vec::new();
+        self.broadcasts
+            .lock()
+            .await
+            .retain(|broadcast_id, pending| {
+                if id.is_none_or(|id| id == broadcast_id) {
+                    pending.remove(&client_id);
+                }
+                if pending.is_empty() {
+                    answered.push(broadcast_id.clone());
+                }
+                !pending.is_empty()
+            });
+        for id in answered {
+            let _ = self.send_message(ResponseSuccess::null(id).into()).await;
+        }
+    }
+
+    /// Ask clients selected by the `apply_edit` config to apply a
+    /// `workspace/applyEdit` request from the server
+    ///
+    /// The server gets a response once one of them applied the edit, all of
+    /// them failed to or after [`APPLY_EDIT_TIMEOUT`].
+    async fn apply_edit(self: &Arc<Self>, clients: &HashMap<usize, ClientData>, mut req: Request) {
+        let id = req.id.clone();
+        let targets = self.apply_edit_targets(clients);
+        if targets.is_empty() {
+            let result = edit_not_applied("no client can apply the edit".into());
+            self.respond_apply_edit(id, result).await;
+            return;
+        }
+
+        let pending = PendingEdit {
+            clients: targets.iter().copied().collect(),
+            failures: Vec::new(),
+        };
+        self.apply_edits.lock().await.insert(id.clone(), pending);
+        for client_id in targets {
+            req.id = id.tag(Tag::ApplyEdit(self.pid(), client_id));
+            self.state.audit.apply_edit(client_id, self.pid(), &req);
+            let _ = clients[&client_id].send_message(req.clone().into()).await;
+        }
+
+        let instance = Arc::downgrade(self);
+        task::spawn(
+            async move {
+                tokio::time::sleep(APPLY_EDIT_TIMEOUT).await;
+                let Some(instance) = instance.upgrade() else {
+                    return;
+                };
+                if instance.apply_edits.lock().await.remove(&id).is_none() {
+                    return;
+                }
+                warn!(?id, "clients didn't answer workspace/applyEdit");
+                let result = edit_not_applied("clients didn't answer in time".into());
+                instance.respond_apply_edit(id, result).await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Select clients asked to apply an edit, attached and observer clients
+    /// never are
+    ///
+    /// A `[routing]` entry for `workspace/applyEdit` takes precedence over the
+    /// `apply_edit` config.
+    fn apply_edit_targets(&self, clients: &HashMap<usize, ClientData>) -> Vec<usize> {
+        let editors = || {
+            clients
+                .values()
+                .filter(|client| !client.attached && !client.is_observer())
+        };
+        let target = match self.config.routing.get("workspace/applyEdit") {
+            Some(Route::Originator) => ApplyEditTarget::Originator,
+            Some(Route::Broadcast) => ApplyEditTarget::All,
+            Some(Route::FirstClient) => {
+                return editors()
+                    .map(|client| client.id())
+                    .min()
+                    .into_iter()
+                    .collect();
+            }
+            Some(Route::Drop) => return Vec::new(),
+            None => self.config.apply_edit,
+        };
+        match target {
+            ApplyEditTarget::Originator => {
+                // The edit is most likely the result of a command the server
+                // is executing for a client.
+                let executing = editors()
+                    .flat_map(|client| {
+                        client
+                            .requests
+                            .values()
+                            .filter(|req| req.method == "workspace/executeCommand")
+                            .map(|req| (req.sent, client.id()))
+                    })
+                    .max()
+                    .map(|(_, client_id)| client_id);
+                let originator = self.originator.load(Ordering::Relaxed);
+                let originator = editors()
+                    .any(|client| client.id() == originator)
+                    .then_some(originator);
+                let first_client = || editors().map(|client| client.id()).min();
+                executing
+                    .or(originator)
+                    .or_else(first_client)
+                    .into_iter()
+                    .collect()
+            }
+            ApplyEditTarget::NewestClient => editors()
+                .map(|client| client.id())
+                .max()
+                .into_iter()
+                .collect(),
+            ApplyEditTarget::All => editors().map(|client| client.id()).collect(),
+        }
+    }
+
+    /// Ask a single client to show a document for a `window/showDocument`
+    /// request from the server, its answer is forwarded to the server
+    ///
+    /// The server gets `success: false` right away if there is no client to
+    /// ask, routing doesn't apply so editors never all open the document.
+    async fn show_document(&self, clients: &HashMap<usize, ClientData>, mut req: Request) {
+        let Some(client_id) = self.show_document_target(clients) else {
+            debug!(id = ?req.id, "no client to show the document");
+            let res = ResponseSuccess {
+                jsonrpc: Version,
+                result: json!({ "success": false }),
+                id: req.id,
+            };
+            let _ = self.send_message(res.into()).await;
+            return;
+        };
+        self.show_documents
+            .lock()
+            .await
+            .insert(req.id.clone(), client_id);
+        req.id = req.id.tag(Tag::Forward(self.pid()));
+        let _ = clients[&client_id].send_message(req.into()).await;
+    }
+
+    /// Tell the server the documents the disconnected client was asked to
+    /// show weren't shown, it's still waiting for the answers
+    async fn show_document_abandoned(&self, client_id: usize) {
+        let mut abandoned = Vec::new();
+        self.show_documents.lock().await.retain(|id, target| {
+            if *target == client_id {
+                abandoned.push(id.clone());
+            }
+            *target != client_id
+        });
+        for id in abandoned {
+            debug!(?id, "client asked to show a document disconnected");
+            let res = ResponseSuccess {
+                jsonrpc: Version,
+                result: json!({ "success": false }),
+                id,
+            };
+            let _ = self.send_message(res.into()).await;
+        }
+    }
+
+    /// Select the client asked to show a document, the one whose request the
+    /// server is handling, or else the client which sent the last request, or
+    /// else the one picked by the `show_document` config
+    fn show_document_target(&self, clients: &HashMap<usize, ClientData>) -> Option<usize> {
+        let editors = || {
+            clients
+                .values()
+                .filter(|client| !client.attached && !client.is_observer())
+        };
+        // Servers show documents as part of handling a request like
+        // `workspace/executeCommand`, the most recent one is the best guess.
+        let requesting = editors()
+            .flat_map(|client| client.requests.values().map(|req| (req.sent, client.id())))
+            .max()
+            .map(|(_, client_id)| client_id);
+        let originator = self.originator.load(Ordering::Relaxed);
+        let originator = editors()
+            .any(|client| client.id() == originator)
+            .then_some(originator);
+        let fallback = || {
+            let ids = editors().map(|client| client.id());
+            match self.config.show_document {
+                ShowDocumentFallback::FirstClient => ids.min(),
+                ShowDocumentFallback::NewestClient => ids.max(),
+                ShowDocumentFallback::Drop => None,
+            }
+        };
+        requesting.or(originator).or_else(fallback)
+    }
+
+    /// Record a client's answer to the `workspace/applyEdit` request `id`, or
+    /// to all of them if `id` is `None`
+    pub async fn apply_edit_answered(
+        &self,
+        client_id: usize,
+        id: Option<&RequestId>,
+        result: Result<lsp::ApplyWorkspaceEditResult, String>,
+    ) {
+        let mut responses = Vec::new();
+        self.apply_edits.lock().await.retain(|edit_id, pending| {
+            if id.is_some_and(|id| id != edit_id) {
+                return true;
+            }
+            match pending.answer(client_id, result.clone()) {
+                Some(response) => {
+                    responses.push((edit_id.clone(), response));
+                    false
+                }
+                None => true,
+            }
+        });
+        for (id, response) in responses {
+            self.respond_apply_edit(id, response).await;
+        }
+    }
+
+    async fn respond_apply_edit(&self, id: RequestId, result: lsp::ApplyWorkspaceEditResult) {
+        let res = ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::to_value(result).unwrap(),
+            id,
+        };
+        let _ = self.send_message(res.into()).await;
+    }
+
+    /// Check if `client` is an attached session adopting the responses of a
+    /// disconnected client of user `uid`
+    ///
+    /// Users are only told apart over Unix sockets, clients connected any
+    /// other way never adopt. The session must be attached with the tag of the
+    /// instance.
+    fn adopts(&self, client: &ClientData, uid: Option<u32>) -> bool {
+        client.attached
+            && uid.is_some()
+            && client.uid() == uid
+            && client.tag() == self.key.tag.as_deref()
+    }
+
+    /// Send the response to a request of the disconnected client `client_id`
+    /// to the attached session of the same user which adopted it, the
+    /// response has the client's request ID
+    async fn adopt_response(
+        &self,
+        clients: &HashMap<usize, ClientData>,
+        client_id: usize,
+        id: RequestId,
+        response: Message,
+    ) {
+        let Some(orphan) = self.orphans.lock().await.remove(&(client_id, id)) else {
+            debug!(?client_id, "no matching client");
+            return;
+        };
+        // The session connected for the longest time adopts it.
+        let adopter = clients
+            .values()
+            .filter(|client| self.adopts(client, orphan.uid))
+            .min_by_key(|client| client.id());
+        let Some(adopter) = adopter else {
+            debug!(
+                ?client_id,
+                method = orphan.method,
+                "adopting session is gone"
+            );
+            return;
+        };
+        let params = ext::AdoptedResponse {
+            client_id,
+            method: orphan.method,
+            response,
+        };
+        let notif = Notification {
+            jsonrpc: Version,
+            method: ext::ADOPTED_RESPONSE.into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        let _ = adopter.send_message(notif.into()).await;
+    }
+
+    /// Exempt the instance from eviction or make it evictable again
+    pub fn pin(&self, pinned: bool) {
+        self.pinned.store(pinned, Ordering::Relaxed);
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.load(Ordering::Relaxed)
+    }
+
+    /// Instance the clients of this one were moved to, clients and messages
+    /// for this instance go there instead
+    pub fn merged_into(&self) -> Option<Arc<Instance>> {
+        self.merged_into.lock().unwrap().clone()
+    }
+
+    /// Move the clients of this instance onto `target` running the same
+    /// server for an overlapping workspace root, returns the number of clients
+    /// moved
+    ///
+    /// The documents the clients have open are opened in `target` and their
+    /// following messages go there. Requests this instance didn't answer yet
+    /// get [`MERGE_TIMEOUT`] to finish before the clients are removed, the
+    /// ones still pending then fail. The instance is closed after
+    /// `instance_timeout` like any other without clients.
+    ///
+    /// Merges of a server run one at a time. If the clients can't be moved
+    /// completely they're removed from `target` again and stay here.
+    pub async fn merge_into(self: &Arc<Self>, target: &Arc<Instance>) -> Result<usize> {
+        let _merging = self.state.merging.lock().await;
+        ensure!(
+            !Arc::ptr_eq(self, target),
+            "can't merge an instance into itself"
+        );
+        ensure!(
+            runs_same_server(&self.key, &target.key),
+            "instances run different servers",
+        );
+        ensure!(
+            target.merged_into().is_none(),
+            "instance {} was merged into another one already",
+            target.pid(),
+        );
+        ensure!(self.merged_into().is_none(), "instance was merged already",);
+        info!(into = target.pid(), "merging instance");
+
+        // The server only knows about files in its workspace folders.
+        let root = Path::new(&self.key.workspace_root);
+        if !root.starts_with(&target.key.workspace_root) {
+            let uri = watcher::file_uri(root).context("invalid workspace root")?;
+            let name = root
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let folder = json!({ "uri": uri, "name": name });
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "workspace/didChangeWorkspaceFolders".into(),
+                params: json!({ "event": { "added": [folder], "removed": [] } }),
+            };
+            target
+                .send_message(notif.into())
+                .await
+                .context("instance closed")?;
+        }
+
+        // Hold the locks until the redirect is set, so no document is opened
+        // or changed here after it was copied.
+        let clients = self.clients.lock().await;
+        let documents = self.documents.lock().await;
+        let moved = clients
+            .values()
+            .filter(|client| !client.attached)
+            .map(|client| client.client.clone())
+            .collect::<Vec<_>>();
+        // Clients of both instances only get the documents.
+        let mut added = Vec::new();
+        let res = async {
+            for client in &moved {
+                if !target.clients.lock().await.contains_key(&client.id()) {
+                    target.add_client(client.clone()).await;
+                    added.push(client.clone());
+                }
+                for uri in &clients[&client.id()].files {
+                    let Some(document) = documents.get(uri) else {
+                        continue;
+                    };
+                    let opened = target.documents.lock().await.contains_key(uri);
+                    let open = serde_json::to_value(&document.open).unwrap();
+                    target.open_file(client.id(), open).await?;
+                    // A document other clients opened already has the changes.
+                    if !opened {
+                        for change in &document.changes {
+                            target
+                                .change_file(client.id(), did_change(change.clone()))
+                                .await
+                                .context("instance closed")?;
+                        }
+                    }
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(err) = res.await {
+            // The clients keep using this instance, `target` mustn't send
+            // them anything anymore.
+            for client in added {
+                let _ = target.cleanup_client(client).await;
+            }
+            return Err(err.context("moving clients"));
+        }
+        let added = added.iter().map(Client::id).collect::<HashSet<_>>();
+        *self.merged_into.lock().unwrap() = Some(target.clone());
+        drop(documents);
+        drop(clients);
+
+        let deadline = Instant::now() + MERGE_TIMEOUT;
+        while Instant::now() < deadline {
+            let clients = self.clients.lock().await;
+            let pending = moved
+                .iter()
+                .filter_map(|client| clients.get(&client.id()))
+                .any(|client| !client.requests.is_empty());
+            drop(clients);
+            if !pending {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for client in &moved {
+            self.fail_pending_requests(client).await;
+            if let Err(err) = self.cleanup_client(client.clone()).await {
+                // The client disconnected while it was being moved.
+                debug!(?err, client_id = client.id(), "client not moved");
+                continue;
+            }
+            self.state
+                .events
+                .publish(events::client(EventKind::ClientDetached, client.id(), self));
+            if added.contains(&client.id()) {
+                self.state.events.publish(events::client(
+                    EventKind::ClientAttached,
+                    client.id(),
+                    target,
+                ));
+            }
+        }
+        Ok(moved.len())
+    }
+
+    /// Fail the requests of `client` this instance didn't answer, a merged
+    /// request waits for a response from every instance it was sent to
+    async fn fail_pending_requests(&self, client: &Client) {
+        let clients = self.clients.lock().await;
+        let Some(data) = clients.get(&client.id()) else {
+            return;
+        };
+        for id in data.requests.keys() {
+            let (_, id) = id.untag();
+            debug!(?id, "failing request of a merged client");
+            let res = ResponseError {
+                jsonrpc: Version,
+                error: jsonrpc::Error {
+                    code: -32803, // RequestFailed
+                    message: "instance was merged into another one".into(),
+                    data: None,
+                },
+                id,
+            };
+            let _ = client.send_message(res.into()).await;
+        }
+    }
+
+    /// Clients following the client with `client_id`
+    pub async fn followers(&self, client_id: usize) -> Vec<Client> {
+        let clients = self.clients.lock().await;
+        clients
+            .values()
+            .filter(|client| client.follows(client_id))
+            .map(|client| client.client.clone())
+            .collect()
+    }
+
+    /// URIs of files opened by the client with `client_id`
+    pub async fn client_files(&self, client_id: usize) -> Vec<String> {
+        let clients = self.clients.lock().await;
+        clients
+            .get(&client_id)
+            .map(|client| client.files.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Number of requests the client with `client_id` is waiting for a
+    /// response to
+    pub async fn client_requests(&self, client_id: usize) -> usize {
+        let clients = self.clients.lock().await;
+        clients
+            .get(&client_id)
+            .map_or(0, |client| client.requests.len())
+    }
+
+    /// Send a message to the language server channel
+    ///
+    /// A debounced change is sent first so the server receives messages in the
+    /// order clients sent them.
+    pub async fn send_message(&self, message: Message) -> Result<(), SendError<Message>> {
+        if self.did_change_debounce.is_some() {
+            self.flush_change(None).await?;
+        }
+        self.server.send(message).await
+    }
+
+    /// Send the debounced change, if `generation` is set only if it's still
+    /// the same change
+    async fn flush_change(&self, generation: Option<u64>) -> Result<(), SendError<Message>> {
+        let mut debounced = self.debounced_change.lock().await;
+        if generation.is_some_and(|generation| generation != debounced.generation) {
+            return Ok(());
+        }
+        match debounced.pending.take() {
+            Some((_, params)) => self.server.send(did_change(params).into()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Tag a client request with the client ID and send it to the language
+    /// server channel
+    ///
+    /// If the instance has a request limit a request over it is queued until
+    /// the number of requests waiting for a response falls under it, without
+    /// holding up the client's other messages. Queued requests of clients
+    /// with the fewest requests in flight are forwarded first.
+    pub async fn send_request(
+        self: &Arc<Self>,
+        client_id: usize,
+        mut req: Request,
+    ) -> Result<(), SendError<Message>> {
+        req.id = req.id.tag(Tag::ClientId(client_id));
+        self.originator.store(client_id, Ordering::Relaxed);
+        let (cancel, cancelled) = oneshot::channel();
+        let mut superseded = Vec::new();
+        let mut acquired = None;
+        if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+            // Request IDs are tagged rather than renumbered so they can only
+            // collide when the client reuses the ID of a pending request.
+            if client.requests.contains_key(&req.id) {
+                warn!(id = ?req.id, "client reused the ID of a pending request");
+                let res = client::id_in_use(req.id.untag().1);
+                let _ = client.send_message(res.into()).await;
+                return Ok(());
+            }
+            let supersede_key = self
+                .config
+                .supersede_requests
+                .contains(&req.method)
+                .then(|| supersede_key(&req.params))
+                .flatten();
+            if supersede_key.is_some() {
+                for (id, pending) in &mut client.requests {
+                    if pending.method == req.method && pending.supersede_key == supersede_key {
+                        // Forget the key so the request isn't cancelled again.
+                        pending.supersede_key = None;
+                        superseded.push((id.clone(), pending.cancel.take()));
+                    }
+                }
+            }
+            // Taken while the clients are locked so a request forwarded right
+            // away is recorded as such.
+            acquired = (self.request_permits.as_ref()).map(|permits| permits.acquire(client_id));
+            let now = Instant::now();
+            let pending = PendingRequest {
+                method: req.method.clone(),
+                sent: now,
+                forwarded: match acquired {
+                    Some(Err(_)) => None,
+                    _ => Some(now),
+                },
+                supersede_key,
+                reported: false,
+                cancel: self.request_permits.is_some().then_some(cancel),
+            };
+            client.requests.insert(req.id.clone(), pending);
+        }
+        for (id, cancel) in superseded {
+            debug!(?id, method = req.method, "cancelling superseded request");
+            // A request still waiting for a permit is answered without ever
+            // reaching the server.
+            if cancel.is_some_and(|cancel| cancel.send(()).is_ok()) {
+                continue;
+            }
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "$/cancelRequest".into(),
+                params: json!({ "id": id }),
+            };
+            self.send_message(notif.into()).await?;
+        }
+
+        let Some(permits) = &self.request_permits else {
+            return self.send_message(req.into()).await;
+        };
+        let queued = match acquired.unwrap_or_else(|| permits.acquire(client_id)) {
+            Ok(permit) => return self.forward_request(req, permit).await,
+            Err(queued) => queued,
+        };
+        debug!(id = ?req.id, "request limit reached, queueing request");
+        let instance = self.clone();
+        task::spawn(
+            async move {
+                select! {
+                    permit = queued.wait() => {
+                        instance.dequeue(client_id, &req.id).await;
+                        let _ = instance.forward_request(req, permit).await;
+                    }
+                    Ok(()) = cancelled => instance.cancel_queued(client_id, req.id).await,
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Send a request holding a request limit `permit` to the server
+    async fn forward_request(
+        &self,
+        req: Request,
+        permit: queue::Permit,
+    ) -> Result<(), SendError<Message>> {
+        self.pending_requests
+            .lock()
+            .await
+            .insert(req.id.clone(), permit);
+        self.send_message(req.into()).await
+    }
+
+    /// Record that a queued request got a permit and is forwarded now
+    async fn dequeue(&self, client_id: usize, id: &RequestId) {
+        let mut clients = self.clients.lock().await;
+        let pending = clients
+            .get_mut(&client_id)
+            .and_then(|client| client.requests.get_mut(id));
+        if let Some(pending) = pending {
+            pending.forwarded = Some(Instant::now());
+        }
+    }
+
+    /// Answer a request cancelled before it was sent to the server
+    async fn cancel_queued(&self, client_id: usize, id: RequestId) {
+        debug!(?id, "cancelling queued request");
+        let mut clients = self.clients.lock().await;
+        let Some(client) = clients.get_mut(&client_id) else {
+            return;
+        };
+        client.requests.remove(&id);
+        client.client.responded();
+        let res = ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: -32800, // RequestCancelled
+                message: "request cancelled".into(),
+                data: None,
+            },
+            id: id.untag().1,
+        };
+        let _ = client.send_message(res.into()).await;
+    }
+
+    /// Client requests the server didn't respond to yet, oldest first
+    pub async fn pending_requests(&self) -> Vec<ext::PendingRequest> {
+        let clients = self.clients.lock().await;
+        let mut requests = clients
+            .values()
+            .flat_map(|client| {
+                client.requests.iter().map(|(id, pending)| {
+                    // Client request IDs are tagged, they're always strings.
+                    let id = match id {
+                        RequestId::String(id) => id.clone(),
+                        RequestId::Number(id) => id.to_string(),
+                    };
+                    let request = ext::PendingRequest {
+                        id,
+                        method: pending.method.clone(),
+                        client: client.id(),
+                        age: pending.sent.elapsed().as_millis() as u64,
+                        queued: pending.cancel.as_ref().is_some_and(|tx| !tx.is_closed()),
+                    };
+                    (pending.sent, request)
+                })
+            })
+            .collect::<Vec<_>>();
+        // Requests sent within the same millisecond keep their order.
+        requests.sort_by_key(|(sent, _)| *sent);
+        requests.into_iter().map(|(_, request)| request).collect()
+    }
+
+    /// Cancel the client request with the tagged `id`, returns `false` if
+    /// there is no such request
+    ///
+    /// A request waiting for a request limit permit is answered right away,
+    /// otherwise the server is sent `$/cancelRequest` and responds itself.
+    pub async fn cancel_request(&self, id: RequestId) -> bool {
+        let cancel = {
+            let mut clients = self.clients.lock().await;
+            let Some(pending) = clients
+                .values_mut()
+                .find_map(|client| client.requests.get_mut(&id))
+            else {
+                return false;
+            };
+            pending.cancel.take()
+        };
+        if cancel.is_some_and(|cancel| cancel.send(()).is_ok()) {
+            return true;
+        }
+        debug!(?id, "cancelling request");
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "$/cancelRequest".into(),
+            params: json!({ "id": id }),
+        };
+        let _ = self.send_message(notif.into()).await;
+        true
+    }
+
+    /// Decide how to deliver a server request or notification
+    fn route(&self, method: &str, is_request: bool) -> Route {
+        match self.config.routing.get(method) {
+            Some(&route) => route,
+            None if is_request && self.config.is_passthrough(method) => Route::Originator,
+            None if !is_request && config::is_log_message(method) => self.config.log_messages,
+            None => default_route(method, is_request),
+        }
+    }
+
+    /// Select IDs of clients receiving a message routed with `route`
+    ///
+    /// If the originator is not connected anymore the first client is picked
+    /// instead. Attached sessions are never picked.
+    fn target_clients(&self, clients: &HashMap<usize, ClientData>, route: Route) -> Vec<usize> {
+        let ids = || {
+            clients
+                .values()
+                .filter(|client| !client.attached)
+                .map(|client| client.id())
+        };
+        // Client IDs are increasing so the smallest belongs to the client
+        // connected for the longest time.
+        let first_client = || ids().min();
+        match route {
+            Route::Originator => {
+                let originator = self.originator.load(Ordering::Relaxed);
+                if clients
+                    .get(&originator)
+                    .is_some_and(|client| !client.attached)
+                {
+                    vec![originator]
+                } else {
+                    first_client().into_iter().collect()
+                }
+            }
+            Route::Broadcast => ids().collect(),
+            Route::FirstClient => first_client().into_iter().collect(),
+            Route::Drop => Vec::new(),
+        }
+    }
+
+    /// Forget a request the server just responded to and release the request
+    /// limit permit held by it
+    ///
+    /// With verbose tracing the time the server took to respond and the time
+    /// the request was queued by ra-multiplex are logged.
+    async fn finish_request(&self, clients: &mut HashMap<usize, ClientData>, id: &RequestId) {
+        if let (Some(Tag::ClientId(client_id)), _) = id.untag() {
+            let pending = clients.get_mut(&client_id).and_then(|client| {
+                client.client.responded();
+                client.requests.remove(id)
+            });
+            if let Some(pending) = pending {
+                let forwarded = pending.forwarded.unwrap_or(pending.sent);
+                let server_ms = forwarded.elapsed().as_millis() as u64;
+                let queued_ms = (forwarded - pending.sent).as_millis() as u64;
+                trace!(
+                    ?id,
+                    method = pending.method,
+                    server_ms,
+                    queued_ms,
+                    "response latency",
+                );
+                self.state.stats.response(server_ms, queued_ms);
+            }
+        }
+        if self.request_permits.is_some() {
+            self.pending_requests.lock().await.remove(id);
+        }
+        self.written_requests.lock().unwrap().remove(id);
+    }
+
+    /// Answer a client request the server is never going to respond to with
+    /// an error
+    async fn fail_request(
+        &self,
+        clients: &mut HashMap<usize, ClientData>,
+        id: &RequestId,
+        message: &str,
+    ) {
+        self.finish_request(clients, id).await;
+        let (Some(Tag::ClientId(client_id)), untagged) = id.untag() else {
+            return;
+        };
+        let Some(client) = clients.get(&client_id) else {
+            return;
+        };
+        let res = ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                // ServerCancelled
+                code: -32802,
+                message: message.into(),
+                data: None,
+            },
+            id: untagged,
+        };
+        let _ = client.send_message(res.into()).await;
+    }
+
+    /// Answer all pending requests of `clients` with an error, the server is
+    /// never going to respond to them
+    async fn fail_requests(&self, clients: &mut HashMap<usize, ClientData>, message: &str) {
+        let ids = clients
+            .values()
+            .flat_map(|client| client.requests.keys().cloned())
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.fail_request(clients, &id, message).await;
+        }
+    }
+
+    /// Count a response of the server, restart it once it responded with
+    /// `restart_after_errors` errors in a row
+    async fn count_response(
+        self: &Arc<Self>,
+        clients: &HashMap<usize, ClientData>,
+        error: Option<&jsonrpc::Error>,
+    ) {
+        let Some(threshold) = self.config.restart_after_errors else {
+            return;
+        };
+        if !error.is_some_and(is_server_error) {
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+            return;
+        }
+        let errors = self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if errors < threshold || self.restarting.load(Ordering::Relaxed) {
+            return;
+        }
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+
+        warn!(
+            pid = self.pid(),
+            errors, "server keeps failing, restarting it"
+        );
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "window/showMessage".into(),
+            params: json!({
+                // Warning
+                "type": 2,
+                "message": format!(
+                    "ra-multiplex: {} (pid {}) failed {errors} requests in a row, restarting it",
+                    self.key.server,
+                    self.pid(),
+                ),
+            }),
+        };
+        for client in clients.values().filter(|client| !client.attached) {
+            let _ = client.send_message(notif.clone().into()).await;
+        }
+
+        // The old server's responses to the shutdown are read by the stdout
+        // task calling us, restart from another task.
+        let instance = self.clone();
+        task::spawn(async move { instance.restart().await }.in_current_span());
+    }
+
+    /// Send `$/cancelRequest` for requests of a disconnected client and
+    /// release their request limit permits
+    async fn cancel_requests(&self, ids: HashSet<RequestId>) {
+        if ids.is_empty() {
+            return;
+        }
+        debug!(count = ids.len(), "cancelling outstanding client requests");
+        let mut pending_requests = self.pending_requests.lock().await;
+        for id in ids {
+            pending_requests.remove(&id);
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "$/cancelRequest".into(),
+                params: json!({ "id": id }),
+            };
+            let _ = self.send_message(notif.into()).await;
+        }
+    }
+
+    /// Save registered capabilities to allow later replaying them to new clients
+    async fn register_capabilities(&self, params: Value) -> Result<()> {
+        let params =
+            serde_json::from_value::<lsp::RegistrationParams>(params).context("parsing params")?;
+
+        let mut dyn_capabilities = self.dynamic_capabilities.lock().await;
+        for reg in params.registrations {
+            dyn_capabilities.insert(reg.id.clone(), reg);
+        }
+
+        Ok(())
+    }
+
+    /// Serve `workspace/didChangeWatchedFiles` registrations with the daemon's
+    /// file watcher, they're removed from `client/registerCapability` params
+    ///
+    /// Returns whether there are registrations left for clients.
+    fn take_file_watchers(&self, params: &mut Value) -> bool {
+        let Some(file_watcher) = &self.file_watcher else {
+            return true;
+        };
+        let Ok(mut reg_params) = serde_json::from_value::<lsp::RegistrationParams>(params.clone())
+        else {
+            return true;
+        };
+        reg_params.registrations.retain(|reg| {
+            if reg.method != watcher::DID_CHANGE_WATCHED_FILES {
+                return true;
+            }
+            file_watcher.register(reg.id.clone(), reg.register_options.clone());
+            false
+        });
+        let left = !reg_params.registrations.is_empty();
+        *params = serde_json::to_value(reg_params).unwrap();
+        left
+    }
+
+    /// Remove `workspace/didChangeWatchedFiles` registrations served by the
+    /// daemon's file watcher from `client/unregisterCapability` params
+    ///
+    /// Returns whether there are unregistrations left for clients.
+    fn release_file_watchers(&self, params: &mut Value) -> bool {
+        let Some(file_watcher) = &self.file_watcher else {
+            return true;
+        };
+        let Ok(mut unreg_params) =
+            serde_json::from_value::<lsp::UnregistrationParams>(params.clone())
+        else {
+            return true;
+        };
+        unreg_params.unregistrations.retain(|unreg| {
+            if unreg.method != watcher::DID_CHANGE_WATCHED_FILES {
+                return true;
+            }
+            file_watcher.unregister(&unreg.id);
+            false
+        });
+        let left = !unreg_params.unregistrations.is_empty();
+        *params = serde_json::to_value(unreg_params).unwrap();
+        left
+    }
+
+    /// Remove cached capability registration to stop replaying them to new clients
+    async fn unregister_capabilities(&self, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::UnregistrationParams>(params)
+            .context("parsing params")?;
+
+        let mut dyn_capabilities = self.dynamic_capabilities.lock().await;
+        for unreg in params.unregistrations {
+            dyn_capabilities.remove(&unreg.id);
+        }
+
+        Ok(())
+    }
+
+    /// Check whether a `textDocument/didOpen` notification opens an already
+    /// open document with different content
+    pub async fn document_diverges(&self, params: &Value) -> bool {
+        let Ok(params) = serde_json::from_value::<lsp::DidOpenTextDocumentParams>(params.clone())
+        else {
+            return false;
+        };
+        let documents = self.documents.lock().await;
+        documents
+            .get(&params.text_document.uri)
+            .is_some_and(|document| document.diverges(&params.text_document.text))
+    }
+
+    /// Handle `textDocument/didOpen` client notification
+    pub async fn open_file(&self, client_id: usize, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::DidOpenTextDocumentParams>(params)
+            .context("parsing params")?;
+        let uri = &params.text_document.uri;
+
+        let mut send_notification = true;
+
+        let mut clients = self.clients.lock().await;
+        if let Some(target) = self.merged_into() {
+            // Sent before the client was moved by `merge_into`.
+            drop(clients);
+            let params = serde_json::to_value(params).unwrap();
+            return Box::pin(target.open_file(client_id, params)).await;
+        }
+        for client in clients.values() {
+            if client.files.contains(uri) {
+                debug!(?uri, "file is already opened by another client");
+                send_notification = false;
+                break;
+            }
+        }
+
+        let client = clients.get_mut(&client_id).expect("no matching client");
+        client.files.insert(uri.clone());
+        // The editor might've discarded the diagnostics since the file was last
+        // opened, make sure the next ones are sent even if they didn't change.
+        client.diagnostics.remove(uri);
+
+        if !send_notification {
+            let mut documents = self.documents.lock().await;
+            if let Some(document) = documents.get_mut(uri) {
+                if document.diverges(&params.text_document.text) {
+                    // Like an editor reopening the document after its change
+                    // was refused. Its content replaces the one in the server
+                    // so it can continue editing, the other clients are stale
+                    // now instead.
+                    debug!(?uri, "client opened file with different content");
+                    let change = replace_content(&params);
+                    document.change(client_id, change.clone());
+                    let _ = self.send_message(did_change(change).into()).await;
+                } else {
+                    document.sync(client_id);
+                }
+            }
+        }
+
+        if send_notification {
+            let document = Document::new(client_id, params.clone());
+            let mut documents = self.documents.lock().await;
+            documents.insert(uri.clone(), document);
+
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "textDocument/didOpen".into(),
+                params: serde_json::to_value(params).unwrap(),
+            };
+            debug!(notif = ?Logged(&notif, &self.state.redaction), "first client opened file");
+            let _ = self.send_message(notif.into()).await;
+        }
+
+        Ok(())
+    }
+
+    /// Handle `textDocument/didChange` client notification
+    ///
+    /// With debouncing enabled the change is held back and consecutive changes
+    /// of the same document by the same client are merged into one.
+    pub async fn change_file(
+        self: &Arc<Self>,
+        client_id: usize,
+        notif: Notification,
+    ) -> Result<(), SendError<Message>> {
+        // Keep the lock while sending so changes are remembered in the same
+        // order the server receives them.
+        let mut documents = self.documents.lock().await;
+        if let Some(target) = self.merged_into() {
+            // Sent before the client was moved by `merge_into`.
+            drop(documents);
+            return Box::pin(target.change_file(client_id, notif)).await;
+        }
+        let params = match serde_json::from_value::<lsp::DidChangeTextDocumentParams>(
+            notif.params.clone(),
+        ) {
+            Ok(params) => params,
+            Err(err) => {
+                warn!(?err, "error parsing didChange params");
+                return self.send_message(notif.into()).await;
+            }
+        };
+        if let Some(document) = documents.get_mut(&params.text_document.uri) {
+            if document.is_stale(client_id, &params) {
+                let other_client = document.last_client;
+                drop(documents);
+                self.refuse_change(client_id, other_client, &params.text_document.uri)
+                    .await;
+                return Ok(());
+            }
+            document.change(client_id, params.clone());
+        }
+        let Some(delay) = self.did_change_debounce else {
+            return self.send_message(notif.into()).await;
+        };
+
+        let mut debounced = self.debounced_change.lock().await;
+        if let Some((pending_client, pending)) = &mut debounced.pending {
+            if *pending_client == client_id && pending.text_document.uri == params.text_document.uri
+            {
+                // Changes are applied in order, the merged change must have
+                // the version after the last one.
+                pending.text_document.version = params.text_document.version;
+                pending.content_changes.extend(params.content_changes);
+                return Ok(());
+            }
+        }
+        if let Some((_, pending)) = debounced.pending.take() {
+            self.server.send(did_change(pending).into()).await?;
+        }
+        debounced.pending = Some((client_id, params));
+        debounced.generation += 1;
+        let generation = debounced.generation;
+
+        let instance = Arc::downgrade(self);
+        task::spawn(
+            async move {
+                tokio::time::sleep(delay).await;
+                if let Some(instance) = instance.upgrade() {
+                    let _ = instance.flush_change(Some(generation)).await;
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Tell both clients editing a document at once that a change was refused
+    async fn refuse_change(&self, client_id: usize, other_client: Option<usize>, uri: &str) {
+        warn!(
+            client_id,
+            ?other_client,
+            ?uri,
+            "refusing change made without knowing about changes by another client",
+        );
+        let show_message = |typ: u8, message: String| Notification {
+            jsonrpc: Version,
+            method: "window/showMessage".into(),
+            params: json!({ "type": typ, "message": message }),
+        };
+        let clients = self.clients.lock().await;
+        if let Some(client) = clients.get(&client_id) {
+            let message = format!(
+                "ra-multiplex: your change to {uri} was refused because another editor \
+                changed the document in the meantime, close and reopen it to continue \
+                editing",
+            );
+            // Error
+            let _ = client.send_message(show_message(1, message).into()).await;
+        }
+        if let Some(client) = other_client.and_then(|id| clients.get(&id)) {
+            let message = format!(
+                "ra-multiplex: another editor changed {uri} without knowing about your \
+                changes, its change was refused",
+            );
+            // Warning
+            let _ = client.send_message(show_message(2, message).into()).await;
+        }
+    }
+
+    /// Handle `textDocument/didClose` client notification
+    pub async fn close_file(&self, client_id: usize, params: Value) -> Result<()> {
+        let params = serde_json::from_value::<lsp::DidCloseTextDocumentParams>(params)
+            .context("parsing params")?;
+
+        let mut clients = self.clients.lock().await;
+        if let Some(target) = self.merged_into() {
+            // Sent before the client was moved by `merge_into`.
+            drop(clients);
+            let params = serde_json::to_value(params).unwrap();
+            return Box::pin(target.close_file(client_id, params)).await;
+        }
+
+        let client = clients.get_mut(&client_id).context("no matching client")?;
+        client.files.remove(&params.text_document.uri);
+        client.diagnostics.remove(&params.text_document.uri);
+        if let Some(document) = self
+            .documents
+            .lock()
+            .await
+            .get_mut(&params.text_document.uri)
+        {
+            document.synced.remove(&client_id);
+        }
+
+        self.close_all_files(&clients, vec![params.text_document.uri])
+            .await
+    }
+
+    /// Handle closing many files at once and sending notifications for
+    /// definitely closed files
+    async fn close_all_files(
+        &self,
+        clients: &HashMap<usize, ClientData>,
+        files: Vec<String>,
+    ) -> Result<()> {
+        for uri in files {
+            let mut send_notification = true;
+
+            for client in clients.values() {
+                if client.files.contains(&uri) {
+                    debug!(?uri, "file still opened by another client");
+                    send_notification = false;
+                    break;
+                }
+            }
+
+            if send_notification {
+                self.documents.lock().await.remove(&uri);
+                let params = lsp::DidCloseTextDocumentParams {
+                    text_document: lsp::TextDocumentIdentifier { uri },
+                };
+                let notif = Notification {
+                    jsonrpc: Version,
+                    method: "textDocument/didClose".into(),
+                    params: serde_json::to_value(params).unwrap(),
+                };
+                debug!(notif = ?Logged(&notif, &self.state.redaction), "last client closed file");
+                let _ = self.send_message(notif.into()).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a request on behalf of ra-multiplex and wait for the response
+    pub async fn internal_request(&self, method: &str, params: Value) -> Result<Value> {
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut internal_requests = self.internal_requests.lock().await;
+            // The counter wraps around, skip IDs still waiting for a response.
+            loop {
+                let id = self.next_internal_id.fetch_add(1, Ordering::Relaxed);
+                let id = RequestId::Number(id).tag(Tag::Internal);
+                if let Entry::Vacant(entry) = internal_requests.entry(id.clone()) {
+                    entry.insert(tx);
+                    break id;
+                }
+                warn!(?id, "internal request ID still in use, skipping it");
+            }
+        };
+
+        let req = Request {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+            id,
+        };
+        self.send_message(req.into())
+            .await
+            .ok()
+            .context("instance closed")?;
+        match rx.await.context("instance closed")? {
+            Ok(result) => Ok(result),
+            Err(err) => bail!("server responded with error {}: {}", err.code, err.message),
+        }
+    }
+
+    /// Restart the language server and wait for the old one to exit
+    ///
+    /// Connected clients stay connected, the new server gets the same
+    /// `initialize` request and all currently opened documents.
+    pub async fn restart(&self) {
+        info!(pid = self.pid(), "restarting instance");
+        self.restarting.store(true, Ordering::Relaxed);
+        self.shutdown().await;
+    }
+
+    /// Replace the language server with a new one once it finished indexing,
+    /// returns the PID of the new server
+    ///
+    /// Unlike [`Instance::restart`] the old server keeps serving clients while
+    /// the new one indexes the workspace, clients are switched over to it
+    /// after like after a restart.
+    pub async fn rollover(&self) -> Result<u32> {
+        if self.restarting.load(Ordering::Relaxed)
+            || self.rolling_over.swap(true, Ordering::Relaxed)
+        {
+            bail!("instance is already being replaced");
+        }
+        info!(pid = self.pid(), "rolling over instance");
+        let standby = self.start_standby().await;
+        self.rolling_over.store(false, Ordering::Relaxed);
+        let mut standby = standby?;
+        let pid = standby.server.pid;
+        info!(
+            pid = self.pid(),
+            new_pid = pid,
+            "switching over to new server"
+        );
+        // The standby would never be used if the instance went away meanwhile.
+        let mut slot = self.standby.lock().await;
+        if self.has_exited.load(Ordering::Relaxed) {
+            let _ = standby.server.process.start_kill();
+            bail!("language server exited during rollover");
+        }
+        *slot = Some(standby);
+        drop(slot);
+        self.restart().await;
+        Ok(pid)
+    }
+
+    /// Start and initialize a new server next to the running one and wait
+    /// until it finished indexing, it's killed if that fails
+    async fn start_standby(&self) -> Result<Standby> {
+        let exited = self.exited.notified();
+        tokio::pin!(exited);
+        exited.as_mut().enable();
+
+        let mut server = start_server(&self.key, &self.config, self.crashes.clone()).await?;
+        let warm_up = async {
+            initialize_handshake(
+                self.init_params.clone(),
+                &mut server.reader,
+                &mut server.writer,
+            )
+            .await
+            .context("server handshake")?;
+            info!(
+                pid = server.pid,
+                "waiting for new server to finish indexing"
+            );
+            let configuration = |params| async move { self.known_configuration(&params).await };
+            wait_indexed(&mut server.reader, &mut server.writer, configuration).await
+        };
+        let held = select! {
+            held = warm_up => held,
+            _ = exited => Err(anyhow::anyhow!("language server exited during rollover")),
+        };
+        match held {
+            Ok(held) => Ok(Standby { server, held }),
+            Err(err) => {
+                let _ = server.process.start_kill();
+                Err(err)
+            }
+        }
+    }
+
+    /// Answer a `workspace/configuration` request with `params` from the
+    /// `settings_file` configured for the server, `None` if there is none or
+    /// it can't be read and clients should answer
+    async fn configuration_from_file(&self, params: &Value) -> Option<Value> {
+        let settings_file = self
+            .config
+            .server_settings(&self.key.server)?
+            .settings_file
+            .as_ref()?;
+        let path = PathBuf::from(self.key.expand(settings_file));
+        match read_settings(&path).await {
+            Ok(settings) => Some(configuration_items(&settings, params)),
+            Err(err) => {
+                match err.downcast_ref::<std::io::Error>() {
+                    Some(err) if err.kind() == ErrorKind::NotFound => {
+                        debug!(?path, "no settings file");
+                    }
+                    _ => warn!(?err, ?path, "error reading settings file"),
+                }
+                None
+            }
+        }
+    }
+
+    /// Forward a client's response to a server request routed to it
+    ///
+    /// Results of `workspace/configuration` requests get the settings of the
+    /// clients whose workspace contains the requested scope.
+    pub async fn forward_response(
+        &self,
+        mut res: ResponseSuccess,
+    ) -> Result<(), SendError<Message>> {
+        self.show_documents.lock().await.remove(&res.id);
+        let params = self.configuration_requests.lock().await.remove(&res.id);
+        if let Some(params) = params {
+            self.overlay_client_settings(&params, &mut res.result).await;
+            self.remember_configuration(&params, &res.result).await;
+        }
+        self.send_message(res.into()).await
+    }
+
+    async fn remember_configuration(&self, params: &Value, result: &Value) {
+        let items = params.get("items").and_then(Value::as_array);
+        let (Some(items), Some(values)) = (items, result.as_array()) else {
+            return;
+        };
+        let mut configuration = self.configuration.lock().await;
+        for (item, value) in items.iter().zip(values) {
+            configuration.insert(item.to_string(), value.clone());
+        }
+    }
+
+    /// Answer a `workspace/configuration` request without asking clients,
+    /// from the `settings_file` or the results clients gave before
+    async fn known_configuration(&self, params: &Value) -> Value {
+        if let Some(mut result) = self.configuration_from_file(params).await {
+            self.overlay_client_settings(params, &mut result).await;
+            return result;
+        }
+        let configuration = self.configuration.lock().await;
+        let items = params.get("items").and_then(Value::as_array);
+        let values = items.into_iter().flatten().map(|item| {
+            let value = configuration.get(&item.to_string());
+            value.cloned().unwrap_or(Value::Null)
+        });
+        values.collect()
+    }
+
+    /// Forward a client's error response to a server request routed to it
+    pub async fn forward_error(&self, res: ResponseError) -> Result<(), SendError<Message>> {
+        self.show_documents.lock().await.remove(&res.id);
+        self.configuration_requests.lock().await.remove(&res.id);
+        self.send_message(res.into()).await
+    }
+
+    fn client_settings_section(&self) -> Option<&str> {
+        let settings = self.config.server_settings(&self.key.server)?;
+        settings.client_settings_section.as_deref()
+    }
+
+    /// Apply the settings of clients to the values of a `workspace/configuration`
+    /// `result` for items with a `scopeUri` in their workspace folders
+    ///
+    /// Items without a scope are left as they are, they apply to all clients.
+    async fn overlay_client_settings(&self, params: &Value, result: &mut Value) {
+        let Some(section) = self.client_settings_section() else {
+            return;
+        };
+        let items = params.get("items").and_then(Value::as_array);
+        let (Some(items), Some(values)) = (items, result.as_array_mut()) else {
+            return;
+        };
+        let clients = self.clients.lock().await;
+        for (item, value) in items.iter().zip(values) {
+            let Some(scope) = item.get("scopeUri").and_then(Value::as_str) else {
+                continue;
+            };
+            let Some(client) = scope_owner(clients.values().map(Deref::deref), scope) else {
+                continue;
+            };
+            let item_section = item.get("section").and_then(Value::as_str);
+            if let Some(overlay) = settings_overlay(client.settings(), section, item_section) {
+                trace!(client_id = client.id(), scope, "applying client settings");
+                merge_patch(value, &overlay);
+            }
+        }
+    }
+
+    /// Ask the server to shut down with `shutdown` request and `exit`
+    /// notification, kill it if it doesn't exit within [`SHUTDOWN_TIMEOUT`]
+    ///
+    /// `exit` is only sent once the server responded to `shutdown`, some
+    /// servers exit right away without it. A server which exited already
+    /// isn't waited for.
+    async fn shutdown(&self) {
+        let exited = self.exited.notified();
+        tokio::pin!(exited);
+        exited.as_mut().enable();
+        if self.has_exited.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let shutdown = async {
+            if let Err(err) = self.internal_request("shutdown", Value::Null).await {
+                debug!(?err, "shutdown request failed");
+            }
+            let exit = Notification {
+                jsonrpc: Version,
+                method: "exit".into(),
+                params: Value::Null,
+            };
+            let _ = self.send_message(exit.into()).await;
+            std::future::pending().await
+        };
+        select! {
+            _ = exited => {}
+            () = shutdown => {}
+            _ = tokio::time::sleep(SHUTDOWN_TIMEOUT) => {
+                warn!(pid = self.pid(), timeout = ?SHUTDOWN_TIMEOUT, "instance didn't exit, killing it");
+                self.close.notify_one();
+            }
+        }
+    }
+
+    /// Save a crash report of the server which exited with `status`, returns
+    /// its path if crash reports are enabled
+    async fn save_crash_report(&self, status: &ExitStatus) -> Option<PathBuf> {
+        let crashes = self.crashes.as_ref()?;
+        let pid = self.pid();
+        let mut init_params = serde_json::to_value(&self.init_params).unwrap();
+        self.state.redaction.redact_params(&mut init_params);
+        let summary = format!(
+            "server: {:?}\nargs: {:?}\nworkspace root: {:?}\npid: {pid}\n{status}\n\
+            initialize params: {}\n",
+            self.key.server,
+            self.key.args,
+            self.key.workspace_root,
+            serde_json::to_string_pretty(&init_params).unwrap(),
+        );
+        match crashes
+            .save(&format!("{}-{pid}", utc_now()), &summary)
+            .await
+        {
+            Ok(path) => {
+                error!(?path, "saved crash report");
+                Some(path)
+            }
+            Err(err) => {
+                error!(?err, "error saving crash report");
+                None
+            }
+        }
+    }
+
+    /// Follow a `$/progress` notification of the server
+    async fn track_progress(&self, params: &Value) {
+        let token = params["token"].to_string();
+        let value = &params["value"];
+        let mut progress = self.progress.lock().await;
+        match value["kind"].as_str() {
+            Some("begin") => {
+                let active = ActiveProgress {
+                    token: params["token"].clone(),
+                    begin: value.clone(),
+                    report: None,
+                };
+                progress.insert(token, active);
+            }
+            Some("report") => {
+                // Fields missing in a report keep their previous values.
+                if let Some(progress) = progress.get_mut(&token) {
+                    let report = progress.report.get_or_insert_with(|| json!({}));
+                    if let (Some(report), Some(value)) = (report.as_object_mut(), value.as_object())
+                    {
+                        report.extend(value.clone());
+                    }
+                }
+            }
+            Some("end") => {
+                progress.remove(&token);
+            }
+            _ => {}
+        }
+    }
+
+    /// Show work done progress which is already running to a new client which
+    /// supports it
+    ///
+    /// The token is created in the client first like the server did, then the
+    /// client gets the `begin` and the latest `report` so it shows the progress
+    /// right away instead of once the next report comes.
+    async fn replay_progress(&self, client: &Client) {
+        let progress = self.progress.lock().await;
+        for (key, active) in progress.iter() {
+            debug!(token = ?active.token, "replaying work done progress");
+            let id = RequestId::String(format!("replay:progress:{key}")).tag(Tag::Drop);
+            let req = Request {
+                id,
+                method: "window/workDoneProgress/create".into(),
+                params: json!({ "token": active.token }),
+                jsonrpc: Version,
+            };
+            let _ = client.send_message(req.into()).await;
+            for value in [Some(&active.begin), active.report.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                let notif = Notification {
+                    jsonrpc: Version,
+                    method: "$/progress".into(),
+                    params: json!({ "token": active.token, "value": value }),
+                };
+                let _ = client.send_message(notif.into()).await;
+            }
+        }
+    }
+
+    /// Short state of the instance for editor statuslines
+    pub async fn statusline(&self) -> ext::Statusline {
+        let server_status = self.server_status.lock().await;
+        let status = server_status.as_ref().map(|notif| &notif.params);
+        let progress = self
+            .progress
+            .lock()
+            .await
+            .values()
+            .map(ActiveProgress::summary)
+            .min_by_key(|progress| progress.percentage.unwrap_or(0));
+        ext::Statusline {
+            pid: self.pid(),
+            server: self.key.server.clone(),
+            clients: self.clients.lock().await.len(),
+            rss: process_rss(self.pid()),
+            busy: progress.is_some() || status.is_some_and(|status| status["quiescent"] == false),
+            progress,
+            health: status
+                .and_then(|status| status["health"].as_str())
+                .map(str::to_owned),
+        }
+    }
+
+    pub fn get_status(&self) -> ext::Instance {
+        let clients = self
+            .clients
+            .blocking_lock()
+            .values()
+            .map(|client| client.get_status())
+            .collect();
+
+        let registered_dyn_capabilities = self
+            .dynamic_capabilities
+            .blocking_lock()
+            .values()
+            .map(|reg| reg.method.clone())
+            .collect();
+
+        let memory_usage = self.memory_usage.blocking_lock().clone();
+
+        ext::Instance {
+            rss: process_rss(self.pid()),
+            memory_usage,
+            traffic: Some(self.traffic.get_status()),
+            pid: self.pid(),
+            server: self.key.server.clone(),
+            args: self.key.args.clone(),
+            env: self.key.env.clone(),
+            workspace_root: self.key.workspace_root.clone(),
+            role: self.key.role,
+            group: self.key.group.clone(),
+            tag: self.key.tag.clone(),
+            last_used: self.last_used.load(Ordering::Relaxed),
+            pinned: self.is_pinned(),
+            clients,
+            registered_dyn_capabilities,
+        }
+    }
+}
+
+/// How long to wait for an instance to exit after asking it to shut down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a server started by `rollover` which doesn't report a quiescent
+/// `experimental/serverStatus` has to be quiet without any work done progress
+/// to be considered done indexing
+const STANDBY_SETTLE: Duration = Duration::from_secs(3);
+
+/// How long to wait for clients to apply a `workspace/applyEdit` request, it's
+/// longer than for other requests because editors may ask the user first
+const APPLY_EDIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for all clients to answer a broadcast server request
+/// before responding to the server without them
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long file watcher events are collected before sending them
+const WATCH_FILES_DELAY: Duration = Duration::from_millis(100);
+
+/// How often to check whether an adopted shim is still running, it isn't our
+/// child so we can't wait for it
+#[cfg(unix)]
+const ADOPTED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of crash report paths shown by `status`
+const CRASH_REPORTS_SHOWN: usize = 10;
+
+/// How long [`Instance::merge_into`] waits for the requests clients are
+/// waiting for before moving them
+const MERGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of server messages `stdout_task` handles before it yields to other
+/// tasks
+const YIELD_MESSAGES: usize = 32;
+
+/// Number of bytes of server messages `stdout_task` parses before it yields to
+/// other tasks
+const YIELD_BYTES: u64 = 1024 * 1024;
+
+/// Work a task does between yield points
+///
+/// Tokio only preempts a task after it polled a number of resources and
+/// reading an already buffered message doesn't count as one. A server flooding
+/// us, like rust-analyzer publishing the diagnostics of a whole workspace
+/// after a cargo check, would keep a runtime thread busy parsing them and hold
+/// up the instances and clients sharing it. Clients connected to several
+/// instances get their messages through one channel, its senders are served
+/// in the order they're waiting so the other instances get their turn there.
+#[derive(Default)]
+struct Budget {
+    messages: usize,
+    bytes: u64,
+}
+
+impl Budget {
+    /// Count a message of `bytes` bytes, yields once the budget is used up
+    async fn spend(&mut self, bytes: u64) {
+        self.messages += 1;
+        self.bytes += bytes;
+        if self.messages >= YIELD_MESSAGES || self.bytes >= YIELD_BYTES {
+            *self = Budget::default();
+            task::yield_now().await;
+        }
+    }
+}
+
+pub struct InstanceMap {
+    instances: HashMap<InstanceKey, Arc<Instance>>,
+
+    /// The daemon is shutting down, no new instances can be spawned
+    closing: bool,
+
+    /// `initialize` results of previously spawned servers by their path and
+    /// arguments, for answering clients before spawning with `lazy_spawn`
+    init_results: HashMap<(String, Vec<String>), lsp::InitializeResult>,
+
+    /// Paths of the last crash reports saved, oldest first
+    crash_reports: Vec<PathBuf>,
+
+    /// Configuration for newly spawned instances
+    config: Arc<Config>,
+
+    /// State of the server the instances belong to
+    state: Arc<ServerState>,
+}
+
+impl InstanceMap {
+    pub async fn new(config: Arc<Config>, state: Arc<ServerState>) -> Arc<Mutex<Self>> {
+        let instance_map = Arc::new(Mutex::new(InstanceMap {
+            instances: HashMap::new(),
+            closing: false,
+            init_results: HashMap::new(),
+            crash_reports: Vec::new(),
+            config: config.clone(),
+            state,
+        }));
+        task::spawn(gc_task(
+            instance_map.clone(),
+            config.gc_interval,
+            config.instance_timeout,
+            config.keep_alive,
+        ));
+        instance_map
+    }
+
+    /// `initialize` result to answer a client with before the instance for
+    /// `key` is spawned
+    ///
+    /// Returns `None` if the instance is already running or the same server
+    /// wasn't spawned before.
+    pub fn lazy_init_result(&self, key: &InstanceKey) -> Option<lsp::InitializeResult> {
+        if self.instances.contains_key(key) {
+            return None;
+        }
+        self.init_results
+            .get(&(key.server.clone(), key.args.clone()))
+            .cloned()
+    }
+
+    /// Finds a primary instance with the longest path such as
+    /// `cwd.starts_with(workspace_root)` is true
+    pub fn get_by_cwd(&self, cwd: &str) -> Option<&Arc<Instance>> {
+        self.instances
+            .iter()
+            .filter(|(key, _)| key.role == InstanceRole::Primary)
+            .filter(|(key, _)| Path::new(cwd).starts_with(&key.workspace_root))
+            .max_by_key(|(key, _)| key.workspace_root.len())
+            .map(|(_, inst)| inst)
+    }
+
+    /// Finds an instance with the language server process ID `pid`
+    pub fn get_by_pid(&self, pid: u32) -> Option<&Arc<Instance>> {
+        self.instances
+            .values()
+            .find(|instance| instance.pid() == pid)
+    }
+
+    /// Shut down all instances and refuse spawning new ones, returns the
+    /// number of instances shut down
+    ///
+    /// With `detach` instances running in a shim are left running.
+    pub async fn shutdown(instance_map: &Arc<Mutex<InstanceMap>>, detach: bool) -> usize {
+        let instances = {
+            let mut instance_map = instance_map.lock().await;
+            instance_map.closing = true;
+            instance_map
+                .instances
+                .values()
+                .filter(|instance| !(detach && instance.is_adoptable()))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        info!(count = instances.len(), "shutting down instances");
+
+        let tasks = instances
+            .iter()
+            .map(|instance| {
+                let instance = instance.clone();
+                task::spawn(async move { instance.shutdown().await }.in_current_span())
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            let _ = task.await;
+        }
+        instances.len()
+    }
+
+    pub fn get_status(&self) -> ext::StatusResponse {
+        ext::StatusResponse {
+            instances: self
+                .instances
+                .values()
+                .map(|instance| instance.get_status())
+                .collect(),
+            duplicates: duplicates(
+                &self
+                    .instances
+                    .values()
+                    .filter(|instance| {
+                        instance.merged_into().is_none()
+                            && !instance.evicted.load(Ordering::Relaxed)
+                    })
+                    .map(|instance| (instance.key(), instance.pid()))
+                    .collect::<Vec<_>>(),
+            ),
+            crash_reports: self
+                .crash_reports
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+        }
+    }
+
+    fn add_crash_report(&mut self, path: PathBuf) {
+        if self.crash_reports.len() == CRASH_REPORTS_SHOWN {
+            self.crash_reports.remove(0);
+        }
+        self.crash_reports.push(path);
+    }
+}
+
+/// Instances run the same server and could share it, their workspace roots
+/// aside
+fn runs_same_server(a: &InstanceKey, b: &InstanceKey) -> bool {
+    a.server == b.server
+        && a.args == b.args
+        && a.env == b.env
+        && a.tag == b.tag
+        && a.role == InstanceRole::Primary
+        && b.role == InstanceRole::Primary
+}
+
+/// Instances with a workspace root inside the root of another instance running
+/// the same server, paired with the outermost one
+fn duplicates(instances: &[(&InstanceKey, u32)]) -> Vec<ext::Duplicate> {
+    let mut duplicates = Vec::new();
+    for &(key, pid) in instances {
+        let root = Path::new(&key.workspace_root);
+        let parent = instances
+            .iter()
+            .filter(|(other, _)| {
+                other.workspace_root != key.workspace_root
+                    && root.starts_with(&other.workspace_root)
+                    && runs_same_server(key, other)
+            })
+            .min_by_key(|(other, _)| Path::new(&other.workspace_root).components().count());
+        if let Some(&(into, into_pid)) = parent {
+            duplicates.push(ext::Duplicate {
+                pid,
+                workspace_root: key.workspace_root.clone(),
+                into_pid,
+                into_workspace_root: into.workspace_root.clone(),
+            });
+        }
+    }
+    duplicates.sort_by(|a, b| a.workspace_root.cmp(&b.workspace_root));
+    duplicates
+}
+
+/// Periodically check for for idle language server instances
+#[instrument("garbage collector", skip_all)]
+async fn gc_task(
+    instance_map: Arc<Mutex<InstanceMap>>,
+    gc_interval: u32,
+    instance_timeout: Option<u32>,
+    keep_alive: Option<KeepAlive>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(gc_interval.into()));
+    loop {
+        interval.tick().await;
+
+        let mut map = instance_map.lock().await;
+        let mut closed = Vec::new();
+        for (key, instance) in &map.instances {
+            let clients = instance.clients.lock().await;
+
+            let idle = instance.idle();
+            let detached = instance.detached();
+            debug!(path = ?key.workspace_root, idle, detached, clients = clients.len(), "check instance");
+
+            // Instances with clients are never closed, however quiet they are.
+            if !clients.is_empty() || instance.is_pinned() {
+                continue;
+            }
+            if let Some(reason) = eviction(idle, detached, instance_timeout, keep_alive) {
+                info!(pid = instance.pid(), path = ?key.workspace_root, idle, detached, reason, "closing instance");
+                instance.close.notify_one();
+                evicted(instance, reason);
+                closed.push(key.clone());
+            }
+        }
+        // Clients connecting while it shuts down spawn a new instance.
+        for key in closed {
+            map.instances.remove(&key);
+        }
+    }
+}
+
+/// Why an instance without clients should be closed, if at all
+///
+/// `idle` counts from the last message exchanged with a client in either
+/// direction and `detached` from the last client disconnecting.
+fn eviction(
+    idle: i64,
+    detached: i64,
+    instance_timeout: Option<u32>,
+    keep_alive: Option<KeepAlive>,
+) -> Option<&'static str> {
+    match keep_alive {
+        // Close instance nobody connected to for too long
+        Some(KeepAlive::Seconds(keep_alive)) => {
+            (detached > i64::from(keep_alive)).then_some("keep alive expired")
+        }
+        Some(KeepAlive::Never) => None,
+        // Close timed out instance
+        None => instance_timeout
+            .filter(|&timeout| idle > i64::from(timeout))
+            .map(|_| "idle timeout"),
+    }
+}
+
+fn evicted(instance: &Instance, reason: &str) {
+    instance.evicted.store(true, Ordering::Relaxed);
+    instance.state.events.publish(ext::Event {
+        reason: Some(reason.into()),
+        ..events::instance(EventKind::InstanceEvicted, instance)
+    });
+}
+
+/// Find existing or spawn a new language server instance
+///
+/// The instance is looked up based on `instance_key`. If an existing one is
+/// found then it's returned and `init_req_params` are discarded. If it's
+/// not found a new instance is spawned and initialized using the provided
+/// `init_req_params`, this insance is then inserted into the map and returned.
+pub async fn get_or_spawn(
+    map: Arc<Mutex<InstanceMap>>,
+    key: InstanceKey,
+    init_req_params: lsp::InitializeParams,
+) -> Result<Arc<Instance>> {
+    // We have locked a clone of an Arc of the map, we can assume noone else
+    // tries to spawn the same instance again. But we have to make sure `spawn`
+    // doesn't try to lock its copy as well. This is a bit unfortunate code
+    // organization but we want to have spawn in a separate tracing context and
+    // we want to include `wait_task` in it as well in it as well
+    let mut instance_map = map.lock().await;
+    if instance_map.closing {
+        bail!(ErrorCode::ShuttingDown);
+    }
+    let config = instance_map.config.clone();
+    let state = instance_map.state.clone();
+    match instance_map.instances.entry(key.clone()) {
+        Entry::Occupied(e) => {
+            info!("reusing language server instance");
+            let mut instance = e.get().clone();
+            while let Some(target) = instance.merged_into() {
+                instance = target;
+            }
+            Ok(instance)
+        }
+        Entry::Vacant(e) => {
+            // The client may be on the transport runtime, the instance's tasks
+            // and the server's stdio go on the main one.
+            let spawn = spawn(key, init_req_params, config, state.clone(), map.clone());
+            let instance = state
+                .runtime
+                .spawn(spawn)
+                .await
+                .context("spawn task failed")
+                .and_then(|res| res)
+                .context("spawning instance")?;
+            e.insert(instance.clone());
+            let server = (instance.key.server.clone(), instance.key.args.clone());
+            instance_map
+                .init_results
+                .insert(server, instance.initialize_result());
+            Ok(instance)
+        }
+    }
+}
+
+#[instrument(name = "instance", fields(pid = field::Empty), skip_all, parent = None)]
+async fn spawn(
+    key: InstanceKey,
+    mut init_req_params: lsp::InitializeParams,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    // Caller `get_or_spawn` is holding a lock to the map, we must not try to
+    // lock it within this function to not cause deadlock, only spawned tasks
+    // are allowed to lock it again.
+    map: Arc<Mutex<InstanceMap>>,
+) -> Result<Arc<Instance>> {
+    if config.watch_files {
+        // The server has to know it can register file watchers no matter
+        // what the first client supports.
+        let capabilities = init_req_params
+            .capabilities
+            .get_or_insert_with(|| json!({}));
+        if let Some(capabilities) = capabilities.as_object_mut() {
+            let workspace = capabilities.entry("workspace").or_insert_with(|| json!({}));
+            if let Some(workspace) = workspace.as_object_mut() {
+                workspace.insert(
+                    "didChangeWatchedFiles".into(),
+                    json!({ "dynamicRegistration": true, "relativePatternSupport": true }),
+                );
+            }
+        }
+    }
+    let crashes = config
+        .crash_report_messages
+        .map(|limit| Arc::new(CrashRecorder::new(limit as usize, state.redaction.clone())));
+    let mut server = start_server(&key, &config, crashes.clone()).await?;
+    tracing::Span::current().record("pid", server.pid);
+
+    let init_result = initialize_handshake(
+        init_req_params.clone(),
+        &mut server.reader,
+        &mut server.writer,
+    )
+    .await
+    .context("server handshake")?;
+
+    info!("initialized server");
+
+    if server.process.is_shim() {
+        let adoption = AdoptionState {
+            key: key.clone(),
+            init_params: init_req_params.clone(),
+            init_result: init_result.clone(),
+        };
+        save_adoption_state(adoption, &mut server.writer).await?;
+    }
+
+    let instance = start_instance(
+        key,
+        init_req_params,
+        init_result,
+        server,
+        crashes,
+        config,
+        state,
+        map,
+    )
+    .await;
+    Ok(instance)
+}
+
+/// Sink configured for mirroring the messages of the instance
+fn mirror_sink(config: &Config, key: &InstanceKey) -> Option<Sink> {
+    let settings = config.server_settings(&key.server)?;
+    if let Some(socket) = &settings.mirror_socket {
+        return Some(Sink::Socket(key.expand(socket)));
+    }
+    let command = settings.mirror_command.as_ref()?;
+    Some(Sink::Command(
+        command.iter().map(|arg| key.expand(arg)).collect(),
+    ))
+}
+
+/// Create an instance for an initialized server and start its tasks
+#[allow(clippy::too_many_arguments)]
+async fn start_instance(
+    key: InstanceKey,
+    init_params: lsp::InitializeParams,
+    init_result: lsp::InitializeResult,
+    server: Server,
+    crashes: Option<Arc<CrashRecorder>>,
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    map: Arc<Mutex<InstanceMap>>,
+) -> Arc<Instance> {
+    let file_watcher = config
+        .watch_files
+        .then(|| FileWatcher::new(&key.workspace_root));
+
+    let (message_writer, rx) = mpsc::channel(64);
+
+    let did_change_debounce = did_change_debounce(&config, &key.server);
+    let pinned = config
+        .server_settings(&key.server)
+        .is_some_and(|settings| settings.pinned);
+    let mirror = mirror_sink(&config, &key)
+        .map(|sink| Arc::new(Mirror::start(sink, state.redaction.clone())));
+    let instance = Arc::new(Instance {
+        key,
+        pid: AtomicU32::new(server.pid),
+        init_params,
+        init_result,
+        server: message_writer,
+        clients: Mutex::default(),
+        dynamic_capabilities: Mutex::default(),
+        server_status: Mutex::default(),
+        progress: Mutex::default(),
+        documents: Mutex::default(),
+        did_change_debounce,
+        debounced_change: Mutex::default(),
+        broadcasts: Mutex::default(),
+        apply_edits: Mutex::default(),
+        show_documents: Mutex::default(),
+        orphans: Mutex::default(),
+        configuration_requests: Mutex::default(),
+        configuration: Mutex::default(),
+        internal_requests: Mutex::default(),
+        next_internal_id: AtomicI64::new(0),
+        memory_usage: Mutex::default(),
+        traffic: Arc::new(TrafficStats::new()),
+        file_watcher: file_watcher
+            .as_ref()
+            .map(|(file_watcher, _)| file_watcher.clone()),
+        crashes,
+        mirror,
+        restarting: AtomicBool::new(false),
+        rolling_over: AtomicBool::new(false),
+        pinned: AtomicBool::new(pinned),
+        evicted: AtomicBool::new(false),
+        merged_into: std::sync::Mutex::new(None),
+        standby: Mutex::default(),
+        consecutive_errors: AtomicU32::new(0),
+        request_permits: config
+            .max_concurrent_requests
+            .map(|permits| RequestQueue::new(permits as usize)),
+        pending_requests: Mutex::default(),
+        written_requests: Arc::default(),
+        config,
+        state,
+        originator: AtomicUsize::new(usize::MAX),
+        close: Notify::new(),
+        exited: Notify::new(),
+        has_exited: AtomicBool::new(false),
+        last_used: AtomicI64::new(utc_now()),
+        last_client_left: AtomicI64::new(utc_now()),
+    });
+
+    let (handoffs, handoff_rx) = mpsc::channel(1);
+    let handoff = Handoff {
+        writer: server.writer,
+        replay: Vec::new(),
+        drained: None,
+    };
+    handoffs.send(handoff).await.unwrap();
+
+    task::spawn(stdout_task(instance.clone(), server.reader, Vec::new()).in_current_span());
+    let traffic = instance.traffic.clone();
+    let crashes = instance.crashes.clone();
+    let mirror = instance.mirror.clone();
+    let written = instance.written_requests.clone();
+    task::spawn(stdin_task(rx, handoff_rx, written, traffic, crashes, mirror).in_current_span());
+
+    let process = server.process;
+    task::spawn(wait_task(instance.clone(), map, process, handoffs).in_current_span());
+
+    instance.state.stats.spawned();
+    hooks::run(
+        &instance.config.hooks,
+        Event::InstanceStart,
+        &instance.hook_vars(),
+    );
+    let started = events::instance(EventKind::InstanceStarted, &instance);
+    instance.state.events.publish(started);
+
+    if let Some(timeout) = instance.config.min_request_timeout() {
+        let instance = Arc::downgrade(&instance);
+        task::spawn(watchdog_task(instance, timeout).in_current_span());
+    }
+
+    if let Some((_, events)) = file_watcher {
+        let instance = Arc::downgrade(&instance);
+        task::spawn(watch_files_task(instance, events).in_current_span());
+    }
+
+    if let Some(interval) = instance.config.memory_usage_interval {
+        let name = Path::new(&instance.key.server).file_stem();
+        if name.is_some_and(|name| name == "rust-analyzer") {
+            let interval = Duration::from_secs(interval.into());
+            let instance = Arc::downgrade(&instance);
+            task::spawn(memory_usage_task(instance, interval).in_current_span());
+        }
+    }
+
+    instance
+}
+
+/// Adopt the instances of shims left running by a previous daemon
+///
+/// Shims which can't be adopted are killed.
+#[cfg(unix)]
+pub async fn adopt_instances(map: &Arc<Mutex<InstanceMap>>) {
+    let dirs = match shim::socket_dirs() {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            warn!(?err, "cannot adopt instances");
+            return;
+        }
+    };
+    for dir in dirs {
+        adopt_instances_in(&dir, map).await;
+    }
+}
+
+#[cfg(unix)]
+async fn adopt_instances_in(dir: &Path, map: &Arc<Mutex<InstanceMap>>) {
+    // There is nothing to adopt if the directory doesn't exist.
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let socket = entry.path();
+        if socket.extension() != Some("sock".as_ref()) {
+            continue;
+        }
+        let instance = match adopt(&socket, map.clone()).await {
+            Ok(instance) => instance,
+            Err(err) => {
+                warn!(?socket, ?err, "cannot adopt instance");
+                let _ = tokio::fs::remove_file(&socket).await;
+                continue;
+            }
+        };
+        let mut instance_map = map.lock().await;
+        let server = (instance.key.server.clone(), instance.key.args.clone());
+        instance_map
+            .init_results
+            .insert(server, instance.initialize_result());
+        match instance_map.instances.entry(instance.key.clone()) {
+            Entry::Occupied(_) => {
+                warn!(pid = instance.pid(), "instance adopted twice, killing it");
+                instance.close.notify_one();
+            }
+            Entry::Vacant(e) => {
+                e.insert(instance);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+#[instrument(name = "instance", fields(pid = field::Empty), skip_all, parent = None)]
+async fn adopt(socket: &Path, map: Arc<Mutex<InstanceMap>>) -> Result<Arc<Instance>> {
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .context("connecting to shim")?;
+    let (read, write) = stream.into_split();
+    let (mut reader, writer) = server_io(read, write);
+    let hello = shim::hello(&mut reader).await?;
+    let state = match serde_json::from_value::<AdoptionState>(hello.state) {
+        Ok(state) => state,
+        Err(err) => {
+            // The previous daemon didn't finish initializing the server.
+            let _ = shim::kill(hello.shim_pid);
+            return Err(err).context("parse adoption state");
+        }
+    };
+    tracing::Span::current().record("pid", hello.server_pid);
+    info!(server = ?state.key.server, path = ?state.key.workspace_root, "adopted instance");
+
+    let (config, server_state) = {
+        let map = map.lock().await;
+        (map.config.clone(), map.state.clone())
+    };
+    let crashes = config.crash_report_messages.map(|limit| {
+        Arc::new(CrashRecorder::new(
+            limit as usize,
+            server_state.redaction.clone(),
+        ))
+    });
+    let server = Server {
+        process: ServerProcess::Shim(None, hello.shim_pid),
+        pid: hello.server_pid,
+        reader,
+        writer,
+    };
+    let instance = start_instance(
+        state.key,
+        state.init_params,
+        state.init_result,
+        server,
+        crashes,
+        config,
+        server_state,
+        map,
+    )
+    .await;
+
+    // Replay the capabilities the server registered with previous daemons.
+    let mut params = serde_json::to_value(lsp::RegistrationParams {
+        registrations: hello.registrations,
+    })
+    .unwrap();
+    instance.take_file_watchers(&mut params);
+    if let Err(err) = instance.register_capabilities(params).await {
+        warn!(?err, "error registering capabilities");
+    }
+    Ok(instance)
+}
+
+/// Reader of language server messages, from its stdout or a shim
+type ServerReader = LspReader<BufReader<Box<dyn AsyncRead + Send + Unpin>>>;
+
+/// Writer of language server messages, to its stdin or a shim
+type ServerWriter = LspWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+
+fn server_io(
+    read: impl AsyncRead + Send + Unpin + 'static,
+    write: impl AsyncWrite + Send + Unpin + 'static,
+) -> (ServerReader, ServerWriter) {
+    let read: Box<dyn AsyncRead + Send + Unpin> = Box::new(read);
+    let write: Box<dyn AsyncWrite + Send + Unpin> = Box::new(write);
+    (
+        LspReader::new(BufReader::new(read), "server"),
+        LspWriter::new(write, "server"),
+    )
+}
+
+/// Started language server
+struct Server {
+    process: ServerProcess,
+    pid: u32,
+    reader: ServerReader,
+    writer: ServerWriter,
+}
+
+/// Server started by `rollover` which finished indexing
+struct Standby {
+    server: Server,
+
+    /// Server requests, diagnostics and the last status sent while indexing,
+    /// handled once clients are switched over
+    held: Vec<Message>,
+}
+
+enum ServerProcess {
+    /// Language server spawned by this daemon
+    Child(Child),
+
+    /// Language server running in a shim by its PID, the shim is our child
+    /// unless it was adopted from a previous daemon
+    #[cfg(unix)]
+    Shim(Option<Child>, u32),
+}
+
+impl ServerProcess {
+    fn is_shim(&self) -> bool {
+        match self {
+            ServerProcess::Child(_) => false,
+            #[cfg(unix)]
+            ServerProcess::Shim(..) => true,
+        }
+    }
+
+    fn start_kill(&mut self) -> std::io::Result<()> {
+        match self {
+            ServerProcess::Child(child) => child.start_kill(),
+            #[cfg(unix)]
+            ServerProcess::Shim(_, shim_pid) => shim::kill(*shim_pid),
+        }
+    }
+
+    /// Wait for the process to exit, the exit status of an adopted shim is
+    /// unknown
+    async fn wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        match self {
+            ServerProcess::Child(child) => child.wait().await.map(Some),
+            #[cfg(unix)]
+            ServerProcess::Shim(Some(child), _) => child.wait().await.map(Some),
+            #[cfg(unix)]
+            ServerProcess::Shim(None, shim_pid) => {
+                while shim::is_running(*shim_pid) {
+                    tokio::time::sleep(ADOPTED_POLL_INTERVAL).await;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// State of an instance kept by its shim for the daemon adopting it
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdoptionState {
+    key: InstanceKey,
+    init_params: lsp::InitializeParams,
+    init_result: lsp::InitializeResult,
+}
+
+/// Leave the state with the shim running the server
+async fn save_adoption_state(state: AdoptionState, writer: &mut ServerWriter) -> Result<()> {
+    let notif = Notification {
+        jsonrpc: Version,
+        method: ext::SHIM_STATE.into(),
+        params: serde_json::to_value(state).unwrap(),
+    };
+    writer
+        .write_message(&notif.into())
+        .await
+        .context("send shim state")
+}
+
+/// The language server process couldn't be executed
+///
+/// Reported to the client in the `initialize` response and with a
+/// `window/showMessage` notification, the details are otherwise only in the
+/// server log.
+#[derive(Debug)]
+pub struct SpawnError {
+    /// Program executed, the wrapper if there's one
+    pub program: String,
+    /// `PATH` the program was looked up in
+    pub path: String,
+    pub source: io::Error,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let program = &self.program;
+        match self.source.kind() {
+            ErrorKind::NotFound => write!(f, "`{program}` not found"),
+            ErrorKind::PermissionDenied => write!(f, "permission denied executing `{program}`"),
+            _ => write!(f, "executing `{program}` failed: {}", self.source),
+        }?;
+        write!(f, ", PATH is {:?}", self.path)
+    }
+}
+
+impl std::error::Error for SpawnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Start the language server process
+///
+/// With `adopt_instances` the server is spawned in a shim and its messages go
+/// through the shim's socket.
+async fn start_server(
+    key: &InstanceKey,
+    config: &Config,
+    crashes: Option<Arc<CrashRecorder>>,
+) -> Result<Server> {
+    let options = &config.server;
+    #[cfg(unix)]
+    let socket = match config.adopt_instances {
+        true => Some(shim::socket_path().await?),
+        false => None,
+    };
+    #[cfg(not(unix))]
+    let socket: Option<PathBuf> = None;
+
+    // The shim holds the server's stdio, it's never run in a shell.
+    let mut launcher = Vec::new();
+    #[cfg(unix)]
+    if let Some(socket) = &socket {
+        launcher = shim::command(socket)?;
+    }
+    let wrapper = launcher
+        .iter()
+        .chain(&options.wrapper)
+        .cloned()
+        .collect::<Vec<_>>();
+    let stdio = || match socket {
+        Some(_) => Stdio::null(),
+        None => Stdio::piped(),
+    };
+
+    let mut command = key.command(&launcher, &options.wrapper, options.shell);
+    scheduling::apply(&mut command, options)?;
+    let path = key
+        .env
+        .get("PATH")
+        .map(<_>::to_owned)
+        // Display PATH from our environment Command will if none was passed
+        // from the client environment.
+        .or_else(|| env::var("PATH").ok())
+        .unwrap_or_default();
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    let mut child = command
+        .stdin(stdio())
+        .stdout(stdio())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| SpawnError {
+            program,
+            path: path.clone(),
+            source,
+        })
+        .with_context(|| {
+            let InstanceKey {
+                server,
+                args,
+                env,
+                workspace_root,
+                ..
+            } = key;
+            format!(
+                "spawning langauge server: wrapper={wrapper:?}, server={server:?}, \
+                args={args:?}, cwd={workspace_root:?}, path={path:?}, env={env:?}",
+            )
+        })?;
+
+    info!(?wrapper, server = ?key.server, args = ?key.args, cwd = ?key.workspace_root, "spawned langauge server");
+
+    let stderr = child.stderr.take().unwrap();
+    task::spawn(stderr_task(stderr, crashes).in_current_span());
+
+    #[cfg(unix)]
+    if let Some(socket) = socket {
+        let stream = match shim::connect(&socket, &mut child).await {
+            Ok(stream) => stream,
+            Err(err) => match err.downcast::<shim::ExecFailed>() {
+                // Report it like a server we spawned ourselves, the program
+                // is the first one the shim runs.
+                Ok(shim::ExecFailed(kind)) => {
+                    let program = command
+                        .as_std()
+                        .get_args()
+                        .nth(launcher.len() - 1)
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned();
+                    return Err(SpawnError {
+                        program,
+                        path,
+                        source: kind.into(),
+                    }
+                    .into());
+                }
+                Err(err) => return Err(err.context("connecting to shim")),
+            },
+        };
+        let (read, write) = stream.into_split();
+        let (mut reader, writer) = server_io(read, write);
+        let hello = shim::hello(&mut reader).await?;
+        return Ok(Server {
+            process: ServerProcess::Shim(Some(child), hello.shim_pid),
+            pid: hello.server_pid,
+            reader,
+            writer,
+        });
+    }
+
+    let pid = child.id().context("child exited early, couldn't get PID")?;
+    let stdout = child.stdout.take().unwrap();
+    let stdin = child.stdin.take().unwrap();
+    let (reader, writer) = server_io(stdout, stdin);
+
+    Ok(Server {
+        process: ServerProcess::Child(child),
+        pid,
+        reader,
+        writer,
+    })
+}
+
+/// Start the language server of a restarting instance again, or switch over
+/// to its standby server, and bring it to the state of the previous one
+async fn restart(
+    instance: &Arc<Instance>,
+    handoffs: &mpsc::Sender<Handoff>,
+) -> Result<ServerProcess> {
+    let standby = instance.standby.lock().await.take();
+    let (server, held) = match standby {
+        Some(Standby { server, held }) => (server, held),
+        None => {
+            let crashes = instance.crashes.clone();
+            let mut server = start_server(&instance.key, &instance.config, crashes).await?;
+            let handshake = initialize_handshake(
+                instance.init_params.clone(),
+                &mut server.reader,
+                &mut server.writer,
+            )
+            .await;
+            if let Err(err) = handshake {
+                let _ = server.process.start_kill();
+                return Err(err.context("server handshake"));
+            }
+            (server, Vec::new())
+        }
+    };
+    let Server {
+        mut process,
+        pid,
+        reader,
+        writer,
+    } = server;
+    // A server which isn't switched over to would keep running unnoticed.
+    let is_shim = process.is_shim();
+    match switch_over(instance, handoffs, (pid, reader, writer), held, is_shim).await {
+        Ok(()) => Ok(process),
+        Err(err) => {
+            let _ = process.start_kill();
+            Err(err)
+        }
+    }
+}
+
+/// Make the restarted server with `pid` the instance's server
+async fn switch_over(
+    instance: &Arc<Instance>,
+    handoffs: &mpsc::Sender<Handoff>,
+    (pid, reader, mut writer): (u32, ServerReader, ServerWriter),
+    held: Vec<Message>,
+    is_shim: bool,
+) -> Result<()> {
+    if is_shim {
+        let state = AdoptionState {
+            key: instance.key.clone(),
+            init_params: instance.init_params.clone(),
+            init_result: instance.init_result.clone(),
+        };
+        save_adoption_state(state, &mut writer).await?;
+    }
+    instance.pid.store(pid, Ordering::Relaxed);
+
+    info!(pid, "restarted server");
+    instance.state.stats.spawned();
+    hooks::run(
+        &instance.config.hooks,
+        Event::InstanceStart,
+        &instance.hook_vars(),
+    );
+    instance
+        .state
+        .events
+        .publish(events::instance(EventKind::InstanceStarted, instance));
+
+    let mut clients = instance.clients.lock().await;
+
+    // The old server is never going to respond to requests it received,
+    // clients may retry them. The ones it didn't get go to the new server.
+    let written = std::mem::take(&mut *instance.written_requests.lock().unwrap());
+    for id in written {
+        instance
+            .fail_request(&mut clients, &id, "language server restarted")
+            .await;
+    }
+
+    // Capabilities registered by the old server are gone, the new one is
+    // going to register its own.
+    let mut dyn_capabilities = instance.dynamic_capabilities.lock().await;
+    if !dyn_capabilities.is_empty() {
+        let params = lsp::UnregistrationParams {
+            unregistrations: dyn_capabilities
+                .drain()
+                .map(|(id, reg)| lsp::Unregistration {
+                    id,
+                    method: reg.method,
+                })
+                .collect(),
+        };
+        let req = Request {
+            id: RequestId::String("restart:unregisterCapabilities".into()).tag(Tag::Drop),
+            method: "client/unregisterCapability".into(),
+            params: serde_json::to_value(params).unwrap(),
+            jsonrpc: Version,
+        };
+        for client in clients.values() {
+            let _ = client.send_message(req.clone().into()).await;
+        }
+    }
+    drop(dyn_capabilities);
+    drop(clients);
+    *instance.server_status.lock().await = None;
+    instance.progress.lock().await.clear();
+
+    // Changes are sent to the server while holding the documents lock, the
+    // ones queued for the old server are already part of the replay. The
+    // lock is held until they're discarded so no new ones are lost with them.
+    let documents = instance.documents.lock().await;
+    // Documents already contain the debounced change.
+    instance.debounced_change.lock().await.pending = None;
+    instance.broadcasts.lock().await.clear();
+    instance.apply_edits.lock().await.clear();
+    if let Some(file_watcher) = &instance.file_watcher {
+        file_watcher.clear();
+    }
+    let replay = documents
+        .values()
+        .flat_map(Document::replay)
+        .map(Message::from)
+        .collect();
+    let (drained, drained_rx) = oneshot::channel();
+    let handoff = Handoff {
+        writer,
+        replay,
+        drained: Some(drained),
+    };
+    handoffs
+        .send(handoff)
+        .await
+        .ok()
+        .context("stdin task stopped")?;
+    let _ = drained_rx.await;
+    drop(documents);
+
+    task::spawn(stdout_task(instance.clone(), reader, held).in_current_span());
+
+    Ok(())
+}
+
+/// Read the messages of a server started by `rollover` until it finished
+/// indexing, returns the messages clients should get once it replaces the
+/// running server
+///
+/// rust-analyzer reports it with a quiescent `experimental/serverStatus`,
+/// other servers are done once no work done progress is running and they
+/// didn't send anything for [`STANDBY_SETTLE`]. Other notifications are
+/// dropped, the server's documents are only opened on switching over.
+///
+/// Servers wait for their configuration before they start indexing,
+/// `workspace/configuration` requests are answered by `configuration` with
+/// what clients answered the running server.
+async fn wait_indexed<F>(
+    reader: &mut ServerReader,
+    writer: &mut ServerWriter,
+    configuration: impl Fn(Value) -> F,
+) -> Result<Vec<Message>>
+where
+    F: Future<Output = Value>,
+{
+    let mut held = Vec::new();
+    let mut progress = HashSet::new();
+    let mut server_status = false;
+    loop {
+        let settled = !server_status && progress.is_empty();
+        let message = select! {
+            message = reader.read_message() => message?.context("server closed stdout")?,
+            _ = tokio::time::sleep(STANDBY_SETTLE), if settled => return Ok(held),
+        };
+        match message {
+            Message::Request(req) if req.method == "window/workDoneProgress/create" => {
+                writer
+                    .write_message(&ResponseSuccess::null(req.id).into())
+                    .await
+                    .context("writing response")?;
+            }
+            Message::Request(req) if req.method == "workspace/configuration" => {
+                let res = ResponseSuccess {
+                    jsonrpc: Version,
+                    result: configuration(req.params).await,
+                    id: req.id,
+                };
+                writer
+                    .write_message(&res.into())
+                    .await
+                    .context("writing response")?;
+            }
+            Message::Request(_) => held.push(message),
+            Message::Notification(notif) => match notif.method.as_str() {
+                "$/progress" => {
+                    let token = notif.params["token"].to_string();
+                    match notif.params["value"]["kind"].as_str() {
+                        Some("begin") => {
+                            progress.insert(token);
+                        }
+                        Some("end") => {
+                            progress.remove(&token);
+                        }
+                        _ => {}
+                    }
+                }
+                "experimental/serverStatus" => {
+                    server_status = true;
+                    let quiescent = notif.params["quiescent"] == true;
+                    held.retain(|message| {
+                        !matches!(message, Message::Notification(notif) if notif.method == "experimental/serverStatus")
+                    });
+                    held.push(notif.into());
+                    if quiescent {
+                        return Ok(held);
+                    }
+                }
+                "textDocument/publishDiagnostics" => held.push(notif.into()),
+                _ => {}
+            },
+            Message::ResponseSuccess(_) | Message::ResponseError(_) => {}
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn initialize_handshake(
+    init_req_params: lsp::InitializeParams,
+    reader: &mut ServerReader,
+    writer: &mut ServerWriter,
+) -> Result<lsp::InitializeResult> {
+    let request_id = "lspmux:initialize_request";
+
+    // Use the first client's `InitializeParams` to initialize server. We assume
+    // all subsequent clients configuration will be somewhat compatible with
+    // whatever the first client negotiated for the same `workspace_root`,
+    // `server` and `args`.
+    let req = Request {
+        jsonrpc: Version,
+        method: "initialize".into(),
+        params: serde_json::to_value(init_req_params).unwrap(),
+        id: RequestId::String(request_id.into()),
+    };
+    writer
+        .write_message(&req.into())
+        .await
+        .context("send initialize request")?;
+
+    let res = match reader
+        .read_message()
+        .await
+        .context("receive initialize response")?
+        .context("stream ended")?
+    {
+        Message::ResponseSuccess(res) if res.id == request_id => res,
+        _ => bail!("first server message was not initialize response"),
+    };
+    let result = serde_json::from_value(res.result).context("parse initialize response result")?;
+
+    // Send a "fake" `initialized` notification to the server. We wait for them
+    // from each client's `initialized` notification ourselves and they don't
+    // contain any data that would need passing on.
+    let init_notif = Notification {
+        jsonrpc: Version,
+        method: "initialized".into(),
+        params: json!({}),
+    };
+    writer
+        .write_message(&init_notif.into())
+        .await
+        .context("send initialized notification")?;
+
+    Ok(result)
+}
+
+/// Check whether an error response means the server itself is in trouble
+///
+/// rust-analyzer responds with `InternalError` when a request handler panicked
+/// and reports the panic in the message.
+fn is_server_error(error: &jsonrpc::Error) -> bool {
+    // InternalError
+    error.code == -32603 || error.message.contains("panicked")
+}
+
+/// Read errors from langauge server stderr and log them
+async fn stderr_task(stderr: ChildStderr, crashes: Option<Arc<CrashRecorder>>) {
+    let mut stderr = BufReader::new(stderr);
+    let mut buffer = String::new();
+
+    loop {
+        buffer.clear();
+        match stderr.read_line(&mut buffer).await {
+            Ok(0) => {
+                // reached EOF
+                debug!("stderr closed");
+                break;
+            }
+            Ok(_) => {
+                let line = buffer.trim_end(); // remove trailing '\n' or possibly '\r\n'
+                error!(%line, "stderr");
+                if let Some(crashes) = &crashes {
+                    crashes.stderr(line);
+                }
+            }
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                error!(?err, "error reading from stderr");
+            }
+        }
+    }
+}
+
+/// Stdin of a started server for `stdin_task`
+struct Handoff {
+    writer: ServerWriter,
+
+    /// Documents of a restarted server, written before anything else
+    replay: Vec<Message>,
+
+    /// Notified once the messages queued for the previous server were
+    /// discarded, `None` for the first server
+    drained: Option<oneshot::Sender<()>>,
+}
+
+/// Receive messages from clients' channel and write them into language server stdin
+///
+/// Once stdin is closed or the `exit` notification is written the task waits
+/// for the stdin of a restarted server from `handoffs`. Client requests the
+/// previous server didn't get are written to the restarted one after its
+/// documents, document notifications and responses meant for the previous
+/// one are discarded. Client requests which were written are added to
+/// `written`.
+async fn stdin_task(
+    mut receiver: mpsc::Receiver<Message>,
+    mut handoffs: mpsc::Receiver<Handoff>,
+    written: Arc<std::sync::Mutex<HashSet<RequestId>>>,
+    traffic: Arc<TrafficStats>,
+    crashes: Option<Arc<CrashRecorder>>,
+    mirror: Option<Arc<Mirror>>,
+) {
+    // Messages which couldn't be written to the previous server
+    let mut unsent = VecDeque::new();
+    // Because we (stdin task) don't keep a reference to `self` it will be dropped when the
+    // child closes and all the clients disconnect including the sender and this receiver
+    // will not keep blocking (unlike in client input task)
+    let mut next = handoffs.recv().await;
+    'writer: while let Some(handoff) = next.take() {
+        let Handoff {
+            mut writer,
+            replay,
+            drained,
+        } = handoff;
+        if let Some(drained) = drained {
+            while let Ok(message) = receiver.try_recv() {
+                unsent.push_back(message);
+            }
+            unsent.retain(|message| !replaced_on_restart(message));
+            let _ = drained.send(());
+        }
+        let mut queued = replay
+            .into_iter()
+            .chain(unsent.drain(..))
+            .collect::<VecDeque<_>>();
+        let mut bytes = writer.bytes();
+        loop {
+            let message = match queued.pop_front() {
+                Some(message) => message,
+                None => select! {
+                    message = receiver.recv() => match message {
+                        Some(message) => message,
+                        None => break 'writer,
+                    },
+                    // The server was killed without getting `exit`.
+                    handoff = handoffs.recv() => {
+                        next = handoff;
+                        continue 'writer;
+                    }
+                },
+            };
+            if let Err(err) = writer.write_message(&message).await {
+                match err.kind() {
+                    // stdin is closed, no need to log an error
+                    ErrorKind::BrokenPipe => {}
+                    _ => {
+                        let err = anyhow::Error::from(err);
+                        error!(?err, "error writing to stdin");
+                    }
+                }
+                unsent.push_back(message);
+                unsent.extend(queued);
+                next = handoffs.recv().await;
+                continue 'writer;
+            }
+            if let Message::Request(req) = &message {
+                if let (Some(Tag::ClientId(_)), _) = req.id.untag() {
+                    written.lock().unwrap().insert(req.id.clone());
+                }
+            }
+            traffic.sent(&message, writer.bytes() - bytes);
+            bytes = writer.bytes();
+            if let Some(crashes) = &crashes {
+                crashes.sent(&message);
+            }
+            if let Some(mirror) = &mirror {
+                mirror.sent(&message);
+            }
+            if matches!(&message, Message::Notification(notif) if notif.method == "exit") {
+                unsent.extend(queued);
+                next = handoffs.recv().await;
+                continue 'writer;
+            }
+        }
+    }
+    debug!("stdin closed");
+}
+
+/// Check if a message queued for a server which was restarted is obsolete,
+/// the restarted server gets the documents replayed and never sent the
+/// requests clients respond to
+fn replaced_on_restart(message: &Message) -> bool {
+    match message {
+        Message::Request(req) => req.method == "shutdown",
+        Message::Notification(notif) => matches!(
+            notif.method.as_str(),
+            "textDocument/didOpen" | "textDocument/didChange" | "textDocument/didClose" | "exit"
+        ),
+        Message::ResponseSuccess(_) | Message::ResponseError(_) => true,
+    }
+}
+
+/// Wait for child and log when it exits
+///
+/// Restarting instances are started again and the new stdin is sent to
+/// `stdin_task` through `handoffs`.
+async fn wait_task(
+    instance: Arc<Instance>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    mut process: ServerProcess,
+    handoffs: mpsc::Sender<Handoff>,
+) {
+    let key = instance.key.clone();
+    // The child was killed by us, it didn't crash.
+    let mut killed = false;
+    loop {
+        select! {
+            _ = instance.close.notified() => {
+                killed = true;
+                if let Err(err) = process.start_kill() {
+                    error!(?err, "failed to close child");
+                }
+            }
+            exit = process.wait() => {
+                match &exit {
+                    Ok(Some(status)) => {
+                        #[cfg(unix)]
+                        let signal = std::os::unix::process::ExitStatusExt::signal(status);
+                        #[cfg(not(unix))]
+                        let signal = tracing::field::Empty;
+
+                        error!(
+                            success = status.success(),
+                            code = status.code(),
+                            signal,
+                            "child exited",
+                        );
+                    }
+                    Ok(None) => error!("adopted child exited"),
+                    Err(err) => error!(?err, "error waiting for child"),
+                }
+                instance.has_exited.store(true, Ordering::Relaxed);
+                instance.exited.notify_waiters();
+
+                let mut vars = instance.hook_vars();
+                if let Ok(Some(status)) = &exit {
+                    let code = status.code().map(|code| code.to_string());
+                    vars.push(("LSPMUX_EXIT_CODE", code.unwrap_or_default()));
+                    #[cfg(unix)]
+                    {
+                        let signal = std::os::unix::process::ExitStatusExt::signal(status);
+                        let signal = signal.map(|signal| signal.to_string());
+                        vars.push(("LSPMUX_SIGNAL", signal.unwrap_or_default()));
+                    }
+                }
+                hooks::run(&instance.config.hooks, Event::InstanceExit, &vars);
+                let status = exit.as_ref().ok().and_then(Option::as_ref);
+                instance.state.events.publish(ext::Event {
+                    exit_code: status.and_then(|status| status.code()),
+                    ..events::instance(EventKind::InstanceExited, &instance)
+                });
+                if let Some(status) = status.filter(|status| !status.success() && !killed) {
+                    instance.state.stats.crashed();
+                    if let Some(path) = instance.save_crash_report(status).await {
+                        vars.push(("LSPMUX_CRASH_REPORT", path.display().to_string()));
+                        instance_map.lock().await.add_crash_report(path);
+                    }
+                    hooks::run(&instance.config.hooks, Event::InstanceCrash, &vars);
+                    instance.state.events.publish(ext::Event {
+                        exit_code: status.code(),
+                        ..events::instance(EventKind::InstanceCrashed, &instance)
+                    });
+                }
+                killed = false;
+
+                if instance.restarting.swap(false, Ordering::Relaxed) {
+                    match restart(&instance, &handoffs).await {
+                        Ok(new_process) => {
+                            instance.has_exited.store(false, Ordering::Relaxed);
+                            process = new_process;
+                            continue;
+                        }
+                        Err(err) => error!(?err, "error restarting instance"),
+                    }
+                }
+                if let Some(mut standby) = instance.standby.lock().await.take() {
+                    let _ = standby.server.process.start_kill();
+                }
+
+                // Remove the closing instance from the map so new clients spawn their own instance,
+                // an evicted one was replaced already
+                let mut map = instance_map.lock().await;
+                if map
+                    .instances
+                    .get(&key)
+                    .is_some_and(|other| Arc::ptr_eq(other, &instance))
+                {
+                    map.instances.remove(&key);
+                }
+                drop(map);
+
+                // Requests merged with the responses of other instances would
+                // wait for this one forever.
+                let mut clients = instance.clients.lock().await;
+                instance.fail_requests(&mut clients, "language server exited").await;
+                drop(clients);
+
+                // Disconnect all current clients
+                //
+                // We'll rely on the editor client to restart the ra-multiplex client,
+                // start a new connection and we'll spawn another instance like we'd with
+                // any other new client.
+                instance.clients.lock().await.clear();
+                break;
+            }
+        }
+    }
+}
+
+/// Document and line of a request with `params`, requests with the same one
+/// supersede each other
+///
+/// Completion or hover at another line is about different code, the earlier
+/// request may still be wanted.
+fn supersede_key(params: &Value) -> Option<(String, Option<u64>)> {
+    let uri = params.pointer("/textDocument/uri")?.as_str()?;
+    let line = params.pointer("/position/line").and_then(Value::as_u64);
+    Some((uri.to_owned(), line))
+}
+
+/// Delay for merging `textDocument/didChange` notifications configured for
+/// `server` by its path or file name
+fn did_change_debounce(config: &Config, server: &str) -> Option<Duration> {
+    let name = Path::new(server).file_name().and_then(|name| name.to_str());
+    let millis = config
+        .did_change_debounce
+        .get(server)
+        .or_else(|| config.did_change_debounce.get(name?))?;
+    Some(Duration::from_millis(millis.get().into()))
+}
+
+/// Change replacing the whole document with the content it's opened with
+fn replace_content(params: &lsp::DidOpenTextDocumentParams) -> lsp::DidChangeTextDocumentParams {
+    lsp::DidChangeTextDocumentParams {
+        text_document: lsp::VersionedTextDocumentIdentifier {
+            uri: params.text_document.uri.clone(),
+            version: params.text_document.version,
+        },
+        content_changes: vec![lsp::TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: params.text_document.text.clone(),
+        }],
+    }
+}
+
+fn did_change(params: lsp::DidChangeTextDocumentParams) -> Notification {
+    Notification {
+        jsonrpc: Version,
+        method: "textDocument/didChange".into(),
+        params: serde_json::to_value(params).unwrap(),
+    }
+}
+
+/// Periodically look for client requests the server didn't respond to for
+/// longer than the timeout of their method and report them
+///
+/// `min_timeout` is the shortest timeout of any method.
+async fn watchdog_task(instance: Weak<Instance>, min_timeout: Duration) {
+    let period = (min_timeout / 4).max(Duration::from_secs(1));
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(instance) = instance.upgrade() else {
+            break;
+        };
+        let mut clients = instance.clients.lock().await;
+        for client in clients.values_mut() {
+            let client_id = client.id();
+            for (id, pending) in &mut client.requests {
+                if pending.reported {
+                    continue;
+                }
+                let elapsed = pending.sent.elapsed();
+                let timeout = instance.config.request_timeout(&pending.method);
+                if timeout.is_none_or(|timeout| elapsed < timeout) {
+                    continue;
+                }
+                pending.reported = true;
+                warn!(
+                    client_id,
+                    ?id,
+                    method = pending.method,
+                    seconds = elapsed.as_secs(),
+                    "request is stuck",
+                );
+                if instance.config.stuck_request_notify && !client.attached {
+                    let notif = Notification {
+                        jsonrpc: Version,
+                        method: "window/showMessage".into(),
+                        params: json!({
+                            // Warning
+                            "type": 2,
+                            "message": format!(
+                                "{} (pid {}) didn't respond to {} for {}s",
+                                instance.key.server,
+                                instance.pid(),
+                                pending.method,
+                                elapsed.as_secs(),
+                            ),
+                        }),
+                    };
+                    let _ = client.client.send_message(notif.into()).await;
+                }
+            }
+        }
+    }
+}
+
+/// Send file changes seen by the daemon's file watcher to the server
+///
+/// Events are collected for [`WATCH_FILES_DELAY`] so changes touching many
+/// files are sent in one notification.
+async fn watch_files_task(
+    instance: Weak<Instance>,
+    mut events: mpsc::UnboundedReceiver<Vec<lsp::FileEvent>>,
+) {
+    while let Some(mut batch) = events.recv().await {
+        tokio::time::sleep(WATCH_FILES_DELAY).await;
+        while let Ok(more) = events.try_recv() {
+            batch.extend(more);
+        }
+        let Some(instance) = instance.upgrade() else {
+            break;
+        };
+        let params = lsp::DidChangeWatchedFilesParams {
+            changes: watcher::coalesce(batch),
+        };
+        trace!(changes = params.changes.len(), "watched files changed");
+        let notif = Notification {
+            jsonrpc: Version,
+            method: watcher::DID_CHANGE_WATCHED_FILES.into(),
+            params: serde_json::to_value(params).unwrap(),
+        };
+        if instance.send_message(notif.into()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Periodically ask rust-analyzer for its memory usage breakdown
+async fn memory_usage_task(instance: Weak<Instance>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(instance) = instance.upgrade() else {
+            break;
+        };
+        let request = instance.internal_request("rust-analyzer/memoryUsage", Value::Null);
+        let result = match tokio::time::timeout(interval.period(), request).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => {
+                debug!(?err, "error requesting memory usage");
+                continue;
+            }
+            Err(_) => {
+                debug!("memory usage request timed out");
+                continue;
+            }
+        };
+        let Some(text) = result.as_str() else {
+            debug!(?result, "unexpected memory usage result");
+            continue;
+        };
+        *instance.memory_usage.lock().await = Some(parse_memory_usage(text));
+    }
+}
+
+/// Parse the text returned by `rust-analyzer/memoryUsage`
+///
+/// Every line starts with a size optionally followed by the number of entries
+/// and ends with the name of what uses the memory.
+fn parse_memory_usage(text: &str) -> Vec<ext::MemoryUsage> {
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace().peekable();
+            let size = words.next()?.to_owned();
+            words.next_if(|word| word.chars().all(|c| c.is_ascii_digit()));
+            let name = words.collect::<Vec<_>>().join(" ");
+            Some(ext::MemoryUsage { name, size })
+        })
+        .collect()
+}
+
+/// Resident set size of a process in bytes
+#[cfg(target_os = "linux")]
+fn process_rss(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_rss(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Read messages from server stdout and send them to corresponding client channels
+async fn stdout_task(instance: Arc<Instance>, mut reader: ServerReader, held: Vec<Message>) {
+    let mut held = held.into_iter();
+    let mut bytes = reader.bytes();
+    let mut budget = Budget::default();
+    loop {
+        // Messages held back by `wait_indexed` go first.
+        let message = match held.next() {
+            Some(message) => message,
+            None => match reader.read_message().await {
+                Ok(Some(message)) => {
+                    let size = reader.bytes() - bytes;
+                    bytes = reader.bytes();
+                    budget.spend(size).await;
+                    instance.traffic.received(&message, size);
+                    if let Some(mirror) = &instance.mirror {
+                        mirror.received(&message);
+                    }
+                    message
+                }
+                Ok(None) => {
+                    debug!("stdout closed");
+                    break;
+                }
+                Err(err) => {
+                    error!(?err, "reading message");
+                    continue;
+                }
+            },
+        };
+
+        if let Message::Request(req) = &message {
+            if req.method == "workspace/configuration" {
+                if let Some(mut result) = instance.configuration_from_file(&req.params).await {
+                    debug!(req = ?Logged(req, &instance.state.redaction), "answering workspace/configuration from settings file");
+                    instance
+                        .overlay_client_settings(&req.params, &mut result)
+                        .await;
+                    let res = ResponseSuccess {
+                        jsonrpc: Version,
+                        result,
+                        id: req.id.clone(),
+                    };
+                    let _ = instance.send_message(res.into()).await;
+                    continue;
+                }
+            }
+        }
+
+        // Lock _after_ we have a message to send, then send and immediately release the lock
+        let mut clients = instance.clients.lock().await;
+        // Messages to clients count as activity, ones nobody receives don't
+        // keep an abandoned instance alive.
+        if !clients.is_empty() {
+            instance.keep_alive();
+        }
+        match message {
+            Message::ResponseSuccess(mut res) => {
+                instance.finish_request(&mut clients, &res.id).await;
+                instance.count_response(&clients, None).await;
+
+                // Forward successful response to the right client based on the
+                // Request ID tag.
+                match res.id.untag() {
+                    (Some(Tag::ClientId(client_id)), id) => {
+                        res.id = id;
+                        if let Some(client) = clients.get(&client_id) {
+                            let _ = client.send_message(res.into()).await;
+                        } else {
+                            let id = res.id.clone();
+                            instance
+                                .adopt_response(&clients, client_id, id, res.into())
+                                .await;
+                        }
+                    }
+                    (Some(Tag::Drop), _) => {
+                        // Drop the message
+                    }
+                    (Some(Tag::Internal), _) => {
+                        if let Some(tx) = instance.internal_requests.lock().await.remove(&res.id) {
+                            let _ = tx.send(Ok(res.result));
+                        }
+                    }
+                    _ => {
+                        warn!(res = ?Logged(&res, &instance.state.redaction), "ignoring improperly tagged server response")
+                    }
+                }
+            }
+
+            Message::ResponseError(mut res) => {
+                instance.finish_request(&mut clients, &res.id).await;
+                instance.count_response(&clients, Some(&res.error)).await;
+
+                // Forward the error response to the right client based on the
+                // Request ID tag.
+                match res.id.untag() {
+                    (Some(Tag::ClientId(client_id)), id) => {
+                        warn!(res = ?Logged(&res, &instance.state.redaction), "server responded with error");
+                        res.id = id;
+                        if let Some(client) = clients.get(&client_id) {
+                            let _ = client.send_message(res.into()).await;
+                        } else {
+                            let id = res.id.clone();
+                            instance
+                                .adopt_response(&clients, client_id, id, res.into())
+                                .await;
+                        }
+                    }
+                    (Some(Tag::Drop), _) => {
+                        // Drop the message
+                    }
+                    (Some(Tag::Internal), _) => {
+                        if let Some(tx) = instance.internal_requests.lock().await.remove(&res.id) {
+                            let _ = tx.send(Err(res.error));
+                        }
+                    }
+                    _ => {
+                        warn!(res = ?Logged(&res, &instance.state.redaction), "ignoring improperly tagged server response")
+                    }
+                }
+            }
+
+            Message::Request(mut req) if req.method == "client/registerCapability" => {
+                // These need to be forwarded to every client so they're aware
+                // of the capability. The response doesn't contain anything
+                // important so we can safely ignore the real answers and send a
+                // fake one to the server.
+                debug!(req = ?Logged(&req, &instance.state.redaction), "server request client/registerCapability");
+
+                let id = req.id;
+                req.id = id.tag(Tag::Drop);
+
+                if instance.take_file_watchers(&mut req.params) && !instance.is_secondary() {
+                    for client in clients.values() {
+                        let _ = client.send_message(req.clone().into()).await;
+                    }
+                }
+
+                // We need to cache the dynamic capabilities registrations for
+                // any client that might come later.
+                if let Err(err) = instance.register_capabilities(req.params).await {
+                    warn!(?err, "error registering capabilities");
+                }
+
+                let _ = instance
+                    .send_message(ResponseSuccess::null(id).into())
+                    .await;
+            }
+
+            Message::Request(mut req) if req.method == "client/unregisterCapability" => {
+                // These need to be forwarded to every client so they're aware
+                // of the capability not being available anymore. The response
+                // doesn't contain anything important so we can safely ignore
+                // the real answers and send a fake one to the server.
+                debug!(req = ?Logged(&req, &instance.state.redaction), "server request client/unregisterCapability");
+
+                let id = req.id;
+                req.id = id.tag(Tag::Drop);
+
+                if instance.release_file_watchers(&mut req.params) && !instance.is_secondary() {
+                    for client in clients.values() {
+                        let _ = client.send_message(req.clone().into()).await;
+                    }
+                }
+
+                // We need to remove this registration from the cache so we
+                // don't announce it to new clients anymore.
+                if let Err(err) = instance.unregister_capabilities(req.params).await {
+                    warn!(?err, "error unregistering capabilities");
+                }
+
+                let _ = instance
+                    .send_message(ResponseSuccess::null(id).into())
+                    .await;
+            }
+
+            Message::Request(req) if req.method == "workspace/applyEdit" => {
+                debug!(req = ?Logged(&req, &instance.state.redaction), "server request workspace/applyEdit");
+                instance.apply_edit(&clients, req).await;
+            }
+
+            Message::Request(req) if req.method == "window/showDocument" => {
+                debug!(req = ?Logged(&req, &instance.state.redaction), "server request window/showDocument");
+                instance.show_document(&clients, req).await;
+            }
+
+            Message::Request(req)
+                if instance.is_secondary()
+                    && instance.route(&req.method, true) == Route::Broadcast =>
+            {
+                // Clients already get the same requests from the primary.
+                trace!(req = ?Logged(&req, &instance.state.redaction), "answering secondary instance request {}", req.method);
+                let _ = instance
+                    .send_message(ResponseSuccess::null(req.id).into())
+                    .await;
+            }
+
+            Message::Notification(notif) if instance.is_secondary() => {
+                trace!(
+                    notif = ?Logged(&notif, &instance.state.redaction),
+                    "dropping secondary instance notification {}",
+                    notif.method
+                );
+            }
+
+            Message::Request(mut req) => match instance.route(&req.method, true) {
+                Route::Broadcast => {
+                    // Inform all clients about the request, the requests
+                    // broadcast this way all have null responses so the server
+                    // gets one once every client answered.
+                    trace!(req = ?Logged(&req, &instance.state.redaction), "broadcasting server request {}", req.method);
+                    instance.broadcast_request(&clients, req).await;
+                }
+                route @ (Route::Originator | Route::FirstClient) => {
+                    // Let a single client answer the request and forward its
+                    // response to the server.
+                    debug!(req = ?Logged(&req, &instance.state.redaction), ?route, "forwarding server request {}", req.method);
+
+                    if req.method == "workspace/configuration" {
+                        let mut requests = instance.configuration_requests.lock().await;
+                        requests.insert(req.id.clone(), req.params.clone());
+                    }
+                    req.id = req.id.tag(Tag::Forward(instance.pid()));
+
+                    if let Some(client_id) = instance.target_clients(&clients, route).first() {
+                        let _ = clients[client_id].send_message(req.into()).await;
+                    } else {
+                        // If there is no client connected at this moment we'll
+                        // ignore the request.
+                    }
+                }
+                Route::Drop => {
+                    debug!(message = ?Logged(&req, &instance.state.redaction), "ignoring server request");
+                }
+            },
+
+            Message::Notification(notif) => {
+                // Server notifications don't expect a response, we only need to
+                // pick the clients receiving them.
+                let route = instance.route(&notif.method, false);
+                let targets = instance.target_clients(&clients, route);
+
+                // rust-analyzer republishes the same diagnostics for every
+                // cargo check run, skip forwarding them to clients which
+                // already have them.
+                let diagnostics = match notif.method.as_str() {
+                    "textDocument/publishDiagnostics" => diagnostics_hash(&notif.params),
+                    _ => None,
+                };
+
+                if notif.method == "$/progress" {
+                    instance.track_progress(&notif.params).await;
+                }
+
+                // Remember the status for clients connecting later.
+                let server_status = notif.method == "experimental/serverStatus";
+                if server_status {
+                    *instance.server_status.lock().await = Some(notif.clone());
+                }
+
+                for client_id in targets {
+                    let client = clients.get_mut(&client_id).unwrap();
+                    if server_status {
+                        let notif = notif.clone();
+                        let _ = client.publish_server_status(instance.pid(), notif).await;
+                        continue;
+                    }
+                    if let Some((uri, hash)) = &diagnostics {
+                        if !client.update_diagnostics(uri, *hash) {
+                            trace!(?uri, client_id, "skipping duplicate diagnostics");
+                            continue;
+                        }
+                        let notif = notif.clone();
+                        let _ = client.publish_diagnostics(instance.pid(), notif).await;
+                        continue;
+                    }
+                    let _ = client.send_message(notif.clone().into()).await;
+                }
+            }
+        }
+    }
+}
+
+/// Read a `settings_file`, a JSON file or a TOML file if its name ends with
+/// `.toml`
+async fn read_settings(path: &Path) -> Result<Value> {
+    let text = tokio::fs::read_to_string(path).await?;
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        return toml::from_str(&text).context("parsing TOML");
+    }
+    serde_json::from_str(&text).context("parsing JSON")
+}
+
+/// Result of a `workspace/configuration` request with `params` answered from
+/// `settings`
+///
+/// Every item gets the value at its dotted `section` path, all settings if it
+/// has no section or `null` if there's no such value.
+fn configuration_items(settings: &Value, params: &Value) -> Value {
+    let Some(items) = params.get("items").and_then(Value::as_array) else {
+        return json!([]);
+    };
+    items
+        .iter()
+        .map(|item| {
+            let section = item.get("section").and_then(Value::as_str);
+            section
+                .into_iter()
+                .flat_map(|section| section.split('.'))
+                .try_fold(settings, |value, key| value.get(key))
+                .cloned()
+                .unwrap_or(Value::Null)
+        })
+        .collect()
+}
+
+/// Find the client with the innermost workspace folder containing `scope`
+fn scope_owner<'a>(clients: impl Iterator<Item = &'a Client>, scope: &str) -> Option<&'a Client> {
+    clients
+        .flat_map(|client| {
+            let folders = client.workspace_folders().into_iter();
+            folders.map(move |folder| (client, folder.uri))
+        })
+        .filter(|(_, folder)| {
+            let folder = folder.trim_end_matches('/');
+            scope
+                .strip_prefix(folder)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(_, folder)| folder.trim_end_matches('/').len())
+        .map(|(client, _)| client)
+}
+
+/// Part of a client's `settings` for the `section` of a
+/// `workspace/configuration` item, they're the settings of `client_section`
+fn settings_overlay(
+    settings: &Map<String, Value>,
+    client_section: &str,
+    section: Option<&str>,
+) -> Option<Value> {
+    if settings.is_empty() {
+        return None;
+    }
+    let settings = Value::Object(settings.clone());
+    let Some(section) = section else {
+        // All settings, nest the client's under its section.
+        let overlay = client_section
+            .rsplit('.')
+            .fold(settings, |value, key| json!({ key: value }));
+        return Some(overlay);
+    };
+    if section == client_section {
+        return Some(settings);
+    }
+    let path = section.strip_prefix(client_section)?.strip_prefix('.')?;
+    path.split('.')
+        .try_fold(&settings, |value, key| value.get(key))
+        .cloned()
+}
+
+/// Built-in routing for server requests and notifications without an entry in
+/// the `routing` config
+fn default_route(method: &str, is_request: bool) -> Route {
+    match method {
+        // All these server requests have null responses and we need to inform
+        // all clients.
+        "window/workDoneProgress/create"
+        | "workspace/codeLens/refresh"
+        | "workspace/semanticTokens/refresh"
+        | "workspace/inlayHint/refresh"
+        | "workspace/inlineValue/refresh"
+        | "workspace/diagnostic/refresh" => Route::Broadcast,
+
+        // Response to `workspace/configuration` should be the same from any
+        // client. So we'll just pick the first and let it answer.
+        "workspace/configuration" => Route::FirstClient,
+
+        // Server notifications can go to all clients.
+        _ if !is_request => Route::Broadcast,
+
+        // Unimplemented server -> client requests I've found in the LSP Spec.
+        // TODO workspace/workspaceFolders request
+        _ => Route::Drop,
+    }
+}
+
+/// Hash `textDocument/publishDiagnostics` params, returns the URI they belong
+/// to and the hash
+fn diagnostics_hash(params: &Value) -> Option<(String, u64)> {
+    let Some(uri) = params.get("uri").and_then(Value::as_str) else {
+        warn!(?params, "publishDiagnostics without uri");
+        return None;
+    };
+    let mut hasher = DefaultHasher::new();
+    params.to_string().hash(&mut hasher);
+    Some((uri.to_owned(), hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_error_message() {
+        let error = |kind: ErrorKind| SpawnError {
+            program: "rust-analyzer".into(),
+            path: "/usr/bin".into(),
+            source: io::Error::from(kind),
+        };
+        assert_eq!(
+            error(ErrorKind::NotFound).to_string(),
+            r#"`rust-analyzer` not found, PATH is "/usr/bin""#,
+        );
+        assert_eq!(
+            error(ErrorKind::PermissionDenied).to_string(),
+            r#"permission denied executing `rust-analyzer`, PATH is "/usr/bin""#,
+        );
+    }
+
+    #[test]
+    fn expand_server_placeholders() {
+        let placeholders = [("{workspace}", "/src/a"), ("{user}", "me")];
+        assert_eq!(
+            expand_placeholders("--target-dir={workspace}/.ra-target", &placeholders),
+            "--target-dir=/src/a/.ra-target",
+        );
+        assert_eq!(
+            expand_placeholders("{user}{{workspace}}{unknown}", &placeholders),
+            "me{/src/a}{unknown}",
+        );
+    }
+
+    #[test]
+    fn stale_document_changes() {
+        let open = serde_json::from_value(json!({
+            "textDocument": { "uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "" },
+        }))
+        .unwrap();
+        let change = |version: u64, range: Option<Value>| {
+            serde_json::from_value::<lsp::DidChangeTextDocumentParams>(json!({
+                "textDocument": { "uri": "file:///a.rs", "version": version },
+                "contentChanges": [{ "range": range, "text": "x" }],
+            }))
+            .unwrap()
+        };
+        let range = || {
+            Some(
+                json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } }),
+            )
+        };
+
+        let mut document = Document::new(1, open);
+        document.sync(2);
+        assert!(!document.is_stale(1, &change(2, range())));
+        document.change(1, change(2, range()));
+        assert!(!document.is_stale(1, &change(3, range())));
+        // Client 2 didn't see the change by client 1.
+        assert!(document.is_stale(2, &change(2, range())));
+        // Replacing the whole document doesn't depend on earlier changes.
+        assert!(!document.is_stale(2, &change(2, None)));
+        document.change(2, change(2, None));
+        assert!(document.is_stale(1, &change(3, range())));
+    }
+
+    #[test]
+    fn apply_edit_answers() {
+        let result = |applied: bool| lsp::ApplyWorkspaceEditResult {
+            applied,
+            failure_reason: None,
+            failed_change: None,
+        };
+        let pending = || PendingEdit {
+            clients: HashSet::from([1, 2]),
+            failures: Vec::new(),
+        };
+
+        // The first client applying the edit answers for all.
+        let mut edit = pending();
+        assert!(edit.answer(1, Err("disconnected".into())).is_none());
+        assert!(edit.answer(2, Ok(result(true))).unwrap().applied);
+
+        // Failures are combined once all clients answered.
+        let mut edit = pending();
+        assert!(edit.answer(1, Ok(result(false))).is_none());
+        assert!(edit.answer(1, Ok(result(true))).is_none());
+        let response = edit.answer(2, Err("disconnected".into())).unwrap();
+        assert!(!response.applied);
+        assert_eq!(
+            response.failure_reason.unwrap(),
+            "client 1 didn't apply the edit; disconnected"
+        );
+    }
+
+    #[test]
+    fn server_errors() {
+        let error = |code, message: &str| jsonrpc::Error {
+            code,
+            message: message.into(),
+            data: None,
+        };
+        assert!(is_server_error(&error(-32603, "internal error")));
+        assert!(is_server_error(&error(
+            -32600,
+            "request handler panicked: index out of bounds",
+        )));
+        // Cancelled requests are not the server's fault.
+        assert!(!is_server_error(&error(-32800, "canceled by client")));
+        assert!(!is_server_error(&error(-32801, "content modified")));
+    }
+
+    #[test]
+    fn diverging_document_content() {
+        let open = serde_json::from_value(json!({
+            "textDocument": { "uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "a" },
+        }))
+        .unwrap();
+        let change = |range: Option<Value>| {
+            serde_json::from_value::<lsp::DidChangeTextDocumentParams>(json!({
+                "textDocument": { "uri": "file:///a.rs", "version": 2 },
+                "contentChanges": [{ "range": range, "text": "b" }],
+            }))
+            .unwrap()
+        };
+
+        let mut document = Document::new(1, open);
+        assert!(!document.diverges("a"));
+        assert!(document.diverges("b"));
+        document.change(1, change(None));
+        assert!(!document.diverges("b"));
+        // The content after incremental changes isn't known.
+        let range =
+            json!({ "start": { "line": 0, "character": 1 }, "end": { "line": 0, "character": 1 } });
+        document.change(1, change(Some(range)));
+        assert!(document.diverges("bb"));
+    }
+
+    #[test]
+    fn parse_rust_analyzer_memory_usage() {
+        let text = "\
+            1.2gb 12 (total)\n\
+            512mb 3 ExpandProcMacroQuery (deps)\n\
+            10kb    Remaining\n";
+        let usage = parse_memory_usage(text);
+        let usage = usage
+            .iter()
+            .map(|usage| (usage.size.as_str(), usage.name.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            usage,
+            [
+                ("1.2gb", "(total)"),
+                ("512mb", "ExpandProcMacroQuery (deps)"),
+                ("10kb", "Remaining"),
+            ],
+        );
+    }
+
+    #[test]
+    fn answer_configuration_from_settings() {
+        let settings = json!({
+            "rust-analyzer": { "cargo": { "targetDir": "target/ra" } },
+            "editor.tabSize": 4,
+        });
+        let params = json!({
+            "items": [
+                { "section": "rust-analyzer" },
+                { "scopeUri": "file:///a", "section": "rust-analyzer.cargo.targetDir" },
+                { "section": "rust-analyzer.missing" },
+                {},
+            ],
+        });
+        assert_eq!(
+            configuration_items(&settings, &params),
+            json!([
+                { "cargo": { "targetDir": "target/ra" } },
+                "target/ra",
+                null,
+                settings,
+            ]),
+        );
+        assert_eq!(configuration_items(&settings, &json!({})), json!([]));
+    }
+
+    #[test]
+    fn select_client_settings_overlay() {
+        let Value::Object(settings) = json!({ "cargo": { "targetDir": "target/ra" } }) else {
+            unreachable!();
+        };
+        let overlay = |section| settings_overlay(&settings, "rust-analyzer", section);
+        assert_eq!(
+            overlay(None),
+            Some(json!({ "rust-analyzer": { "cargo": { "targetDir": "target/ra" } } })),
+        );
+        assert_eq!(
+            overlay(Some("rust-analyzer")),
+            Some(Value::Object(settings.clone()))
+        );
+        assert_eq!(
+            overlay(Some("rust-analyzer.cargo.targetDir")),
+            Some(json!("target/ra")),
+        );
+        assert_eq!(overlay(Some("rust-analyzer.missing")), None);
+        assert_eq!(overlay(Some("rust-analyzer-extra")), None);
+        assert_eq!(overlay(Some("editor")), None);
+        assert_eq!(
+            settings_overlay(&settings, "lang.rust", None),
+            Some(json!({ "lang": { "rust": { "cargo": { "targetDir": "target/ra" } } } })),
+        );
+        assert_eq!(settings_overlay(&Map::new(), "rust-analyzer", None), None);
+    }
+
+    #[test]
+    fn progress_summary() {
+        let mut progress = ActiveProgress {
+            token: json!("index"),
+            begin: json!({ "kind": "begin", "title": "Indexing", "percentage": 10 }),
+            report: None,
+        };
+        let summary = |progress: &ActiveProgress| {
+            let summary = progress.summary();
+            (summary.title, summary.percentage)
+        };
+        assert_eq!(summary(&progress), ("Indexing".into(), Some(10)));
+        progress.report = Some(json!({ "kind": "report", "message": "1/3" }));
+        assert_eq!(summary(&progress), ("Indexing".into(), Some(10)));
+        progress.report = Some(json!({ "kind": "report", "percentage": 42 }));
+        assert_eq!(summary(&progress), ("Indexing".into(), Some(42)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn spawn_arguments_verbatim() {
+        let key = |args: &[&str]| InstanceKey {
+            server: "printf".into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: BTreeMap::from([("RA_MUX_TEST".into(), "expanded".into())]),
+            workspace_root: "/".into(),
+            role: InstanceRole::Primary,
+            group: None,
+            tag: None,
+        };
+        let output = |key: InstanceKey, shell: bool| async move {
+            let output = key.command(&[], &[], shell).output().await.unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let args = [
+            "%s|",
+            "with space",
+            r#"quote " and \ backslash"#,
+            "'single'",
+            "$RA_MUX_TEST",
+            "ünïcødé ✓",
+            "",
+        ];
+        assert_eq!(
+            output(key(&args), false).await,
+            r#"with space|quote " and \ backslash|'single'|$RA_MUX_TEST|ünïcødé ✓||"#,
+        );
+        assert_eq!(
+            output(key(&["'%s|'", "\"$RA_MUX_TEST\"", "'a b'"]), true).await,
+            "expanded|a b|",
+        );
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn spawn_batch_file_arguments_escaped() {
+        let dir = env::temp_dir().join(format!("ra-mux-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = dir.join("server.cmd");
+        std::fs::write(&server, "@echo off\r\necho arg=%1\r\n").unwrap();
+        let key = InstanceKey {
+            server: server.display().to_string(),
+            args: vec!["a & echo injected".into()],
+            env: BTreeMap::new(),
+            workspace_root: dir.display().to_string(),
+            role: InstanceRole::Primary,
+            group: None,
+            tag: None,
+        };
+
+        let output = key.command(&[], &[], false).output().await.unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines = stdout.lines().map(str::trim).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1, "{stdout}");
+        assert!(lines[0].starts_with("arg="), "{stdout}");
+        assert!(lines[0].contains("a & echo injected"), "{stdout}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hold_messages_until_indexed() {
+        let (stdout, server_stdout) = tokio::io::duplex(64 * 1024);
+        let (stdin, _server_stdin) = tokio::io::duplex(64 * 1024);
+        let (mut reader, mut writer) = server_io(stdout, stdin);
+        let mut server = LspWriter::new(server_stdout, "test");
+
+        let notif = |method: &str, params: Value| -> Message {
+            Notification {
+                jsonrpc: Version,
+                method: method.into(),
+                params,
+            }
+            .into()
+        };
+        let request = |id: i64, method: &str| -> Message {
+            Request {
+                jsonrpc: Version,
+                method: method.into(),
+                params: Value::Null,
+                id: RequestId::Number(id),
+            }
+            .into()
+        };
+        let messages = [
+            request(1, "window/workDoneProgress/create"),
+            notif(
+                "$/progress",
+                json!({ "token": "index", "value": { "kind": "begin" } }),
+            ),
+            notif("experimental/serverStatus", json!({ "quiescent": false })),
+            request(2, "client/registerCapability"),
+            request(3, "workspace/configuration"),
+            notif(
+                "window/logMessage",
+                json!({ "type": 3, "message": "indexing" }),
+            ),
+            notif(
+                "textDocument/publishDiagnostics",
+                json!({ "uri": "file:///a.rs" }),
+            ),
+            notif(
+                "$/progress",
+                json!({ "token": "index", "value": { "kind": "end" } }),
+            ),
+            notif("experimental/serverStatus", json!({ "quiescent": true })),
+            notif(
+                "window/logMessage",
+                json!({ "type": 3, "message": "indexed" }),
+            ),
+        ];
+        for message in &messages {
+            server.write_message(message).await.unwrap();
+        }
+
+        let configuration = |_| async { json!([{ "checkOnSave": true }]) };
+        let held = wait_indexed(&mut reader, &mut writer, configuration)
+            .await
+            .unwrap();
+        let held = held
+            .iter()
+            .map(|message| match message {
+                Message::Request(req) => req.method.as_str(),
+                Message::Notification(notif) => notif.method.as_str(),
+                _ => panic!("unexpected message {message:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            held,
+            [
+                "client/registerCapability",
+                "textDocument/publishDiagnostics",
+                "experimental/serverStatus",
+            ],
+        );
+        // The rest is left for `stdout_task`.
+        let next = reader.read_message().await.unwrap().unwrap();
+        assert!(
+            matches!(next, Message::Notification(notif) if notif.method == "window/logMessage")
+        );
+    }
+
+    #[tokio::test]
+    async fn flooding_server_yields() {
+        // Diagnostics which are already buffered, reading them never waits.
+        let mut data = LspWriter::new(Vec::new(), "test");
+        let notif: Message = Notification {
+            jsonrpc: Version,
+            method: "textDocument/publishDiagnostics".into(),
+            params: json!({ "uri": "file:///a.rs", "diagnostics": [] }),
+        }
+        .into();
+        for _ in 0..1000 {
+            data.write_message(&notif).await.unwrap();
+        }
+        let data = data.get_mut().clone();
+
+        // The test runtime has a single thread, count the messages the flooded
+        // instance handles between the turns of a quiet one.
+        let handled = Arc::new(AtomicUsize::new(0));
+        let flood = task::spawn({
+            let handled = handled.clone();
+            async move {
+                let mut reader = LspReader::new(data.as_slice(), "test");
+                let mut budget = Budget::default();
+                let mut bytes = 0;
+                while let Some(_message) = reader.read_message().await.unwrap() {
+                    budget.spend(reader.bytes() - bytes).await;
+                    bytes = reader.bytes();
+                    handled.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+        let quiet = task::spawn(async move {
+            let mut gaps = Vec::new();
+            let mut last = 0;
+            while !flood.is_finished() {
+                task::yield_now().await;
+                let now = handled.load(Ordering::Relaxed);
+                gaps.push(now - last);
+                last = now;
+            }
+            gaps
+        });
+        let gaps = quiet.await.unwrap();
+        // Both tasks yield, depending on the order they're woken in the flood
+        // gets up to two budgets between the turns of the quiet one.
+        assert!(gaps.len() > 1000 / YIELD_MESSAGES, "{gaps:?}");
+        assert!(
+            gaps.iter().all(|&gap| gap <= 2 * YIELD_MESSAGES),
+            "{gaps:?}"
+        );
+    }
+
+    #[test]
+    fn evict_idle_instances() {
+        assert_eq!(eviction(301, 0, Some(300), None), Some("idle timeout"));
+        assert_eq!(eviction(300, 1000, Some(300), None), None);
+        assert_eq!(eviction(1000, 1000, None, None), None);
+        let keep_alive = Some(KeepAlive::Seconds(60));
+        assert_eq!(
+            eviction(0, 61, Some(300), keep_alive),
+            Some("keep alive expired")
+        );
+        assert_eq!(eviction(1000, 60, Some(300), keep_alive), None);
+        assert_eq!(
+            eviction(1000, 1000, Some(300), Some(KeepAlive::Never)),
+            None
+        );
+    }
+
+    #[test]
+    fn duplicate_instances() {
+        let key = |server: &str, workspace_root: &str| InstanceKey {
+            server: server.into(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            workspace_root: workspace_root.into(),
+            role: InstanceRole::Primary,
+            group: None,
+            tag: None,
+        };
+        let outer = key("rust-analyzer", "/work");
+        let inner = key("rust-analyzer", "/work/project");
+        let innermost = key("rust-analyzer", "/work/project/crate");
+        let sibling = key("rust-analyzer", "/workspace");
+        let other_server = key("clangd", "/work/project/c");
+        let replica = InstanceKey {
+            role: InstanceRole::Replica,
+            ..key("rust-analyzer", "/work/replica")
+        };
+        let instances = [
+            (&innermost, 3),
+            (&outer, 1),
+            (&inner, 2),
+            (&sibling, 4),
+            (&other_server, 5),
+            (&replica, 6),
+        ];
+        let duplicate = |pid, workspace_root: &str| ext::Duplicate {
+            pid,
+            workspace_root: workspace_root.into(),
+            into_pid: 1,
+            into_workspace_root: "/work".into(),
+        };
+        assert_eq!(
+            duplicates(&instances),
+            [
+                duplicate(2, "/work/project"),
+                duplicate(3, "/work/project/crate")
+            ]
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Language server instance sharing behind `ra-multiplex`
+//!
+//! The `ra-multiplex` binary is a thin command line around this crate. IDE
+//! backends and remote development agents can embed the multiplexer
+//! in-process instead of running the binary:
+//!
+//! - [`server::Server`] manages the language server instances and connects
+//!   clients in-process with [`Server::connect`](server::Server::connect) or
+//!   over the `listen` address with [`Server::listen`](server::Server::listen)
+//! - [`config::Config`] holds the settings, usually loaded from the config
+//!   files with [`Config::try_load`](config::Config::try_load), [`paths`]
+//!   says where they and the state are kept
+//! - [`proxy`] connects a client speaking LSP on stdio to a server over a
+//!   socket, [`ext`] sends the server commands like `status` and `restart`
+//!   defined in [`lsp::ext`]
+//! - [`channel::Multiplexer`] carries many client sessions over one
+//!   connection to the server
+//! - [`stats`], [`download`] and [`resolve`] expose what the server records
+//!   and looks up for the command line to report
+
+mod alias;
+mod audit;
+mod cargo;
+mod client;
+mod crash;
+mod events;
+mod git;
+mod hooks;
+mod instance;
+mod merge;
+mod mirror;
+mod queue;
+mod quic;
+mod ratelimit;
+mod resume;
+mod scheduling;
+mod socketwrapper;
+mod state;
+mod toolchain;
+mod traffic;
+mod watcher;
+
+pub mod channel;
+pub mod chaos;
+pub mod config;
+pub mod download;
+pub mod ext;
+pub mod log_filter;
+pub mod lsp;
+pub mod paths;
+pub mod proxy;
+pub mod resolve;
+pub mod server;
+#[cfg(unix)]
+pub mod shim;
+pub mod stats;
+
+/// Name of the config, data and cache directories
+const APP_NAME: &str = "ra-multiplex";
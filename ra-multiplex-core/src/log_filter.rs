@@ -0,0 +1,158 @@
+//! Changing the log filter of a running server
+//!
+//! `ra-multiplex log-level` replaces the filter set by `log_filters` or
+//! `RUST_LOG` to trace a problem without restarting the server and losing the
+//! state that reproduces it. A change made with a duration reverts to the
+//! previous filter once it's over, unless another change replaced it first.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::{task, time};
+use tracing::info;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::{reload, Registry};
+
+use crate::lsp::ext;
+
+type Handle = reload::Handle<EnvFilter, Registry>;
+
+struct State {
+    /// Filter the logger was set up with
+    original: String,
+
+    /// Filter in effect
+    current: String,
+
+    /// Number of changes so far, a pending revert only applies if there was
+    /// no change after the one it belongs to
+    generation: u64,
+}
+
+/// Filter of the logger changed at runtime
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: Handle,
+    state: Arc<Mutex<State>>,
+}
+
+impl LogFilter {
+    /// Make `filter` the one changed at runtime, returns the layer to install
+    /// it with
+    pub fn new(filter: EnvFilter) -> (LogFilter, reload::Layer<EnvFilter, Registry>) {
+        let current = filter.to_string();
+        let (layer, handle) = reload::Layer::new(filter);
+        let state = State {
+            original: current.clone(),
+            current,
+            generation: 0,
+        };
+        let log_filter = LogFilter {
+            handle,
+            state: Arc::new(Mutex::new(state)),
+        };
+        (log_filter, layer)
+    }
+
+    /// Filter in effect, the one it replaced and the one the logger was set
+    /// up with
+    pub fn get(&self) -> ext::LogFilter {
+        let state = self.state.lock().unwrap();
+        ext::LogFilter {
+            filter: state.current.clone(),
+            previous: state.current.clone(),
+            original: state.original.clone(),
+        }
+    }
+
+    /// Replace the filter in effect with `filter`, or the original one if
+    /// it's `None`, and revert to the replaced one after `revert_after`
+    pub fn set(
+        &self,
+        filter: Option<&str>,
+        revert_after: Option<Duration>,
+    ) -> Result<ext::LogFilter> {
+        let mut state = self.state.lock().unwrap();
+        let filter = filter.unwrap_or(&state.original);
+        let new =
+            EnvFilter::try_new(filter).with_context(|| format!("invalid filter {filter:?}"))?;
+        let current = new.to_string();
+        self.handle
+            .reload(new)
+            .context("replacing the log filter")?;
+        info!(
+            filter = current,
+            previous = state.current,
+            "log filter changed"
+        );
+
+        let previous = std::mem::replace(&mut state.current, current);
+        state.generation += 1;
+        if let Some(duration) = revert_after {
+            let generation = state.generation;
+            let filter = previous.clone();
+            let this = self.clone();
+            task::spawn(async move {
+                time::sleep(duration).await;
+                this.revert(generation, &filter);
+            });
+        }
+        Ok(ext::LogFilter {
+            filter: state.current.clone(),
+            previous,
+            original: state.original.clone(),
+        })
+    }
+
+    /// Go back to `filter` unless the change `generation` was replaced
+    /// already
+    fn revert(&self, generation: u64, filter: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        if self.handle.reload(EnvFilter::new(filter)).is_ok() {
+            info!(filter, previous = state.current, "log filter reverted");
+            state.current = filter.to_owned();
+            state.generation += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn change_and_revert() {
+        let (log_filter, _layer) = LogFilter::new(EnvFilter::new("info"));
+        assert_eq!(log_filter.get().filter, "info");
+
+        let target = "ra_multiplex_core::instance=trace";
+        let changed = log_filter
+            .set(Some(target), Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(
+            (changed.filter.as_str(), changed.previous.as_str()),
+            (target, "info")
+        );
+        assert!(log_filter.set(Some("instance=loud"), None).is_err());
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filter.get().filter, "info");
+
+        // A later change isn't reverted by an earlier one expiring.
+        log_filter
+            .set(Some("debug"), Some(Duration::from_millis(50)))
+            .unwrap();
+        log_filter.set(Some("warn"), None).unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filter.get().filter, "warn");
+
+        let reset = log_filter.set(None, None).unwrap();
+        assert_eq!(
+            (reset.filter.as_str(), reset.original.as_str()),
+            ("info", "info")
+        );
+    }
+}
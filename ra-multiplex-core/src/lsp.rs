@@ -45,6 +45,7 @@ macro_rules! impl_json_debug {
 
 pub mod ext;
 pub mod jsonrpc;
+pub mod redact;
 pub mod transport;
 
 /// Params for the `initialize` request
@@ -175,6 +176,34 @@ pub struct TextDocumentItem {
     pub text: String,
 }
 
+/// Params for `textDocument/didChange` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeTextDocumentParams {
+    pub text_document: VersionedTextDocumentIdentifier,
+    pub content_changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionedTextDocumentIdentifier {
+    pub uri: String,
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TextDocumentContentChangeEvent {
+    /// Changed range, the change replaces the whole document if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range_length: Option<u32>,
+
+    pub text: String,
+}
+
 /// Params for `textDocument/didClose` notification
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -187,3 +216,73 @@ pub struct DidCloseTextDocumentParams {
 pub struct TextDocumentIdentifier {
     pub uri: String,
 }
+
+/// Result of `workspace/applyEdit` request
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplyWorkspaceEditResult {
+    pub applied: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_change: Option<u32>,
+}
+
+/// Registration options for `workspace/didChangeWatchedFiles`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeWatchedFilesRegistrationOptions {
+    pub watchers: Vec<FileSystemWatcher>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSystemWatcher {
+    pub glob_pattern: GlobPattern,
+
+    /// Bit mask of `WatchKind` create (1), change (2) and delete (4), all of
+    /// them if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum GlobPattern {
+    Pattern(String),
+    Relative(RelativePattern),
+}
+
+/// Glob pattern matched relative to a base URI
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RelativePattern {
+    pub base_uri: BaseUri,
+    pub pattern: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum BaseUri {
+    Uri(String),
+    WorkspaceFolder(WorkspaceFolder),
+}
+
+/// Params for `workspace/didChangeWatchedFiles` notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeWatchedFilesParams {
+    pub changes: Vec<FileEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileEvent {
+    pub uri: String,
+
+    /// `FileChangeType` created (1), changed (2) or deleted (3)
+    #[serde(rename = "type")]
+    pub typ: u8,
+}
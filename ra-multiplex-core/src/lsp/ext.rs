@@ -0,0 +1,1099 @@
+//! LSP-mux (ra-multiplex) specific protocol extensions
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use super::jsonrpc::{Message, RequestId};
+use super::transport::{Compression, WireEncoding};
+use super::Registration;
+
+/// Notification periodically sent by the proxy to check the connection is alive
+///
+/// The server answers every ping with a [`HEARTBEAT_PONG`] notification. Both
+/// are consumed by ra-multiplex and never forwarded to the language server or
+/// the editor.
+pub const HEARTBEAT_PING: &str = "$/lspMux/ping";
+
+/// Server's answer to [`HEARTBEAT_PING`]
+pub const HEARTBEAT_PONG: &str = "$/lspMux/pong";
+
+/// Notification sent to clients following another client when it opens a
+/// document, the params are [`FollowedDocument`]
+pub const DOCUMENT_OPENED: &str = "$/lspMux/documentOpened";
+
+/// Notification sent to clients following another client when it closes a
+/// document or disconnects, the params are [`FollowedDocument`]
+pub const DOCUMENT_CLOSED: &str = "$/lspMux/documentClosed";
+
+/// Notification sent by an `ra-multiplex shim` to the daemon right after it
+/// connects, the params are [`ShimHello`]
+pub const SHIM_HELLO: &str = "$/lspMux/shimHello";
+
+/// Notification sent by the daemon to an `ra-multiplex shim` with the state it
+/// needs to adopt the instance later, the shim returns it in [`ShimHello`]
+pub const SHIM_STATE: &str = "$/lspMux/shimState";
+
+/// Notification wrapping every message of a client session on a multiplexed
+/// connection, the params are [`ChannelMessage`]
+pub const CHANNEL_MESSAGE: &str = "$/lspMux/channelMessage";
+
+/// Notification ending a client session on a multiplexed connection, sent by
+/// the client to close a session and by the server once a session ended, the
+/// params are [`ChannelClosed`]
+pub const CHANNEL_CLOSED: &str = "$/lspMux/channelClosed";
+
+/// Notification sent by a resumable client before closing its connection on
+/// purpose, the daemon ends the session instead of waiting for it to reconnect
+pub const END_SESSION: &str = "$/lspMux/endSession";
+
+/// Notification sent to clients connected with [`Request::Subscribe`] for
+/// every lifecycle event, the params are [`Event`]
+pub const EVENT: &str = "$/lspMux/event";
+
+/// Notification sent to an `ra-multiplex attach` session with the response to
+/// a request of a disconnected client it adopted, the params are
+/// [`AdoptedResponse`]
+pub const ADOPTED_RESPONSE: &str = "$/lspMux/adoptedResponse";
+
+/// JSON-RPC error codes of lspMux `initialize` failures
+///
+/// The codes are stable so editor plugins can tell the failures apart, they're
+/// taken from the range JSON-RPC reserves for implementation-defined server
+/// errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The client speaks another lspMux protocol version than the server
+    VersionMismatch = -32050,
+    /// The requested language server isn't configured to be started
+    ServerNotAllowed = -32051,
+    /// The language server couldn't be started or initialized
+    SpawnFailed = -32052,
+    /// The server is shutting down and doesn't start instances anymore
+    ShuttingDown = -32053,
+    /// No workspace root could be selected for the client
+    NoWorkspaceRoot = -32054,
+    /// The managed language server couldn't be downloaded
+    DownloadFailed = -32055,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 6] = [
+        ErrorCode::VersionMismatch,
+        ErrorCode::ServerNotAllowed,
+        ErrorCode::SpawnFailed,
+        ErrorCode::ShuttingDown,
+        ErrorCode::NoWorkspaceRoot,
+        ErrorCode::DownloadFailed,
+    ];
+
+    pub fn code(self) -> i64 {
+        self as i64
+    }
+
+    pub fn from_code(code: i64) -> Option<ErrorCode> {
+        ErrorCode::ALL
+            .into_iter()
+            .find(|error| error.code() == code)
+    }
+
+    /// What the user can do about the failure
+    pub fn hint(self) -> &'static str {
+        match self {
+            ErrorCode::VersionMismatch => {
+                "restart the server with `ra-multiplex server stop` after upgrading"
+            }
+            ErrorCode::ServerNotAllowed => "check the `download` section of the server config",
+            ErrorCode::SpawnFailed => "check the server log or run `ra-multiplex doctor`",
+            ErrorCode::ShuttingDown => "restart the language server once the server is up again",
+            ErrorCode::NoWorkspaceRoot => "open a folder or a file inside a project",
+            ErrorCode::DownloadFailed => "check the server log and the `download` section",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::VersionMismatch => "lspMux protocol version mismatch",
+            ErrorCode::ServerNotAllowed => "language server not allowed",
+            ErrorCode::SpawnFailed => "language server failed to start",
+            ErrorCode::ShuttingDown => "server is shutting down",
+            ErrorCode::NoWorkspaceRoot => "no workspace root",
+            ErrorCode::DownloadFailed => "language server download failed",
+        })
+    }
+}
+
+/// Additional metadata inserted into LSP RequestId
+pub enum Tag {
+    /// Request is coming from a client connected with this ID
+    ClientId(usize),
+    /// Response to this request should be ignored
+    Drop,
+    /// Response to this request should be forwarded to the instance with this PID
+    Forward(u32),
+    /// Request was sent to multiple instances, this is the instance index
+    Merge(usize),
+    /// Request was sent by ra-multiplex itself and the response is handled
+    /// internally
+    Internal,
+    /// Server request was broadcast by the instance with this PID to all
+    /// clients, this is the ID of the receiving client
+    Broadcast(u32, usize),
+    /// `workspace/applyEdit` request from the instance with this PID, this is
+    /// the ID of the client asked to apply the edit
+    ApplyEdit(u32, usize),
+}
+
+impl RequestId {
+    /// Serializes the ID to a string and prepends Tag
+    ///
+    /// The original ID is kept whole instead of being mapped to a counter, so
+    /// there's no ID space to run out of. Tagged IDs only collide when the
+    /// original IDs do, e.g. a client reusing the ID of a pending request.
+    pub fn tag(&self, tag: Tag) -> RequestId {
+        let tag = match tag {
+            Tag::ClientId(client_id) => format!("client_id:{client_id}"),
+            Tag::Drop => "drop".into(),
+            Tag::Forward(pid) => format!("forward:{pid}"),
+            Tag::Merge(index) => format!("merge:{index}"),
+            Tag::Internal => "internal".into(),
+            Tag::Broadcast(pid, client_id) => format!("broadcast:{pid}:{client_id}"),
+            Tag::ApplyEdit(pid, client_id) => format!("apply_edit:{pid}:{client_id}"),
+        };
+        let id = match self {
+            RequestId::Number(number) => format!("n:{number}"),
+            RequestId::String(string) => format!("s:{string}"),
+        };
+        RequestId::String(format!("{tag}:{id}"))
+    }
+
+    // Attempts to parse Tag out of the ID
+    pub fn untag(&self) -> (Option<Tag>, RequestId) {
+        fn parse_inner_id(input: &str) -> Result<RequestId> {
+            let (value_type, serialized_id) = input.split_once(':').context("missing `:`")?;
+            Ok(match value_type {
+                "n" => RequestId::Number(serialized_id.parse().context("invalid numeric ID")?),
+                "s" => RequestId::String(serialized_id.to_owned()),
+                _ => bail!("invalid tag type `{value_type}`"),
+            })
+        }
+
+        fn parse_client_id(input: &str) -> Result<(usize, &str)> {
+            let (client_id, rest) = input.split_once(':').context("missing`:`")?;
+            let client_id = usize::from_str(client_id).context("invalid client ID")?;
+            Ok((client_id, rest))
+        }
+
+        fn parse_pid(input: &str) -> Result<(u32, &str)> {
+            let (pid, rest) = input.split_once(':').context("missing`:`")?;
+            let pid = u32::from_str(pid).context("invalid PID")?;
+            Ok((pid, rest))
+        }
+
+        fn parse_index(input: &str) -> Result<(usize, &str)> {
+            let (index, rest) = input.split_once(':').context("missing`:`")?;
+            let index = usize::from_str(index).context("invalid instance index")?;
+            Ok((index, rest))
+        }
+
+        fn parse_tag(input: &RequestId) -> Result<(Tag, RequestId)> {
+            let RequestId::String(input) = input else {
+                bail!("tagged id must be a String found `{input:?}`");
+            };
+
+            if let Some(rest) = input.strip_prefix("client_id:") {
+                let (client_id, rest) = parse_client_id(rest)?;
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::ClientId(client_id), inner_id));
+            }
+
+            if let Some(rest) = input.strip_prefix("drop:") {
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::Drop, inner_id));
+            }
+
+            if let Some(rest) = input.strip_prefix("forward:") {
+                let (pid, rest) = parse_pid(rest)?;
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::Forward(pid), inner_id));
+            }
+
+            if let Some(rest) = input.strip_prefix("merge:") {
+                let (index, rest) = parse_index(rest)?;
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::Merge(index), inner_id));
+            }
+
+            if let Some(rest) = input.strip_prefix("broadcast:") {
+                let (pid, rest) = parse_pid(rest)?;
+                let (client_id, rest) = parse_client_id(rest)?;
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::Broadcast(pid, client_id), inner_id));
+            }
+
+            if let Some(rest) = input.strip_prefix("apply_edit:") {
+                let (pid, rest) = parse_pid(rest)?;
+                let (client_id, rest) = parse_client_id(rest)?;
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::ApplyEdit(pid, client_id), inner_id));
+            }
+
+            if let Some(rest) = input.strip_prefix("internal:") {
+                let inner_id = parse_inner_id(rest).context("failed to parse inner ID")?;
+                return Ok((Tag::Internal, inner_id));
+            }
+
+            bail!("unrecognized prefix: {input:?}");
+        }
+
+        match parse_tag(self) {
+            Ok((tag, inner_id)) => (Some(tag), inner_id),
+            Err(err) => {
+                warn!(?err, "invalid tagged ID");
+                (None, self.clone())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LspMuxOptions {
+    /// Version number of the protocol
+    ///
+    /// Version is for now naively checked for equality with
+    /// [`PROTOCOL_VERSION`](LspMuxOptions::PROTOCOL_VERSION), the server will
+    /// refuse connections to mismatched clients.
+    pub version: String,
+
+    /// How the daemon treats messages of a connecting client, defaults to
+    /// [`ClientMode::Normal`] if omitted
+    #[serde(default, skip_serializing_if = "ClientMode::is_normal")]
+    pub mode: ClientMode,
+
+    /// ID of a client whose opened and closed documents are reported with
+    /// [`DOCUMENT_OPENED`] and [`DOCUMENT_CLOSED`] notifications
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow: Option<usize>,
+
+    /// Encoding of all following messages in both directions, defaults to
+    /// [`WireEncoding::Lsp`] if omitted
+    #[serde(default, skip_serializing_if = "WireEncoding::is_lsp")]
+    pub encoding: WireEncoding,
+
+    /// Compression of the messages after the `initialize` response in both
+    /// directions, defaults to [`Compression::Off`] if omitted
+    ///
+    /// The daemon compresses with the level clamped to
+    /// [`Compression::MAX_ZSTD_LEVEL`] and returns the compression it accepted
+    /// as `compression` in `lspMux` of the uncompressed `initialize` result.
+    /// The client only compresses once it got that, a daemon without
+    /// compression support ignores the option.
+    #[serde(default, skip_serializing_if = "Compression::is_off")]
+    pub compression: Compression,
+
+    /// Ask the daemon to issue a reconnect token, it's returned as
+    /// [`Session`] in `lspMux` of the `initialize` result
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub resumable: bool,
+
+    /// Token of an earlier [`Session`], the connection re-binds to the client
+    /// it was issued to instead of connecting a new one
+    ///
+    /// The daemon answers the `initialize` request with only the [`Session`]
+    /// and resends what the proxy didn't receive, or with an error if the
+    /// token expired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_token: Option<String>,
+
+    /// Number of messages the proxy received from the daemon after the
+    /// `initialize` responses of all its connections so far
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received: Option<u64>,
+
+    #[serde(flatten)]
+    pub method: Request,
+}
+
+impl_json_debug! {
+    LspMuxOptions,
+}
+
+impl LspMuxOptions {
+    /// Protocol version
+    ///
+    /// This doesn't match the crate version, it starts at `"1"` and will only
+    /// increase if we make a backwards-incompatible change.
+    pub const PROTOCOL_VERSION: &'static str = "1";
+}
+
+/// Sent by the daemon to resumable clients as `lspMux` in the `initialize`
+/// result, it's stripped by the proxy
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub reconnect_token: String,
+
+    /// Number of messages the daemon received from the proxy after the
+    /// `initialize` requests of all its connections so far, the proxy resends
+    /// the following ones
+    pub received: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ClientMode {
+    /// Client can edit documents like any other editor
+    #[default]
+    Normal,
+
+    /// Client only watches the session of other clients
+    ///
+    /// Its document changes, `didOpen` notifications with content different
+    /// from the already open document and responses to `workspace/applyEdit`
+    /// are dropped. Read-only requests are forwarded and diagnostics delivered
+    /// as usual.
+    Observer,
+}
+
+impl ClientMode {
+    pub fn is_normal(&self) -> bool {
+        *self == ClientMode::Normal
+    }
+}
+
+/// Params of [`DOCUMENT_OPENED`] and [`DOCUMENT_CLOSED`] notifications
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FollowedDocument {
+    /// ID of the followed client
+    pub client_id: usize,
+    pub uri: String,
+}
+
+/// Params of [`ADOPTED_RESPONSE`] notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdoptedResponse {
+    /// ID of the disconnected client which sent the request
+    pub client_id: usize,
+    pub method: String,
+
+    /// Response with the request ID the client used
+    pub response: Message,
+}
+
+/// Params of [`SHIM_HELLO`] notification
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ShimHello {
+    pub shim_pid: u32,
+    pub server_pid: u32,
+
+    /// Last params of [`SHIM_STATE`] notification, `null` if the daemon
+    /// didn't send any yet
+    pub state: Value,
+
+    /// Capabilities the server registered dynamically and didn't unregister
+    pub registrations: Vec<Registration>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "method")]
+#[serde(rename_all = "camelCase")]
+pub enum Request {
+    /// Connect to a language server
+    Connect {
+        /// The language server to run
+        ///
+        /// Can be either an absolute path like `/usr/local/bin/rust-analyzer` or a
+        /// plain name like `rust-analyzer` which will then be resolved according to
+        /// the *server's* path. A name like `managed:rust-analyzer@nightly`
+        /// refers to a server downloaded according to the `[download]` config
+        /// section.
+        server: String,
+
+        /// Arguments which will be passed to the language server, defaults to an
+        /// empty list if omitted.
+        #[serde(default = "Vec::new")]
+        args: Vec<String>,
+
+        /// Environment variables which will be set for the language server,
+        /// defaults to an empty set if omitted.
+        #[serde(default = "BTreeMap::new", skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+
+        /// Current working directory of the proxy command. This is only used as
+        /// fallback if the client doesn't provide any workspace root.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+
+        /// Label keeping the instances of clients with different tags apart,
+        /// even if they're otherwise the same
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+    },
+
+    /// List instances and connected clients
+    Status {},
+
+    /// Reload the workspace of an instance
+    ///
+    /// For rust-analyzer send the `rust-analyzer/reloadWorkspace` extension
+    /// request, for other language servers their configured `reload_method`.
+    /// Responds once the server responded.
+    Reload {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+    },
+
+    /// Exempt an instance from eviction or make it evictable again
+    ///
+    /// A pinned instance keeps running without clients regardless of
+    /// `instance_timeout` and `keep_alive`.
+    Pin {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+
+        /// Pin the instance, unpin it if `false`
+        pinned: bool,
+    },
+
+    /// Restart an instance
+    ///
+    /// Connected clients stay connected to the restarted language server.
+    Restart {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+    },
+
+    /// Replace the language server of an instance with a new one
+    ///
+    /// The new server is started next to the running one and clients are
+    /// switched over to it once it finished indexing. Responds with the PID of
+    /// the new server.
+    Rollover {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+    },
+
+    /// Attach to an instance and send it requests
+    ///
+    /// After the response the connection stays open. Requests sent over it are
+    /// forwarded to the instance and their responses sent back, the attached
+    /// session doesn't receive any server requests or notifications.
+    Attach {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+
+        /// Tag of the clients whose responses the session adopts with
+        /// `adopt_responses`, it only adopts from an instance with the same tag
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+    },
+
+    /// Send a notification to an instance
+    ///
+    /// Notifications managed by ra-multiplex itself, like `exit` or document
+    /// synchronization, are refused.
+    Notify {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+
+        /// Notification method
+        notification: String,
+
+        #[serde(default)]
+        params: Value,
+    },
+
+    /// Move the clients of an instance onto another one running the same
+    /// server for an overlapping workspace root
+    ///
+    /// Their open documents are opened in the other instance and the workspace
+    /// root of the instance is added to its workspace folders unless it's
+    /// inside of its root already. Responds with the number of clients moved.
+    Merge {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+
+        /// Selects the instance the clients are moved to like `pid`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        into_pid: Option<u32>,
+
+        /// Selects the instance the clients are moved to like `cwd`
+        into_cwd: String,
+    },
+
+    /// List the client requests an instance didn't respond to yet
+    ///
+    /// Responds with a list of [`PendingRequest`]. With `cancel` the request
+    /// with this ID is cancelled instead, with `$/cancelRequest` if it was
+    /// already sent to the server, and the response is null.
+    Queue {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+
+        /// ID of the request to cancel as listed in [`PendingRequest::id`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cancel: Option<String>,
+    },
+
+    /// Shut down all instances and stop the server
+    ///
+    /// With `detach` instances running in a shim are left running for the
+    /// next daemon to adopt. Responds with the number of instances shut down.
+    Shutdown {
+        #[serde(default)]
+        detach: bool,
+    },
+
+    /// Report the state of an instance for editor statuslines
+    ///
+    /// Responds with a [`Statusline`], or null if there is no such instance.
+    /// It's cheap enough to be polled a few times a second.
+    Statusline {
+        /// Selects instance with this language server PID, if omitted the
+        /// instance is selected by `cwd` like for `reload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pid: Option<u32>,
+
+        /// Selects instance with the longest path where
+        /// `cwd.starts_with(workspace_root)` is true
+        cwd: String,
+    },
+
+    /// Report the server's environment for `ra-multiplex doctor`
+    ///
+    /// Responds with a [`DoctorResponse`].
+    Doctor {
+        /// Language servers to look up like for `connect`
+        servers: Vec<String>,
+
+        /// Environment variables the language servers would be started with
+        #[serde(default = "BTreeMap::new", skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+    },
+
+    /// Stream lifecycle events as [`EVENT`] notifications after the response
+    /// until the client disconnects
+    Subscribe {},
+
+    /// Change the log filter of the server without restarting it
+    ///
+    /// Responds with a [`LogFilter`]. Without `filter` and `reset` the filter
+    /// is only reported.
+    LogFilter {
+        /// `RUST_LOG` style filter replacing the one in effect
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<String>,
+
+        /// Go back to the filter the server was started with
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        reset: bool,
+
+        /// Seconds after which the replaced filter is restored
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revert_after: Option<u32>,
+    },
+
+    /// Carry many client sessions over the connection
+    ///
+    /// After the response every message is wrapped in a [`CHANNEL_MESSAGE`]
+    /// notification. A channel ID not seen before starts a new session which
+    /// begins with its own `initialize` request like a separate connection,
+    /// IDs of ended sessions must not be reused.
+    Multiplex {},
+}
+
+/// Response to [`Request::LogFilter`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LogFilter {
+    /// Filter in effect
+    pub filter: String,
+
+    /// Filter in effect before the request
+    pub previous: String,
+
+    /// Filter the server was started with
+    pub original: String,
+}
+
+/// Params of [`EVENT`] notification
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub kind: EventKind,
+
+    /// Unix time in milliseconds
+    pub time: i64,
+
+    /// PID of the language server instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace_root: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<usize>,
+
+    /// Exit code of an exited instance, `None` if it was killed by a signal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+
+    /// Why an instance was evicted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// Number of events a lagging subscriber missed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EventKind {
+    InstanceStarted,
+    InstanceExited,
+    /// Sent after [`EventKind::InstanceExited`] if the instance exited
+    /// unsuccessfully without being killed
+    InstanceCrashed,
+    /// An idle instance was closed
+    InstanceEvicted,
+    ClientAttached,
+    ClientDetached,
+    /// The subscriber didn't keep up and missed events
+    Lagged,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChannelMessage {
+    pub channel: u64,
+    pub message: Message,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelClosed {
+    pub channel: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusResponse {
+    pub instances: Vec<Instance>,
+
+    /// Paths of the last crash reports of language servers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub crash_reports: Vec<String>,
+
+    /// Instances running the same server for overlapping workspace roots
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub duplicates: Vec<Duplicate>,
+}
+
+/// Instance whose workspace root is inside of the one of another instance
+/// running the same server, suggested to be merged into it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Duplicate {
+    pub pid: u32,
+    pub workspace_root: String,
+    pub into_pid: u32,
+    pub into_workspace_root: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Instance {
+    pub pid: u32,
+    pub server: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub workspace_root: String,
+    #[serde(default)]
+    pub role: InstanceRole,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub registered_dyn_capabilities: Vec<String>,
+    pub last_used: i64,
+
+    /// The instance is exempt from eviction
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+
+    pub clients: Vec<Client>,
+
+    /// Resident set size of the language server process in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rss: Option<u64>,
+
+    /// Last `rust-analyzer/memoryUsage` breakdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_usage: Option<Vec<MemoryUsage>>,
+
+    /// Messages exchanged with the language server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<Traffic>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Statusline {
+    pub pid: u32,
+    pub server: String,
+    pub clients: usize,
+
+    /// Resident set size of the language server process in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rss: Option<u64>,
+
+    /// The server runs work done progress or reported it's not quiescent
+    pub busy: bool,
+
+    /// Least advanced work done progress of the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<Progress>,
+
+    /// `health` of the last `experimental/serverStatus`, like "ok" or
+    /// "warning"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<String>,
+}
+
+/// Work done progress of a language server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DoctorResponse {
+    /// Version of the ra-multiplex server
+    pub version: String,
+
+    /// `PATH` language servers are looked up in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    pub servers: Vec<ServerLookup>,
+}
+
+/// Where the server finds a language server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerLookup {
+    pub server: String,
+
+    /// Path of the executable, `None` if it wasn't found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// Client request an instance didn't respond to yet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingRequest {
+    /// Request ID tagged with the ID of the client
+    pub id: String,
+    pub method: String,
+    pub client: usize,
+    /// Milliseconds since the client sent the request
+    pub age: u64,
+    /// The request waits for a slot under `max_concurrent_requests` and
+    /// wasn't sent to the server yet
+    pub queued: bool,
+}
+
+/// What an instance is used for among the instances of the same server and
+/// workspace
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum InstanceRole {
+    /// Answers all requests except the ones in `replica_methods` and
+    /// `background_methods`
+    #[default]
+    Primary,
+
+    /// Answers only requests in `replica_methods`, kept in sync with the
+    /// primary's documents
+    Replica,
+
+    /// Answers only requests in `background_methods`, kept in sync with the
+    /// primary's documents
+    Background,
+}
+
+/// Traffic between ra-multiplex and a language server since the instance
+/// was spawned
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Traffic {
+    /// Seconds since the instance was spawned
+    pub seconds: u64,
+    pub messages_to_server: u64,
+    /// Size of message bodies sent to the server in bytes
+    pub bytes_to_server: u64,
+    pub messages_from_server: u64,
+    /// Size of message bodies received from the server in bytes
+    pub bytes_from_server: u64,
+    /// Error responses received from the server
+    pub errors: u64,
+    /// Number of requests in both directions by method
+    pub requests: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryUsage {
+    pub name: String,
+    pub size: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Client {
+    pub id: usize,
+    pub files: Vec<String>,
+
+    /// Workspace folders the client asked for, the instance's workspace root
+    /// was selected from them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub workspace_folders: Vec<ClientFolder>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientFolder {
+    /// Folder URI, or a path if the client sent only `rootPath`
+    pub uri: String,
+
+    /// The folder was added with `workspace/didChangeWorkspaceFolders` after
+    /// initialization
+    #[serde(default)]
+    pub added: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use serde_json::{from_value, json, to_value, Value};
+
+    use crate::lsp::InitializationOptions;
+
+    fn test<T>(input: Value)
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let deserialized = from_value::<T>(input.clone()).expect("failed to deserialize");
+        let serialized = to_value(&deserialized).expect("failed to serialize");
+        assert_eq!(input, serialized);
+    }
+
+    #[test]
+    fn lsp_mux_only() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "method": "connect",
+                "server": "some-language-server",
+                "args": ["a", "b", "c"],
+                "cwd": "/home/user",
+            }
+        }))
+    }
+
+    #[test]
+    fn lsp_mux_tag() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "method": "connect",
+                "server": "some-language-server",
+                "args": [],
+                "tag": "tests",
+            }
+        }))
+    }
+
+    #[test]
+    fn lsp_mux_without_tag() {
+        let options = from_value::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "method": "connect",
+                "server": "some-language-server",
+            }
+        }))
+        .expect("failed to deserialize");
+        let serialized = to_value(&options).expect("failed to serialize");
+        assert_eq!(serialized["lspMux"].get("tag"), None, "{serialized}");
+    }
+
+    #[test]
+    fn lsp_mux_and_other_stuff() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "method": "connect",
+                "server": "some-language-server",
+                "args": ["a", "b", "c"],
+            },
+            "lsp_mux": "not the right key",
+            "lspmux": "also not it",
+            "lsp mux": "wrong one",
+            "a": 1,
+            "b": null,
+            "c": {},
+            "d": [],
+        }))
+    }
+
+    #[test]
+    fn lsp_mux_observer_following() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "mode": "observer",
+                "follow": 3,
+                "method": "connect",
+                "server": "some-language-server",
+                "args": [],
+            }
+        }))
+    }
+
+    #[test]
+    fn broadcast_tag() {
+        use super::Tag;
+        use crate::lsp::jsonrpc::RequestId;
+
+        let id = RequestId::Number(7).tag(Tag::Broadcast(1234, 2));
+        assert_eq!(id, RequestId::String("broadcast:1234:2:n:7".into()));
+        let (Some(Tag::Broadcast(1234, 2)), RequestId::Number(7)) = id.untag() else {
+            panic!("wrong tag");
+        };
+    }
+
+    #[test]
+    #[should_panic = "missing field `version`"]
+    fn missing_version() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "method": "connect",
+                "server": "some-language-server",
+                "args": ["a", "b", "c"],
+            },
+        }))
+    }
+
+    #[test]
+    #[should_panic = "missing field `method`"]
+    fn missing_method() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "server": "some-language-server",
+                "args": ["a", "b", "c"],
+            },
+        }))
+    }
+
+    #[test]
+    #[should_panic = "missing field `server`"]
+    fn missing_server() {
+        test::<InitializationOptions>(json!({
+            "lspMux": {
+                "version": "1",
+                "method": "connect",
+                "args": ["a", "b", "c"],
+            },
+        }))
+    }
+
+    #[test]
+    fn error_codes() {
+        use super::ErrorCode;
+
+        for error in ErrorCode::ALL {
+            assert_eq!(ErrorCode::from_code(error.code()), Some(error));
+        }
+        assert_eq!(ErrorCode::from_code(-32600), None);
+
+        let err = anyhow::anyhow!("not in the allowlist").context(ErrorCode::ServerNotAllowed);
+        assert_eq!(
+            err.downcast_ref::<ErrorCode>(),
+            Some(&ErrorCode::ServerNotAllowed)
+        );
+        assert_eq!(
+            format!("{err:#}"),
+            "language server not allowed: not in the allowlist"
+        );
+    }
+}
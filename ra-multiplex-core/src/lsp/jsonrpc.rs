@@ -0,0 +1,470 @@
+//! JSON-RPC 2.0 support
+//!
+//! Support for the JSON-RPC 2.0 protocol as definied here
+//! <https://www.jsonrpc.org/specification>. With the exception of batching which
+//! is handled in [`read_message`](super::transport::LspReader::read_message).
+
+use std::fmt;
+
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Message {
+    Request(Request),
+    Notification(Notification),
+    ResponseError(ResponseError),
+    ResponseSuccess(ResponseSuccess),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+    pub jsonrpc: Version,
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+    pub id: RequestId,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Notification {
+    pub jsonrpc: Version,
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseError {
+    pub jsonrpc: Version,
+    pub error: Error,
+    pub id: RequestId,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseSuccess {
+    pub jsonrpc: Version,
+    #[serde(default)]
+    pub result: serde_json::Value,
+    pub id: RequestId,
+}
+
+impl ResponseSuccess {
+    /// Creates a new response with `null` result
+    pub fn null(id: RequestId) -> Self {
+        ResponseSuccess {
+            jsonrpc: Version,
+            result: serde_json::Value::Null,
+            id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
+    // It can also be null but we intentionally skip those as "invalid"
+}
+
+impl<S> PartialEq<S> for RequestId
+where
+    S: AsRef<str>,
+{
+    fn eq(&self, rhs: &S) -> bool {
+        matches!(self, RequestId::String(lhs) if lhs == rhs.as_ref())
+    }
+}
+
+/// ZST representation of the `"2.0"` version string
+#[derive(Clone, Copy)]
+pub struct Version;
+
+impl serde::ser::Serialize for Version {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        serializer.serialize_str("2.0")
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for Version {
+    type Value = Version;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(r#"string value "2.0""#)
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        match v {
+            "2.0" => Ok(Version),
+            _ => Err(E::custom("unsupported JSON-RPC version")),
+        }
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for Version {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(Version)
+    }
+}
+
+impl From<Request> for Message {
+    fn from(value: Request) -> Self {
+        Message::Request(value)
+    }
+}
+
+impl From<Notification> for Message {
+    fn from(value: Notification) -> Self {
+        Message::Notification(value)
+    }
+}
+
+impl From<ResponseSuccess> for Message {
+    fn from(value: ResponseSuccess) -> Self {
+        Message::ResponseSuccess(value)
+    }
+}
+
+impl From<ResponseError> for Message {
+    fn from(value: ResponseError) -> Self {
+        Message::ResponseError(value)
+    }
+}
+
+impl_json_debug! {
+    Message, Request, Notification, ResponseSuccess, ResponseError,
+}
+
+/// Limits on the shape of JSON messages read from untrusted peers
+///
+/// Messages are checked by [`Scanner`] before they're parsed, so a message
+/// nested too deeply can't exhaust the stack and one too large or with too
+/// long arrays can't exhaust memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of a message body in bytes
+    pub max_message_size: usize,
+    /// Maximum number of nested objects and arrays
+    pub max_depth: usize,
+    /// Maximum number of elements of an array
+    pub max_array_length: usize,
+}
+
+impl Limits {
+    /// No limits besides the nesting depth serde_json enforces itself
+    pub const NONE: Limits = Limits {
+        max_message_size: usize::MAX,
+        max_depth: usize::MAX,
+        max_array_length: usize::MAX,
+    };
+
+    /// Check a whole message body
+    pub fn check(&self, body: &[u8]) -> Result<(), Rejected> {
+        let mut scanner = Scanner::new(*self);
+        scanner.feed(body);
+        scanner.finish()
+    }
+}
+
+/// Message rejected for exceeding [`Limits`]
+///
+/// Its request ID is recovered if the message is an object with a top-level
+/// `id` so the request can be answered with an error.
+#[derive(Debug)]
+pub struct Rejected {
+    pub id: Option<RequestId>,
+    pub reason: String,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message rejected: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Maximum length of the captured top-level `id` value
+const MAX_ID_LENGTH: usize = 256;
+
+/// Checks JSON text against [`Limits`] without parsing it
+///
+/// It's fed the message in chunks and keeps only a stack of the open arrays,
+/// so it's also used to skip messages too large to be read. Scanning goes on
+/// after a limit is exceeded to find the request ID.
+pub struct Scanner {
+    limits: Limits,
+    /// Number of open objects and arrays
+    depth: usize,
+    /// Open containers up to `max_depth`, arrays with their number of commas
+    stack: Vec<Option<usize>>,
+    in_string: bool,
+    escaped: bool,
+    /// Next string at the top level of an object is a member name
+    expect_key: bool,
+    /// Start of the member name at the top level of an object being read
+    key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    /// Text of the top-level `id` value being read
+    id: Option<Vec<u8>>,
+    found_id: Option<Vec<u8>>,
+    violation: Option<String>,
+}
+
+impl Scanner {
+    pub fn new(limits: Limits) -> Scanner {
+        Scanner {
+            limits,
+            depth: 0,
+            stack: Vec::new(),
+            in_string: false,
+            escaped: false,
+            expect_key: false,
+            key: None,
+            last_key: Vec::new(),
+            id: None,
+            found_id: None,
+            violation: None,
+        }
+    }
+
+    /// Report a violation found without scanning, like the message size
+    pub fn reject(&mut self, reason: String) {
+        self.violation.get_or_insert(reason);
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.feed_byte(byte);
+        }
+    }
+
+    fn feed_byte(&mut self, byte: u8) {
+        let top_level = self.depth == 1;
+        if let Some(id) = &mut self.id {
+            if top_level && !self.in_string && matches!(byte, b',' | b'}') {
+                self.found_id = self.id.take();
+            } else if id.len() < MAX_ID_LENGTH {
+                id.push(byte);
+            }
+        }
+
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+                if let Some(key) = self.key.take() {
+                    self.last_key = key;
+                }
+                return;
+            }
+            if let Some(key) = &mut self.key {
+                if key.len() < 3 {
+                    key.push(byte);
+                }
+            }
+            return;
+        }
+
+        match byte {
+            b'"' => {
+                self.in_string = true;
+                if top_level && self.expect_key {
+                    self.key = Some(Vec::new());
+                }
+            }
+            b'{' | b'[' => {
+                self.depth += 1;
+                if self.depth > self.limits.max_depth {
+                    let max = self.limits.max_depth;
+                    self.reject(format!("nested deeper than {max} levels"));
+                } else {
+                    self.stack.push((byte == b'[').then_some(0));
+                }
+                self.expect_key = self.depth == 1 && byte == b'{';
+            }
+            b'}' | b']' => {
+                if self.depth <= self.limits.max_depth {
+                    self.stack.pop();
+                }
+                self.depth = self.depth.saturating_sub(1);
+            }
+            b',' => {
+                if self.depth <= self.limits.max_depth {
+                    if let Some(Some(commas)) = self.stack.last_mut() {
+                        *commas += 1;
+                        if *commas >= self.limits.max_array_length {
+                            let max = self.limits.max_array_length;
+                            self.reject(format!("array longer than {max} elements"));
+                        }
+                    }
+                }
+                self.expect_key = top_level;
+            }
+            b':' if top_level => {
+                self.expect_key = false;
+                if self.last_key == b"id" && self.found_id.is_none() {
+                    self.id = Some(Vec::new());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Finish scanning, returns the first violation found
+    pub fn finish(self) -> Result<(), Rejected> {
+        match self.violation {
+            Some(reason) => Err(Rejected {
+                id: self
+                    .found_id
+                    .and_then(|id| serde_json::from_slice(&id).ok()),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NotResponseError(pub Message);
+
+impl Message {
+    pub fn into_response(self) -> Result<Result<ResponseSuccess, ResponseError>, NotResponseError> {
+        match self {
+            Message::ResponseSuccess(success) => Ok(Ok(success)),
+            Message::ResponseError(error) => Ok(Err(error)),
+            other => Err(NotResponseError(other)),
+        }
+    }
+}
+
+impl fmt::Display for NotResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "expected Response* Message found {msg:?}",
+            msg = &self.0,
+        ))
+    }
+}
+
+impl std::error::Error for NotResponseError {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json, to_value, Value};
+
+    use super::*;
+
+    fn test(input: Value) {
+        let deserialized = from_value::<Message>(input.clone()).expect("failed to deserialize");
+        let serialized = to_value(deserialized).expect("failed to serialize");
+        assert_eq!(input, serialized);
+    }
+
+    #[test]
+    fn call_with_positional_parameters() {
+        test(json!({
+            "jsonrpc": "2.0",
+            "method": "subtract",
+            "params": [42, 23],
+            "id": 1,
+        }))
+    }
+
+    #[test]
+    fn call_with_named_parameters() {
+        test(json!({
+            "jsonrpc": "2.0",
+            "method": "subtract",
+            "params": { "minuend": 42, "subtrahend": 23 },
+            "id": 3,
+        }))
+    }
+
+    #[test]
+    fn response() {
+        test(json!({
+            "jsonrpc": "2.0",
+            "result": 19,
+            "id": 1,
+        }))
+    }
+
+    #[test]
+    fn notification() {
+        test(json!({
+            "jsonrpc": "2.0",
+            "method": "update",
+            "params": [1, 2, 3, 4, 5],
+        }))
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_message_size: usize::MAX,
+            max_depth: 4,
+            max_array_length: 3,
+        };
+        let message = |params: &str| {
+            format!(r#"{{"jsonrpc":"2.0","method":"m","params":{params},"id":"a,}}\"b"}}"#)
+        };
+        assert!(limits
+            .check(message(r#"{"a":[[1,2,3]]}"#).as_bytes())
+            .is_ok());
+        assert!(limits
+            .check(message(r#"["[[[[",",,,,"]"#).as_bytes())
+            .is_ok());
+
+        let rejected = limits.check(message("[[[[]]]]").as_bytes()).unwrap_err();
+        assert_eq!(rejected.reason, "nested deeper than 4 levels");
+        assert_eq!(rejected.id, Some(RequestId::String(r#"a,}"b"#.into())));
+
+        let rejected = limits.check(message("[[1,2,3,4]]").as_bytes()).unwrap_err();
+        assert_eq!(rejected.reason, "array longer than 3 elements");
+
+        let body = br#"{"id": 7, "params": [1,2,3,4,5]}"#;
+        let rejected = limits.check(body).unwrap_err();
+        assert_eq!(rejected.id, Some(RequestId::Number(7)));
+        let rejected = limits.check(br#"[{"id": 7}, [1,2,3,4]]"#).unwrap_err();
+        assert_eq!(rejected.id, None);
+    }
+
+    #[test]
+    fn error() {
+        test(json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": -32601,
+                "message": "Method not found",
+            },
+            "id": "1",
+        }))
+    }
+}
@@ -0,0 +1,235 @@
+//! Hiding sensitive `initializationOptions` members and message contents
+//!
+//! Members are selected by their dotted path like `github.token`. Stripped
+//! members are removed before the `initialize` request is forwarded to the
+//! server, redacted ones are forwarded but like the stripped ones replaced with
+//! a placeholder in logs and crash reports. With `privacy` enabled all strings
+//! in messages are hashed or elided there as well.
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+
+use serde_json::{Map, Value};
+
+use crate::config::{Config, Privacy};
+use crate::lsp::jsonrpc::Message;
+
+/// Replaces the values of redacted members
+const PLACEHOLDER: &str = "<redacted>";
+
+/// What is hidden in logs and crash reports
+#[derive(Debug)]
+pub struct Redaction {
+    /// Paths of the hidden `initializationOptions` members
+    redacted: Vec<String>,
+
+    /// How strings in messages are hidden
+    privacy: Privacy,
+}
+
+impl Redaction {
+    /// Hides nothing
+    pub const NONE: Redaction = Redaction {
+        redacted: Vec::new(),
+        privacy: Privacy::Off,
+    };
+
+    /// Hide the stripped and redacted initialization options and with
+    /// `privacy` enabled the strings of all messages
+    pub fn new(config: &Config) -> Self {
+        let redacted = config
+            .strip_initialization_options
+            .iter()
+            .chain(&config.redact_initialization_options);
+        Redaction {
+            redacted: redacted.cloned().collect(),
+            privacy: config.privacy,
+        }
+    }
+
+    /// Replace the redacted members in the `initializationOptions` of
+    /// `initialize` request params with a placeholder
+    pub fn redact_params(&self, params: &mut Value) {
+        let Some(Value::Object(options)) = params.get_mut("initializationOptions") else {
+            return;
+        };
+        for path in &self.redacted {
+            redact(options, path);
+        }
+    }
+
+    /// Whether messages are changed before they're logged
+    fn is_hiding(&self, message: &Message) -> bool {
+        let initialize = matches!(message, Message::Request(req) if req.method == "initialize");
+        self.privacy != Privacy::Off || (initialize && !self.redacted.is_empty())
+    }
+
+    /// The message with the redacted members of an `initialize` request and
+    /// with `privacy` enabled all strings hidden
+    pub fn redacted<'a>(&self, message: &'a Message) -> Cow<'a, Message> {
+        if !self.is_hiding(message) {
+            return Cow::Borrowed(message);
+        }
+        let mut message = message.clone();
+        let privacy = self.privacy;
+        match &mut message {
+            Message::Request(req) => {
+                if req.method == "initialize" {
+                    self.redact_params(&mut req.params);
+                }
+                hide_strings(&mut req.params, privacy);
+            }
+            Message::Notification(notif) => hide_strings(&mut notif.params, privacy),
+            Message::ResponseSuccess(res) => hide_strings(&mut res.result, privacy),
+            Message::ResponseError(res) => {
+                if let Some(data) = &mut res.error.data {
+                    hide_strings(data, privacy);
+                }
+            }
+        }
+        Cow::Owned(message)
+    }
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction::NONE
+    }
+}
+
+/// Remove the member with a dotted `path` from `options`, returns whether it
+/// was there
+pub fn strip(options: &mut Map<String, Value>, path: &str) -> bool {
+    match path.split_once('.') {
+        Some((key, rest)) => match options.get_mut(key) {
+            Some(Value::Object(options)) => strip(options, rest),
+            _ => false,
+        },
+        None => options.remove(path).is_some(),
+    }
+}
+
+fn redact(options: &mut Map<String, Value>, path: &str) {
+    match path.split_once('.') {
+        Some((key, rest)) => {
+            if let Some(Value::Object(options)) = options.get_mut(key) {
+                redact(options, rest);
+            }
+        }
+        None => {
+            if let Some(value) = options.get_mut(path) {
+                *value = PLACEHOLDER.into();
+            }
+        }
+    }
+}
+
+/// Replace every string in `value` according to `privacy`, object keys are
+/// kept
+fn hide_strings(value: &mut Value, privacy: Privacy) {
+    match value {
+        Value::String(string) => {
+            *string = match privacy {
+                Privacy::Off => return,
+                Privacy::Hash => {
+                    let mut hasher = DefaultHasher::new();
+                    string.hash(&mut hasher);
+                    format!("<hash {:016x}>", hasher.finish())
+                }
+                Privacy::Elide => format!("<{} bytes>", string.len()),
+            };
+        }
+        Value::Array(values) => {
+            for value in values {
+                hide_strings(value, privacy);
+            }
+        }
+        Value::Object(members) => {
+            for value in members.values_mut() {
+                hide_strings(value, privacy);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+/// Formats a message for logs hiding what the [`Redaction`] hides
+pub struct Logged<'a, T>(pub &'a T, pub &'a Redaction);
+
+impl<T> Debug for Logged<'_, T>
+where
+    T: Debug + Clone + Into<Message>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = self.0.clone().into();
+        match self.1.redacted(&message) {
+            Cow::Borrowed(_) => self.0.fmt(f),
+            Cow::Owned(message) => message.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn strip_and_redact_options() {
+        let mut options = json!({
+            "github": { "token": "secret", "user": "me" },
+            "cargo": { "targetDir": "/home/me/target" },
+            "files": "/home/me",
+        });
+        let Value::Object(map) = &mut options else {
+            unreachable!();
+        };
+        assert!(strip(map, "github.token"));
+        assert!(!strip(map, "github.token.value"));
+        assert!(!strip(map, "files.exclude"));
+        assert!(!strip(map, "missing"));
+        redact(map, "cargo.targetDir");
+        redact(map, "files");
+        redact(map, "missing.path");
+        assert_eq!(
+            options,
+            json!({
+                "github": { "user": "me" },
+                "cargo": { "targetDir": "<redacted>" },
+                "files": "<redacted>",
+            }),
+        );
+    }
+
+    #[test]
+    fn hide_strings_keeping_structure() {
+        let params = json!({
+            "textDocument": { "uri": "file:///a.rs", "version": 2 },
+            "contentChanges": [{ "text": "fn main() {}" }],
+        });
+
+        let mut elided = params.clone();
+        hide_strings(&mut elided, Privacy::Elide);
+        assert_eq!(
+            elided,
+            json!({
+                "textDocument": { "uri": "<12 bytes>", "version": 2 },
+                "contentChanges": [{ "text": "<12 bytes>" }],
+            }),
+        );
+
+        let mut hashed = params.clone();
+        hide_strings(&mut hashed, Privacy::Hash);
+        let uri = hashed["textDocument"]["uri"].as_str().unwrap();
+        let text = hashed["contentChanges"][0]["text"].as_str().unwrap();
+        assert!(uri.starts_with("<hash "));
+        assert_ne!(uri, text);
+
+        let mut off = params.clone();
+        hide_strings(&mut off, Privacy::Off);
+        assert_eq!(off, params);
+    }
+}
@@ -0,0 +1,921 @@
+use std::io::{self, ErrorKind};
+use std::str;
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+use crate::lsp::jsonrpc::{Limits, Message, Scanner};
+use crate::lsp::redact::{Logged, Redaction};
+
+/// Encoding of messages on the connection between the proxy and the server
+///
+/// Editors and language servers always speak standard LSP, the encoding is
+/// only negotiated for the internal hop.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WireEncoding {
+    /// JSON bodies with a `Content-Length` header
+    #[default]
+    Lsp,
+
+    /// MessagePack bodies prefixed with their length as a big endian `u32`
+    Msgpack,
+}
+
+impl WireEncoding {
+    pub fn is_lsp(&self) -> bool {
+        *self == WireEncoding::Lsp
+    }
+}
+
+/// Compression of message bodies on the connection between the proxy and the
+/// server
+///
+/// Every body is compressed on its own after it's encoded, the framing stays
+/// the same. The level only matters for the writer, so both sides can pick
+/// their own.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    #[default]
+    Off,
+
+    /// Zstandard with this level
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Highest zstd level the server compresses with, the ones above cost a
+    /// lot of CPU time and memory for little gain
+    pub const MAX_ZSTD_LEVEL: i32 = 19;
+
+    pub fn is_off(&self) -> bool {
+        *self == Compression::Off
+    }
+
+    /// Compression the server uses when a client asks for `self`
+    pub fn accepted(self) -> Compression {
+        match self {
+            Compression::Off => Compression::Off,
+            Compression::Zstd(level) => Compression::Zstd(level.clamp(1, Self::MAX_ZSTD_LEVEL)),
+        }
+    }
+}
+
+/// Decompressed bodies are limited to this size unless the reader has lower
+/// limits, so a small message can't make us allocate without bounds
+const MAX_DECOMPRESSED_SIZE: usize = 1 << 30;
+
+/// Decompress a zstd compressed body of at most `max_size` bytes
+fn decompress(body: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let decoder = zstd::stream::read::Decoder::with_buffer(body)?;
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .context("decompressing message")?;
+    ensure!(
+        decompressed.len() <= max_size,
+        "decompressed message is larger than {max_size} bytes",
+    );
+    Ok(decompressed)
+}
+
+/// UTF-8 byte order mark
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Size of the chunks the body of a streamed message is read in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Message read by [`LspReader::read_part`], bodies of large ones are read in
+/// chunks without parsing them
+#[derive(Debug)]
+pub enum Part {
+    Message(Message),
+
+    /// Header of a large message, its body of this many bytes follows in
+    /// chunks
+    Header(usize),
+
+    /// Next chunk of the body of a large message
+    Chunk(Vec<u8>),
+}
+
+pub struct LspReader<R> {
+    reader: R,
+    batch: Vec<Message>,
+    buffer: Vec<u8>,
+    tag: &'static str,
+    redaction: Arc<Redaction>,
+    bytes: u64,
+    messages: u64,
+    encoding: WireEncoding,
+    compression: Compression,
+    limits: Option<Limits>,
+    stream_size: Option<usize>,
+
+    /// Bytes of the body of a streamed message still to be read
+    remaining: usize,
+}
+
+/// Every message begins with a HTTP-style header
+///
+/// Headers are terminated by `\r\n` sequence and the final header is followed by another `\r\n`.
+/// The currently recognized headers are `content-type` which is optional and contains a `string`
+/// (something like a MIME-type) and `content-length` which contains the length of the message body
+/// after the final `\r\n` of the header. Header names and values are separated by `: `.
+///
+/// While we parse the `content-type` header ignore it completely and we don't forward it,
+/// expecting both the server and client to assume the default.
+///
+/// Framing of some editors and test harnesses deviates from this slightly, so
+/// lines may also end with a bare `\n`, the separator may lack the space,
+/// unknown headers are ignored and a UTF-8 byte order mark before a header or
+/// the body is skipped.
+///
+/// For mor details see <https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#headerPart>.
+pub struct Header {
+    pub content_length: usize,
+}
+
+impl<R> LspReader<R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    pub fn new(reader: R, tag: &'static str) -> Self {
+        LspReader {
+            reader,
+            batch: Vec::new(),
+            buffer: Vec::with_capacity(1024),
+            tag,
+            redaction: Arc::default(),
+            bytes: 0,
+            messages: 0,
+            encoding: WireEncoding::Lsp,
+            compression: Compression::Off,
+            limits: None,
+            stream_size: None,
+            remaining: 0,
+        }
+    }
+
+    /// Total size of message bodies read so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Number of message bodies read so far, including rejected and invalid
+    /// ones, a batch counts once
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    /// Read the following messages in `encoding`
+    pub fn set_encoding(&mut self, encoding: WireEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Decompress the following messages
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Hide what `redaction` hides in the logged messages
+    pub fn set_redaction(&mut self, redaction: Arc<Redaction>) {
+        self.redaction = redaction;
+    }
+
+    /// Reject messages exceeding `limits` with a [`Rejected`] error, the
+    /// reader can go on reading the following messages
+    ///
+    /// Only the size of MessagePack messages is limited.
+    ///
+    /// [`Rejected`]: crate::lsp::jsonrpc::Rejected
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = Some(limits);
+    }
+
+    /// Let [`read_part`](Self::read_part) return messages larger than `size`
+    /// bytes in chunks instead of parsing them, `None` parses all of them
+    ///
+    /// Only messages in the LSP encoding without compression are streamed,
+    /// their size isn't limited by the `limits`.
+    pub fn set_stream_size(&mut self, size: Option<usize>) {
+        self.stream_size = size;
+    }
+
+    pub async fn read_header(&mut self) -> Result<Option<Header>> {
+        let mut content_type = None;
+        let mut content_length = None;
+
+        loop {
+            self.buffer.clear();
+            loop {
+                // Bytes read before an error are kept in the buffer, reading
+                // continues after them.
+                match self.reader.read_until(b'\n', &mut self.buffer).await {
+                    Ok(0) if self.buffer.is_empty() => return Ok(None), // EOF
+                    Ok(_) => break,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) if is_closed(&err) => return Ok(None),
+                    Err(err) => bail!(err),
+                }
+            }
+            let header_text = self
+                .buffer
+                .strip_suffix(b"\n")
+                .context(r"malformed header, missing `\n` terminator")?;
+            let header_text = header_text.strip_suffix(b"\r").unwrap_or(header_text);
+            let header_text = header_text.strip_prefix(BOM).unwrap_or(header_text);
+            let header_text = str::from_utf8(header_text)
+                .context("malformed header, ascii encoding is a subset of utf-8")?;
+
+            if header_text.is_empty() {
+                if content_length.is_none() && content_type.is_none() {
+                    // blank lines between messages
+                    continue;
+                }
+                // headers are separated by an empty line from the body
+                break;
+            }
+            let (name, value) = match header_text.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => bail!("malformed header, missing value separator: {}", header_text),
+            };
+
+            match name.to_ascii_lowercase().as_str() {
+                "content-type" => {
+                    ensure!(content_type.is_none(), "repeated header content-type");
+                    content_type = Some(value.to_owned());
+                }
+                "content-length" => {
+                    ensure!(content_length.is_none(), "repeated header content-length");
+                    content_length = Some(value.parse::<usize>().context("content-length header")?);
+                }
+                _ => trace!(?name, ?value, "ignoring unknown header"),
+            }
+        }
+
+        let content_length = content_length.context("missing required header content-length")?;
+        Ok(Some(Header { content_length }))
+    }
+
+    /// Read one message
+    ///
+    /// Returns `None` if the reader was closed and it'll never return another
+    /// message after the first `None`.
+    ///
+    /// Batch messages are transparently split into individual messages and
+    /// delivered in order.
+    pub async fn read_message(&mut self) -> Result<Option<Message>> {
+        // return pending messages until the last batch is drained
+        if let Some(pending) = self.batch.pop() {
+            trace!(message = ?Logged(&pending, &self.redaction), "<- {}", self.tag);
+            return Ok(Some(pending));
+        }
+
+        match self.read_length().await? {
+            Some(content_length) => self.read_body(content_length).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Read one message or, if it's larger than the stream size, its header
+    /// and the chunks of its body with the following calls
+    ///
+    /// The chunks are passed on as they are, without parsing the message.
+    pub async fn read_part(&mut self) -> Result<Option<Part>> {
+        if self.remaining > 0 {
+            let mut chunk = vec![0; self.remaining.min(CHUNK_SIZE)];
+            if !read_exact(&mut self.reader, &mut chunk).await? {
+                self.remaining = 0;
+                return Ok(None);
+            }
+            self.remaining -= chunk.len();
+            return Ok(Some(Part::Chunk(chunk)));
+        }
+
+        let stream_size = self.stream_size.filter(|_| {
+            self.batch.is_empty() && self.encoding.is_lsp() && self.compression.is_off()
+        });
+        let Some(stream_size) = stream_size else {
+            return Ok(self.read_message().await?.map(Part::Message));
+        };
+        let Some(content_length) = self.read_length().await? else {
+            return Ok(None);
+        };
+        if content_length <= stream_size {
+            return Ok(self.read_body(content_length).await?.map(Part::Message));
+        }
+        trace!(content_length, "<- {} streaming message", self.tag);
+        self.bytes += content_length as u64;
+        self.messages += 1;
+        self.remaining = content_length;
+        Ok(Some(Part::Header(content_length)))
+    }
+
+    /// Read the header or length prefix of the next message, `None` if the
+    /// reader was closed
+    async fn read_length(&mut self) -> Result<Option<usize>> {
+        let content_length = match self.encoding {
+            WireEncoding::Lsp => match self.read_header().await.context("parsing header")? {
+                Some(header) => header.content_length,
+                None => return Ok(None),
+            },
+            WireEncoding::Msgpack => {
+                let mut length = [0; 4];
+                if !read_exact(&mut self.reader, &mut length).await? {
+                    return Ok(None);
+                }
+                u32::from_be_bytes(length) as usize
+            }
+        };
+        Ok(Some(content_length))
+    }
+
+    /// Read and parse the body of a message
+    async fn read_body(&mut self, content_length: usize) -> Result<Option<Message>> {
+        if let Some(limits) = self.limits {
+            if content_length > limits.max_message_size {
+                // Skip the body without keeping it, it's still scanned for
+                // the request ID.
+                let mut scanner = Scanner::new(limits);
+                scanner.reject(format!(
+                    "{content_length} bytes is larger than {} bytes",
+                    limits.max_message_size,
+                ));
+                let scan = self.encoding.is_lsp().then_some(&mut scanner);
+                if !skip(&mut self.reader, content_length, scan).await? {
+                    return Ok(None);
+                }
+                self.bytes += content_length as u64;
+                self.messages += 1;
+                return Err(scanner.finish().unwrap_err().into());
+            }
+        }
+
+        self.buffer.clear();
+        self.buffer.resize(content_length, 0);
+        if !read_exact(&mut self.reader, &mut self.buffer).await? {
+            return Ok(None);
+        }
+        self.bytes += content_length as u64;
+        self.messages += 1;
+
+        if !self.compression.is_off() {
+            let max_size = self.limits.map_or(MAX_DECOMPRESSED_SIZE, |limits| {
+                limits.max_message_size.min(MAX_DECOMPRESSED_SIZE)
+            });
+            self.buffer = decompress(&self.buffer, max_size)?;
+        }
+
+        if self.encoding == WireEncoding::Msgpack {
+            let message = rmp_serde::from_slice(&self.buffer).context("parsing msgpack message")?;
+            trace!(message = ?Logged(&message, &self.redaction), "<- {}", self.tag);
+            return Ok(Some(message));
+        }
+
+        let bytes = self.buffer.as_slice();
+        let bytes = bytes.strip_prefix(BOM).unwrap_or(bytes);
+        if let Some(limits) = self.limits {
+            limits.check(bytes)?;
+        }
+        let body = str::from_utf8(bytes)
+            .with_context(|| {
+                let lossy_utf8 = String::from_utf8_lossy(bytes);
+                format!("parsing body `{lossy_utf8}`")
+            })
+            .context("parsing LSP message")?;
+
+        // handle batches
+        if body.starts_with('[') {
+            self.batch = serde_json::from_str(body)
+                .with_context(|| format!("parsing body `{body}`"))
+                .context("parsing LSP message")?;
+            // we're popping the messages from the end of the vec
+            self.batch.reverse();
+            let message = self.batch.pop().context("received an empty batch")?;
+            trace!(message = ?Logged(&message, &self.redaction), "<- {}", self.tag);
+            Ok(Some(message))
+        } else {
+            let message = serde_json::from_str(body)
+                .with_context(|| format!("parsing body `{body}`"))
+                .context("parsing LSP message")?;
+            trace!(message = ?Logged(&message, &self.redaction), "<- {}", self.tag);
+            Ok(Some(message))
+        }
+    }
+}
+
+/// Skip `length` bytes feeding them to `scanner`, returns `false` if the
+/// reader was closed
+async fn skip<R>(
+    reader: &mut R,
+    mut length: usize,
+    mut scanner: Option<&mut Scanner>,
+) -> Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    while length > 0 {
+        let chunk = match reader.fill_buf().await {
+            Ok([]) => return Ok(false),
+            Ok(chunk) => chunk,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) if is_closed(&err) => return Ok(false),
+            Err(err) => bail!(err),
+        };
+        let chunk = &chunk[..chunk.len().min(length)];
+        if let Some(scanner) = &mut scanner {
+            scanner.feed(chunk);
+        }
+        let read = chunk.len();
+        reader.consume(read);
+        length -= read;
+    }
+    Ok(true)
+}
+
+/// Whether the error means the reader was closed, no need to log it
+fn is_closed(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/// Fill `buffer`, returns `false` if the reader was closed
+///
+/// Interrupted reads are retried without losing what was already read.
+async fn read_exact<R>(reader: &mut R, buffer: &mut [u8]) -> Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await {
+            Ok(0) => return Ok(false),
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) if is_closed(&err) => return Ok(false),
+            Err(err) => bail!(err),
+        }
+    }
+    Ok(true)
+}
+
+/// Write all of `bytes`, retrying interrupted and continuing short writes
+async fn write_all<W>(writer: &mut W, mut bytes: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while !bytes.is_empty() {
+        match writer.write(bytes).await {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => bytes = &bytes[written..],
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+async fn flush<W>(writer: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        match writer.flush().await {
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            res => return res,
+        }
+    }
+}
+
+pub struct LspWriter<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    tag: &'static str,
+    redaction: Arc<Redaction>,
+    bytes: u64,
+    messages: u64,
+    encoding: WireEncoding,
+    compression: Compression,
+}
+
+impl<W> LspWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(writer: W, tag: &'static str) -> Self {
+        LspWriter {
+            writer,
+            buffer: Vec::with_capacity(1024),
+            tag,
+            redaction: Arc::default(),
+            bytes: 0,
+            messages: 0,
+            encoding: WireEncoding::Lsp,
+            compression: Compression::Off,
+        }
+    }
+
+    /// Total size of message bodies written so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Number of messages written so far
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
+
+    /// Write the following messages in `encoding`
+    pub fn set_encoding(&mut self, encoding: WireEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Compress the following messages
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Hide what `redaction` hides in the logged messages
+    pub fn set_redaction(&mut self, redaction: Arc<Redaction>) {
+        self.redaction = redaction;
+    }
+
+    /// Messages are written in the LSP encoding without compression, parts of
+    /// streamed messages can be passed on as they are
+    pub fn is_plain(&self) -> bool {
+        self.encoding.is_lsp() && self.compression.is_off()
+    }
+
+    /// Write a message or a part of a streamed one
+    ///
+    /// Headers and chunks can only be written by a plain writer, see
+    /// [`is_plain`](Self::is_plain).
+    pub async fn write_part(&mut self, part: &Part) -> io::Result<()> {
+        match part {
+            Part::Message(message) => self.write_message(message).await,
+            Part::Header(length) => {
+                debug_assert!(self.is_plain(), "BUG: streaming to {}", self.tag);
+                trace!(length, "-> {} streaming message", self.tag);
+                let header = format!("Content-Length: {length}\r\n\r\n");
+                write_all(&mut self.writer, header.as_bytes()).await?;
+                self.messages += 1;
+                Ok(())
+            }
+            Part::Chunk(chunk) => {
+                write_all(&mut self.writer, chunk).await?;
+                self.bytes += chunk.len() as u64;
+                flush(&mut self.writer).await
+            }
+        }
+    }
+
+    /// serialize LSP message into a writer, prepending the appropriate content-length header
+    pub async fn write_message(&mut self, message: &Message) -> io::Result<()> {
+        trace!(message = ?Logged(message, &self.redaction), "-> {}", self.tag);
+
+        self.buffer.clear();
+        match self.encoding {
+            WireEncoding::Lsp => {
+                serde_json::to_writer(&mut self.buffer, message).expect("BUG: invalid message");
+            }
+            WireEncoding::Msgpack => {
+                rmp_serde::encode::write_named(&mut self.buffer, message)
+                    .expect("BUG: invalid message");
+            }
+        }
+        if let Compression::Zstd(level) = self.compression {
+            self.buffer = zstd::bulk::compress(&self.buffer, level)?;
+        }
+        match self.encoding {
+            WireEncoding::Lsp => {
+                let header = format!("Content-Length: {}\r\n\r\n", self.buffer.len());
+                write_all(&mut self.writer, header.as_bytes()).await?;
+            }
+            WireEncoding::Msgpack => {
+                let length = u32::try_from(self.buffer.len())
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "message too large"))?;
+                write_all(&mut self.writer, &length.to_be_bytes()).await?;
+            }
+        }
+        write_all(&mut self.writer, &self.buffer).await?;
+        self.bytes += self.buffer.len() as u64;
+        self.messages += 1;
+        flush(&mut self.writer).await
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Flush and shut down the writer, the reading end sees the stream ending
+    /// after everything written so far
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        flush(&mut self.writer).await?;
+        loop {
+            match self.writer.shutdown().await {
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use serde_json::json;
+    use tokio::io::{AsyncRead, BufReader, ReadBuf};
+
+    use super::*;
+    use crate::lsp::jsonrpc::{
+        self, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+    };
+
+    #[tokio::test]
+    async fn msgpack_roundtrip() {
+        let messages: [Message; 4] = [
+            Request {
+                jsonrpc: Version,
+                method: "textDocument/hover".into(),
+                params: json!({ "position": { "line": 1, "character": 2 } }),
+                id: RequestId::String("client:1".into()),
+            }
+            .into(),
+            Notification {
+                jsonrpc: Version,
+                method: "initialized".into(),
+                params: json!({}),
+            }
+            .into(),
+            ResponseError {
+                jsonrpc: Version,
+                error: jsonrpc::Error {
+                    code: -32603,
+                    message: "panicked".into(),
+                    data: None,
+                },
+                id: RequestId::Number(3),
+            }
+            .into(),
+            ResponseSuccess::null(RequestId::Number(4)).into(),
+        ];
+        let mut writer = LspWriter::new(Vec::new(), "test");
+        writer.set_encoding(WireEncoding::Msgpack);
+        for message in &messages {
+            writer.write_message(message).await.unwrap();
+        }
+
+        // Each message is prefixed with its big-endian length.
+        let data = writer.writer;
+        let length = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let first: Message = rmp_serde::from_slice(&data[4..4 + length]).unwrap();
+        assert!(matches!(first, Message::Request(req) if req.method == "textDocument/hover"));
+
+        let mut reader = LspReader::new(data.as_slice(), "test");
+        reader.set_encoding(WireEncoding::Msgpack);
+        for message in messages {
+            let decoded = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(
+                serde_json::to_value(decoded).unwrap(),
+                serde_json::to_value(message).unwrap(),
+            );
+        }
+        assert!(reader.read_message().await.unwrap().is_none());
+        assert_eq!(reader.messages(), 4);
+    }
+
+    #[tokio::test]
+    async fn lenient_headers() {
+        let body = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let len = body.len();
+        let input = [
+            format!("Content-Length: {len}\r\n\r\n{body}"),
+            // The byte order mark of the body counts towards its length.
+            format!("\u{feff}content-length: {}\n\n\u{feff}{body}", len + 3),
+            format!("\r\nCONTENT-LENGTH:{len}\r\nX-Custom: 1\r\n\r\n{body}"),
+            format!(
+                "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n\
+                 Content-Length: {len}\r\n\r\n{body}"
+            ),
+        ];
+        for input in input {
+            let mut reader = LspReader::new(input.as_bytes(), "test");
+            let message = reader.read_message().await.unwrap();
+            assert!(
+                matches!(message, Some(Message::Notification(notif)) if notif.method == "exit"),
+                "{input:?}",
+            );
+            assert!(reader.read_message().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_rejected_messages() {
+        let large = format!(r#"{{"id":1,"params":"{}"}}"#, "x".repeat(100));
+        let deep = r#"{"id":2,"params":[[[[]]]]}"#;
+        let ok = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = [large.as_str(), deep, ok]
+            .map(|body| format!("Content-Length: {}\r\n\r\n{body}", body.len()))
+            .concat();
+
+        let mut reader = LspReader::new(input.as_bytes(), "test");
+        reader.set_limits(Limits {
+            max_message_size: 64,
+            max_depth: 3,
+            max_array_length: 10,
+        });
+        for id in [1, 2] {
+            let err = reader.read_message().await.unwrap_err();
+            let rejected = err.downcast_ref::<jsonrpc::Rejected>().unwrap();
+            assert_eq!(rejected.id, Some(RequestId::Number(id)));
+        }
+        let Some(Message::Notification(notif)) = reader.read_message().await.unwrap() else {
+            panic!("expected a notification");
+        };
+        assert_eq!(notif.method, "exit");
+    }
+
+    #[tokio::test]
+    async fn stream_large_messages() {
+        let small = r#"{"jsonrpc":"2.0","method":"exit","params":null}"#;
+        let large = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"result":"{}"}}"#,
+            "x".repeat(3 * CHUNK_SIZE)
+        );
+        let input = [small, &large, small]
+            .map(|body| format!("Content-Length: {}\r\n\r\n{body}", body.len()))
+            .concat();
+
+        let mut reader = LspReader::new(input.as_bytes(), "test");
+        reader.set_stream_size(Some(1024));
+        let mut writer = LspWriter::new(Vec::new(), "test");
+        let mut parts = Vec::new();
+        while let Some(part) = reader.read_part().await.unwrap() {
+            writer.write_part(&part).await.unwrap();
+            parts.push(match part {
+                Part::Message(_) => "message",
+                Part::Header(length) => {
+                    assert_eq!(length, large.len());
+                    "header"
+                }
+                Part::Chunk(chunk) => {
+                    assert!(chunk.len() <= CHUNK_SIZE);
+                    "chunk"
+                }
+            });
+        }
+        assert_eq!(
+            parts,
+            ["message", "header", "chunk", "chunk", "chunk", "chunk", "message"],
+        );
+        assert_eq!(reader.messages(), 3);
+        assert_eq!(writer.messages(), 3);
+        assert_eq!(String::from_utf8(writer.writer).unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn zstd_roundtrip() {
+        let message: Message = Notification {
+            jsonrpc: Version,
+            method: "textDocument/didOpen".into(),
+            params: json!({ "text": "fn main() {}\n".repeat(100) }),
+        }
+        .into();
+        for encoding in [WireEncoding::Lsp, WireEncoding::Msgpack] {
+            let mut writer = LspWriter::new(Vec::new(), "test");
+            writer.set_encoding(encoding);
+            writer.set_compression(Compression::Zstd(3));
+            writer.write_message(&message).await.unwrap();
+            assert!(writer.bytes() < 200, "{encoding:?} not compressed");
+
+            let data = writer.writer;
+            let mut reader = LspReader::new(data.as_slice(), "test");
+            reader.set_encoding(encoding);
+            reader.set_compression(Compression::Zstd(1));
+            let decoded = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(
+                serde_json::to_value(decoded).unwrap(),
+                serde_json::to_value(&message).unwrap(),
+            );
+        }
+
+        let compressed = zstd::bulk::compress(&[b'x'; 1000], 3).unwrap();
+        assert!(decompress(&compressed, 1000).is_ok());
+        assert!(decompress(&compressed, 999).is_err());
+        assert_eq!(Compression::Zstd(22).accepted(), Compression::Zstd(19));
+    }
+
+    /// Peer interrupting every other call and reading or writing at most 3
+    /// bytes at a time
+    #[derive(Default)]
+    struct Flaky {
+        data: Vec<u8>,
+        read: usize,
+        calls: usize,
+        shut_down: bool,
+    }
+
+    impl Flaky {
+        fn interrupt(&mut self) -> bool {
+            self.calls += 1;
+            self.calls % 2 == 1
+        }
+    }
+
+    impl AsyncRead for Flaky {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.interrupt() {
+                return Poll::Ready(Err(ErrorKind::Interrupted.into()));
+            }
+            let start = self.read;
+            let end = self.data.len().min(start + 3).min(start + buf.remaining());
+            buf.put_slice(&self.data[start..end]);
+            self.read = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Flaky {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            assert!(!self.shut_down, "write after shutdown");
+            if self.interrupt() {
+                return Poll::Ready(Err(ErrorKind::Interrupted.into()));
+            }
+            let written = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..written]);
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.interrupt() {
+                true => Poll::Ready(Err(ErrorKind::Interrupted.into())),
+                false => Poll::Ready(Ok(())),
+            }
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            if self.interrupt() {
+                return Poll::Ready(Err(ErrorKind::Interrupted.into()));
+            }
+            self.shut_down = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_and_short_io() {
+        let messages: [Message; 2] = [
+            Request {
+                jsonrpc: Version,
+                method: "textDocument/hover".into(),
+                params: json!({ "text": "ünïcödé ".repeat(20) }),
+                id: RequestId::Number(1),
+            }
+            .into(),
+            ResponseSuccess::null(RequestId::Number(1)).into(),
+        ];
+
+        let mut writer = LspWriter::new(Flaky::default(), "test");
+        for message in &messages {
+            writer.write_message(message).await.unwrap();
+        }
+        // The peer only shuts down its write side, what it sent is still read.
+        writer.shutdown().await.unwrap();
+        let peer = std::mem::take(writer.get_mut());
+        assert!(peer.shut_down);
+
+        let mut reader = LspReader::new(BufReader::with_capacity(4, peer), "test");
+        for message in &messages {
+            let read = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(
+                serde_json::to_value(read).unwrap(),
+                serde_json::to_value(message).unwrap(),
+            );
+        }
+        assert!(reader.read_message().await.unwrap().is_none());
+    }
+}
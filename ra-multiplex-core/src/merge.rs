@@ -0,0 +1,442 @@
+//! Merging messages from multiple language server instances
+//!
+//! A client can be connected to more than one instance at once. Some requests
+//! are then sent to all of them and their responses are merged into a single
+//! response for the client. Diagnostics published by different instances for
+//! the same file and their server statuses are merged as well, otherwise
+//! they'd keep replacing each other in the editor.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{Map, Value};
+
+use crate::config::{Config, MergeStrategy};
+use crate::lsp::ext::Tag;
+use crate::lsp::jsonrpc::{Message, Notification, RequestId, ResponseError, ResponseSuccess};
+
+/// Select the merge strategy for requests which should be sent to all
+/// instances, returns `None` for requests handled by a single instance
+pub fn strategy(config: &Config, method: &str) -> Option<MergeStrategy> {
+    if config.is_passthrough(method) {
+        return None;
+    }
+    if let Some(&strategy) = config.merge_strategies.get(method) {
+        return Some(strategy);
+    }
+    match method {
+        "workspace/symbol" | "textDocument/references" => Some(MergeStrategy::Concat),
+        _ => None,
+    }
+}
+
+/// Responses to a request sent to multiple instances
+struct PendingMerge {
+    strategy: MergeStrategy,
+
+    /// Responses indexed by the instance they came from
+    responses: Vec<Option<Result<ResponseSuccess, ResponseError>>>,
+}
+
+/// Merge state shared by all instances connected to a single client
+#[derive(Clone, Default)]
+pub struct Merges(Arc<Mutex<MergeState>>);
+
+#[derive(Default)]
+struct MergeState {
+    /// Requests waiting for responses from multiple instances
+    pending: HashMap<RequestId, PendingMerge>,
+
+    /// Latest diagnostics for each URI by the PID of the instance which
+    /// published them
+    diagnostics: HashMap<String, BTreeMap<u32, Value>>,
+
+    /// Latest `experimental/serverStatus` params by the PID of the instance
+    /// which sent them
+    server_status: BTreeMap<u32, Value>,
+}
+
+impl Merges {
+    /// Start waiting for responses to request `id` sent to `count` instances
+    ///
+    /// Each instance must receive the request with ID tagged with
+    /// [`Tag::Merge`] containing the instance index. Returns `false` without
+    /// starting if a merge for `id` is still waiting, the responses couldn't
+    /// be told apart.
+    pub fn start(&self, id: RequestId, strategy: MergeStrategy, count: usize) -> bool {
+        let pending = PendingMerge {
+            strategy,
+            responses: vec![None; count],
+        };
+        match self.0.lock().unwrap().pending.entry(id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(pending);
+                true
+            }
+        }
+    }
+
+    /// Number of instances the pending merge of request `id` was sent to,
+    /// `None` if there is none
+    pub fn instances(&self, id: &RequestId) -> Option<usize> {
+        let state = self.0.lock().unwrap();
+        Some(state.pending.get(id)?.responses.len())
+    }
+
+    /// Process a response before it's forwarded to the client
+    ///
+    /// Returns the response unchanged if it's not part of a merge, `None` if
+    /// the merge is still waiting for other instances or the merged response
+    /// once all instances responded.
+    pub fn response(&self, message: Message) -> Option<Message> {
+        let tagged_id = match &message {
+            Message::ResponseSuccess(res) => &res.id,
+            Message::ResponseError(res) => &res.id,
+            _ => return Some(message),
+        };
+        let (index, id) = match tagged_id {
+            RequestId::String(string) if string.starts_with("merge:") => match tagged_id.untag() {
+                (Some(Tag::Merge(index)), id) => (index, id),
+                _ => return Some(message),
+            },
+            _ => return Some(message),
+        };
+
+        let mut state = self.0.lock().unwrap();
+        let Some(pending) = state.pending.get_mut(&id) else {
+            return Some(message);
+        };
+        let response = match message {
+            Message::ResponseSuccess(res) => Ok(res),
+            Message::ResponseError(res) => Err(res),
+            _ => unreachable!(),
+        };
+        if let Some(slot) = pending.responses.get_mut(index) {
+            *slot = Some(response);
+        }
+        if pending.responses.iter().any(Option::is_none) {
+            return None;
+        }
+        let pending = state.pending.remove(&id).unwrap();
+        let responses = pending.responses.into_iter().flatten().collect();
+        Some(merge(pending.strategy, id, responses))
+    }
+
+    /// Replace the diagnostics in a `textDocument/publishDiagnostics`
+    /// notification with diagnostics for the same file from all instances
+    pub fn diagnostics(&self, pid: u32, mut notif: Notification) -> Notification {
+        let Some(uri) = notif.params.get("uri").and_then(Value::as_str) else {
+            return notif;
+        };
+        let diagnostics = notif.params.get("diagnostics").cloned();
+        let diagnostics = diagnostics.unwrap_or_else(|| Value::Array(Vec::new()));
+
+        let mut state = self.0.lock().unwrap();
+        let sources = state.diagnostics.entry(uri.to_owned()).or_default();
+        sources.insert(pid, diagnostics);
+        // Only merge if more than one instance knows about the file.
+        let merged = (sources.len() > 1).then(|| {
+            let merged = sources
+                .values()
+                .filter_map(Value::as_array)
+                .flatten()
+                .cloned()
+                .collect();
+            Value::Array(merged)
+        });
+        sources.retain(|_, diagnostics| !is_empty_array(diagnostics));
+        if sources.is_empty() {
+            state.diagnostics.remove(uri);
+        }
+
+        if let Some(merged) = merged {
+            notif.params["diagnostics"] = merged;
+        }
+        notif
+    }
+
+    /// Replace the status in a `experimental/serverStatus` notification with
+    /// the combined status of all instances
+    ///
+    /// The combined health is the worst one, the servers are quiescent only if
+    /// all of them are and the messages are joined.
+    pub fn server_status(&self, pid: u32, mut notif: Notification) -> Notification {
+        let mut state = self.0.lock().unwrap();
+        state.server_status.insert(pid, notif.params.clone());
+        if state.server_status.len() < 2 {
+            return notif;
+        }
+
+        let statuses = state.server_status.values();
+        let health = statuses
+            .clone()
+            .filter_map(|status| status.get("health").and_then(Value::as_str))
+            .max_by_key(|health| match *health {
+                "ok" => 0,
+                "warning" => 1,
+                _ => 2,
+            })
+            .unwrap_or("ok");
+        let quiescent = statuses
+            .clone()
+            .all(|status| status.get("quiescent") != Some(&Value::Bool(false)));
+        let messages = statuses
+            .filter_map(|status| status.get("message").and_then(Value::as_str))
+            .filter(|message| !message.is_empty())
+            .collect::<Vec<_>>();
+
+        let mut params = serde_json::json!({ "health": health, "quiescent": quiescent });
+        if !messages.is_empty() {
+            params["message"] = messages.join("\n").into();
+        }
+        notif.params = params;
+        notif
+    }
+}
+
+fn is_empty_array(value: &Value) -> bool {
+    value.as_array().is_some_and(Vec::is_empty)
+}
+
+/// Merge responses from all instances into one
+///
+/// Responses are ordered by the instance index. Errors are ignored unless all
+/// instances responded with an error, then the first one is returned.
+fn merge(
+    strategy: MergeStrategy,
+    id: RequestId,
+    responses: Vec<Result<ResponseSuccess, ResponseError>>,
+) -> Message {
+    let mut results = Vec::new();
+    let mut first_error = None;
+    for response in responses {
+        match response {
+            Ok(res) => results.push(res.result),
+            Err(res) => {
+                first_error.get_or_insert(res);
+            }
+        }
+    }
+
+    if results.is_empty() {
+        if let Some(mut error) = first_error {
+            error.id = id;
+            return error.into();
+        }
+    }
+
+    let result = match strategy {
+        MergeStrategy::Concat => {
+            if results.iter().all(Value::is_null) {
+                Value::Null
+            } else {
+                let items = results
+                    .into_iter()
+                    .flat_map(|result| match result {
+                        Value::Array(items) => items,
+                        Value::Null => Vec::new(),
+                        other => vec![other],
+                    })
+                    .collect();
+                Value::Array(items)
+            }
+        }
+        MergeStrategy::FirstNonNull => results
+            .into_iter()
+            .find(|result| !result.is_null())
+            .unwrap_or(Value::Null),
+    };
+    let mut res = ResponseSuccess::null(id);
+    res.result = result;
+    res.into()
+}
+
+/// Apply a JSON merge patch to `target` as described in RFC 7386
+///
+/// Objects are merged recursively, `null` removes a member and any other
+/// value replaces the target's.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!();
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{self, Version};
+
+    fn success(index: usize, result: Value) -> Message {
+        ResponseSuccess {
+            jsonrpc: Version,
+            result,
+            id: RequestId::Number(1).tag(Tag::Merge(index)),
+        }
+        .into()
+    }
+
+    fn error(index: usize) -> Message {
+        ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: -32603,
+                message: "internal error".into(),
+                data: None,
+            },
+            id: RequestId::Number(1).tag(Tag::Merge(index)),
+        }
+        .into()
+    }
+
+    fn result(message: Message) -> Value {
+        match message {
+            Message::ResponseSuccess(res) => {
+                assert_eq!(res.id, RequestId::Number(1));
+                res.result
+            }
+            other => panic!("expected success response, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn concat_waits_for_all_instances() {
+        let merges = Merges::default();
+        assert!(merges.start(RequestId::Number(1), MergeStrategy::Concat, 2));
+        assert!(!merges.start(RequestId::Number(1), MergeStrategy::Concat, 2));
+
+        assert!(merges.response(success(1, json!([3]))).is_none());
+        let merged = merges.response(success(0, json!([1, 2]))).unwrap();
+        assert_eq!(result(merged), json!([1, 2, 3]));
+        assert!(merges.start(RequestId::Number(1), MergeStrategy::Concat, 2));
+    }
+
+    #[test]
+    fn concat_ignores_errors_and_nulls() {
+        let merges = Merges::default();
+        merges.start(RequestId::Number(1), MergeStrategy::Concat, 3);
+
+        assert!(merges.response(error(0)).is_none());
+        assert!(merges.response(success(1, Value::Null)).is_none());
+        let merged = merges.response(success(2, json!([1]))).unwrap();
+        assert_eq!(result(merged), json!([1]));
+    }
+
+    #[test]
+    fn first_non_null_in_instance_order() {
+        let merges = Merges::default();
+        merges.start(RequestId::Number(1), MergeStrategy::FirstNonNull, 3);
+
+        assert!(merges.response(success(2, json!("c"))).is_none());
+        assert!(merges.response(success(1, json!("b"))).is_none());
+        let merged = merges.response(success(0, Value::Null)).unwrap();
+        assert_eq!(result(merged), json!("b"));
+    }
+
+    #[test]
+    fn all_errors_forward_error() {
+        let merges = Merges::default();
+        merges.start(RequestId::Number(1), MergeStrategy::Concat, 2);
+
+        assert!(merges.response(error(0)).is_none());
+        match merges.response(error(1)).unwrap() {
+            Message::ResponseError(res) => assert_eq!(res.id, RequestId::Number(1)),
+            other => panic!("expected error response, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn untagged_responses_pass_through() {
+        let merges = Merges::default();
+        let res = ResponseSuccess::null(RequestId::Number(1));
+        assert!(merges.response(res.into()).is_some());
+    }
+
+    #[test]
+    fn diagnostics_from_multiple_instances() {
+        let merges = Merges::default();
+        let publish = |diagnostics: Value| Notification {
+            jsonrpc: Version,
+            method: "textDocument/publishDiagnostics".into(),
+            params: json!({ "uri": "file:///a.rs", "diagnostics": diagnostics }),
+        };
+
+        let notif = merges.diagnostics(1, publish(json!(["a"])));
+        assert_eq!(notif.params["diagnostics"], json!(["a"]));
+        let notif = merges.diagnostics(2, publish(json!(["b"])));
+        assert_eq!(notif.params["diagnostics"], json!(["a", "b"]));
+        let notif = merges.diagnostics(1, publish(json!([])));
+        assert_eq!(notif.params["diagnostics"], json!(["b"]));
+    }
+
+    #[test]
+    fn server_status_from_multiple_instances() {
+        let merges = Merges::default();
+        let status = |params: Value| Notification {
+            jsonrpc: Version,
+            method: "experimental/serverStatus".into(),
+            params,
+        };
+
+        let notif = merges.server_status(1, status(json!({ "health": "ok", "quiescent": false })));
+        assert_eq!(notif.params, json!({ "health": "ok", "quiescent": false }));
+        let notif = merges.server_status(
+            2,
+            status(json!({ "health": "warning", "quiescent": true, "message": "no cargo" })),
+        );
+        assert_eq!(
+            notif.params,
+            json!({ "health": "warning", "quiescent": false, "message": "no cargo" }),
+        );
+        let notif = merges.server_status(1, status(json!({ "health": "ok", "quiescent": true })));
+        assert_eq!(notif.params["quiescent"], json!(true));
+        assert_eq!(notif.params["health"], json!("warning"));
+    }
+
+    #[test]
+    fn apply_merge_patch() {
+        let mut target = json!({
+            "cargo": { "features": "all", "targetDir": true },
+            "checkOnSave": true,
+            "files": ["a"],
+        });
+        merge_patch(
+            &mut target,
+            &json!({
+                "cargo": { "targetDir": "target/ra" },
+                "checkOnSave": null,
+                "files": ["b"],
+                "procMacro": { "enable": true },
+            }),
+        );
+        assert_eq!(
+            target,
+            json!({
+                "cargo": { "features": "all", "targetDir": "target/ra" },
+                "files": ["b"],
+                "procMacro": { "enable": true },
+            }),
+        );
+
+        let mut target = json!("a");
+        merge_patch(&mut target, &json!({ "b": { "c": null } }));
+        assert_eq!(target, json!({ "b": {} }));
+    }
+}
@@ -0,0 +1,192 @@
+//! Mirroring the traffic of language server instances
+//!
+//! Every message exchanged with the server is written as a JSON line to a Unix
+//! socket or the stdin of a command while the instance runs, so external tools
+//! can observe a session live. The mirror never holds up the server, messages
+//! are dropped while the sink can't keep up or isn't connected.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio::{process, task};
+use tracing::{debug, warn, Instrument};
+
+use crate::lsp::jsonrpc::Message;
+use crate::lsp::redact::Redaction;
+
+/// Number of messages buffered for a slow sink before they're dropped
+const BUFFER: usize = 1024;
+
+/// How long to wait before connecting to the socket again after it went away
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where mirrored messages are written
+#[derive(Debug, Clone)]
+pub enum Sink {
+    /// Unix socket, connected again when the listener restarts
+    Socket(String),
+    /// Command started with the instance, reading messages from stdin
+    Command(Vec<String>),
+}
+
+pub struct Mirror {
+    lines: mpsc::Sender<String>,
+    dropped: AtomicU64,
+
+    /// What is hidden in the mirrored messages
+    redaction: Arc<Redaction>,
+}
+
+impl Mirror {
+    /// Start writing mirrored messages to `sink`
+    pub fn start(sink: Sink, redaction: Arc<Redaction>) -> Mirror {
+        let (lines, rx) = mpsc::channel(BUFFER);
+        task::spawn(write_task(sink, rx).in_current_span());
+        Mirror {
+            lines,
+            dropped: AtomicU64::new(0),
+            redaction,
+        }
+    }
+
+    /// Mirror a message written to the server
+    pub fn sent(&self, message: &Message) {
+        self.mirror("to_server", message);
+    }
+
+    /// Mirror a message read from the server
+    pub fn received(&self, message: &Message) {
+        self.mirror("from_server", message);
+    }
+
+    fn mirror(&self, direction: &str, message: &Message) {
+        if self.lines.is_closed() {
+            return;
+        }
+        let line = json!({
+            "time": time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000,
+            "direction": direction,
+            "message": self.redaction.redacted(message),
+        });
+        if self.lines.try_send(format!("{line}\n")).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if dropped.is_power_of_two() {
+                debug!(
+                    dropped = dropped + 1,
+                    "mirror sink is behind, dropping messages"
+                );
+            }
+        }
+    }
+}
+
+async fn write_task(sink: Sink, mut lines: mpsc::Receiver<String>) {
+    let mut output = None;
+    let mut retry = Instant::now();
+    while let Some(line) = lines.recv().await {
+        if output.is_none() {
+            if Instant::now() < retry {
+                continue;
+            }
+            match open(&sink).await {
+                Ok(opened) => output = Some(opened),
+                Err(err) => {
+                    warn!(?err, ?sink, "opening mirror sink");
+                    match sink {
+                        Sink::Socket(_) => {
+                            retry = Instant::now() + RECONNECT_INTERVAL;
+                            continue;
+                        }
+                        Sink::Command(_) => return,
+                    }
+                }
+            }
+        }
+        let Some((writer, _)) = &mut output else {
+            continue;
+        };
+        if let Err(err) = writer.write_all(line.as_bytes()).await {
+            debug!(?err, ?sink, "mirror sink closed");
+            output = None;
+            match sink {
+                Sink::Socket(_) => retry = Instant::now() + RECONNECT_INTERVAL,
+                Sink::Command(_) => return,
+            }
+        }
+    }
+}
+
+type Output = (Box<dyn AsyncWrite + Send + Unpin>, Option<process::Child>);
+
+/// Connect to the socket or start the command, the child is killed when it's
+/// dropped
+async fn open(sink: &Sink) -> Result<Output> {
+    match sink {
+        #[cfg(unix)]
+        Sink::Socket(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .context("connecting")?;
+            Ok((Box::new(stream), None))
+        }
+        #[cfg(not(unix))]
+        Sink::Socket(_) => bail!("mirror sockets are only supported on unix"),
+        Sink::Command(command) => {
+            let Some((program, args)) = command.split_first() else {
+                bail!("empty command");
+            };
+            let mut child = process::Command::new(program)
+                .args(args)
+                .stdin(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .context("spawning")?;
+            let stdin = child.stdin.take().context("no stdin")?;
+            Ok((Box::new(stdin), Some(child)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::io::AsyncBufReadExt;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{Notification, Version};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mirror_to_socket() {
+        let dir = std::env::temp_dir().join(format!("mirror-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mirror.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let mirror = Mirror::start(Sink::Socket(path.display().to_string()), Arc::default());
+        let message = Message::from(Notification {
+            jsonrpc: Version,
+            method: "initialized".into(),
+            params: json!({}),
+        });
+        mirror.sent(&message);
+        mirror.received(&message);
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = tokio::io::BufReader::new(stream).lines();
+        for direction in ["to_server", "from_server"] {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let line: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(line["direction"], direction);
+            assert_eq!(line["message"]["method"], "initialized");
+            assert!(line["time"].is_i64());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+//! Where config files are looked up and state is kept
+//!
+//! The config is layered from up to three files, later ones override single
+//! settings of earlier ones:
+//!
+//! 1. the system config, `/etc/ra-multiplex/config.toml` or
+//!    `%PROGRAMDATA%\ra-multiplex\config.toml` on Windows
+//! 2. the user config in the platform config directory, `$XDG_CONFIG_HOME` on
+//!    Linux and `%APPDATA%` on Windows
+//! 3. the project config, the nearest `.ra-multiplex.toml` in the current
+//!    directory or one of its parents, limited to the
+//!    [`PROJECT_SETTINGS`](crate::config::PROJECT_SETTINGS) unless the others
+//!    set `trust_project_config`
+//!
+//! Crash reports, the audit log and the servers proxies recently couldn't
+//! reach are kept in the state directory, the shim sockets and the server's
+//! pidfile in the runtime directory. Platforms without them use the local
+//! data directory. `ra-multiplex paths` prints them all.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+
+/// File name of the project config
+pub const PROJECT_CONFIG: &str = ".ra-multiplex.toml";
+
+fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("", "", crate::APP_NAME).context("project directories not found")
+}
+
+/// Path of the system config file
+pub fn system_config() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let dir = PathBuf::from(env::var_os("PROGRAMDATA")?);
+    #[cfg(not(windows))]
+    let dir = PathBuf::from("/etc");
+    Some(dir.join(crate::APP_NAME).join("config.toml"))
+}
+
+/// Directory of the user config file
+pub fn config_dir() -> Result<PathBuf> {
+    Ok(project_dirs()?.config_dir().to_owned())
+}
+
+/// Path of the user config file
+pub fn user_config() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.toml"))
+}
+
+/// Path of the nearest project config in `dir` or its parents
+pub fn project_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+/// Existing config files from the first layer to the last
+pub fn config_files() -> Vec<PathBuf> {
+    let cwd = env::current_dir().ok();
+    let project = cwd.as_deref().and_then(project_config);
+    [system_config(), user_config().ok(), project]
+        .into_iter()
+        .flatten()
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Directory of state kept across restarts, like crash reports and logs
+pub fn state_dir() -> Result<PathBuf> {
+    let dirs = project_dirs()?;
+    Ok(dirs.state_dir().unwrap_or(dirs.data_local_dir()).to_owned())
+}
+
+/// Directory of the reports saved when language servers crash
+pub fn crash_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("crashes"))
+}
+
+/// Path of the log written with `audit_log`
+pub fn audit_log() -> Result<PathBuf> {
+    Ok(state_dir()?.join("audit.log"))
+}
+
+/// Directory of sockets and the pidfile, they're useless after a reboot
+pub fn runtime_dir() -> Result<PathBuf> {
+    match project_dirs()?.runtime_dir() {
+        Some(dir) => Ok(dir.to_owned()),
+        None => state_dir(),
+    }
+}
+
+/// Directory of data kept until the user removes it, like statistics
+pub fn data_dir() -> Result<PathBuf> {
+    Ok(project_dirs()?.data_local_dir().to_owned())
+}
+
+/// Directory of downloads which can be fetched again
+pub fn cache_dir() -> Result<PathBuf> {
+    Ok(project_dirs()?.cache_dir().to_owned())
+}
+
+/// Path of the pidfile written by `ra-multiplex server`
+pub fn pidfile() -> Result<PathBuf> {
+    Ok(runtime_dir()?.join("server.pid"))
+}
+
+/// Pidfile removed again when the server stops
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create() -> Result<Self> {
+        let path = pidfile()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))?;
+        }
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("writing {path:?}"))?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_project_config() {
+        let dir = env::temp_dir().join(format!("ra-mux-paths-{}", std::process::id()));
+        let nested = dir.join("project").join("src");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(project_config(&nested), None);
+
+        std::fs::write(dir.join(PROJECT_CONFIG), "").unwrap();
+        assert_eq!(project_config(&nested), Some(dir.join(PROJECT_CONFIG)));
+        let project = dir.join("project").join(PROJECT_CONFIG);
+        std::fs::write(&project, "").unwrap();
+        assert_eq!(project_config(&nested), Some(project));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
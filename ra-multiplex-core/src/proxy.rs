@@ -0,0 +1,1235 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{io as std_io, slice};
+
+use anyhow::{bail, Context as _, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs;
+use tokio::io::{self, AsyncBufRead, AsyncWrite, BufReader};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{Address, Config};
+use crate::lsp::ext::{self, ClientMode, ErrorCode, LspMuxOptions, Request};
+use crate::lsp::jsonrpc::{self, Message, Notification, Version};
+use crate::lsp::redact::Redaction;
+use crate::lsp::transport::{Compression, LspReader, LspWriter, Part, WireEncoding};
+use crate::lsp::{InitializationOptions, InitializeParams};
+use crate::paths;
+use crate::resume::Replay;
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf, Stream};
+
+/// Initial delay between connection attempts, doubles after every attempt
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Upper bound for the delay between connection attempts
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Time a server has to accept a connection before it's considered down and
+/// the next address is tried
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Seconds a client connected through ssh tries to resume its session without
+/// `reconnect_timeout`, an ssh connection drops more easily than a local one
+const SSH_RECONNECT_TIMEOUT: u32 = 60;
+
+/// Seconds a server a proxy failed to reach is tried after the other ones
+const UNREACHABLE_PENALTY: u64 = 60;
+
+pub async fn run(
+    config: &Config,
+    server: String,
+    args: Vec<String>,
+    observer: bool,
+    follow: Option<usize>,
+    tag: Option<String>,
+) -> Result<()> {
+    let cwd = env::current_dir()
+        .ok()
+        .and_then(|path| path.to_str().map(String::from));
+
+    let mode = match observer {
+        true => ClientMode::Observer,
+        false => ClientMode::Normal,
+    };
+
+    let mut env = BTreeMap::new();
+    for key in &config.pass_environment {
+        if let Ok(val) = std::env::var(key) {
+            env.insert(key.clone(), val);
+        }
+    }
+
+    let redaction = Arc::new(Redaction::new(config));
+    let mut client_reader = LspReader::new(BufReader::new(io::stdin()), "client");
+    client_reader.set_redaction(redaction.clone());
+    let mut client_writer = LspWriter::new(io::stdout(), "client");
+    client_writer.set_redaction(redaction.clone());
+
+    // Wait for the client to send `initialize` request.
+    let mut req = match client_reader
+        .read_message()
+        .await?
+        .context("stdin closed")?
+    {
+        Message::Request(req) if req.method == "initialize" => req,
+        _ => bail!("first client message was not initialize request"),
+    };
+
+    // Patch `initializationOptions` with our own data.
+    let mut params = serde_json::from_value::<InitializeParams>(req.params)
+        .context("parse initialize request params")?;
+    let workspace = params
+        .root_uri
+        .as_deref()
+        .and_then(|uri| crate::client::parse_file_uri(uri).ok())
+        .or_else(|| cwd.clone());
+    let addresses = config.connect_addresses(workspace.as_deref().map(Path::new));
+    // Only a choice of servers needs the record of unreachable ones.
+    let mut health = match addresses.len() {
+        1 => None,
+        _ => Some(Health::load().await),
+    };
+    let addresses = match &health {
+        Some(health) => health.order(addresses, unix_time()),
+        None => addresses.to_vec(),
+    };
+    let over_ssh = addresses
+        .iter()
+        .any(|address| matches!(address, Address::Ssh(_)));
+    let reconnect_timeout = config
+        .reconnect_timeout
+        .or(over_ssh.then_some(SSH_RECONNECT_TIMEOUT));
+    let options = params
+        .initialization_options
+        .get_or_insert_with(InitializationOptions::default)
+        .lsp_mux
+        .get_or_insert_with(|| LspMuxOptions {
+            version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            mode,
+            follow,
+            encoding: config.wire_encoding,
+            compression: config.compression,
+            resumable: reconnect_timeout.is_some(),
+            reconnect_token: None,
+            received: None,
+            method: Request::Connect {
+                server,
+                args,
+                env,
+                cwd,
+                tag,
+            },
+        });
+    let encoding = options.encoding;
+    req.params = serde_json::to_value(&params).expect("BUG: invalid data");
+
+    // Connect only after we have the `initialize` request, the client is
+    // waiting for a response anyway so it doesn't notice we're still retrying.
+    let (connected, (mut server_reader, server_writer, first_message)) = connect_with_retry(
+        &addresses,
+        config.connect_retry,
+        &req.clone().into(),
+        encoding,
+        &redaction,
+    )
+    .await
+    .context("connecting to server")?;
+    if let Some(health) = &mut health {
+        health.update(&addresses, connected, unix_time());
+        health.save().await;
+    }
+    // The session only exists on the server it was started on.
+    let server = slice::from_ref(&addresses[connected]);
+    let mut first_message =
+        forward_notifications(first_message, &mut server_reader, &mut client_writer).await?;
+    if let Message::ResponseError(res) = &mut first_message {
+        explain_error(res);
+    }
+    let token = take_session(&mut first_message).map(|session| session.reconnect_token);
+    client_writer
+        .write_message(&first_message)
+        .await
+        .context("forward message to client")?;
+
+    // Forward everything else, interleaving heartbeat pings into
+    // the messages sent to the server. The client is read and written by
+    // separate tasks so nothing is lost when a connection is.
+    let heartbeat_interval = config
+        .heartbeat_interval
+        .map(|secs| Duration::from_secs(secs.into()));
+    let heartbeat_timeout =
+        heartbeat_interval.map(|_| Duration::from_secs(config.heartbeat_timeout.into()));
+    // Large messages are passed on in chunks without parsing them, a message
+    // cut off by a lost connection couldn't be resent in a resumed session.
+    let stream_size = config.stream_message_size.map(|size| size as usize);
+    if token.is_none() {
+        server_reader.set_stream_size(stream_size);
+        if server_writer.is_plain() {
+            client_reader.set_stream_size(stream_size);
+        }
+    }
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    task::spawn(client_to_server(client_reader, client_tx));
+    let (output_tx, output_rx) = mpsc::channel(16);
+    let output = task::spawn(write_client(output_rx, client_writer));
+
+    let mut state = State {
+        token,
+        sent: Replay::new(0, config.reconnect_buffer),
+        received: 0,
+        input_closed: false,
+        shutdown: false,
+    };
+    let mut connection = (server_reader, server_writer);
+    let res = loop {
+        let res = forward(
+            connection,
+            &mut client_rx,
+            &output_tx,
+            &mut state,
+            (heartbeat_interval, heartbeat_timeout),
+        )
+        .await;
+
+        // Resume the session unless it's over.
+        let token = match &state.token {
+            Some(_) if state.input_closed || state.shutdown || output_tx.is_closed() => break res,
+            Some(token) => token,
+            None => break res,
+        };
+        match &res {
+            Ok(()) => info!("server closed the connection, reconnecting"),
+            Err(err) => info!(?err, "connection to server lost, reconnecting"),
+        }
+        let mut params = params.clone();
+        let options = params
+            .initialization_options
+            .as_mut()
+            .and_then(|options| options.lsp_mux.as_mut())
+            .expect("BUG: missing lspMux options");
+        options.reconnect_token = Some(token.clone());
+        options.received = Some(state.received);
+        let mut req = req.clone();
+        req.params = serde_json::to_value(params).expect("BUG: invalid data");
+        let initialize = req.into();
+        connection = match reconnect(
+            server,
+            reconnect_timeout,
+            &initialize,
+            encoding,
+            &redaction,
+            &state.sent,
+        )
+        .await
+        {
+            Ok(connection) => connection,
+            Err(err) => break Err(err),
+        };
+    };
+
+    // Let the client receive everything forwarded so far.
+    drop(output_tx);
+    match output.await {
+        Ok(Err(err)) => Err(err),
+        _ => res,
+    }
+}
+
+/// Client session kept across connections to the server
+struct State {
+    /// Token re-binding a new connection to the session, `None` if the server
+    /// doesn't keep it
+    token: Option<String>,
+
+    /// Messages sent to the server, only kept with a `token`
+    sent: Replay,
+
+    /// Number of messages received from the server after the `initialize`
+    /// responses
+    received: u64,
+
+    /// The client closed stdin
+    input_closed: bool,
+
+    /// The client asked the server to shut down
+    shutdown: bool,
+}
+
+/// Forward messages between the client and the server over one connection
+/// until it's closed or lost
+async fn forward(
+    (mut reader, mut writer): (
+        LspReader<BufReader<OwnedReadHalf>>,
+        LspWriter<OwnedWriteHalf>,
+    ),
+    client_rx: &mut mpsc::Receiver<Part>,
+    output_tx: &mpsc::Sender<Part>,
+    state: &mut State,
+    (heartbeat_interval, heartbeat_timeout): (Option<Duration>, Option<Duration>),
+) -> Result<()> {
+    let State {
+        token,
+        sent,
+        received,
+        input_closed,
+        shutdown,
+    } = state;
+    let resumable = token.is_some();
+    let sent = resumable.then_some(sent);
+    let to_server = async {
+        write_server(client_rx, &mut writer, sent, shutdown, heartbeat_interval).await?;
+        *input_closed = true;
+        if resumable {
+            let end = Notification {
+                jsonrpc: Version,
+                method: ext::END_SESSION.into(),
+                params: Value::Null,
+            };
+            writer
+                .write_message(&end.into())
+                .await
+                .context("forward message to server")?;
+        }
+        // The client closed stdin, only close the sending side so responses to
+        // its last requests still reach it. The server closes the connection
+        // once it answered them.
+        writer
+            .shutdown()
+            .await
+            .context("close connection to server")?;
+        std::future::pending().await
+    };
+    let res = tokio::select! {
+        res = to_server => res,
+        res = server_to_client(&mut reader, output_tx, received, heartbeat_timeout) => res,
+    };
+    let _ = writer.get_mut().close().await;
+    res
+}
+
+/// Read messages from the client and send them to the server channel
+///
+/// A message which can't be read leaves the reader out of sync with the
+/// client, it stops like it does once stdin is closed.
+async fn client_to_server<R>(mut reader: LspReader<R>, tx: mpsc::Sender<Part>) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let message = match reader.read_part().await {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                debug!("client output closed");
+                return Ok(());
+            }
+            Err(err) => {
+                error!(?err, "error reading client output");
+                return Err(err);
+            }
+        };
+        if tx.send(message).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Wait for the next heartbeat, never finishes if heartbeats are disabled
+async fn heartbeat(interval: Option<&mut Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Receive messages from the client channel and write them to the server
+/// socket, interleaving a heartbeat ping every `interval`
+///
+/// The first ping is sent right away so the server starts timing out the
+/// connection from its start instead of only after the first interval.
+///
+/// Messages are kept in `sent` for resending before they're written, streamed
+/// messages are only received without it. Finishes once the client closed
+/// stdin.
+async fn write_server(
+    rx: &mut mpsc::Receiver<Part>,
+    writer: &mut LspWriter<OwnedWriteHalf>,
+    mut sent: Option<&mut Replay>,
+    shutdown: &mut bool,
+    interval: Option<Duration>,
+) -> Result<()> {
+    let mut interval = interval.map(|period| {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    // Bytes of a streamed message still to be written, pings can't go
+    // between its chunks.
+    let mut streaming = 0;
+    loop {
+        let part = tokio::select! {
+            part = rx.recv() => match part {
+                Some(part) => part,
+                None => return Ok(()),
+            },
+            () = heartbeat(interval.as_mut()), if streaming == 0 => Part::Message(Notification {
+                jsonrpc: Version,
+                method: ext::HEARTBEAT_PING.into(),
+                params: Value::Null,
+            }
+            .into()),
+        };
+        match &part {
+            Part::Message(message) => {
+                if let Message::Request(req) = message {
+                    *shutdown |= req.method == "shutdown";
+                }
+                // The server counts the `shutdown` request like any other.
+                if let Some(sent) = sent.as_deref_mut() {
+                    sent.push(message.clone());
+                }
+            }
+            Part::Header(length) => streaming = *length,
+            Part::Chunk(chunk) => streaming -= chunk.len(),
+        }
+        writer
+            .write_part(&part)
+            .await
+            .context("forward message to server")?;
+    }
+}
+
+/// Receive messages from channel and write them to the client
+async fn write_client<W>(mut rx: mpsc::Receiver<Part>, mut writer: LspWriter<W>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(part) = rx.recv().await {
+        writer
+            .write_part(&part)
+            .await
+            .context("forward message to client")?;
+    }
+    Ok(())
+}
+
+/// Read messages from the server socket and send them to the client channel,
+/// counting them in `received`
+///
+/// Fails if `timeout` is set and the server doesn't send anything for that
+/// long.
+async fn server_to_client(
+    reader: &mut LspReader<BufReader<OwnedReadHalf>>,
+    tx: &mpsc::Sender<Part>,
+    received: &mut u64,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let base = *received;
+    loop {
+        // Reserve the slot first, a message is counted only once it can't get
+        // lost anymore.
+        let permit = tx.reserve().await.context("client output closed")?;
+        let message = match timeout {
+            Some(timeout) => match time::timeout(timeout, reader.read_part()).await {
+                Ok(message) => message,
+                Err(_) => {
+                    bail!("server didn't respond to heartbeat for {timeout:?}, connection lost")
+                }
+            },
+            None => reader.read_part().await,
+        };
+        // Don't count the `initialize` response.
+        *received = base + reader.messages() - 1;
+        let message = match message {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                debug!("server output closed");
+                return Ok(());
+            }
+            Err(err) => return Err(err.context("reading server output")),
+        };
+
+        match message {
+            Part::Message(Message::Notification(notif)) if notif.method == ext::HEARTBEAT_PONG => {
+                // Consume the heartbeat, it's not meant for the client.
+            }
+            message => permit.send(message),
+        }
+    }
+}
+
+/// Forward the notifications the server sends before its `initialize`
+/// response to the client, returns the response
+///
+/// The server tells the user why spawning the language server failed before
+/// it responds.
+async fn forward_notifications<R, W>(
+    mut message: Message,
+    reader: &mut LspReader<R>,
+    writer: &mut LspWriter<W>,
+) -> Result<Message>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Message::Notification(_) = message {
+        writer
+            .write_message(&message)
+            .await
+            .context("forward message to client")?;
+        message = reader
+            .read_message()
+            .await
+            .context("reading server response")?
+            .context("server closed the connection")?;
+    }
+    Ok(message)
+}
+
+/// Remove the session issued by the server from its `initialize` response,
+/// it's not meant for the client
+fn take_session(message: &mut Message) -> Option<ext::Session> {
+    let Message::ResponseSuccess(res) = message else {
+        return None;
+    };
+    let session = res.result.as_object_mut()?.remove("lspMux")?;
+    serde_json::from_value(session).ok()
+}
+
+/// Add what the user can do about a failed `initialize` with a known lspMux
+/// error code to the message shown by the editor
+fn explain_error(res: &mut jsonrpc::ResponseError) {
+    let Some(code) = ErrorCode::from_code(res.error.code) else {
+        return;
+    };
+    error!(%code, message = res.error.message, hint = code.hint(), "server refused connection");
+    res.error.message = format!("ra-multiplex: {} ({})", res.error.message, code.hint());
+}
+
+/// Connect to the server again re-binding to the session, the messages the
+/// server missed are resent from `sent`
+async fn reconnect(
+    addresses: &[Address],
+    retry_timeout: Option<u32>,
+    initialize: &Message,
+    encoding: WireEncoding,
+    redaction: &Arc<Redaction>,
+    sent: &Replay,
+) -> Result<(
+    LspReader<BufReader<OwnedReadHalf>>,
+    LspWriter<OwnedWriteHalf>,
+)> {
+    let (_, (reader, mut writer, mut message)) =
+        connect_with_retry(addresses, retry_timeout, initialize, encoding, redaction)
+            .await
+            .context("reconnecting to server")?;
+    let session = match take_session(&mut message) {
+        Some(session) => session,
+        None => match message {
+            Message::ResponseError(mut res) => {
+                explain_error(&mut res);
+                bail!(
+                    "server refused to resume the session: {}",
+                    res.error.message
+                )
+            }
+            _ => bail!("server didn't resume the session"),
+        },
+    };
+    let missed = sent
+        .since(session.received)
+        .context("messages the server missed aren't kept anymore")?;
+    for message in missed {
+        writer
+            .write_message(message)
+            .await
+            .context("resend message to server")?;
+    }
+    info!(received = session.received, "reconnected to server");
+    Ok((reader, writer))
+}
+
+/// Connect to the first reachable server, retrying with a jittered exponential
+/// backoff for up to `retry_timeout` seconds
+///
+/// Every attempt tries all `addresses` in order. With `retry_timeout` set to
+/// `None` only a single attempt is made. A server which doesn't accept the
+/// connection within [`PROBE_TIMEOUT`], like one which hangs or a remote host
+/// dropping packets, counts as not reachable. Returns the index of the server
+/// connected to, the ones before it weren't reachable.
+async fn connect_with_retry(
+    addresses: &[Address],
+    retry_timeout: Option<u32>,
+    initialize: &Message,
+    encoding: WireEncoding,
+    redaction: &Arc<Redaction>,
+) -> Result<(usize, ServerConnection)> {
+    let deadline = retry_timeout.map(|secs| Instant::now() + Duration::from_secs(secs.into()));
+    let mut backoff = RETRY_INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        let mut errors = Vec::new();
+        for (index, address) in addresses.iter().enumerate() {
+            match initialize_server(address, initialize, encoding, redaction).await {
+                Ok(connection) => {
+                    if !errors.is_empty() {
+                        info!(?address, "connected to fallback server");
+                    }
+                    return Ok((index, connection));
+                }
+                Err(ConnectError::Failed(err)) => return Err(err),
+                Err(ConnectError::Unreachable(err)) => {
+                    debug!(?err, ?address, "server not reachable");
+                    errors.push(err);
+                }
+            }
+        }
+        // Report the failure of the preferred server.
+        let err = errors.swap_remove(0);
+
+        let Some(deadline) = deadline else {
+            return Err(err);
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(err.context(format!("server not reachable after {attempt} attempts")));
+        }
+
+        let delay = jitter(backoff).min(deadline - now);
+        debug!(?err, ?delay, attempt, "server not reachable, retrying");
+        time::sleep(delay).await;
+
+        backoff = (backoff * 2).min(RETRY_MAX_BACKOFF);
+        attempt += 1;
+    }
+}
+
+/// Servers proxies recently failed to reach, kept in the state directory so
+/// proxies started afterwards don't wait for them to time out again
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+struct Health {
+    unreachable: Vec<Unreachable>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Unreachable {
+    address: Address,
+    /// Unix time of the last failed attempt
+    since: u64,
+}
+
+impl Health {
+    fn path() -> Result<PathBuf> {
+        Ok(paths::state_dir()?.join("unreachable.json"))
+    }
+
+    /// Read the record, a missing or broken one is empty
+    async fn load() -> Self {
+        let res = async {
+            let path = Self::path()?;
+            match fs::read_to_string(&path).await {
+                Ok(json) => {
+                    serde_json::from_str(&json).with_context(|| format!("parsing {path:?}"))
+                }
+                Err(err) if err.kind() == std_io::ErrorKind::NotFound => Ok(Self::default()),
+                Err(err) => Err(err).with_context(|| format!("reading {path:?}")),
+            }
+        };
+        res.await.unwrap_or_else(|err| {
+            warn!(?err, "error loading unreachable servers");
+            Self::default()
+        })
+    }
+
+    async fn save(&self) {
+        let res = async {
+            let path = Self::path()?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .await
+                    .with_context(|| format!("creating {dir:?}"))?;
+            }
+            // Proxies started at once could read a half written file.
+            let tmp = path.with_extension(format!("json.{}", std::process::id()));
+            let json = serde_json::to_string_pretty(self).unwrap();
+            fs::write(&tmp, json)
+                .await
+                .with_context(|| format!("writing {tmp:?}"))?;
+            fs::rename(&tmp, &path)
+                .await
+                .with_context(|| format!("replacing {path:?}"))
+        };
+        if let Err(err) = res.await {
+            warn!(?err, "error saving unreachable servers");
+        }
+    }
+
+    fn is_unreachable(&self, address: &Address, now: u64) -> bool {
+        self.unreachable
+            .iter()
+            .any(|entry| entry.address == *address && entry.since + UNREACHABLE_PENALTY > now)
+    }
+
+    /// `addresses` with the servers which were unreachable within the last
+    /// [`UNREACHABLE_PENALTY`] seconds moved to the end, the order is kept
+    /// otherwise
+    fn order(&self, addresses: &[Address], now: u64) -> Vec<Address> {
+        let mut addresses = addresses.to_vec();
+        addresses.sort_by_key(|address| self.is_unreachable(address, now));
+        addresses
+    }
+
+    /// Record that the servers before `connected` weren't reachable and that
+    /// `connected` is
+    fn update(&mut self, addresses: &[Address], connected: usize, now: u64) {
+        self.unreachable.retain(|entry| {
+            entry.since + UNREACHABLE_PENALTY > now && entry.address != addresses[connected]
+        });
+        for address in &addresses[..connected] {
+            self.unreachable.retain(|entry| entry.address != *address);
+            self.unreachable.push(Unreachable {
+                address: address.clone(),
+                since: now,
+            });
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Why connecting to a server failed
+enum ConnectError {
+    /// The server wasn't reached, it can be tried again
+    Unreachable(anyhow::Error),
+
+    /// The server got the `initialize` request, sending it again would start
+    /// another session
+    Failed(anyhow::Error),
+}
+
+type ServerConnection = (
+    LspReader<BufReader<OwnedReadHalf>>,
+    LspWriter<OwnedWriteHalf>,
+    Message,
+);
+
+/// Connect to the server and forward the `initialize` request, returns the
+/// first message the server sends back
+///
+/// Spawning a new language server instance can take a while and the server
+/// doesn't read any heartbeats until it's done, they only start after this.
+/// An ssh process also only fails once it's spawned, a connection which closes
+/// before the first message because ssh failed counts as unreachable.
+///
+/// Compression is only requested from servers on other machines, for a local
+/// one it would only cost CPU time. It's used for the messages after the
+/// response once the server accepted it there, a server which doesn't support
+/// compression ignores the request and answers uncompressed.
+async fn initialize_server(
+    address: &Address,
+    initialize: &Message,
+    encoding: WireEncoding,
+    redaction: &Arc<Redaction>,
+) -> Result<ServerConnection, ConnectError> {
+    let stream = time::timeout(PROBE_TIMEOUT, Stream::connect(address))
+        .await
+        .context("server didn't accept the connection in time")
+        .and_then(|res| res)
+        .map_err(ConnectError::Unreachable)?;
+    let (server_read, server_write) = stream.into_split();
+    let mut reader = LspReader::new(BufReader::new(server_read), "server");
+    reader.set_redaction(redaction.clone());
+    let mut writer = LspWriter::new(server_write, "server");
+    writer.set_redaction(redaction.clone());
+
+    let local;
+    let (initialize, requested) = match requested_compression(initialize) {
+        Compression::Off => (initialize, Compression::Off),
+        _ if address.is_local() => {
+            local = without_compression(initialize);
+            (&local, Compression::Off)
+        }
+        compression => (initialize, compression.accepted()),
+    };
+
+    // Forward the modified `initialize` request, everything after it is in the
+    // requested encoding.
+    writer
+        .write_message(initialize)
+        .await
+        .context("forward initialize request")
+        .map_err(ConnectError::Unreachable)?;
+    reader.set_encoding(encoding);
+    writer.set_encoding(encoding);
+
+    let res = reader
+        .read_message()
+        .await
+        .context("reading server response")
+        .and_then(|message| message.context("server closed the connection"));
+    let message = match res {
+        Ok(message) => message,
+        Err(err) if writer.get_mut().transport_failed().await => {
+            return Err(ConnectError::Unreachable(err))
+        }
+        Err(err) => return Err(ConnectError::Failed(err)),
+    };
+    let compression = match requested.is_off() {
+        true => Compression::Off,
+        false => accepted_compression(&message),
+    };
+    if !compression.is_off() {
+        debug!(?compression, "server accepted compression");
+    }
+    reader.set_compression(compression);
+    writer.set_compression(compression);
+    Ok((reader, writer, message))
+}
+
+/// Compression requested in the `lspMux` options of `initialize`
+fn requested_compression(initialize: &Message) -> Compression {
+    let Message::Request(req) = initialize else {
+        return Compression::Off;
+    };
+    req.params
+        .pointer("/initializationOptions/lspMux/compression")
+        .and_then(|compression| serde_json::from_value(compression.clone()).ok())
+        .unwrap_or_default()
+}
+
+/// Compression the server accepted in the `lspMux` result of its `initialize`
+/// response, off if it didn't answer with one
+fn accepted_compression(response: &Message) -> Compression {
+    let Message::ResponseSuccess(res) = response else {
+        return Compression::Off;
+    };
+    res.result
+        .pointer("/lspMux/compression")
+        .and_then(|compression| serde_json::from_value(compression.clone()).ok())
+        .unwrap_or_default()
+}
+
+/// `initialize` request without requesting compression
+fn without_compression(initialize: &Message) -> Message {
+    let mut initialize = initialize.clone();
+    if let Message::Request(req) = &mut initialize {
+        let options = req
+            .params
+            .pointer_mut("/initializationOptions/lspMux")
+            .and_then(Value::as_object_mut);
+        if let Some(options) = options {
+            options.remove("compression");
+        }
+    }
+    initialize
+}
+
+/// Randomly scale the delay to somewhere between 50% and 100% of its value so
+/// multiple proxies started at once don't retry in lockstep
+fn jitter(delay: Duration) -> Duration {
+    // We don't need good randomness, the sub-second part of the clock is
+    // unpredictable enough for spreading out retries.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let factor = 0.5 + f64::from(nanos % 1000) / 2000.0;
+    delay.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{RequestId, ResponseSuccess};
+
+    fn initialize() -> Message {
+        jsonrpc::Request {
+            jsonrpc: Version,
+            method: "initialize".into(),
+            params: Value::Null,
+            id: RequestId::Number(1),
+        }
+        .into()
+    }
+
+    /// Answer the `initialize` request of the first connection
+    async fn serve(listener: TcpListener) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, write) = socket.into_split();
+        let mut reader = LspReader::new(BufReader::new(read), "client");
+        let mut writer = LspWriter::new(write, "client");
+        let Some(Message::Request(req)) = reader.read_message().await.unwrap() else {
+            panic!("expected `initialize` request");
+        };
+        let res = ResponseSuccess::null(req.id);
+        writer.write_message(&res.into()).await.unwrap();
+    }
+
+    async fn unused_address() -> Address {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        Address::Tcp(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    /// Connection to a server, with the other end of it
+    fn connection() -> (OwnedReadHalf, OwnedWriteHalf, DuplexStream) {
+        let (channel, server) = io::duplex(4096);
+        let (read, write) = Stream::Channel { channel }.into_split();
+        (read, write, server)
+    }
+
+    #[tokio::test]
+    async fn stop_on_client_read_error() {
+        let input: &[u8] = b"Content-Length: many\r\n\r\n{}";
+        let (tx, mut rx) = mpsc::channel(1);
+        let res = time::timeout(
+            Duration::from_secs(1),
+            client_to_server(LspReader::new(input, "client"), tx),
+        )
+        .await
+        .expect("kept reading after the error");
+        assert!(res.is_err());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stop_on_server_read_error() {
+        let (read, _write, mut server) = connection();
+        let mut reader = LspReader::new(BufReader::new(read), "server");
+        let mut writer = LspWriter::new(&mut server, "client");
+        let res = ResponseSuccess::null(RequestId::Number(1));
+        writer.write_message(&res.into()).await.unwrap();
+        reader.read_message().await.unwrap();
+        server
+            .write_all(b"Content-Length: many\r\n\r\n{}")
+            .await
+            .unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let res = time::timeout(
+            Duration::from_secs(1),
+            server_to_client(&mut reader, &tx, &mut 0, None),
+        )
+        .await
+        .expect("kept reading after the error");
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn forward_notifications_before_response() {
+        let notif = |message: &str| -> Message {
+            Notification {
+                jsonrpc: Version,
+                method: "window/showMessage".into(),
+                params: serde_json::json!({ "type": 1, "message": message }),
+            }
+            .into()
+        };
+        let (read, _write, mut server) = connection();
+        let mut reader = LspReader::new(BufReader::new(read), "server");
+        let mut server_writer = LspWriter::new(&mut server, "client");
+        server_writer.write_message(&notif("second")).await.unwrap();
+        let res = jsonrpc::ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: ErrorCode::SpawnFailed.code(),
+                message: "spawn failed".into(),
+                data: None,
+            },
+            id: RequestId::Number(1),
+        };
+        server_writer.write_message(&res.into()).await.unwrap();
+
+        let mut output = Vec::new();
+        let mut writer = LspWriter::new(&mut output, "client");
+        let response = forward_notifications(notif("first"), &mut reader, &mut writer)
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::ResponseError(_)));
+
+        let mut client = LspReader::new(&output[..], "client");
+        for expected in ["first", "second"] {
+            let Some(Message::Notification(notif)) = client.read_message().await.unwrap() else {
+                panic!("expected a notification");
+            };
+            assert_eq!(notif.params["message"], expected);
+        }
+        assert!(client.read_message().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn compress_only_once_accepted() {
+        let response = |result: Value| -> Message {
+            ResponseSuccess {
+                jsonrpc: Version,
+                result,
+                id: RequestId::Number(1),
+            }
+            .into()
+        };
+        let accepted = response(serde_json::json!({ "lspMux": { "compression": { "zstd": 3 } } }));
+        assert_eq!(accepted_compression(&accepted), Compression::Zstd(3));
+        // Servers without compression support ignore the request.
+        let ignored = response(serde_json::json!({ "capabilities": {} }));
+        assert_eq!(accepted_compression(&ignored), Compression::Off);
+        let error = jsonrpc::ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: ErrorCode::VersionMismatch.code(),
+                message: "unsupported protocol version".into(),
+                data: None,
+            },
+            id: RequestId::Number(1),
+        };
+        assert_eq!(accepted_compression(&error.into()), Compression::Off);
+    }
+
+    #[tokio::test]
+    async fn time_out_silent_server() {
+        let (read, _write, _server) = connection();
+        let mut reader = LspReader::new(BufReader::new(read), "server");
+        let (tx, _rx) = mpsc::channel(1);
+        let timeout = Some(Duration::from_millis(50));
+        let err = server_to_client(&mut reader, &tx, &mut 0, timeout)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("didn't respond to heartbeat"));
+    }
+
+    #[tokio::test]
+    async fn keep_every_message_for_resending() {
+        let (_read, write, mut server) = connection();
+        let mut writer = LspWriter::new(write, "server");
+        let (tx, mut rx) = mpsc::channel(4);
+        let request = |id, method: &str| -> Part {
+            Part::Message(
+                jsonrpc::Request {
+                    jsonrpc: Version,
+                    method: method.into(),
+                    params: Value::Null,
+                    id: RequestId::Number(id),
+                }
+                .into(),
+            )
+        };
+        tx.send(request(2, "textDocument/hover")).await.unwrap();
+        tx.send(request(3, "shutdown")).await.unwrap();
+        let exit = Notification {
+            jsonrpc: Version,
+            method: "exit".into(),
+            params: Value::Null,
+        };
+        tx.send(Part::Message(exit.into())).await.unwrap();
+        drop(tx);
+
+        let mut sent = Replay::new(0, 16);
+        let mut shutdown = false;
+        write_server(&mut rx, &mut writer, Some(&mut sent), &mut shutdown, None)
+            .await
+            .unwrap();
+        assert!(shutdown);
+        let methods = sent
+            .since(0)
+            .unwrap()
+            .map(|message| match message {
+                Message::Request(req) => req.method.clone(),
+                Message::Notification(notif) => notif.method.clone(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(methods, ["textDocument/hover", "shutdown", "exit"]);
+        // A server resuming after the `shutdown` request gets what follows it.
+        assert_eq!(sent.since(2).unwrap().count(), 1);
+
+        let mut buf = vec![0; 4096];
+        let len = server.read(&mut buf).await.unwrap();
+        let written = String::from_utf8_lossy(&buf[..len]);
+        assert!(written.contains("\"shutdown\""), "{written}");
+    }
+
+    #[tokio::test]
+    async fn first_heartbeat_right_away() {
+        let (_read, write, mut server) = connection();
+        let mut writer = LspWriter::new(write, "server");
+        let (_tx, mut rx) = mpsc::channel(1);
+        let mut shutdown = false;
+        let interval = Some(Duration::from_secs(3600));
+        let write = write_server(&mut rx, &mut writer, None, &mut shutdown, interval);
+        let read = async {
+            let mut buf = vec![0; 256];
+            let len = server.read(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+        let ping = tokio::select! {
+            res = write => panic!("writer stopped: {res:?}"),
+            ping = read => ping,
+            () = time::sleep(Duration::from_secs(1)) => panic!("no heartbeat sent"),
+        };
+        assert!(ping.contains(ext::HEARTBEAT_PING));
+    }
+
+    #[tokio::test]
+    async fn retry_until_server_is_up() {
+        let address = unused_address().await;
+        let Address::Tcp(ip, port) = address else {
+            unreachable!()
+        };
+        let server = task::spawn(async move {
+            time::sleep(Duration::from_millis(300)).await;
+            serve(TcpListener::bind((ip, port)).await.unwrap()).await;
+        });
+
+        let addresses = [address];
+        let (_, (_, _, message)) = connect_with_retry(
+            &addresses,
+            Some(5),
+            &initialize(),
+            WireEncoding::default(),
+            &Arc::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(message, Message::ResponseSuccess(_)));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_reachable_server() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = task::spawn(serve(listener));
+
+        let addresses = [
+            unused_address().await,
+            Address::Tcp(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+        ];
+        let (connected, (_, _, message)) = connect_with_retry(
+            &addresses,
+            None,
+            &initialize(),
+            WireEncoding::default(),
+            &Arc::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(connected, 1);
+        assert!(matches!(message, Message::ResponseSuccess(_)));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn try_unreachable_servers_last() {
+        let address = |port| Address::Tcp(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        let addresses = [address(1), address(2), address(3)];
+        let mut health = Health::default();
+        assert_eq!(health.order(&addresses, 100), addresses);
+
+        health.update(&addresses, 2, 100);
+        assert_eq!(
+            health.order(&addresses, 110),
+            [address(3), address(1), address(2)]
+        );
+
+        // The third server went down, the first one is back.
+        let ordered = health.order(&addresses, 110);
+        health.update(&ordered, 1, 110);
+        assert_eq!(health.order(&addresses, 120), addresses);
+        assert!(health.is_unreachable(&address(3), 120));
+
+        // Servers are tried in order again once the penalty is over.
+        let now = 110 + UNREACHABLE_PENALTY;
+        assert!(!health.is_unreachable(&address(3), now));
+        health.update(&addresses, 0, now);
+        assert_eq!(health, Health::default());
+    }
+
+    #[tokio::test]
+    async fn send_initialize_once() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Close every connection after reading the request.
+        let server = task::spawn(async move {
+            let mut accepted = 0;
+            while let Ok(Ok((mut socket, _))) =
+                time::timeout(Duration::from_millis(500), listener.accept()).await
+            {
+                accepted += 1;
+                let _ = socket.read(&mut [0; 1024]).await;
+            }
+            accepted
+        });
+
+        let addresses = [Address::Tcp(IpAddr::V4(Ipv4Addr::LOCALHOST), port)];
+        let res = connect_with_retry(
+            &addresses,
+            Some(5),
+            &initialize(),
+            WireEncoding::default(),
+            &Arc::default(),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(server.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_after_ssh_failed() {
+        let ssh = |status: i32| {
+            let mut child = tokio::process::Command::new("sh")
+                .args(["-c", &format!("exit {status}")])
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            let stdin = child.stdin.take().unwrap();
+            let stdout = child.stdout.take().unwrap();
+            Stream::Ssh {
+                stdin,
+                stdout,
+                child,
+            }
+            .into_split()
+        };
+        let (_, mut failed) = ssh(255);
+        assert!(failed.transport_failed().await);
+        let (_, mut closed) = ssh(0);
+        assert!(!closed.transport_failed().await);
+    }
+
+    #[tokio::test]
+    async fn give_up_after_retry_timeout() {
+        let addresses = [unused_address().await];
+        let start = Instant::now();
+        let err = connect_with_retry(
+            &addresses,
+            Some(1),
+            &initialize(),
+            WireEncoding::default(),
+            &Arc::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(format!("{err:#}").contains("not reachable after"));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,155 @@
+//! Fair queueing of client requests for instances with a request limit
+//!
+//! Once the limit is reached requests wait for a free slot. A freed slot goes
+//! to the waiting client with the fewest requests in flight, ties are broken
+//! in the order the requests arrived, so a client sending a burst of requests
+//! doesn't starve other clients sharing the instance.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+pub struct RequestQueue {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    limit: usize,
+
+    /// Requests in flight by client ID
+    in_flight: HashMap<usize, usize>,
+
+    /// Requests in flight of all clients
+    total: usize,
+
+    /// Clients waiting for a slot in the order they started waiting
+    waiting: VecDeque<(usize, oneshot::Sender<Permit>)>,
+}
+
+/// Request waiting for a slot, it gives up its place when dropped
+pub struct Queued(oneshot::Receiver<Permit>);
+
+impl Queued {
+    pub async fn wait(self) -> Permit {
+        self.0.await.expect("BUG: request queue dropped")
+    }
+}
+
+/// Slot for one request in flight, released when dropped
+pub struct Permit {
+    state: Arc<Mutex<State>>,
+    client_id: usize,
+}
+
+impl RequestQueue {
+    pub fn new(limit: usize) -> RequestQueue {
+        RequestQueue {
+            state: Arc::new(Mutex::new(State {
+                limit,
+                in_flight: HashMap::new(),
+                total: 0,
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Take a slot for a request of client `client_id`, or queue it if there
+    /// is none free
+    ///
+    /// The request takes its place in the queue right away, not once it's
+    /// waited for, so requests queued one after another keep their order.
+    pub fn acquire(&self, client_id: usize) -> Result<Permit, Queued> {
+        let mut state = self.state.lock().unwrap();
+        if state.total < state.limit {
+            return Ok(self.grant(&mut state, client_id));
+        }
+        let (tx, rx) = oneshot::channel();
+        state.waiting.push_back((client_id, tx));
+        Err(Queued(rx))
+    }
+
+    fn grant(&self, state: &mut State, client_id: usize) -> Permit {
+        state.take(client_id);
+        Permit {
+            state: self.state.clone(),
+            client_id,
+        }
+    }
+}
+
+impl State {
+    fn take(&mut self, client_id: usize) {
+        self.total += 1;
+        *self.in_flight.entry(client_id).or_default() += 1;
+    }
+
+    fn release(&mut self, client_id: usize) {
+        self.total -= 1;
+        if let Some(count) = self.in_flight.get_mut(&client_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&client_id);
+            }
+        }
+    }
+
+    /// Remove the waiting client which should get the next slot
+    fn next_waiting(&mut self) -> Option<(usize, oneshot::Sender<Permit>)> {
+        let in_flight = |client_id| self.in_flight.get(client_id).copied().unwrap_or(0);
+        let index = self
+            .waiting
+            .iter()
+            .enumerate()
+            .min_by_key(|(index, (client_id, _))| (in_flight(client_id), *index))
+            .map(|(index, _)| index)?;
+        self.waiting.remove(index)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let next = {
+            let mut state = self.state.lock().unwrap();
+            state.release(self.client_id);
+            state.next_waiting().map(|(client_id, tx)| {
+                state.take(client_id);
+                (client_id, tx)
+            })
+        };
+        // Send without holding the lock, if the waiting request was dropped
+        // the permit is dropped too and passed on to the next one.
+        if let Some((client_id, tx)) = next {
+            let permit = Permit {
+                state: self.state.clone(),
+                client_id,
+            };
+            let _ = tx.send(permit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fewest_in_flight_first() {
+        let queue = RequestQueue::new(2);
+        let mut state = queue.state.lock().unwrap();
+        state.take(1);
+        state.take(1);
+
+        // Client 1 started waiting first but it already has requests in
+        // flight, client 2 doesn't.
+        for client_id in [1, 2, 1, 3] {
+            state.waiting.push_back((client_id, oneshot::channel().0));
+        }
+        let mut next = || state.next_waiting().map(|(client_id, _)| client_id);
+        assert_eq!(next(), Some(2));
+        assert_eq!(next(), Some(3));
+        assert_eq!(next(), Some(1));
+        assert_eq!(next(), Some(1));
+        assert_eq!(next(), None);
+    }
+}
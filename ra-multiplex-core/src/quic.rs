@@ -0,0 +1,326 @@
+//! QUIC transport between the client and the server
+//!
+//! A QUIC connection survives the client changing its address and a lost
+//! packet only holds up the stream it belongs to, which helps over lossy Wi-Fi
+//! and VPN links. Every bidirectional stream is served as a connection of its
+//! own.
+//!
+//! QUIC is always encrypted, the server generates a self-signed certificate
+//! the first time it listens and keeps it in the data directory. The client
+//! only accepts the certificate with the fingerprint in its `connect` address
+//! or, without one, the certificate of the server on its own machine.
+
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use quinn::rustls::crypto::{self, CryptoProvider};
+use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use quinn::rustls::{self, DigitallySignedStruct, SignatureScheme};
+use quinn::{
+    ClientConfig, Connection, Endpoint, Incoming, RecvStream, SendStream, ServerConfig,
+    TransportConfig,
+};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task;
+use tracing::{debug, info, warn, Instrument};
+
+use crate::config::Fingerprint;
+use crate::paths;
+
+/// Server name of the self-signed certificate
+const SERVER_NAME: &str = "localhost";
+
+/// Keeps NAT mappings of idle connections open
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Directory of the server's certificate and private key
+fn cert_dir() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join("quic"))
+}
+
+pub fn fingerprint(cert: &[u8]) -> Fingerprint {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    Fingerprint(digest.as_ref().try_into().unwrap())
+}
+
+/// Fingerprint of the certificate of the server on this machine
+fn local_fingerprint() -> Result<Fingerprint> {
+    let path = cert_dir()?.join("cert.der");
+    let cert = fs::read(&path).with_context(|| {
+        format!(
+            "reading {path:?}, for a server on another machine add the fingerprint \
+             it logs to the address as quic:<ip>:<port>#<fingerprint>"
+        )
+    })?;
+    Ok(fingerprint(&cert))
+}
+
+/// Certificate and private key of a server
+struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl Identity {
+    fn generate() -> Result<Identity> {
+        let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()])
+            .context("generating certificate")?;
+        Ok(Identity {
+            cert: cert.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()),
+        })
+    }
+
+    /// Load the identity in `dir`, generating it the first time, so clients
+    /// can keep trusting the same certificate
+    fn load_or_generate(dir: &Path) -> Result<Identity> {
+        let (cert_path, key_path) = (dir.join("cert.der"), dir.join("key.der"));
+        if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+            return Ok(Identity {
+                cert: cert.into(),
+                key: key.into(),
+            });
+        }
+        let identity = Identity::generate()?;
+        fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(
+            &mut options.open(&key_path)?,
+            identity.key.secret_pkcs8_der(),
+        )
+        .with_context(|| format!("writing {key_path:?}"))?;
+        fs::write(&cert_path, &identity.cert).with_context(|| format!("writing {cert_path:?}"))?;
+        Ok(identity)
+    }
+}
+
+/// Open a connection and a stream on it, the server's certificate must have
+/// `fingerprint` or be the one of the server on this machine
+pub async fn connect(
+    addr: SocketAddr,
+    fingerprint: Option<Fingerprint>,
+) -> Result<(SendStream, RecvStream)> {
+    let fingerprint = match fingerprint {
+        Some(fingerprint) => fingerprint,
+        None => local_fingerprint()?,
+    };
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let mut endpoint = Endpoint::client(bind_addr).context("creating quic endpoint")?;
+    endpoint.set_default_client_config(client_config(fingerprint)?);
+
+    let connection = endpoint
+        .connect(addr, SERVER_NAME)
+        .context("connecting")?
+        .await
+        .context("quic handshake")?;
+    connection.open_bi().await.context("opening quic stream")
+}
+
+fn client_config(fingerprint: Fingerprint) -> Result<ClientConfig> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("tls config")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            fingerprint,
+            provider,
+        }))
+        .with_no_client_auth();
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Accepts the streams of all connections
+pub struct Listener {
+    endpoint: Endpoint,
+    streams: Mutex<mpsc::Receiver<(SendStream, RecvStream, SocketAddr)>>,
+}
+
+impl Listener {
+    pub fn bind(addr: SocketAddr) -> Result<Listener> {
+        let identity = Identity::load_or_generate(&cert_dir()?)?;
+        info!(fingerprint = %fingerprint(&identity.cert), "quic certificate");
+        Listener::with_identity(addr, identity)
+    }
+
+    fn with_identity(addr: SocketAddr, identity: Identity) -> Result<Listener> {
+        let config = ServerConfig::with_single_cert(vec![identity.cert], identity.key.into())
+            .context("tls config")?;
+        let endpoint = Endpoint::server(config, addr)?;
+
+        let (tx, rx) = mpsc::channel(16);
+        task::spawn(accept_connections(endpoint.clone(), tx).in_current_span());
+        Ok(Listener {
+            endpoint,
+            streams: Mutex::new(rx),
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<(SendStream, RecvStream, SocketAddr)> {
+        self.streams
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("quic endpoint closed"))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"");
+    }
+}
+
+async fn accept_connections(
+    endpoint: Endpoint,
+    tx: mpsc::Sender<(SendStream, RecvStream, SocketAddr)>,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        task::spawn(accept_streams(incoming, tx.clone()).in_current_span());
+    }
+}
+
+/// Complete the handshake and pass on every stream the client opens
+async fn accept_streams(
+    incoming: Incoming,
+    tx: mpsc::Sender<(SendStream, RecvStream, SocketAddr)>,
+) {
+    let addr = incoming.remote_address();
+    let connection: Connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            warn!(?err, %addr, "quic handshake failed");
+            return;
+        }
+    };
+    loop {
+        match connection.accept_bi().await {
+            Ok((send, recv)) => {
+                if tx
+                    .send((send, recv, connection.remote_address()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(err) => {
+                debug!(?err, %addr, "quic connection closed");
+                return;
+            }
+        }
+    }
+}
+
+/// Accepts only the certificate with the fingerprint, the server's is
+/// self-signed
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: Fingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connect_to_pinned_certificate() {
+        let identity = Identity::generate().unwrap();
+        let pinned = fingerprint(&identity.cert);
+        let listener =
+            Listener::with_identity(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), identity).unwrap();
+        let addr = listener.endpoint.local_addr().unwrap();
+
+        let (mut send, _recv) = connect(addr, Some(pinned)).await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        let (_send, mut recv, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 5];
+        recv.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        let err = connect(addr, Some(Fingerprint([0; 32]))).await.unwrap_err();
+        assert!(format!("{err:?}").contains("handshake"), "{err:?}");
+    }
+
+    #[test]
+    fn keep_certificate() {
+        let dir = std::env::temp_dir().join(format!("ra-mux-quic-{}", std::process::id()));
+        let first = Identity::load_or_generate(&dir).unwrap();
+        let second = Identity::load_or_generate(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(fingerprint(&first.cert), fingerprint(&second.cert));
+    }
+}
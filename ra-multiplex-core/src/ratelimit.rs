@@ -0,0 +1,300 @@
+//! Rate limiting of noisy server notifications and client requests
+//!
+//! Some language servers send bursts of notifications which only supersede each
+//! other, rust-analyzer for example sends thousands of `$/progress` reports
+//! during indexing. Forwarding all of them can make editors struggle rendering
+//! them so we only forward a limited number per second for each client and
+//! collapse the rest into the latest one.
+//!
+//! Clients can be limited in how many requests they send to shared instances,
+//! so a runaway script can't keep a server busy for everyone else.
+
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::lsp::jsonrpc::Notification;
+
+/// Limits the requests of one client
+pub struct RequestQuota {
+    max_in_flight: Option<u32>,
+    per_second: Option<u32>,
+
+    /// Start of the current one second window and the number of requests
+    /// sent in it
+    window: (Instant, u32),
+}
+
+impl RequestQuota {
+    pub fn new(config: &Config, now: Instant) -> Self {
+        RequestQuota {
+            max_in_flight: config.max_client_requests,
+            per_second: config.max_client_requests_per_second,
+            window: (now, 0),
+        }
+    }
+
+    /// Check whether the number of requests in flight is limited
+    pub fn limits_in_flight(&self) -> bool {
+        self.max_in_flight.is_some()
+    }
+
+    /// Check whether a client with `in_flight` requests waiting for a response
+    /// may send another one, otherwise returns the reason it may not
+    pub fn check(&mut self, in_flight: usize, now: Instant) -> Result<(), String> {
+        if let Some(max) = self.max_in_flight {
+            if in_flight >= max as usize {
+                return Err(format!("too many requests in flight, the limit is {max}"));
+            }
+        }
+        if let Some(per_second) = self.per_second {
+            let (start, count) = &mut self.window;
+            if now.duration_since(*start) >= Duration::from_secs(1) {
+                *start = now;
+                *count = 0;
+            }
+            if *count >= per_second {
+                return Err(format!(
+                    "too many requests, the limit is {per_second} per second"
+                ));
+            }
+            *count += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Throttles notifications sent to one client
+pub struct NotificationLimiter {
+    /// Minimum time between two forwarded notifications of each rate limited
+    /// method
+    intervals: HashMap<String, Duration>,
+
+    /// Throttling state of notification groups
+    ///
+    /// Notifications are grouped by their method and the `token` or `uri`
+    /// parameter so only notifications superseding each other are collapsed,
+    /// for example progress reports with the same token or diagnostics for the
+    /// same file.
+    groups: HashMap<(String, Option<String>), Group>,
+}
+
+struct Group {
+    /// When was the last notification of this group forwarded
+    last_sent: Instant,
+
+    /// Latest notification held back because the group was over its limit
+    pending: Option<Notification>,
+}
+
+impl NotificationLimiter {
+    pub fn new(limits: &BTreeMap<String, NonZeroU32>) -> Self {
+        let intervals = limits
+            .iter()
+            .map(|(method, per_second)| {
+                let interval = Duration::from_secs(1) / per_second.get();
+                (method.clone(), interval)
+            })
+            .collect();
+        NotificationLimiter {
+            intervals,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Returns the notification if it should be forwarded right away,
+    /// otherwise holds it back until [`take_due`](Self::take_due) releases it
+    ///
+    /// A held back notification is replaced by any later notification from the
+    /// same group.
+    pub fn check(&mut self, notif: Notification, now: Instant) -> Option<Notification> {
+        let Some(&interval) = self.intervals.get(&notif.method) else {
+            return Some(notif);
+        };
+        let key = (notif.method.clone(), group_id(&notif.params));
+
+        if notif.method == "$/progress" {
+            // Only progress reports can be collapsed, the client must see all
+            // begin and end notifications. After the end any held back report
+            // is outdated.
+            match notif.params.pointer("/value/kind").and_then(Value::as_str) {
+                Some("report") => {}
+                Some("end") => {
+                    self.groups.remove(&key);
+                    return Some(notif);
+                }
+                _ => return Some(notif),
+            }
+        }
+
+        match self.groups.get_mut(&key) {
+            Some(group) if now < group.last_sent + interval => {
+                group.pending = Some(notif);
+                None
+            }
+            Some(group) => {
+                group.last_sent = now;
+                group.pending = None;
+                Some(notif)
+            }
+            None => {
+                let group = Group {
+                    last_sent: now,
+                    pending: None,
+                };
+                self.groups.insert(key, group);
+                Some(notif)
+            }
+        }
+    }
+
+    /// When is the next held back notification due to be forwarded
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.groups
+            .iter()
+            .filter(|(_, group)| group.pending.is_some())
+            .map(|((method, _), group)| group.last_sent + self.intervals[method])
+            .min()
+    }
+
+    /// Take all held back notifications which are due to be forwarded at `now`
+    pub fn take_due(&mut self, now: Instant) -> Vec<Notification> {
+        let mut due = Vec::new();
+        let intervals = &self.intervals;
+        self.groups.retain(|(method, _), group| {
+            if now < group.last_sent + intervals[method] {
+                return true;
+            }
+            match group.pending.take() {
+                Some(notif) => {
+                    due.push(notif);
+                    group.last_sent = now;
+                    true
+                }
+                // The group is idle and not limited anymore, forget about it.
+                None => false,
+            }
+        });
+        due
+    }
+}
+
+/// Find the parameter identifying notifications which supersede each other
+fn group_id(params: &Value) -> Option<String> {
+    let id = params.get("token").or_else(|| params.get("uri"))?;
+    Some(id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lsp::jsonrpc::Version;
+
+    fn limiter() -> NotificationLimiter {
+        let limits = BTreeMap::from([("$/progress".to_owned(), NonZeroU32::new(10).unwrap())]);
+        NotificationLimiter::new(&limits)
+    }
+
+    fn progress(token: &str, kind: &str, percentage: u32) -> Notification {
+        Notification {
+            jsonrpc: Version,
+            method: "$/progress".into(),
+            params: json!({
+                "token": token,
+                "value": { "kind": kind, "percentage": percentage },
+            }),
+        }
+    }
+
+    fn percentage(notif: &Notification) -> u64 {
+        notif
+            .params
+            .pointer("/value/percentage")
+            .unwrap()
+            .as_u64()
+            .unwrap()
+    }
+
+    #[test]
+    fn collapses_burst_into_latest() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+
+        assert!(limiter.check(progress("a", "report", 1), start).is_some());
+        assert!(limiter.check(progress("a", "report", 2), start).is_none());
+        assert!(limiter.check(progress("a", "report", 3), start).is_none());
+
+        let deadline = limiter.next_deadline().unwrap();
+        assert_eq!(deadline, start + Duration::from_millis(100));
+        assert!(limiter.take_due(start).is_empty());
+
+        let due = limiter.take_due(deadline);
+        assert_eq!(due.len(), 1);
+        assert_eq!(percentage(&due[0]), 3);
+        assert!(limiter.next_deadline().is_none());
+    }
+
+    #[test]
+    fn groups_are_independent() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+
+        assert!(limiter.check(progress("a", "report", 1), start).is_some());
+        assert!(limiter.check(progress("b", "report", 1), start).is_some());
+    }
+
+    #[test]
+    fn begin_and_end_are_never_held_back() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+
+        assert!(limiter.check(progress("a", "begin", 0), start).is_some());
+        assert!(limiter.check(progress("a", "report", 1), start).is_some());
+        assert!(limiter.check(progress("a", "report", 2), start).is_none());
+        assert!(limiter.check(progress("a", "end", 100), start).is_some());
+
+        // The held back report is outdated after the end.
+        assert!(limiter.next_deadline().is_none());
+    }
+
+    #[test]
+    fn other_methods_pass_through() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+        let notif = Notification {
+            jsonrpc: Version,
+            method: "window/logMessage".into(),
+            params: json!({ "type": 4, "message": "hello" }),
+        };
+
+        assert!(limiter.check(notif.clone(), start).is_some());
+        assert!(limiter.check(notif, start).is_some());
+    }
+
+    #[test]
+    fn request_quota() {
+        let config = Config {
+            max_client_requests: Some(2),
+            max_client_requests_per_second: Some(3),
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let mut quota = RequestQuota::new(&config, start);
+
+        assert!(quota.check(0, start).is_ok());
+        assert!(quota.check(2, start).is_err());
+        assert!(quota.check(1, start).is_ok());
+        assert!(quota.check(1, start).is_ok());
+        assert!(quota.check(0, start).is_err());
+
+        // A new window starts after a second.
+        let later = start + Duration::from_secs(1);
+        assert!(quota.check(0, later).is_ok());
+    }
+}
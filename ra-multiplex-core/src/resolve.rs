@@ -0,0 +1,242 @@
+//! Resolving plain language server names to executables
+//!
+//! The daemon often runs as a service with a minimal environment whose `PATH`
+//! doesn't contain the directories language servers are installed to. Servers
+//! requested by a plain name like `rust-analyzer` are looked up in this order:
+//!
+//! 1. the `path` setting of the server
+//! 2. the directories in `server.search_path`
+//! 3. the `PATH` the server would be executed with
+//! 4. the user's login shell with `server.login_shell`
+//!
+//! The name is returned unchanged if none of them has it, so is a server given
+//! as a path. Otherwise the path the executable was found at is returned. Resolved paths are part of the instance key and shown by
+//! `status`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::process::Command;
+use tokio::{task, time};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// How long the login shell may take to start and look up the server
+const LOGIN_SHELL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Servers found by the login shell, starting it takes long enough to not do
+/// it for every client
+static LOGIN_SHELL_CACHE: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Resolve `server` to the executable it names, `env` is the environment the
+/// server is executed with
+pub async fn resolve_server(
+    config: &Config,
+    server: &str,
+    env: &BTreeMap<String, String>,
+) -> String {
+    if is_path(server) {
+        return server.to_owned();
+    }
+    let settings = config.server_settings(server);
+    if let Some(path) = settings.and_then(|settings| settings.path.as_ref()) {
+        return path.clone();
+    }
+
+    let options = &config.server;
+    let search_path = match options.search_path.is_empty() {
+        // An empty PATH would be the current directory.
+        true => None,
+        false => env::join_paths(&options.search_path).ok(),
+    };
+    let search_path = search_path.and_then(|path| path.into_string().ok());
+    if let Some(path) = find_executable_blocking(server, search_path).await {
+        debug!(?server, ?path, "resolved server in search path");
+        return path.display().to_string();
+    }
+    let path = env.get("PATH").cloned().or_else(|| env::var("PATH").ok());
+    if let Some(path) = find_executable_blocking(server, path).await {
+        debug!(?server, ?path, "resolved server in PATH");
+        return path.display().to_string();
+    }
+    if options.login_shell {
+        match login_shell_lookup(server).await {
+            Ok(path) => {
+                debug!(?server, ?path, "resolved server with login shell");
+                return path;
+            }
+            Err(err) => warn!(?err, ?server, "error resolving server with login shell"),
+        }
+    }
+    server.to_owned()
+}
+
+/// Server is given with a directory, relative or absolute
+fn is_path(server: &str) -> bool {
+    Path::new(server).components().count() > 1
+}
+
+/// [`find_executable`] on the blocking thread pool, looking through a long
+/// `PATH` on a slow filesystem would stall the runtime
+async fn find_executable_blocking(name: &str, path: Option<String>) -> Option<PathBuf> {
+    let name = name.to_owned();
+    task::spawn_blocking(move || find_executable(&name, path.as_deref()))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Ask the user's login shell where `server` is, it sets up `PATH` from the
+/// user's profile
+async fn login_shell_lookup(server: &str) -> Result<String> {
+    let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned());
+    shell_lookup(&shell, server).await
+}
+
+/// Look `server` up with `shell` started as a login shell
+async fn shell_lookup(shell: &str, server: &str) -> Result<String> {
+    if let Some(path) = LOGIN_SHELL_CACHE.lock().unwrap().get(server) {
+        return Ok(path.clone());
+    }
+    // The name is passed as a positional argument so it's never interpreted
+    // by the shell.
+    let output = Command::new(shell)
+        .args(["-lc", r#"command -v -- "$1""#, "ra-multiplex", server])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = time::timeout(LOGIN_SHELL_TIMEOUT, output)
+        .await
+        .context("login shell timed out")?
+        .with_context(|| format!("running {shell:?}"))?;
+    if !output.status.success() {
+        bail!("{server:?} not found by {shell:?}");
+    }
+    let stdout = String::from_utf8(output.stdout).context("shell output is not utf-8")?;
+    // Profiles may print something, the path is the last line.
+    let path = stdout.lines().last().unwrap_or_default().trim();
+    if !Path::new(path).is_absolute() {
+        bail!("{server:?} is not an executable in {shell:?}: {path:?}");
+    }
+    LOGIN_SHELL_CACHE
+        .lock()
+        .unwrap()
+        .insert(server.to_owned(), path.to_owned());
+    Ok(path.to_owned())
+}
+
+/// Find the executable a command `name` runs when looked up in `path`
+pub fn find_executable(name: &str, path: Option<&str>) -> Option<PathBuf> {
+    let name = Path::new(name);
+    if name.components().count() > 1 {
+        return is_executable(name).then(|| name.to_owned());
+    }
+    let exe_name = match env::consts::EXE_EXTENSION {
+        "" => None,
+        extension => Some(name.with_extension(extension)),
+    };
+    env::split_paths(path?)
+        .flat_map(|dir| {
+            let exe = exe_name.as_ref().map(|exe_name| dir.join(exe_name));
+            [Some(dir.join(name)), exe].into_iter().flatten()
+        })
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_servers() {
+        let dir = env::temp_dir().join(format!("ra-mux-resolve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = dir.join("some-server");
+        std::fs::write(&server, "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(0o755);
+            std::fs::set_permissions(&server, permissions).unwrap();
+        }
+
+        let config = toml::from_str::<Config>(&format!(
+            "[server]\nsearch_path = [{dir:?}]\n[server.clangd]\npath = \"/opt/clangd\"\n",
+        ))
+        .unwrap();
+        let env = BTreeMap::from([("PATH".to_owned(), String::new())]);
+        let resolve = |server| resolve_server(&config, server, &env);
+
+        assert_eq!(resolve("some-server").await, server.display().to_string());
+        assert_eq!(resolve("clangd").await, "/opt/clangd");
+        assert_eq!(resolve("./clangd").await, "./clangd");
+        assert_eq!(resolve("missing-server").await, "missing-server");
+
+        // Servers on the PATH are resolved to where they were found too.
+        let config = Config::default();
+        let env = BTreeMap::from([("PATH".to_owned(), dir.display().to_string())]);
+        let resolved = resolve_server(&config, "some-server", &env).await;
+        assert_eq!(resolved, server.display().to_string());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn look_up_in_login_shell() {
+        let path = shell_lookup("/bin/sh", "sh").await.unwrap();
+        assert!(Path::new(&path).is_absolute(), "{path}");
+        assert!(path.ends_with("/sh"), "{path}");
+        assert_eq!(LOGIN_SHELL_CACHE.lock().unwrap().get("sh"), Some(&path));
+
+        let missing = "ra-mux-missing-server";
+        assert!(shell_lookup("/bin/sh", missing).await.is_err());
+        // Arguments are never interpreted by the shell.
+        let injected = "sh; echo /injected";
+        assert!(shell_lookup("/bin/sh", injected).await.is_err());
+        assert!(!LOGIN_SHELL_CACHE.lock().unwrap().contains_key(missing));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn find_executable_on_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("ra-mux-find-executable-{}", std::process::id()));
+        let bin = dir.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        let server = bin.join("server");
+        std::fs::write(&server, "").unwrap();
+        std::fs::write(bin.join("data"), "").unwrap();
+        std::fs::set_permissions(&server, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = env::join_paths([dir.join("missing"), bin.clone()]).unwrap();
+        let path = path.to_str();
+        assert_eq!(find_executable("server", path), Some(server.clone()));
+        assert_eq!(find_executable("data", path), None);
+        assert_eq!(find_executable("missing", path), None);
+        assert_eq!(find_executable("server", None), None);
+        let absolute = server.to_str().unwrap();
+        assert_eq!(find_executable(absolute, None), Some(server.clone()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
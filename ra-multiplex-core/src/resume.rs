@@ -0,0 +1,271 @@
+//! Resuming client sessions after a lost connection
+//!
+//! Clients connecting with `resumable` get a reconnect token in the
+//! `initialize` response. When the connection breaks the proxy connects again
+//! with the token and the new connection takes the place of the lost one, the
+//! client keeps its ID, open documents and pending requests. Both ends number
+//! the messages they send, keep the last ones in a [`Replay`] buffer and count
+//! the messages they receive. On reconnect they exchange the counts and resend
+//! exactly what the other end missed.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::BufReader;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::debug;
+
+use crate::lsp::ext;
+use crate::lsp::jsonrpc::{Message, RequestId};
+use crate::lsp::transport::{Compression, LspReader, LspWriter};
+use crate::socketwrapper::{OwnedReadHalf, OwnedWriteHalf};
+
+/// Sent messages numbered in order, the last `capacity` are kept
+pub struct Replay {
+    /// Number of the oldest kept message
+    start: u64,
+    messages: VecDeque<Message>,
+    capacity: usize,
+}
+
+impl Replay {
+    /// Buffer numbering the following messages from `start`, configured by
+    /// `reconnect_buffer`
+    pub fn new(start: u64, capacity: u32) -> Self {
+        Replay {
+            start,
+            messages: VecDeque::new(),
+            capacity: capacity.max(1) as usize,
+        }
+    }
+
+    /// Number of the oldest kept message
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn push(&mut self, message: Message) {
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+            self.start += 1;
+        }
+        self.messages.push_back(message);
+    }
+
+    /// Messages numbered `seq` and up, `None` if some of them were dropped
+    /// already
+    pub fn since(&self, seq: u64) -> Option<impl Iterator<Item = &Message>> {
+        let end = self.start + self.messages.len() as u64;
+        if seq < self.start || seq > end {
+            return None;
+        }
+        Some(self.messages.iter().skip((seq - self.start) as usize))
+    }
+}
+
+type Reader = LspReader<BufReader<OwnedReadHalf>>;
+type Writer = LspWriter<OwnedWriteHalf>;
+
+/// Connection of a client reconnecting with its token
+pub struct Reconnect {
+    /// ID of its `initialize` request
+    pub id: RequestId,
+
+    /// Number of messages the proxy received so far
+    pub received: u64,
+
+    /// Compression accepted for the messages after the response
+    pub compression: Compression,
+
+    pub reader: Reader,
+    pub writer: Writer,
+}
+
+/// Connection handed over to the client input task, it answers the handshake
+/// and resends what the proxy missed
+pub struct Rebind {
+    pub id: RequestId,
+    pub session: ext::Session,
+
+    /// Number of messages the proxy received so far
+    pub received: u64,
+
+    /// Compression accepted for the messages after the response
+    pub compression: Compression,
+
+    pub writer: Writer,
+}
+
+/// Resumable sessions of one server by their token
+#[derive(Default, Clone)]
+pub struct Sessions(Arc<Mutex<BTreeMap<String, mpsc::Sender<Reconnect>>>>);
+
+impl Sessions {
+    /// Hand the connection over to the session `token` was issued to, it's
+    /// returned back if the session expired
+    pub async fn reconnect(&self, token: &str, reconnect: Reconnect) -> Result<(), Reconnect> {
+        let sender = self.0.lock().unwrap().get(token).cloned();
+        match sender {
+            Some(sender) => sender.send(reconnect).await.map_err(|err| err.0),
+            None => Err(reconnect),
+        }
+    }
+}
+
+/// Session of a resumable client, reconnects are accepted until it's dropped
+pub struct Resumable {
+    sessions: Sessions,
+    token: String,
+    timeout: Duration,
+
+    /// Number of messages received on the previous connections
+    received: u64,
+
+    reconnects: mpsc::Receiver<Reconnect>,
+    rebinds: mpsc::Sender<Rebind>,
+}
+
+impl Resumable {
+    /// Issue a token for the client, its input task receives the rebound
+    /// connections
+    pub fn register(
+        sessions: &Sessions,
+        client_id: usize,
+        timeout: Duration,
+    ) -> (Resumable, mpsc::Receiver<Rebind>) {
+        let token = token(client_id);
+        let (reconnect_tx, reconnects) = mpsc::channel(1);
+        let (rebinds, rebind_rx) = mpsc::channel(1);
+        sessions
+            .0
+            .lock()
+            .unwrap()
+            .insert(token.clone(), reconnect_tx);
+        let resumable = Resumable {
+            sessions: sessions.clone(),
+            token,
+            timeout,
+            received: 0,
+            reconnects,
+            rebinds,
+        };
+        (resumable, rebind_rx)
+    }
+
+    /// Session sent in the first `initialize` response
+    pub fn session(&self) -> ext::Session {
+        ext::Session {
+            reconnect_token: self.token.clone(),
+            received: 0,
+        }
+    }
+
+    /// Wait for the client to reconnect, never returns while it's connected
+    pub async fn next(&mut self) -> Reconnect {
+        // The sender is only dropped with us.
+        self.reconnects
+            .recv()
+            .await
+            .expect("BUG: session unregistered")
+    }
+
+    /// Wait for the client to reconnect after losing its connection, `None`
+    /// if it doesn't in time
+    pub async fn wait(&mut self) -> Option<Reconnect> {
+        debug!(timeout = ?self.timeout, "waiting for client to reconnect");
+        time::timeout(self.timeout, self.next()).await.ok()
+    }
+
+    /// Replace the lost connection read by `reader`, returns the new reader or
+    /// `None` if the client input task is gone
+    pub async fn rebind(&mut self, reader: &Reader, reconnect: Reconnect) -> Option<Reader> {
+        // Don't count the `initialize` request.
+        self.received += reader.messages() - 1;
+        let rebind = Rebind {
+            id: reconnect.id,
+            session: ext::Session {
+                reconnect_token: self.token.clone(),
+                received: self.received,
+            },
+            received: reconnect.received,
+            compression: reconnect.compression,
+            writer: reconnect.writer,
+        };
+        self.rebinds.send(rebind).await.ok()?;
+        Some(reconnect.reader)
+    }
+}
+
+impl Drop for Resumable {
+    fn drop(&mut self) {
+        self.sessions.0.lock().unwrap().remove(&self.token);
+    }
+}
+
+/// Generate an unguessable token
+fn token(client_id: usize) -> String {
+    // `RandomState` keys are seeded from the OS, hashing with two of them
+    // gives us 128 random bits without another dependency.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let high = RandomState::new().hash_one((client_id, nanos));
+    let low = RandomState::new().hash_one((nanos, client_id));
+    format!("{high:016x}{low:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{Notification, Version};
+
+    fn message(n: u64) -> Message {
+        Notification {
+            jsonrpc: Version,
+            method: "test".into(),
+            params: Value::from(n),
+        }
+        .into()
+    }
+
+    fn numbers<'a>(messages: impl Iterator<Item = &'a Message>) -> Vec<u64> {
+        messages
+            .map(|message| match message {
+                Message::Notification(notif) => notif.params.as_u64().unwrap(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_since() {
+        let mut replay = Replay::new(5, 16);
+        for n in 5..8 {
+            replay.push(message(n));
+        }
+        assert_eq!(numbers(replay.since(6).unwrap()), [6, 7]);
+        assert_eq!(numbers(replay.since(8).unwrap()), [] as [u64; 0]);
+        assert!(replay.since(4).is_none());
+        assert!(replay.since(9).is_none());
+
+        for n in 8..(5 + 16 + 2) {
+            replay.push(message(n));
+        }
+        assert_eq!(replay.start(), 7);
+        assert!(replay.since(6).is_none());
+        assert_eq!(replay.since(7).unwrap().next().map(|_| ()), Some(()));
+    }
+
+    #[test]
+    fn unique_tokens() {
+        assert_ne!(token(1), token(1));
+        assert_eq!(token(1).len(), 32);
+    }
+}
@@ -0,0 +1,141 @@
+//! CPU and IO scheduling of spawned language servers
+//!
+//! The settings are applied in the forked child right before it executes the
+//! server so they're inherited by every thread the server starts.
+
+use anyhow::Result;
+use tokio::process::Command;
+
+use crate::config::ServerOptions;
+
+/// Apply the configured niceness, IO priority and CPU affinity to the
+/// process spawned by `command`
+#[cfg(unix)]
+pub fn apply(command: &mut Command, options: &ServerOptions) -> Result<()> {
+    use std::io;
+
+    if options.nice.is_none() && options.io_priority.is_none() && options.cpu_affinity.is_none() {
+        return Ok(());
+    }
+    let nice = options.nice;
+    let io_priority = options.io_priority.map(imp::io_priority);
+    let cpu_set = options
+        .cpu_affinity
+        .as_deref()
+        .map(imp::cpu_set)
+        .transpose()?;
+
+    // SAFETY: The closure runs in the forked child, it must only call async
+    // signal safe functions and not allocate, everything is prepared above.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(io_priority) = io_priority {
+                imp::set_io_priority(io_priority)?;
+            }
+            if let Some(cpu_set) = &cpu_set {
+                imp::set_cpu_affinity(cpu_set)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(_command: &mut Command, options: &ServerOptions) -> Result<()> {
+    use anyhow::ensure;
+
+    ensure!(
+        options.nice.is_none() && options.io_priority.is_none() && options.cpu_affinity.is_none(),
+        "server scheduling options are not supported on this platform",
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{io, mem};
+
+    use anyhow::{ensure, Result};
+
+    use crate::config::IoPriority;
+
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    pub fn io_priority(priority: IoPriority) -> libc::c_int {
+        match priority {
+            IoPriority::BestEffort(level) => {
+                IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | libc::c_int::from(level)
+            }
+            IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        }
+    }
+
+    pub fn set_io_priority(io_priority: libc::c_int) -> io::Result<()> {
+        // SAFETY: ioprio_set only reads its integer arguments.
+        let res =
+            unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io_priority) };
+        match res {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn cpu_set(cpus: &[usize]) -> Result<libc::cpu_set_t> {
+        let max = mem::size_of::<libc::cpu_set_t>() * 8;
+        ensure!(!cpus.is_empty(), "cpu_affinity must list at least one CPU");
+        // SAFETY: cpu_set_t is a plain bit mask, all zeroes is an empty set.
+        let mut set = unsafe { mem::zeroed::<libc::cpu_set_t>() };
+        for &cpu in cpus {
+            ensure!(cpu < max, "CPU {cpu} in cpu_affinity is out of range");
+            // SAFETY: The index is checked to be in range.
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        Ok(set)
+    }
+
+    pub fn set_cpu_affinity(set: &libc::cpu_set_t) -> io::Result<()> {
+        // SAFETY: The set is a valid cpu_set_t of the size passed.
+        let res = unsafe { libc::sched_setaffinity(0, mem::size_of_val(set), set) };
+        match res {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod imp {
+    use std::io;
+
+    use anyhow::{bail, Result};
+
+    use crate::config::IoPriority;
+
+    #[derive(Clone, Copy)]
+    pub struct Unsupported;
+
+    pub fn io_priority(_priority: IoPriority) -> Unsupported {
+        Unsupported
+    }
+
+    pub fn set_io_priority(_io_priority: Unsupported) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn cpu_set(_cpus: &[usize]) -> Result<Unsupported> {
+        bail!("cpu_affinity is only supported on linux");
+    }
+
+    pub fn set_cpu_affinity(_set: &Unsupported) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
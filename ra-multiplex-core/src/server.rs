@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{self, DuplexStream};
+use tokio::runtime::{self, Handle};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::{select, task};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::chaos::Chaos;
+use crate::client;
+use crate::config::{Config, IpNetwork};
+use crate::instance::InstanceMap;
+use crate::log_filter::LogFilter;
+use crate::lsp::ext::{self, ClientMode, LspMuxOptions};
+use crate::lsp::transport::{Compression, WireEncoding};
+use crate::paths;
+use crate::socketwrapper::{Listener, SocketAddr, Stream};
+use crate::state::ServerState;
+
+/// Buffer size of in-process client connections
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Run a server accepting clients on the `listen` address until it's stopped
+pub async fn run(config: &Config, options: ServerOptions) -> Result<()> {
+    let server = Server::with_options(config.clone(), options).await?;
+    let listener = server.bind().await?;
+    // Written only once listening, a server failing to start must not replace
+    // the pidfile of the running one.
+    let _pidfile = paths::PidFile::create()
+        .map_err(|err| warn!(?err, "cannot write pidfile"))
+        .ok();
+    server.accept(listener).await
+}
+
+/// Language server instances shared by clients connecting in-process or over
+/// the `listen` address
+///
+/// Embedding a server lets IDE backends share instances without running
+/// `ra-multiplex server` and going through a socket:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use ra_multiplex_core::config::Config;
+/// use ra_multiplex_core::server::{ClientOptions, Server};
+///
+/// let server = Server::new(Config::default()).await?;
+/// let stream = server.connect(ClientOptions::new("rust-analyzer"));
+/// // Talk LSP over `stream` like over the language server's stdio, starting
+/// // with the `initialize` request.
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    instance_map: Arc<Mutex<InstanceMap>>,
+    shutdown: Arc<Notify>,
+    transport: Option<Arc<Transport>>,
+}
+
+/// Current-thread runtime running the client connections with
+/// `runtime.transport_thread`, it stops when the server is dropped
+struct Transport {
+    handle: Handle,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Transport {
+    fn start() -> Result<Self> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("building the transport runtime")?;
+        let handle = runtime.handle().clone();
+        let (stop, stopped) = oneshot::channel::<()>();
+        thread::Builder::new()
+            .name("ra-mux-transport".into())
+            .spawn(move || {
+                let _ = runtime.block_on(stopped);
+            })
+            .context("starting the transport thread")?;
+        Ok(Transport {
+            handle,
+            _stop: stop,
+        })
+    }
+}
+
+/// Settings of a server which aren't part of its [`Config`], see
+/// [`Server::with_options`]
+#[derive(Clone, Default)]
+pub struct ServerOptions {
+    /// Filter of the logger the server changes for `ra-multiplex log-level`
+    pub log_filter: Option<LogFilter>,
+    /// Faults to inject into client connections
+    pub chaos: Option<Chaos>,
+}
+
+/// Language server an in-process client uses, see [`Server::connect`]
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// Language server command, a name is looked up on the `PATH`
+    pub server: String,
+    /// Arguments of the language server
+    pub args: Vec<String>,
+    /// Environment variables the language server is started with
+    pub env: BTreeMap<String, String>,
+    /// Directory used as the workspace root if `initialize` has none
+    pub cwd: Option<String>,
+    /// Label keeping the instances of clients with different tags apart
+    pub tag: Option<String>,
+    /// Connect as an observer whose document changes and edits are dropped
+    pub observer: bool,
+    /// Get notified about the documents the client with this ID opens and
+    /// closes
+    pub follow: Option<usize>,
+}
+
+impl ClientOptions {
+    pub fn new(server: impl Into<String>) -> Self {
+        ClientOptions {
+            server: server.into(),
+            ..ClientOptions::default()
+        }
+    }
+
+    fn lsp_mux(self) -> LspMuxOptions {
+        LspMuxOptions {
+            version: LspMuxOptions::PROTOCOL_VERSION.to_owned(),
+            mode: match self.observer {
+                true => ClientMode::Observer,
+                false => ClientMode::Normal,
+            },
+            follow: self.follow,
+            encoding: WireEncoding::Lsp,
+            compression: Compression::Off,
+            resumable: false,
+            reconnect_token: None,
+            received: None,
+            method: ext::Request::Connect {
+                server: self.server,
+                args: self.args,
+                env: self.env,
+                cwd: self.cwd,
+                tag: self.tag,
+            },
+        }
+    }
+}
+
+impl Server {
+    /// Start a server with `config`
+    ///
+    /// With `adopt_instances` enabled the instances a detached server left
+    /// running are adopted.
+    pub async fn new(config: Config) -> Result<Self> {
+        Server::with_options(config, ServerOptions::default()).await
+    }
+
+    /// Start a server with `config` and `options`
+    pub async fn with_options(config: Config, options: ServerOptions) -> Result<Self> {
+        let config = Arc::new(config);
+        let state = ServerState::new(&config, options.log_filter, options.chaos).await?;
+        let instance_map = InstanceMap::new(config.clone(), state.clone()).await;
+        #[cfg(unix)]
+        if config.adopt_instances {
+            crate::instance::adopt_instances(&instance_map).await;
+        }
+        let transport = match config.runtime.transport_thread {
+            true => Some(Arc::new(Transport::start()?)),
+            false => None,
+        };
+        Ok(Server {
+            config,
+            state,
+            instance_map,
+            shutdown: Arc::new(Notify::new()),
+            transport,
+        })
+    }
+
+    /// Run `future` on the transport runtime if there is one
+    async fn on_transport<T>(
+        &self,
+        future: impl Future<Output = Result<T>> + Send + 'static,
+    ) -> Result<T>
+    where
+        T: Send + 'static,
+    {
+        match &self.transport {
+            Some(transport) => transport
+                .handle
+                .spawn(future)
+                .await
+                .context("transport task failed")
+                .and_then(|res| res),
+            None => future.await,
+        }
+    }
+
+    /// Another handle to the same server for tasks outliving a borrow of it
+    fn share(&self) -> Server {
+        Server {
+            config: self.config.clone(),
+            state: self.state.clone(),
+            instance_map: self.instance_map.clone(),
+            shutdown: self.shutdown.clone(),
+            transport: self.transport.clone(),
+        }
+    }
+
+    /// Connect a client in-process
+    ///
+    /// Returns the client's end of the connection. It's used like the stdio
+    /// of the language server, the first message must be the `initialize`
+    /// request. It needs no `lspMux` initialization options.
+    pub fn connect(&self, options: ClientOptions) -> DuplexStream {
+        let (ours, theirs) = io::duplex(PIPE_SIZE);
+        let client_id = client::next_client_id();
+        self.state.audit.in_process(client_id);
+        let instance_map = self.instance_map.clone();
+        let config = self.config.clone();
+        let state = self.state.clone();
+        let shutdown = self.shutdown.clone();
+        let process = async move {
+            info!("client connected in-process");
+            let socket = Stream::Channel { channel: ours };
+            let preset = Some(options.lsp_mux());
+            let res = client::process(
+                socket,
+                client_id,
+                instance_map,
+                config,
+                state,
+                shutdown,
+                preset,
+            )
+            .await;
+            if let Err(err) = res {
+                error!("client error: {err:?}");
+            }
+        }
+        .instrument(info_span!("client", %client_id));
+        match &self.transport {
+            Some(transport) => transport.handle.spawn(process),
+            None => task::spawn(process),
+        };
+        theirs
+    }
+
+    /// Shut down all instances and make [`listen`](Self::listen) return,
+    /// returns the number of instances shut down
+    ///
+    /// With `detach` and `adopt_instances` enabled the instances are left
+    /// running for the next server to adopt.
+    pub async fn stop(&self, detach: bool) -> usize {
+        let count = InstanceMap::shutdown(&self.instance_map, detach).await;
+        self.shutdown.notify_one();
+        count
+    }
+
+    /// Accept clients on the `listen` address until the server is stopped
+    pub async fn listen(&self) -> Result<()> {
+        let listener = self.bind().await?;
+        self.accept(listener).await
+    }
+
+    /// Bind the `listen` address, on the transport runtime the connections
+    /// accepted later are served on
+    async fn bind(&self) -> Result<Listener> {
+        allowed_ips(&self.config)?;
+        let address = self.config.listen.clone();
+        let listener = self
+            .on_transport(async move { Listener::bind(&address).await.context("listen") })
+            .await?;
+        info!(socket = ?self.config.listen, "listening");
+        Ok(listener)
+    }
+
+    async fn accept(&self, listener: Listener) -> Result<()> {
+        let server = self.share();
+        self.on_transport(async move { server.accept_clients(listener).await })
+            .await
+    }
+
+    async fn accept_clients(&self, listener: Listener) -> Result<()> {
+        let Server {
+            config,
+            state,
+            instance_map,
+            shutdown,
+            transport: _,
+        } = self;
+        let allowed_ips = allowed_ips(config)?;
+        loop {
+            let accept = select! {
+                accept = listener.accept() => accept,
+                _ = shutdown.notified() => {
+                    state.stats.flush().await;
+                    info!("server stopped");
+                    return Ok(());
+                }
+            };
+            match accept {
+                Ok((socket, addr)) => {
+                    if let (Some(allowed_ips), SocketAddr::Ip(ip_addr)) = (allowed_ips, &addr) {
+                        let ip = ip_addr.ip();
+                        let allowed = ip.to_canonical().is_loopback()
+                            || allowed_ips.iter().any(|network| network.contains(ip));
+                        if !allowed {
+                            warn!(%addr, "rejecting connection from address not in allowed_ips");
+                            continue;
+                        }
+                    }
+                    let client_id = client::next_client_id();
+                    state.audit.accept(client_id, &addr, &socket);
+                    let instance_map = instance_map.clone();
+                    let config = config.clone();
+                    let state = state.clone();
+                    let shutdown = shutdown.clone();
+
+                    task::spawn(
+                        async move {
+                            info!(%addr, "client connected");
+                            match client::process(
+                                socket,
+                                client_id,
+                                instance_map,
+                                config,
+                                state,
+                                shutdown,
+                                None,
+                            )
+                            .await
+                            {
+                                Ok(_) => {}
+                                Err(err) => error!("client error: {err:?}"),
+                            }
+                        }
+                        .instrument(info_span!("client", %client_id)),
+                    );
+                }
+                Err(err) => match err.kind() {
+                    // ignore benign errors
+                    std::io::ErrorKind::NotConnected => {
+                        warn!("listener error {err}");
+                    }
+                    _ => {
+                        Err(err).context("accept connection")?;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Networks clients may connect from, `None` if the server only listens on
+/// loopback or not on IP at all
+///
+/// Refuses to listen on another IP address without `allowed_ips`, the
+/// protocol has no authentication.
+fn allowed_ips(config: &Config) -> Result<Option<&[IpNetwork]>> {
+    let Some(ip) = config.listen.ip() else {
+        return Ok(None);
+    };
+    if ip.to_canonical().is_loopback() {
+        return Ok(None);
+    }
+    if config.allowed_ips.is_empty() {
+        bail!(
+            "listening on non-loopback address {ip} requires `allowed_ips` listing the networks \
+             clients connect from, e.g. [\"192.168.1.0/24\"]"
+        );
+    }
+    Ok(Some(&config.allowed_ips))
+}
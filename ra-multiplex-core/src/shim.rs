@@ -0,0 +1,513 @@
+//! Shim keeping language servers running across daemon restarts
+//!
+//! With `adopt_instances` the daemon doesn't spawn language servers directly
+//! but through `ra-multiplex shim` which owns the server's stdio and relays it
+//! over a unix socket. When the daemon goes away the shim and the server keep
+//! running, a newly started daemon connects to the socket again and adopts the
+//! instance with its in-memory state.
+//!
+//! Right after a daemon connects the shim sends it [`ext::SHIM_HELLO`] with
+//! the state the previous daemon left and the server's dynamic capability
+//! registrations. Documents opened through a daemon are closed when it
+//! disconnects, the clients opening them are gone with it. Requests of the
+//! server the daemon didn't answer are sent again to the next one.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use tokio::io::{self, AsyncBufRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, ChildStderr, ChildStdin, Command};
+use tokio::sync::mpsc;
+use tokio::{fs, select, task, time};
+use tracing::{debug, error};
+
+use crate::lsp::ext::{self, ShimHello};
+use crate::lsp::jsonrpc::{Message, Notification, Request, RequestId, Version};
+use crate::lsp::transport::{LspReader, LspWriter};
+use crate::lsp::{self, Registration};
+use crate::paths;
+
+/// How long the daemon waits for a spawned shim to start listening
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exit status of a shim which didn't find the language server, like a shell's
+const NOT_FOUND_STATUS: i32 = 127;
+
+/// Exit status of a shim which wasn't allowed to execute the language server
+const NOT_EXECUTABLE_STATUS: i32 = 126;
+
+/// The shim couldn't execute the language server, tells the daemon why with
+/// its exit status
+#[derive(Debug)]
+pub struct ExecFailed(pub std::io::ErrorKind);
+
+impl std::fmt::Display for ExecFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shim couldn't execute the server: {}", self.0)
+    }
+}
+
+impl std::error::Error for ExecFailed {}
+
+/// Directory of the shims' sockets, the daemon adopts every shim listening in
+/// it when it starts
+pub fn socket_dir() -> Result<PathBuf> {
+    Ok(paths::runtime_dir()?.join("instances"))
+}
+
+/// Directories the daemon adopts shims from, shims started by versions before
+/// the sockets moved to the runtime directory listen in the data directory
+pub fn socket_dirs() -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![socket_dir()?];
+    let legacy = paths::data_dir()?.join("instances");
+    if !dirs.contains(&legacy) {
+        dirs.push(legacy);
+    }
+    Ok(dirs)
+}
+
+/// Create a unique socket path for a new shim
+pub async fn socket_path() -> Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = socket_dir()?;
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("creating {dir:?}"))?;
+    let now = ::time::OffsetDateTime::now_utc().unix_timestamp();
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    Ok(dir.join(format!("{now}-{}-{id}.sock", process::id())))
+}
+
+/// Command prefix running the language server through a shim listening on
+/// `socket`
+pub fn command(socket: &Path) -> Result<Vec<String>> {
+    let exe = std::env::current_exe().context("finding ra-multiplex executable")?;
+    let exe = exe.to_str().context("executable path is not valid utf-8")?;
+    let socket = socket.to_str().context("socket path is not valid utf-8")?;
+    Ok(["shim", "--socket", socket, "--"].into_iter().fold(
+        vec![exe.to_owned()],
+        |mut command, arg| {
+            command.push(arg.to_owned());
+            command
+        },
+    ))
+}
+
+/// Connect to a shim spawned as `child` once it starts listening
+pub async fn connect(socket: &Path, child: &mut Child) -> Result<UnixStream> {
+    let deadline = time::Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        if let Ok(stream) = UnixStream::connect(socket).await {
+            return Ok(stream);
+        }
+        if let Some(status) = child.try_wait().context("waiting for shim")? {
+            match status.code() {
+                Some(NOT_FOUND_STATUS) => bail!(ExecFailed(std::io::ErrorKind::NotFound)),
+                Some(NOT_EXECUTABLE_STATUS) => {
+                    bail!(ExecFailed(std::io::ErrorKind::PermissionDenied))
+                }
+                _ => bail!("shim exited with {status}"),
+            }
+        }
+        if time::Instant::now() > deadline {
+            bail!("shim didn't start listening on {socket:?}");
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Receive the hello message a shim sends first
+pub async fn hello<R>(reader: &mut LspReader<R>) -> Result<ShimHello>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let hello = match reader
+        .read_message()
+        .await
+        .context("receive shim hello")?
+        .context("stream ended")?
+    {
+        Message::Notification(notif) if notif.method == ext::SHIM_HELLO => notif.params,
+        _ => bail!("first shim message was not hello"),
+    };
+    serde_json::from_value(hello).context("parse shim hello")
+}
+
+/// Kill a shim and the language server it runs
+pub fn kill(shim_pid: u32) -> std::io::Result<()> {
+    // The shim is the leader of its own process group.
+    // SAFETY: Sending a signal has no memory safety implications.
+    if unsafe { libc::kill(-(shim_pid as libc::pid_t), libc::SIGKILL) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Check whether a shim is still running
+pub fn is_running(shim_pid: u32) -> bool {
+    // SAFETY: Signal 0 only checks the process exists.
+    unsafe { libc::kill(shim_pid as libc::pid_t, 0) == 0 }
+}
+
+/// Messages and state shared by the tasks of a shim
+#[derive(Default)]
+struct Shared {
+    /// Sends messages to the connected daemon, `None` while there is none
+    daemon: Mutex<Option<mpsc::Sender<Message>>>,
+
+    /// Server requests no daemon answered yet in the order they were sent,
+    /// only locked while holding the `daemon` lock
+    unanswered: Mutex<Vec<Request>>,
+
+    /// Capabilities registered by the server by registration ID
+    registrations: Mutex<HashMap<String, Registration>>,
+}
+
+/// Run the language server `command` and relay its stdio to daemons
+/// connecting to `socket`
+///
+/// Exits the process the way the language server exited.
+pub async fn run(socket: PathBuf, command: Vec<String>) -> Result<()> {
+    // Leave the session of the daemon so the shim isn't killed together with
+    // its terminal or process group.
+    // SAFETY: setsid has no preconditions, it fails if we're a group leader.
+    unsafe { libc::setsid() };
+
+    let (program, args) = command.split_first().context("missing server command")?;
+    let spawned = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(err) => {
+            // The daemon tells the client, it only learns why from the status.
+            error!(?err, ?program, "spawning language server");
+            match err.kind() {
+                std::io::ErrorKind::NotFound => process::exit(NOT_FOUND_STATUS),
+                std::io::ErrorKind::PermissionDenied => process::exit(NOT_EXECUTABLE_STATUS),
+                _ => return Err(err).with_context(|| format!("spawning {program:?}")),
+            }
+        }
+    };
+    let server_pid = child.id().context("child exited early, couldn't get PID")?;
+
+    task::spawn(relay_stderr(child.stderr.take().unwrap()));
+    let shared = Arc::new(Shared::default());
+    let reader = LspReader::new(BufReader::new(child.stdout.take().unwrap()), "server");
+    task::spawn(server_output(reader, shared.clone()));
+    let writer = LspWriter::new(child.stdin.take().unwrap(), "server");
+
+    let _ = fs::remove_file(&socket).await;
+    let listener = UnixListener::bind(&socket).with_context(|| format!("binding {socket:?}"))?;
+
+    let status = select! {
+        status = child.wait() => status,
+        Err(err) = serve(listener, writer, shared, server_pid) => {
+            // Nobody can talk to the server anymore.
+            error!(?err, "shim stopped serving");
+            let _ = child.start_kill();
+            child.wait().await
+        }
+    };
+    let _ = fs::remove_file(&socket).await;
+    let status = status.context("waiting for server")?;
+
+    use std::os::unix::process::ExitStatusExt;
+    if let Some(signal) = status.signal() {
+        // SAFETY: Resetting the handler and raising the signal the server
+        // died of is always sound.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// Pass the server's stderr on, it's the daemon's log as long as the daemon
+/// which spawned the shim is running
+async fn relay_stderr(mut stderr: ChildStderr) {
+    let mut out = io::stderr();
+    let mut buffer = vec![0; 4096];
+    while let Ok(n @ 1..) = stderr.read(&mut buffer).await {
+        // Keep reading after the daemon is gone, the server would block
+        // writing into a full pipe otherwise.
+        let _ = out.write_all(&buffer[..n]).await;
+    }
+}
+
+/// Whether reading a message failed because of the stream rather than the
+/// message, reading again won't succeed then
+fn is_io_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| err.is::<std::io::Error>())
+}
+
+/// Forward server messages to the connected daemon, notifications are dropped
+/// while no daemon is connected and requests wait for the next one
+async fn server_output(
+    mut reader: LspReader<BufReader<tokio::process::ChildStdout>>,
+    shared: Arc<Shared>,
+) {
+    loop {
+        let message = match reader.read_message().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) if is_io_error(&err) => {
+                error!(?err, "reading server message");
+                break;
+            }
+            Err(err) => {
+                debug!(?err, "reading server message");
+                continue;
+            }
+        };
+        let daemon = {
+            let daemon = shared.daemon.lock().unwrap();
+            if let Message::Request(req) = &message {
+                track_registrations(&mut shared.registrations.lock().unwrap(), req);
+                shared.unanswered.lock().unwrap().push(req.clone());
+            }
+            daemon.clone()
+        };
+        if let Some(daemon) = daemon {
+            let _ = daemon.send(message).await;
+        }
+    }
+}
+
+/// Accept daemon connections one at a time and forward their messages to the
+/// server
+async fn serve(
+    listener: UnixListener,
+    mut writer: LspWriter<ChildStdin>,
+    shared: Arc<Shared>,
+    server_pid: u32,
+) -> Result<()> {
+    let mut state = Value::Null;
+    loop {
+        let (stream, _) = listener.accept().await.context("accept connection")?;
+        debug!("daemon connected");
+        let (read, write) = stream.into_split();
+        let mut reader = LspReader::new(BufReader::new(read), "daemon");
+        let mut daemon_writer = LspWriter::new(write, "daemon");
+
+        let hello = ShimHello {
+            shim_pid: process::id(),
+            server_pid,
+            state: state.clone(),
+            registrations: shared
+                .registrations
+                .lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect(),
+        };
+        let hello = Notification {
+            jsonrpc: Version,
+            method: ext::SHIM_HELLO.into(),
+            params: serde_json::to_value(hello).unwrap(),
+        };
+        if daemon_writer.write_message(&hello.into()).await.is_err() {
+            continue;
+        }
+        let (tx, mut rx) = mpsc::channel::<Message>(64);
+        let output = task::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if daemon_writer.write_message(&message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        // Requests sent after the lock is released are forwarded by
+        // `server_output`, the ones before are in the snapshot.
+        let unanswered = {
+            let mut daemon = shared.daemon.lock().unwrap();
+            *daemon = Some(tx.clone());
+            shared.unanswered.lock().unwrap().clone()
+        };
+        for req in unanswered {
+            let _ = tx.send(req.into()).await;
+        }
+        drop(tx);
+
+        let mut opened = HashSet::new();
+        loop {
+            let message = match reader.read_message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(err) if is_io_error(&err) => {
+                    debug!(?err, "reading daemon message");
+                    break;
+                }
+                Err(err) => {
+                    debug!(?err, "reading daemon message");
+                    continue;
+                }
+            };
+            match &message {
+                Message::Notification(notif) if notif.method == ext::SHIM_STATE => {
+                    state = notif.params.clone();
+                    continue;
+                }
+                Message::Notification(notif) => track_document(&mut opened, notif),
+                Message::ResponseSuccess(res) => answered(&shared, &res.id),
+                Message::ResponseError(res) => answered(&shared, &res.id),
+                Message::Request(_) => {}
+            }
+            writer
+                .write_message(&message)
+                .await
+                .context("writing to server")?;
+        }
+
+        debug!("daemon disconnected");
+        *shared.daemon.lock().unwrap() = None;
+        output.abort();
+        for uri in opened {
+            let notif = Notification {
+                jsonrpc: Version,
+                method: "textDocument/didClose".into(),
+                params: serde_json::json!({ "textDocument": { "uri": uri } }),
+            };
+            writer
+                .write_message(&notif.into())
+                .await
+                .context("writing to server")?;
+        }
+    }
+}
+
+/// Forget a server request once the daemon answered it
+fn answered(shared: &Shared, id: &RequestId) {
+    let _daemon = shared.daemon.lock().unwrap();
+    shared
+        .unanswered
+        .lock()
+        .unwrap()
+        .retain(|req| req.id != *id);
+}
+
+/// Keep track of the documents opened through a daemon
+fn track_document(opened: &mut HashSet<String>, notif: &Notification) {
+    let uri = notif
+        .params
+        .pointer("/textDocument/uri")
+        .and_then(Value::as_str);
+    match (notif.method.as_str(), uri) {
+        ("textDocument/didOpen", Some(uri)) => {
+            opened.insert(uri.to_owned());
+        }
+        ("textDocument/didClose", Some(uri)) => {
+            opened.remove(uri);
+        }
+        _ => {}
+    }
+}
+
+/// Keep track of the capabilities the server registered
+fn track_registrations(registrations: &mut HashMap<String, Registration>, req: &Request) {
+    match req.method.as_str() {
+        "client/registerCapability" => {
+            if let Ok(params) =
+                serde_json::from_value::<lsp::RegistrationParams>(req.params.clone())
+            {
+                for reg in params.registrations {
+                    registrations.insert(reg.id.clone(), reg);
+                }
+            }
+        }
+        "client/unregisterCapability" => {
+            if let Ok(params) =
+                serde_json::from_value::<lsp::UnregistrationParams>(req.params.clone())
+            {
+                for unreg in params.unregistrations {
+                    registrations.remove(&unreg.id);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn track_shim_state() {
+        let notif = |method: &str, uri: &str| Notification {
+            jsonrpc: Version,
+            method: method.into(),
+            params: json!({ "textDocument": { "uri": uri } }),
+        };
+        let mut opened = HashSet::new();
+        track_document(&mut opened, &notif("textDocument/didOpen", "file:///a.rs"));
+        track_document(&mut opened, &notif("textDocument/didOpen", "file:///b.rs"));
+        track_document(
+            &mut opened,
+            &notif("textDocument/didChange", "file:///c.rs"),
+        );
+        track_document(&mut opened, &notif("textDocument/didClose", "file:///a.rs"));
+        assert_eq!(opened, HashSet::from(["file:///b.rs".to_owned()]));
+
+        let req = |method: &str, params| Request {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+            id: RequestId::Number(1),
+        };
+        let mut registrations = HashMap::new();
+        let register = json!({ "registrations": [
+            { "id": "a", "method": "workspace/didChangeWatchedFiles" },
+            { "id": "b", "method": "textDocument/formatting" },
+        ] });
+        let unregister = json!({ "unregisterations": [
+            { "id": "a", "method": "workspace/didChangeWatchedFiles" },
+        ] });
+        track_registrations(
+            &mut registrations,
+            &req("client/registerCapability", register),
+        );
+        track_registrations(
+            &mut registrations,
+            &req("client/unregisterCapability", unregister),
+        );
+        assert_eq!(registrations.keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[tokio::test]
+    async fn report_exec_failure() {
+        let socket = std::env::temp_dir().join(format!("ra-mux-shim-{}.sock", process::id()));
+        for (status, kind) in [
+            (NOT_FOUND_STATUS, std::io::ErrorKind::NotFound),
+            (NOT_EXECUTABLE_STATUS, std::io::ErrorKind::PermissionDenied),
+        ] {
+            let mut child = Command::new("sh")
+                .args(["-c", &format!("exit {status}")])
+                .spawn()
+                .unwrap();
+            let err = connect(&socket, &mut child).await.unwrap_err();
+            let failed = err
+                .downcast_ref::<ExecFailed>()
+                .expect("not an exec failure");
+            assert_eq!(failed.0, kind);
+        }
+
+        let mut child = Command::new("sh").args(["-c", "exit 1"]).spawn().unwrap();
+        let err = connect(&socket, &mut child).await.unwrap_err();
+        assert!(err.downcast_ref::<ExecFailed>().is_none());
+    }
+}
@@ -0,0 +1,549 @@
+#[cfg(target_family = "unix")]
+use std::fs;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, io, net};
+
+use anyhow::{bail, Context as _, Result};
+use pin_project_lite::pin_project;
+use quinn::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{tcp, TcpListener, TcpStream};
+#[cfg(target_family = "unix")]
+use tokio::net::{unix, UnixListener, UnixStream};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time;
+#[cfg(target_os = "linux")]
+use tokio_vsock::{VsockAddr, VsockListener, VsockStream};
+
+use crate::config::{Address, SshAddress};
+use crate::quic;
+
+/// How long [`OwnedWriteHalf::close`] waits for the peer
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long [`OwnedWriteHalf::transport_failed`] waits for ssh to exit after
+/// its stdout closed
+const SSH_EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Exit status of ssh failing itself rather than the remote command
+const SSH_ERROR_STATUS: i32 = 255;
+
+pub enum SocketAddr {
+    Ip(net::SocketAddr),
+    #[cfg(target_family = "unix")]
+    Unix(tokio::net::unix::SocketAddr),
+    #[cfg(target_os = "linux")]
+    Vsock(VsockAddr),
+}
+
+impl From<net::SocketAddr> for SocketAddr {
+    fn from(val: net::SocketAddr) -> Self {
+        SocketAddr::Ip(val)
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl From<tokio::net::unix::SocketAddr> for SocketAddr {
+    fn from(val: tokio::net::unix::SocketAddr) -> Self {
+        SocketAddr::Unix(val)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<VsockAddr> for SocketAddr {
+    fn from(val: VsockAddr) -> Self {
+        SocketAddr::Vsock(val)
+    }
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketAddr::Ip(addr) => addr.fmt(f),
+            #[cfg(target_family = "unix")]
+            SocketAddr::Unix(addr) => match addr.as_pathname() {
+                Some(path) => path.display().fmt(f),
+                None => f.write_str("(unnamed)"),
+            },
+            #[cfg(target_os = "linux")]
+            SocketAddr::Vsock(addr) => write!(f, "vsock:{}:{}", addr.cid(), addr.port()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pin_project! {
+    #[project = OwnedReadHalfProj]
+    pub enum OwnedReadHalf {
+        Tcp{#[pin] tcp: tcp::OwnedReadHalf},
+        Channel{#[pin] channel: ReadHalf<DuplexStream>},
+        Quic{#[pin] quic: RecvStream},
+        Ssh{#[pin] stdout: ChildStdout},
+        Unix{#[pin] unix: unix::OwnedReadHalf},
+        Vsock{#[pin] vsock: tokio_vsock::OwnedReadHalf},
+    }
+}
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+pin_project! {
+    #[project = OwnedReadHalfProj]
+    pub enum OwnedReadHalf {
+        Tcp{#[pin] tcp: tcp::OwnedReadHalf},
+        Channel{#[pin] channel: ReadHalf<DuplexStream>},
+        Quic{#[pin] quic: RecvStream},
+        Ssh{#[pin] stdout: ChildStdout},
+        Unix{#[pin] unix: unix::OwnedReadHalf},
+    }
+}
+#[cfg(not(target_family = "unix"))]
+pin_project! {
+    #[project = OwnedReadHalfProj]
+    pub enum OwnedReadHalf {
+        Tcp{#[pin] tcp: tcp::OwnedReadHalf},
+        Channel{#[pin] channel: ReadHalf<DuplexStream>},
+        Quic{#[pin] quic: RecvStream},
+        Ssh{#[pin] stdout: ChildStdout},
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.project() {
+            OwnedReadHalfProj::Tcp { tcp } => tcp.poll_read(cx, buf),
+            OwnedReadHalfProj::Channel { channel } => channel.poll_read(cx, buf),
+            OwnedReadHalfProj::Quic { quic } => quic.poll_read(cx, buf),
+            OwnedReadHalfProj::Ssh { stdout, .. } => stdout.poll_read(cx, buf),
+            #[cfg(target_family = "unix")]
+            OwnedReadHalfProj::Unix { unix } => unix.poll_read(cx, buf),
+            #[cfg(target_os = "linux")]
+            OwnedReadHalfProj::Vsock { vsock } => vsock.poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pin_project! {
+    #[project = OwnedWriteHalfProj]
+    pub enum OwnedWriteHalf {
+        Tcp{#[pin] tcp: tcp::OwnedWriteHalf},
+        Channel{#[pin] channel: WriteHalf<DuplexStream>},
+        Quic{#[pin] quic: SendStream},
+        Ssh{#[pin] stdin: ChildStdin, child: Child},
+        Unix{#[pin] unix: unix::OwnedWriteHalf},
+        Vsock{#[pin] vsock: tokio_vsock::OwnedWriteHalf},
+    }
+}
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+pin_project! {
+    #[project = OwnedWriteHalfProj]
+    pub enum OwnedWriteHalf {
+        Tcp{#[pin] tcp: tcp::OwnedWriteHalf},
+        Channel{#[pin] channel: WriteHalf<DuplexStream>},
+        Quic{#[pin] quic: SendStream},
+        Ssh{#[pin] stdin: ChildStdin, child: Child},
+        Unix{#[pin] unix: unix::OwnedWriteHalf},
+    }
+}
+#[cfg(not(target_family = "unix"))]
+pin_project! {
+    #[project = OwnedWriteHalfProj]
+    pub enum OwnedWriteHalf {
+        Tcp{#[pin] tcp: tcp::OwnedWriteHalf},
+        Channel{#[pin] channel: WriteHalf<DuplexStream>},
+        Quic{#[pin] quic: SendStream},
+        Ssh{#[pin] stdin: ChildStdin, child: Child},
+    }
+}
+
+impl OwnedWriteHalf {
+    /// Shut down the writer and wait until the peer received everything
+    ///
+    /// A QUIC connection isn't closed together with the process like the
+    /// others, without this the peer only notices when the connection times
+    /// out.
+    pub async fn close(&mut self) -> io::Result<()> {
+        match self {
+            OwnedWriteHalf::Quic { quic } => {
+                quic.finish()?;
+                match time::timeout(CLOSE_TIMEOUT, quic.stopped()).await {
+                    Ok(res) => res.map(|_| ()).map_err(io::Error::from),
+                    Err(_) => Err(io::ErrorKind::TimedOut.into()),
+                }
+            }
+            _ => self.shutdown().await,
+        }
+    }
+
+    /// Whether a connection which ended failed before reaching the server,
+    /// only an ssh process can tell
+    pub async fn transport_failed(&mut self) -> bool {
+        match self {
+            OwnedWriteHalf::Ssh { child, .. } => {
+                let status = time::timeout(SSH_EXIT_TIMEOUT, child.wait()).await;
+                matches!(status, Ok(Ok(status)) if status.code() == Some(SSH_ERROR_STATUS))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.project() {
+            OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_write(cx, buf),
+            OwnedWriteHalfProj::Channel { channel } => channel.poll_write(cx, buf),
+            OwnedWriteHalfProj::Quic { quic } => AsyncWrite::poll_write(quic, cx, buf),
+            OwnedWriteHalfProj::Ssh { stdin, .. } => stdin.poll_write(cx, buf),
+            #[cfg(target_family = "unix")]
+            OwnedWriteHalfProj::Unix { unix } => unix.poll_write(cx, buf),
+            #[cfg(target_os = "linux")]
+            OwnedWriteHalfProj::Vsock { vsock } => vsock.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.project() {
+            OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_write_vectored(cx, bufs),
+            OwnedWriteHalfProj::Channel { channel } => channel.poll_write_vectored(cx, bufs),
+            OwnedWriteHalfProj::Quic { quic } => quic.poll_write_vectored(cx, bufs),
+            OwnedWriteHalfProj::Ssh { stdin, .. } => stdin.poll_write_vectored(cx, bufs),
+            #[cfg(target_family = "unix")]
+            OwnedWriteHalfProj::Unix { unix } => unix.poll_write_vectored(cx, bufs),
+            #[cfg(target_os = "linux")]
+            OwnedWriteHalfProj::Vsock { vsock } => vsock.poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.project() {
+            OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_flush(cx),
+            OwnedWriteHalfProj::Channel { channel } => channel.poll_flush(cx),
+            OwnedWriteHalfProj::Quic { quic } => quic.poll_flush(cx),
+            OwnedWriteHalfProj::Ssh { stdin, .. } => stdin.poll_flush(cx),
+            #[cfg(target_family = "unix")]
+            OwnedWriteHalfProj::Unix { unix } => unix.poll_flush(cx),
+            #[cfg(target_os = "linux")]
+            OwnedWriteHalfProj::Vsock { vsock } => vsock.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.project() {
+            OwnedWriteHalfProj::Tcp { tcp } => tcp.poll_shutdown(cx),
+            OwnedWriteHalfProj::Channel { channel } => channel.poll_shutdown(cx),
+            OwnedWriteHalfProj::Quic { quic } => quic.poll_shutdown(cx),
+            OwnedWriteHalfProj::Ssh { stdin, .. } => stdin.poll_shutdown(cx),
+            #[cfg(target_family = "unix")]
+            OwnedWriteHalfProj::Unix { unix } => unix.poll_shutdown(cx),
+            #[cfg(target_os = "linux")]
+            OwnedWriteHalfProj::Vsock { vsock } => vsock.poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pin_project! {
+    #[project = StreamProj]
+    pub enum Stream {
+        Tcp{#[pin] tcp: TcpStream},
+        Channel{#[pin] channel: DuplexStream},
+        Quic{#[pin] send: SendStream, #[pin] recv: RecvStream},
+        Ssh{#[pin] stdin: ChildStdin, #[pin] stdout: ChildStdout, child: Child},
+        Unix{#[pin] unix: UnixStream},
+        Vsock{#[pin] vsock: VsockStream},
+    }
+}
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+pin_project! {
+    #[project = StreamProj]
+    pub enum Stream {
+        Tcp{#[pin] tcp: TcpStream},
+        Channel{#[pin] channel: DuplexStream},
+        Quic{#[pin] send: SendStream, #[pin] recv: RecvStream},
+        Ssh{#[pin] stdin: ChildStdin, #[pin] stdout: ChildStdout, child: Child},
+        Unix{#[pin] unix: UnixStream},
+    }
+}
+#[cfg(not(target_family = "unix"))]
+pin_project! {
+    #[project = StreamProj]
+    pub enum Stream {
+        Tcp{#[pin] tcp: TcpStream},
+        Channel{#[pin] channel: DuplexStream},
+        Quic{#[pin] send: SendStream, #[pin] recv: RecvStream},
+        Ssh{#[pin] stdin: ChildStdin, #[pin] stdout: ChildStdout, child: Child},
+    }
+}
+
+impl Stream {
+    pub async fn connect(addr: &Address) -> Result<Stream> {
+        match addr {
+            Address::Tcp(ip_addr, port) => TcpStream::connect((*ip_addr, *port))
+                .await
+                .with_context(|| format!("connecting to tcp socket {ip_addr}:{port}"))
+                .map(|tcp| Stream::Tcp { tcp }),
+            Address::Quic(addr, fingerprint) => quic::connect(*addr, *fingerprint)
+                .await
+                .with_context(|| format!("connecting to quic socket {addr}"))
+                .map(|(send, recv)| Stream::Quic { send, recv }),
+            Address::Ssh(addr) => connect_ssh(addr)
+                .with_context(|| format!("connecting through {addr}"))
+                .map(|(stdin, stdout, child)| Stream::Ssh {
+                    stdin,
+                    stdout,
+                    child,
+                }),
+            #[cfg(target_family = "unix")]
+            Address::Unix(path) => UnixStream::connect(path)
+                .await
+                .with_context(|| format!("connecting to unix socket {path:?}"))
+                .map(|unix| Stream::Unix { unix }),
+            #[cfg(target_os = "linux")]
+            Address::Vsock(cid, port) => VsockStream::connect(VsockAddr::new(*cid, *port))
+                .await
+                .with_context(|| format!("connecting to vsock socket {cid}:{port}"))
+                .map(|vsock| Stream::Vsock { vsock }),
+        }
+    }
+
+    /// User and process ID of the peer of a Unix socket
+    pub fn peer_credentials(&self) -> Option<(u32, Option<i32>)> {
+        match self {
+            #[cfg(target_family = "unix")]
+            Stream::Unix { unix } => unix.peer_cred().ok().map(|cred| (cred.uid(), cred.pid())),
+            _ => None,
+        }
+    }
+
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        match self {
+            Stream::Tcp { tcp } => {
+                let (read, write) = tcp.into_split();
+                (
+                    OwnedReadHalf::Tcp { tcp: read },
+                    OwnedWriteHalf::Tcp { tcp: write },
+                )
+            }
+            Stream::Channel { channel } => {
+                let (read, write) = tokio::io::split(channel);
+                (
+                    OwnedReadHalf::Channel { channel: read },
+                    OwnedWriteHalf::Channel { channel: write },
+                )
+            }
+            Stream::Quic { send, recv } => (
+                OwnedReadHalf::Quic { quic: recv },
+                OwnedWriteHalf::Quic { quic: send },
+            ),
+            Stream::Ssh {
+                stdin,
+                stdout,
+                child,
+            } => (
+                OwnedReadHalf::Ssh { stdout },
+                OwnedWriteHalf::Ssh { stdin, child },
+            ),
+            #[cfg(target_family = "unix")]
+            Stream::Unix { unix } => {
+                let (read, write) = unix.into_split();
+                (
+                    OwnedReadHalf::Unix { unix: read },
+                    OwnedWriteHalf::Unix { unix: write },
+                )
+            }
+            #[cfg(target_os = "linux")]
+            Stream::Vsock { vsock } => {
+                let (read, write) = vsock.into_split();
+                (
+                    OwnedReadHalf::Vsock { vsock: read },
+                    OwnedWriteHalf::Vsock { vsock: write },
+                )
+            }
+        }
+    }
+}
+
+/// Run `ssh -W` forwarding its stdin and stdout to the server on the other
+/// machine
+///
+/// The process is killed when the write half is dropped.
+fn connect_ssh(addr: &SshAddress) -> Result<(ChildStdin, ChildStdout, Child)> {
+    let mut command = Command::new("ssh");
+    // There is no terminal to ask for a password.
+    command.args(["-o", "BatchMode=yes", "-o", "ServerAliveInterval=15"]);
+    if let Some(port) = addr.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    command
+        .arg("-W")
+        .arg(&addr.forward)
+        .arg("--")
+        .arg(&addr.destination)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command.spawn().context("spawning ssh")?;
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    Ok((stdin, stdout, child))
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.project() {
+            StreamProj::Tcp { tcp } => tcp.poll_read(cx, buf),
+            StreamProj::Channel { channel } => channel.poll_read(cx, buf),
+            StreamProj::Quic { recv, .. } => recv.poll_read(cx, buf),
+            StreamProj::Ssh { stdout, .. } => stdout.poll_read(cx, buf),
+            #[cfg(target_family = "unix")]
+            StreamProj::Unix { unix } => unix.poll_read(cx, buf),
+            #[cfg(target_os = "linux")]
+            StreamProj::Vsock { vsock } => vsock.poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.project() {
+            StreamProj::Tcp { tcp } => tcp.poll_write(cx, buf),
+            StreamProj::Channel { channel } => channel.poll_write(cx, buf),
+            StreamProj::Quic { send, .. } => AsyncWrite::poll_write(send, cx, buf),
+            StreamProj::Ssh { stdin, .. } => stdin.poll_write(cx, buf),
+            #[cfg(target_family = "unix")]
+            StreamProj::Unix { unix } => unix.poll_write(cx, buf),
+            #[cfg(target_os = "linux")]
+            StreamProj::Vsock { vsock } => vsock.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.project() {
+            StreamProj::Tcp { tcp } => tcp.poll_write_vectored(cx, bufs),
+            StreamProj::Channel { channel } => channel.poll_write_vectored(cx, bufs),
+            StreamProj::Quic { send, .. } => send.poll_write_vectored(cx, bufs),
+            StreamProj::Ssh { stdin, .. } => stdin.poll_write_vectored(cx, bufs),
+            #[cfg(target_family = "unix")]
+            StreamProj::Unix { unix } => unix.poll_write_vectored(cx, bufs),
+            #[cfg(target_os = "linux")]
+            StreamProj::Vsock { vsock } => vsock.poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.project() {
+            StreamProj::Tcp { tcp } => tcp.poll_flush(cx),
+            StreamProj::Channel { channel } => channel.poll_flush(cx),
+            StreamProj::Quic { send, .. } => send.poll_flush(cx),
+            StreamProj::Ssh { stdin, .. } => stdin.poll_flush(cx),
+            #[cfg(target_family = "unix")]
+            StreamProj::Unix { unix } => unix.poll_flush(cx),
+            #[cfg(target_os = "linux")]
+            StreamProj::Vsock { vsock } => vsock.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.project() {
+            StreamProj::Tcp { tcp } => tcp.poll_shutdown(cx),
+            StreamProj::Channel { channel } => channel.poll_shutdown(cx),
+            StreamProj::Quic { send, .. } => send.poll_shutdown(cx),
+            StreamProj::Ssh { stdin, .. } => stdin.poll_shutdown(cx),
+            #[cfg(target_family = "unix")]
+            StreamProj::Unix { unix } => unix.poll_shutdown(cx),
+            #[cfg(target_os = "linux")]
+            StreamProj::Vsock { vsock } => vsock.poll_shutdown(cx),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Quic(quic::Listener),
+    #[cfg(target_family = "unix")]
+    Unix(UnixListener),
+    #[cfg(target_os = "linux")]
+    Vsock(VsockListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &Address) -> Result<Listener> {
+        match addr {
+            Address::Tcp(ip_addr, port) => TcpListener::bind((*ip_addr, *port))
+                .await
+                .with_context(|| format!("binding to tcp socket {ip_addr}:{port}"))
+                .map(Listener::Tcp),
+            Address::Quic(addr, _) => quic::Listener::bind(*addr)
+                .with_context(|| format!("binding to quic socket {addr}"))
+                .map(Listener::Quic),
+            Address::Ssh(addr) => bail!("can't listen on ssh address {addr}"),
+            #[cfg(target_family = "unix")]
+            Address::Unix(path) => {
+                match fs::remove_file(path) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("removing old unix socket file {path:?}"))
+                    }
+                }
+                UnixListener::bind(path)
+                    .with_context(|| format!("binding to unix socket {path:?}"))
+                    .map(Listener::Unix)
+            }
+            #[cfg(target_os = "linux")]
+            Address::Vsock(cid, port) => VsockListener::bind(VsockAddr::new(*cid, *port))
+                .with_context(|| format!("binding to vsock socket {cid}:{port}"))
+                .map(Listener::Vsock),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(tcp) => {
+                let (stream, addr) = tcp.accept().await?;
+                Ok((Stream::Tcp { tcp: stream }, addr.into()))
+            }
+            Listener::Quic(quic) => {
+                let (send, recv, addr) = quic.accept().await?;
+                Ok((Stream::Quic { send, recv }, addr.into()))
+            }
+            #[cfg(target_family = "unix")]
+            Listener::Unix(unix) => {
+                let (stream, addr) = unix.accept().await?;
+                Ok((Stream::Unix { unix: stream }, addr.into()))
+            }
+            #[cfg(target_os = "linux")]
+            Listener::Vsock(vsock) => {
+                let (stream, addr) = vsock.accept().await?;
+                Ok((Stream::Vsock { vsock: stream }, addr.into()))
+            }
+        }
+    }
+}
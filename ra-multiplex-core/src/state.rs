@@ -0,0 +1,80 @@
+//! State of a server shared by its clients and instances
+//!
+//! Everything here belongs to one [`Server`](crate::server::Server), servers
+//! embedded in the same process don't see each other's sessions, events or
+//! statistics.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::audit::AuditLog;
+use crate::chaos::Chaos;
+use crate::config::Config;
+use crate::events::Events;
+use crate::log_filter::LogFilter;
+use crate::lsp::redact::Redaction;
+use crate::resume::Sessions;
+use crate::stats::Stats;
+
+pub struct ServerState {
+    /// What is hidden in logs and crash reports
+    pub redaction: Arc<Redaction>,
+
+    pub audit: AuditLog,
+    pub stats: Stats,
+    pub events: Events,
+
+    /// Sessions of resumable clients waiting for them to reconnect
+    pub sessions: Sessions,
+
+    /// Faults injected into client connections, `None` if disabled
+    pub chaos: Option<Chaos>,
+
+    /// Filter changed by `ra-multiplex log-level`, `None` unless the logger
+    /// was set up with one
+    pub log_filter: Option<LogFilter>,
+
+    /// Held while `ra-multiplex merge` moves clients, merges in opposite
+    /// directions would lock the two instances in opposite order
+    pub merging: Mutex<()>,
+
+    /// Runtime the instances run on, clients may be on the transport runtime
+    /// of `runtime.transport_thread`
+    pub runtime: Handle,
+}
+
+impl ServerState {
+    pub async fn new(
+        config: &Config,
+        log_filter: Option<LogFilter>,
+        chaos: Option<Chaos>,
+    ) -> Result<Arc<Self>> {
+        let audit = match config.audit_log {
+            true => {
+                let (audit, path) = AuditLog::open().await.context("open audit log")?;
+                info!(?path, "writing audit log");
+                audit
+            }
+            false => AuditLog::default(),
+        };
+        let stats = match config.record_stats {
+            true => Stats::start(),
+            false => Stats::default(),
+        };
+        Ok(Arc::new(ServerState {
+            redaction: Arc::new(Redaction::new(config)),
+            audit,
+            stats,
+            events: Events::new(),
+            sessions: Sessions::default(),
+            chaos,
+            log_filter,
+            merging: Mutex::new(()),
+            runtime: Handle::current(),
+        }))
+    }
+}
@@ -0,0 +1,208 @@
+//! Daily statistics kept across server restarts
+//!
+//! With `record_stats` enabled the server counts instance spawns and crashes,
+//! client requests and the latency of their responses per UTC day. The counts
+//! are added to `stats.json` in the data directory every minute and when the
+//! server stops, `ra-multiplex stats` prints them to show trends when tuning
+//! timeouts and which instances to keep around.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{fs, task};
+use tracing::{warn, Instrument};
+
+use crate::paths;
+
+/// How often the counts are added to the stored statistics
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of days kept, older ones are removed
+const MAX_DAYS: usize = 366;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct DayStats {
+    /// Language servers started, including restarts
+    pub spawns: u64,
+    /// Language servers which exited unsuccessfully
+    pub crashes: u64,
+    /// Requests clients sent
+    pub requests: u64,
+    /// Responses to client requests
+    pub responses: u64,
+    /// Total milliseconds servers took to respond
+    pub server_ms: u64,
+    /// Total milliseconds requests waited for `max_concurrent_requests`
+    pub queued_ms: u64,
+}
+
+impl DayStats {
+    /// Add the counts of `other`
+    pub fn add(&mut self, other: &DayStats) {
+        self.spawns += other.spawns;
+        self.crashes += other.crashes;
+        self.requests += other.requests;
+        self.responses += other.responses;
+        self.server_ms += other.server_ms;
+        self.queued_ms += other.queued_ms;
+    }
+}
+
+/// Counts since the last flush by UTC day
+type Pending = Mutex<BTreeMap<String, DayStats>>;
+
+/// Statistics of one server, counts nothing unless it's started
+#[derive(Default)]
+pub struct Stats {
+    pending: Option<Arc<Pending>>,
+}
+
+impl Stats {
+    /// Start counting and flush the counts periodically until dropped
+    pub fn start() -> Self {
+        let pending = Arc::<Pending>::default();
+        let weak = Arc::downgrade(&pending);
+        task::spawn(
+            async move {
+                loop {
+                    tokio::time::sleep(FLUSH_INTERVAL).await;
+                    let Some(pending) = weak.upgrade() else {
+                        return;
+                    };
+                    flush(&pending).await;
+                }
+            }
+            .in_current_span(),
+        );
+        Stats {
+            pending: Some(pending),
+        }
+    }
+
+    /// A language server was started
+    pub fn spawned(&self) {
+        self.count(|day| day.spawns += 1);
+    }
+
+    /// A language server exited unsuccessfully
+    pub fn crashed(&self) {
+        self.count(|day| day.crashes += 1);
+    }
+
+    /// A client sent a request
+    pub fn request(&self) {
+        self.count(|day| day.requests += 1);
+    }
+
+    /// A server responded to a client request
+    pub fn response(&self, server_ms: u64, queued_ms: u64) {
+        self.count(|day| {
+            day.responses += 1;
+            day.server_ms += server_ms;
+            day.queued_ms += queued_ms;
+        });
+    }
+
+    fn count(&self, f: impl FnOnce(&mut DayStats)) {
+        let Some(pending) = &self.pending else {
+            return;
+        };
+        let today = time::OffsetDateTime::now_utc().date().to_string();
+        f(pending.lock().unwrap().entry(today).or_default());
+    }
+
+    /// Add the counts since the last flush to the stored statistics
+    pub async fn flush(&self) {
+        if let Some(pending) = &self.pending {
+            flush(pending).await;
+        }
+    }
+}
+
+/// Add the counts in `pending` to the stored statistics
+async fn flush(pending: &Pending) {
+    let pending = std::mem::take(&mut *pending.lock().unwrap());
+    if pending.is_empty() {
+        return;
+    }
+    let res = async {
+        let mut days = load().await?;
+        merge(&mut days, &pending);
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating {dir:?}"))?;
+        }
+        // Write a new file and replace the old one so it's never left half
+        // written.
+        let tmp = path.with_extension("json.tmp");
+        let json = serde_json::to_string_pretty(&days).unwrap();
+        fs::write(&tmp, json)
+            .await
+            .with_context(|| format!("writing {tmp:?}"))?;
+        fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("replacing {path:?}"))
+    };
+    if let Err(err) = res.await {
+        warn!(?err, "error saving statistics");
+    }
+}
+
+/// Add `pending` counts to `days` and remove the oldest days above
+/// [`MAX_DAYS`]
+fn merge(days: &mut BTreeMap<String, DayStats>, pending: &BTreeMap<String, DayStats>) {
+    for (day, stats) in pending {
+        days.entry(day.clone()).or_default().add(stats);
+    }
+    while days.len() > MAX_DAYS {
+        days.pop_first();
+    }
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(paths::data_dir()?.join("stats.json"))
+}
+
+/// Stored statistics by UTC day, oldest first
+pub async fn load() -> Result<BTreeMap<String, DayStats>> {
+    let path = path()?;
+    match fs::read_to_string(&path).await {
+        Ok(json) => serde_json::from_str(&json).with_context(|| format!("parsing {path:?}")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err).with_context(|| format!("reading {path:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_days() {
+        let day = |spawns| DayStats {
+            spawns,
+            ..DayStats::default()
+        };
+        let mut days = (0..MAX_DAYS)
+            .map(|n| (format!("day-{n:03}"), day(1)))
+            .collect::<BTreeMap<_, _>>();
+        let pending = BTreeMap::from([
+            ("day-365".to_owned(), day(2)),
+            ("day-366".to_owned(), day(5)),
+        ]);
+        merge(&mut days, &pending);
+
+        assert_eq!(days.len(), MAX_DAYS);
+        assert!(!days.contains_key("day-000"));
+        assert_eq!(days["day-365"].spawns, 3);
+        assert_eq!(days["day-366"].spawns, 5);
+    }
+}
@@ -0,0 +1,164 @@
+//! Selecting rust-analyzer from the toolchain a workspace is pinned to
+//!
+//! Workspaces can pin a toolchain with a `rust-toolchain.toml` or legacy
+//! `rust-toolchain` file. The rust-analyzer binary of that toolchain is found
+//! with `rustup which` and the resolved path is used as the server, so
+//! workspaces on different toolchains don't end up sharing an instance.
+//! Resolved paths are remembered per toolchain, they don't change when the
+//! toolchain is updated.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use serde_derive::Deserialize;
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Paths of rust-analyzer resolved by `rustup which` by toolchain
+static RESOLVED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Resolve `server` to the rust-analyzer of the toolchain `workspace_root` is
+/// pinned to
+///
+/// Other servers and workspaces without a toolchain file are returned
+/// unchanged, so is `server` if resolving it fails.
+pub async fn resolve_server(server: &str, workspace_root: &str) -> String {
+    if Path::new(server).file_stem() != Some("rust-analyzer".as_ref()) {
+        return server.to_owned();
+    }
+    let toolchain = match find_toolchain(Path::new(workspace_root)).await {
+        Ok(Some(toolchain)) => toolchain,
+        Ok(None) => return server.to_owned(),
+        Err(err) => {
+            warn!(?err, ?workspace_root, "error reading toolchain file");
+            return server.to_owned();
+        }
+    };
+    let resolved = RESOLVED.lock().unwrap().get(&toolchain).cloned();
+    if let Some(path) = resolved {
+        return path;
+    }
+    match rustup_which(&toolchain, workspace_root).await {
+        Ok(path) => {
+            debug!(?toolchain, ?path, "resolved rust-analyzer");
+            RESOLVED.lock().unwrap().insert(toolchain, path.clone());
+            path
+        }
+        Err(err) => {
+            warn!(?err, ?toolchain, "error resolving rust-analyzer");
+            server.to_owned()
+        }
+    }
+}
+
+/// Find the toolchain file closest to `dir` and return the toolchain it pins
+async fn find_toolchain(dir: &Path) -> Result<Option<String>> {
+    for dir in dir.ancestors() {
+        for name in ["rust-toolchain.toml", "rust-toolchain"] {
+            let path = dir.join(name);
+            if fs::try_exists(&path).await? {
+                let text = fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("reading {path:?}"))?;
+                return parse_toolchain_file(&text)
+                    .with_context(|| format!("parsing {path:?}"))
+                    .map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Parse the channel out of a toolchain file
+///
+/// The legacy `rust-toolchain` file may contain just the channel name.
+fn parse_toolchain_file(text: &str) -> Result<String> {
+    #[derive(Deserialize)]
+    struct ToolchainFile {
+        toolchain: Toolchain,
+    }
+
+    #[derive(Deserialize)]
+    struct Toolchain {
+        channel: Option<String>,
+        path: Option<PathBuf>,
+    }
+
+    let text = text.trim();
+    if !text.is_empty() && !text.contains(['\n', '=', '[']) {
+        return Ok(text.to_owned());
+    }
+    let file = toml::from_str::<ToolchainFile>(text)?;
+    match file.toolchain {
+        Toolchain {
+            channel: Some(channel),
+            ..
+        } => Ok(channel),
+        Toolchain {
+            path: Some(path), ..
+        } => Ok(path.display().to_string()),
+        _ => bail!("toolchain file specifies neither channel nor path"),
+    }
+}
+
+/// Ask rustup for the path of rust-analyzer in `toolchain`
+async fn rustup_which(toolchain: &str, cwd: &str) -> Result<String> {
+    let output = Command::new("rustup")
+        .args(["which", "--toolchain", toolchain, "rust-analyzer"])
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("running rustup")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "rustup failed: {}",
+            stderr.lines().next().unwrap_or_default()
+        );
+    }
+    let path = String::from_utf8(output.stdout).context("rustup output is not utf-8")?;
+    Ok(path.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn remember_resolved_path() {
+        let dir = std::env::temp_dir().join(format!("ra-mux-toolchain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("rust-toolchain"), "ra-mux-test-toolchain\n").unwrap();
+        let root = dir.to_str().unwrap();
+
+        // rustup doesn't know the toolchain, it's never asked for it again.
+        let path = "/opt/ra-mux-test/rust-analyzer".to_owned();
+        RESOLVED
+            .lock()
+            .unwrap()
+            .insert("ra-mux-test-toolchain".into(), path.clone());
+        assert_eq!(resolve_server("rust-analyzer", root).await, path);
+        assert_eq!(resolve_server("clangd", root).await, "clangd");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_toolchain_files() {
+        let parse = |text| parse_toolchain_file(text).unwrap();
+
+        assert_eq!(parse("nightly-2024-05-01\n"), "nightly-2024-05-01");
+        assert_eq!(parse("[toolchain]\nchannel = \"1.78\"\n"), "1.78");
+        assert_eq!(
+            parse("[toolchain]\nchannel = \"stable\"\ncomponents = [\"rust-analyzer\"]\n"),
+            "stable"
+        );
+        assert_eq!(parse("[toolchain]\npath = \"/opt/rust\"\n"), "/opt/rust");
+        assert!(parse_toolchain_file("[toolchain]\nprofile = \"minimal\"\n").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! Traffic statistics of language server instances
+//!
+//! Counts messages and bytes exchanged with each language server so `status`
+//! can show which instance is the busiest.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::lsp::ext;
+use crate::lsp::jsonrpc::Message;
+
+pub struct TrafficStats {
+    started: Instant,
+    to_server: Counter,
+    from_server: Counter,
+    errors: AtomicU64,
+    requests: Mutex<BTreeMap<String, u64>>,
+}
+
+#[derive(Default)]
+struct Counter {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counter {
+    fn add(&self, bytes: u64) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl TrafficStats {
+    pub fn new() -> TrafficStats {
+        TrafficStats {
+            started: Instant::now(),
+            to_server: Counter::default(),
+            from_server: Counter::default(),
+            errors: AtomicU64::new(0),
+            requests: Mutex::default(),
+        }
+    }
+
+    /// Record a message written to the server
+    pub fn sent(&self, message: &Message, bytes: u64) {
+        self.to_server.add(bytes);
+        self.count_request(message);
+    }
+
+    /// Record a message read from the server
+    ///
+    /// Messages after the first one in a batch are read with 0 `bytes`.
+    pub fn received(&self, message: &Message, bytes: u64) {
+        self.from_server.add(bytes);
+        if let Message::ResponseError(_) = message {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.count_request(message);
+    }
+
+    fn count_request(&self, message: &Message) {
+        if let Message::Request(req) = message {
+            let mut requests = self.requests.lock().unwrap();
+            *requests.entry(req.method.clone()).or_default() += 1;
+        }
+    }
+
+    pub fn get_status(&self) -> ext::Traffic {
+        ext::Traffic {
+            seconds: self.started.elapsed().as_secs(),
+            messages_to_server: self.to_server.messages.load(Ordering::Relaxed),
+            bytes_to_server: self.to_server.bytes.load(Ordering::Relaxed),
+            messages_from_server: self.from_server.messages.load(Ordering::Relaxed),
+            bytes_from_server: self.from_server.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            requests: self.requests.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::lsp::jsonrpc::{self, Request, RequestId, ResponseError, Version};
+
+    #[test]
+    fn count_messages_and_requests() {
+        let traffic = TrafficStats::new();
+        let request = |method: &str| -> Message {
+            Request {
+                jsonrpc: Version,
+                method: method.into(),
+                params: Value::Null,
+                id: RequestId::Number(1),
+            }
+            .into()
+        };
+        let error = ResponseError {
+            jsonrpc: Version,
+            error: jsonrpc::Error {
+                code: -32603,
+                message: "internal error".into(),
+                data: None,
+            },
+            id: RequestId::Number(1),
+        };
+
+        traffic.sent(&request("textDocument/hover"), 10);
+        traffic.sent(&request("textDocument/hover"), 20);
+        traffic.received(&request("workspace/configuration"), 5);
+        traffic.received(&error.into(), 7);
+
+        let status = traffic.get_status();
+        assert_eq!(status.messages_to_server, 2);
+        assert_eq!(status.bytes_to_server, 30);
+        assert_eq!(status.messages_from_server, 2);
+        assert_eq!(status.bytes_from_server, 12);
+        assert_eq!(status.errors, 1);
+        assert_eq!(status.requests["textDocument/hover"], 2);
+        assert_eq!(status.requests["workspace/configuration"], 1);
+    }
+}
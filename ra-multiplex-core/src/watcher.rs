@@ -0,0 +1,682 @@
+//! File watching for language servers done by the daemon
+//!
+//! Language servers ask clients to watch files with dynamic
+//! `workspace/didChangeWatchedFiles` registrations. With many editors open on
+//! the same workspace each one runs its own watcher and the server receives
+//! the same change from every one of them. When enabled the daemon serves the
+//! registrations itself with a single watcher per instance and the clients
+//! never see them.
+//!
+//! Directories are watched one by one so the ones excluded by a `.gitignore`,
+//! like `target/`, and `.git` itself aren't watched at all.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::{debug, warn, Instrument};
+
+use crate::lsp::{self, BaseUri, FileEvent, GlobPattern};
+
+pub const DID_CHANGE_WATCHED_FILES: &str = "workspace/didChangeWatchedFiles";
+
+/// `FileChangeType` of a created file
+const CREATED: u8 = 1;
+/// `FileChangeType` of a changed file
+const CHANGED: u8 = 2;
+/// `FileChangeType` of a deleted file
+const DELETED: u8 = 3;
+
+/// Characters encoded in file URIs
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+#[derive(Clone)]
+pub struct FileWatcher {
+    /// Queue of the task applying operations to the watcher
+    ops: mpsc::UnboundedSender<Op>,
+}
+
+/// Operations are applied one at a time in the order they're queued
+enum Op {
+    Register(String, Option<Value>),
+    Unregister(String),
+    Clear,
+    /// Watch a directory created after the watcher started
+    Watch(PathBuf),
+    /// Answered once the operations queued before are applied
+    #[cfg(test)]
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+struct State {
+    /// Workspace root
+    root: PathBuf,
+
+    /// Watched patterns by registration ID
+    registrations: HashMap<String, Vec<Matcher>>,
+
+    /// Running only while there are registrations
+    watcher: Option<RecommendedWatcher>,
+
+    /// Rules of the `.gitignore` files read while walking the workspace
+    ignore: Ignore,
+
+    /// Sends the file events matching a registration
+    events: mpsc::UnboundedSender<Vec<FileEvent>>,
+
+    /// Queues watching new directories, weak so the task applying operations
+    /// stops once every `FileWatcher` is dropped
+    ops: mpsc::WeakUnboundedSender<Op>,
+}
+
+/// Directories excluded by `.gitignore` files
+///
+/// Only the files read while walking the workspace are used, negated
+/// patterns aren't supported.
+#[derive(Clone, Default)]
+struct Ignore {
+    rules: Vec<IgnoreRule>,
+}
+
+#[derive(Clone)]
+struct IgnoreRule {
+    /// Directory of the `.gitignore`, paths are matched relative to it
+    base: PathBuf,
+
+    glob: GlobMatcher,
+
+    /// The pattern ends with a slash
+    dir_only: bool,
+}
+
+/// Compiled `FileSystemWatcher`
+struct Matcher {
+    glob: GlobMatcher,
+
+    /// Paths are matched relative to this directory if set
+    base: Option<PathBuf>,
+
+    /// Bit mask of `WatchKind`
+    kind: u8,
+}
+
+impl FileWatcher {
+    /// Create a watcher for the workspace at `root`, the returned channel
+    /// receives batches of events matching the registered patterns
+    pub fn new(root: &str) -> (FileWatcher, mpsc::UnboundedReceiver<Vec<FileEvent>>) {
+        let (events, rx) = mpsc::unbounded_channel();
+        let (ops, ops_rx) = mpsc::unbounded_channel();
+        let state = State {
+            root: PathBuf::from(root),
+            registrations: HashMap::new(),
+            watcher: None,
+            ignore: Ignore::default(),
+            events,
+            ops: ops.downgrade(),
+        };
+        task::spawn(apply_ops(Arc::new(Mutex::new(state)), ops_rx).in_current_span());
+        (FileWatcher { ops }, rx)
+    }
+
+    /// Add a `workspace/didChangeWatchedFiles` registration
+    ///
+    /// Starting the watcher walks the whole workspace, it's done in the
+    /// background.
+    pub fn register(&self, id: String, options: Option<Value>) {
+        let _ = self.ops.send(Op::Register(id, options));
+    }
+
+    /// Remove a registration, the watcher stops once there are none left
+    pub fn unregister(&self, id: &str) {
+        let _ = self.ops.send(Op::Unregister(id.to_owned()));
+    }
+
+    /// Remove all registrations and stop the watcher
+    pub fn clear(&self) {
+        let _ = self.ops.send(Op::Clear);
+    }
+
+    /// Wait until the operations queued so far are applied
+    #[cfg(test)]
+    async fn flush(&self) {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let _ = self.ops.send(Op::Flush(tx));
+        let _ = rx.await;
+    }
+}
+
+/// Apply queued operations until every `FileWatcher` is dropped
+async fn apply_ops(state: Arc<Mutex<State>>, mut ops: mpsc::UnboundedReceiver<Op>) {
+    while let Some(op) = ops.recv().await {
+        let state = state.clone();
+        // Walking directories and adding watches blocks.
+        match task::spawn_blocking(move || apply(&state, op)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!(?err, "error watching files"),
+            Err(err) => warn!(?err, "file watcher task failed"),
+        }
+    }
+}
+
+fn apply(state: &Arc<Mutex<State>>, op: Op) -> Result<()> {
+    match op {
+        Op::Register(id, options) => register(state, id, options),
+        Op::Unregister(id) => {
+            let stopped = {
+                let mut state = state.lock().unwrap();
+                state.registrations.remove(&id);
+                match state.registrations.is_empty() {
+                    true => state.watcher.take(),
+                    false => None,
+                }
+            };
+            // Dropping the watcher waits for the thread calling the event
+            // handler, which locks the state.
+            if stopped.is_some() {
+                drop(stopped);
+                debug!("stopped file watcher");
+            }
+            Ok(())
+        }
+        Op::Clear => {
+            let stopped = {
+                let mut state = state.lock().unwrap();
+                state.registrations.clear();
+                state.watcher.take()
+            };
+            drop(stopped);
+            Ok(())
+        }
+        Op::Watch(dir) => watch_new_dir(state, dir),
+        #[cfg(test)]
+        Op::Flush(tx) => {
+            let _ = tx.send(());
+            Ok(())
+        }
+    }
+}
+
+fn register(state: &Arc<Mutex<State>>, id: String, options: Option<Value>) -> Result<()> {
+    let options = serde_json::from_value::<lsp::DidChangeWatchedFilesRegistrationOptions>(
+        options.unwrap_or_default(),
+    )
+    .context("parsing registration options")?;
+    let matchers = options
+        .watchers
+        .into_iter()
+        .map(Matcher::new)
+        .collect::<Result<Vec<_>>>()?;
+
+    let (root, running) = {
+        let state = state.lock().unwrap();
+        (state.root.clone(), state.watcher.is_some())
+    };
+    // Start watching without holding the lock, the event handler locks it
+    // and adding a watch waits for the thread calling the handler.
+    let mut watcher = None;
+    let mut ignore = Ignore::default();
+    if !running {
+        let mut dirs = Vec::new();
+        walk(&root, &mut ignore, &mut |path, is_dir| {
+            if is_dir {
+                dirs.push(path.to_owned());
+            }
+        });
+        let mut new_watcher = event_handler(Arc::downgrade(state))?;
+        watch_dirs(&mut new_watcher, &dirs)?;
+        debug!(?root, dirs = dirs.len(), "started file watcher");
+        watcher = Some(new_watcher);
+    }
+
+    let mut state = state.lock().unwrap();
+    if watcher.is_some() {
+        state.watcher = watcher;
+        state.ignore = ignore;
+    }
+    state.registrations.insert(id, matchers);
+    Ok(())
+}
+
+/// Watch a directory created while the watcher is running and report the
+/// files created in it before the watch was added
+fn watch_new_dir(state: &Arc<Mutex<State>>, dir: PathBuf) -> Result<()> {
+    let (mut watcher, mut ignore) = {
+        let mut state = state.lock().unwrap();
+        let Some(watcher) = state.watcher.take() else {
+            return Ok(());
+        };
+        (watcher, state.ignore.clone())
+    };
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    walk(&dir, &mut ignore, &mut |path, is_dir| match is_dir {
+        true => dirs.push(path.to_owned()),
+        false => files.push(path.to_owned()),
+    });
+    let res = watch_dirs(&mut watcher, &dirs);
+
+    let mut state = state.lock().unwrap();
+    state.watcher = Some(watcher);
+    state.ignore = ignore;
+    let events = files
+        .iter()
+        .filter_map(|path| state.file_event(path, CREATED))
+        .collect::<Vec<_>>();
+    if !events.is_empty() {
+        let _ = state.events.send(events);
+    }
+    res
+}
+
+fn watch_dirs(watcher: &mut RecommendedWatcher, dirs: &[PathBuf]) -> Result<()> {
+    let mut paths = watcher.paths_mut();
+    for dir in dirs {
+        match paths.add(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {}
+            // Removed since it was walked.
+            Err(_) if !dir.exists() => {}
+            Err(err) => return Err(err).with_context(|| format!("watching {dir:?}")),
+        }
+    }
+    paths.commit().context("adding watches")
+}
+
+/// Visit `dir` and the files and directories in it which aren't ignored,
+/// reading `.gitignore` files on the way
+///
+/// Symbolic links aren't followed.
+fn walk(dir: &Path, ignore: &mut Ignore, visit: &mut impl FnMut(&Path, bool)) {
+    ignore.read(dir);
+    visit(dir, true);
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(typ) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if ignore.is_ignored(&path, typ.is_dir()) {
+            continue;
+        }
+        match typ.is_dir() {
+            true => walk(&path, ignore, visit),
+            false => visit(&path, false),
+        }
+    }
+}
+
+fn event_handler(state: Weak<Mutex<State>>) -> Result<RecommendedWatcher> {
+    let handler = move |event: notify::Result<Event>| {
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        match event {
+            Ok(event) => {
+                let state = state.lock().unwrap();
+                if let Some(ops) = state.ops.upgrade() {
+                    for dir in state.new_dirs(&event) {
+                        let _ = ops.send(Op::Watch(dir));
+                    }
+                }
+                let events = state.file_events(&event);
+                if !events.is_empty() {
+                    let _ = state.events.send(events);
+                }
+            }
+            Err(err) => warn!(?err, "file watcher error"),
+        }
+    };
+    notify::recommended_watcher(handler).context("creating file watcher")
+}
+
+impl State {
+    /// Translate a notify event to the file events the server is interested in
+    fn file_events(&self, event: &Event) -> Vec<FileEvent> {
+        let typ = |path: &Path| match event.kind {
+            EventKind::Create(_) => Some(CREATED),
+            EventKind::Remove(_) => Some(DELETED),
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => Some(DELETED),
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => Some(CREATED),
+            // The first path is the old name.
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if event.paths.first().is_some_and(|from| from == path) {
+                    Some(DELETED)
+                } else {
+                    Some(CREATED)
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                if path.exists() {
+                    Some(CREATED)
+                } else {
+                    Some(DELETED)
+                }
+            }
+            // Only content changes are interesting.
+            EventKind::Modify(ModifyKind::Metadata(_)) => None,
+            EventKind::Modify(_) => Some(CHANGED),
+            _ => None,
+        };
+        event
+            .paths
+            .iter()
+            .filter_map(|path| self.file_event(path, typ(path)?))
+            .collect()
+    }
+
+    fn file_event(&self, path: &Path, typ: u8) -> Option<FileEvent> {
+        if !self.is_watched(path, typ) || self.ignore.is_ignored(path, path.is_dir()) {
+            return None;
+        }
+        let uri = file_uri(path)?;
+        Some(FileEvent { uri, typ })
+    }
+
+    /// Directories created or moved into the workspace which need a watch
+    fn new_dirs(&self, event: &Event) -> Vec<PathBuf> {
+        let paths = match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                &event.paths[..]
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                event.paths.get(1..).unwrap_or_default()
+            }
+            _ => return Vec::new(),
+        };
+        paths
+            .iter()
+            .filter(|path| path.is_dir() && !self.ignore.is_ignored(path, true))
+            .cloned()
+            .collect()
+    }
+
+    fn is_watched(&self, path: &Path, typ: u8) -> bool {
+        self.registrations
+            .values()
+            .flatten()
+            .any(|matcher| matcher.matches(&self.root, path, typ))
+    }
+}
+
+impl Matcher {
+    fn new(watcher: lsp::FileSystemWatcher) -> Result<Matcher> {
+        let (pattern, base) = match watcher.glob_pattern {
+            GlobPattern::Pattern(pattern) => (pattern, None),
+            GlobPattern::Relative(relative) => {
+                let uri = match relative.base_uri {
+                    BaseUri::Uri(uri) => uri,
+                    BaseUri::WorkspaceFolder(folder) => folder.uri,
+                };
+                let base = crate::client::parse_file_uri(&uri).context("invalid base URI")?;
+                (relative.pattern, Some(PathBuf::from(base)))
+            }
+        };
+        let glob = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob pattern {pattern:?}"))?
+            .compile_matcher();
+        Ok(Matcher {
+            glob,
+            base,
+            // Create | Change | Delete
+            kind: watcher.kind.unwrap_or(7),
+        })
+    }
+
+    fn matches(&self, root: &Path, path: &Path, typ: u8) -> bool {
+        // WatchKind bits are in the same order as FileChangeType values.
+        if self.kind & (1 << (typ - 1)) == 0 {
+            return false;
+        }
+        match &self.base {
+            Some(base) => path
+                .strip_prefix(base)
+                .is_ok_and(|path| self.glob.is_match(path)),
+            // Plain patterns can be absolute or relative to the workspace.
+            None => {
+                self.glob.is_match(path)
+                    || path
+                        .strip_prefix(root)
+                        .is_ok_and(|path| self.glob.is_match(path))
+            }
+        }
+    }
+}
+
+impl Ignore {
+    /// Add the rules of the `.gitignore` in `dir`
+    fn read(&mut self, dir: &Path) {
+        // A directory created again may have a different file.
+        self.rules.retain(|rule| rule.base != dir);
+        let Ok(text) = fs::read_to_string(dir.join(".gitignore")) else {
+            return;
+        };
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            let (pattern, dir_only) = match line.strip_suffix('/') {
+                Some(pattern) => (pattern, true),
+                None => (line, false),
+            };
+            // Patterns without a slash match at any depth.
+            let pattern = match pattern.strip_prefix('/') {
+                Some(pattern) => pattern.to_owned(),
+                None if pattern.contains('/') => pattern.to_owned(),
+                None => format!("**/{pattern}"),
+            };
+            let Ok(glob) = GlobBuilder::new(&pattern).literal_separator(true).build() else {
+                continue;
+            };
+            self.rules.push(IgnoreRule {
+                base: dir.to_owned(),
+                glob: glob.compile_matcher(),
+                dir_only,
+            });
+        }
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path.file_name().is_some_and(|name| name == ".git") {
+            return true;
+        }
+        self.rules.iter().any(|rule| {
+            (is_dir || !rule.dir_only)
+                && path
+                    .strip_prefix(&rule.base)
+                    .is_ok_and(|path| rule.glob.is_match(path))
+        })
+    }
+}
+
+pub fn file_uri(path: &Path) -> Option<String> {
+    let path = path.to_str()?;
+    Some(format!("file://{}", utf8_percent_encode(path, PATH)))
+}
+
+/// Merge the events of a batch so each file is reported once
+///
+/// A file created and then changed is still reported as created.
+pub fn coalesce(events: impl IntoIterator<Item = FileEvent>) -> Vec<FileEvent> {
+    let mut coalesced: Vec<FileEvent> = Vec::new();
+    // Position of each file's event in `coalesced`
+    let mut index = HashMap::new();
+    for event in events {
+        match index.get(&event.uri) {
+            Some(&i) => {
+                let other: &mut FileEvent = &mut coalesced[i];
+                if other.typ != CREATED || event.typ != CHANGED {
+                    other.typ = event.typ;
+                }
+            }
+            None => {
+                index.insert(event.uri.clone(), coalesced.len());
+                coalesced.push(event);
+            }
+        }
+    }
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use serde_json::json;
+    use tokio::time::{self, Duration};
+
+    use super::*;
+
+    fn matcher(watcher: Value) -> Matcher {
+        Matcher::new(serde_json::from_value(watcher).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn match_watched_files() {
+        let root = Path::new("/ws");
+        let rust = matcher(json!({ "globPattern": "**/*.rs" }));
+        assert!(rust.matches(root, Path::new("/ws/src/main.rs"), CHANGED));
+        assert!(!rust.matches(root, Path::new("/ws/Cargo.toml"), CHANGED));
+
+        let manifest = matcher(json!({ "globPattern": "Cargo.toml", "kind": 1 }));
+        assert!(manifest.matches(root, Path::new("/ws/Cargo.toml"), CREATED));
+        assert!(!manifest.matches(root, Path::new("/ws/Cargo.toml"), CHANGED));
+        assert!(!manifest.matches(root, Path::new("/ws/sub/Cargo.toml"), CREATED));
+
+        let relative = matcher(json!({
+            "globPattern": { "baseUri": "file:///ws/crates", "pattern": "*/Cargo.toml" },
+        }));
+        assert!(relative.matches(root, Path::new("/ws/crates/a/Cargo.toml"), DELETED));
+        assert!(!relative.matches(root, Path::new("/ws/Cargo.toml"), DELETED));
+    }
+
+    #[test]
+    fn coalesce_events() {
+        let event = |uri: &str, typ| FileEvent {
+            uri: uri.into(),
+            typ,
+        };
+        let events = coalesce([
+            event("file:///a.rs", CREATED),
+            event("file:///b.rs", CHANGED),
+            event("file:///a.rs", CHANGED),
+            event("file:///b.rs", CHANGED),
+            event("file:///b.rs", DELETED),
+        ]);
+        assert_eq!(
+            events,
+            [
+                event("file:///a.rs", CREATED),
+                event("file:///b.rs", DELETED)
+            ],
+        );
+    }
+
+    #[test]
+    fn ignore_rules() {
+        let dir = env::temp_dir().join(format!("ra-mux-ignore-{}", process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(
+            dir.join(".gitignore"),
+            "# build\n/target\n*.log\nout/\n!keep.log\n",
+        )
+        .unwrap();
+        let mut ignore = Ignore::default();
+        ignore.read(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(ignore.is_ignored(&dir.join("target"), true));
+        assert!(!ignore.is_ignored(&dir.join("sub/target"), true));
+        assert!(ignore.is_ignored(&dir.join("sub/a.log"), false));
+        assert!(ignore.is_ignored(&dir.join("sub/out"), true));
+        assert!(!ignore.is_ignored(&dir.join("sub/out"), false));
+        assert!(ignore.is_ignored(&dir.join("sub/.git"), true));
+        assert!(!ignore.is_ignored(&dir.join("sub/a.rs"), false));
+    }
+
+    /// Wait for the next batch of events
+    async fn next_events(events: &mut mpsc::UnboundedReceiver<Vec<FileEvent>>) -> Vec<FileEvent> {
+        let events = time::timeout(Duration::from_secs(10), events.recv()).await;
+        events.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn watch_workspace() {
+        let root = env::temp_dir().join(format!("ra-mux-watcher-{}", process::id()));
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "/target\n").unwrap();
+        let options = |glob| Some(json!({ "watchers": [{ "globPattern": glob }] }));
+        let (watcher, mut events) = FileWatcher::new(root.to_str().unwrap());
+
+        // Operations are applied in order, the first registration is gone.
+        watcher.register("rust".into(), options("**/*.rs"));
+        watcher.unregister("rust");
+        watcher.register("toml".into(), options("**/*.toml"));
+        watcher.flush().await;
+
+        fs::write(root.join("a.rs"), "").unwrap();
+        fs::write(root.join("target/a.toml"), "").unwrap();
+        fs::write(root.join("a.toml"), "").unwrap();
+        let uri = |path: &str| file_uri(&root.join(path)).unwrap();
+        let events_of_a = next_events(&mut events).await;
+        assert_eq!(
+            events_of_a[0],
+            FileEvent {
+                uri: uri("a.toml"),
+                typ: CREATED
+            }
+        );
+
+        // Directories created later are watched too.
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/b.toml"), "").unwrap();
+        loop {
+            let events = next_events(&mut events).await;
+            if events.iter().any(|event| event.uri == uri("sub/b.toml")) {
+                break;
+            }
+            assert!(
+                events.iter().all(|event| event.uri == uri("a.toml")),
+                "{events:?}"
+            );
+        }
+
+        drop(watcher);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn encode_file_uri() {
+        assert_eq!(
+            file_uri(Path::new("/ws/a b#.rs")).unwrap(),
+            "file:///ws/a%20b%23.rs",
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Environment diagnostics for `ra-multiplex doctor`
+//!
+//! Checks the things most broken setups come down to: the config file, the
+//! addresses the server listens on and clients connect to, whether the server
+//! is reachable and speaks the same protocol, and whether it finds the
+//! language servers editors ask for. Every problem is printed with a fix.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use ra_multiplex_core::config::{Address, Config};
+use ra_multiplex_core::download;
+use ra_multiplex_core::ext::ext_request;
+use ra_multiplex_core::lsp::ext::{self, DoctorResponse, LspMuxOptions, StatusResponse};
+use ra_multiplex_core::paths;
+use ra_multiplex_core::resolve::find_executable;
+use tokio::time;
+
+/// How long to wait for the server to respond
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Language server used by clients without `--server-path`
+const DEFAULT_SERVER: &str = "rust-analyzer";
+
+pub async fn run(config: &Config) -> Result<()> {
+    let mut report = Report::default();
+    check_config(&mut report);
+    check_addresses(config, &mut report);
+    #[cfg(unix)]
+    check_socket(config, &mut report);
+    check_download_tools(config, &mut report);
+    if let Some(status) = check_server(config, &mut report).await {
+        check_language_servers(config, &status, &mut report).await;
+    }
+
+    if report.errors > 0 {
+        bail!("problems found: {}", report.errors);
+    }
+    println!("no problems found");
+    Ok(())
+}
+
+#[derive(Default)]
+struct Report {
+    errors: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: impl Display) {
+        println!("ok: {message}");
+    }
+
+    fn warn(&mut self, message: impl Display, fix: impl Display) {
+        println!("warning: {message}");
+        println!("  fix: {fix}");
+    }
+
+    fn error(&mut self, message: impl Display, fix: impl Display) {
+        self.errors += 1;
+        println!("error: {message}");
+        println!("  fix: {fix}");
+    }
+}
+
+fn check_config(report: &mut Report) {
+    let path = match Config::path() {
+        Ok(path) => path,
+        Err(err) => {
+            report.warn(
+                format!("{err:#}"),
+                "set HOME so the config file can be found",
+            );
+            return;
+        }
+    };
+    match Config::try_load() {
+        Ok(config) => {
+            for file in paths::config_files() {
+                report.ok(format!("config file {file:?} is valid"));
+            }
+            for setting in config.ignored_settings {
+                report.warn(
+                    format!("ignoring {setting}, the project config isn't trusted with it"),
+                    "move the setting to the user config or set `trust_project_config = true` \
+                     there",
+                );
+            }
+        }
+        Err(err) if is_io_error(&err, io::ErrorKind::NotFound) => {
+            report.ok(format!("no config file at {path:?}, using the defaults"))
+        }
+        Err(err) => report.error(
+            format!("{err:#}"),
+            "correct the config file, ra-multiplex falls back to the defaults when it's invalid \
+             and the server has to be restarted to pick it up",
+        ),
+    }
+}
+
+fn check_addresses(config: &Config, report: &mut Report) {
+    // Only the ports need to match, the server can listen on all interfaces.
+    let differs = match (&config.listen, &config.connect) {
+        (Address::Tcp(_, listen), Address::Tcp(_, connect)) => listen != connect,
+        (Address::Quic(listen, _), Address::Quic(connect, _)) => listen.port() != connect.port(),
+        #[cfg(unix)]
+        (Address::Unix(listen), Address::Unix(connect)) => listen != connect,
+        _ => false,
+    };
+    if differs {
+        report.warn(
+            format!(
+                "clients connect to {} but the server listens on {}",
+                address(&config.connect),
+                address(&config.listen),
+            ),
+            "make `connect` point at the `listen` address unless the server runs elsewhere",
+        );
+    }
+}
+
+/// Check the socket clients connect to is one they can use
+#[cfg(unix)]
+fn check_socket(config: &Config, report: &mut Report) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+
+    let Address::Unix(path) = &config.connect else {
+        return;
+    };
+    // A missing socket is reported as the server not running.
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return;
+    };
+    if !metadata.file_type().is_socket() {
+        report.error(
+            format!("{path:?} is not a socket"),
+            "remove it, the server creates the socket when it starts",
+        );
+        return;
+    }
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return;
+    };
+    if unsafe { libc::access(c_path.as_ptr(), libc::R_OK | libc::W_OK) } != 0 {
+        report.error(
+            format!("no permission to connect to {path:?}"),
+            "run editors as the user running the server, or move `listen` and `connect` to a \
+             socket in a directory of that user",
+        );
+    }
+}
+
+/// Check the programs needed to fetch the `[download]` servers are installed
+fn check_download_tools(config: &Config, report: &mut Report) {
+    let path = env::var("PATH").ok();
+    let mut missing = BTreeSet::new();
+    for (name, versions) in &config.download {
+        for (version, download) in versions {
+            for tools in download::required_tools(download) {
+                if !tools
+                    .iter()
+                    .any(|tool| find_executable(tool, path.as_deref()).is_some())
+                {
+                    missing.insert((tools.join(" or "), format!("{name}@{version}")));
+                }
+            }
+        }
+    }
+    for (tools, server) in missing {
+        report.error(
+            format!("{tools} is needed to download {server:?} but isn't installed"),
+            format!("install {tools} or remove {server:?} from the `[download]` section"),
+        );
+    }
+}
+
+/// Check the server responds to a status request with the same protocol
+async fn check_server(config: &Config, report: &mut Report) -> Option<StatusResponse> {
+    let address = address(&config.connect);
+    let status = time::timeout(
+        TIMEOUT,
+        ext_request::<StatusResponse>(config, ext::Request::Status {}),
+    )
+    .await;
+    let status = match status {
+        Ok(Ok(status)) => status,
+        Ok(Err(err)) if err.to_string().contains("unsupported protocol version") => {
+            report.error(
+                format!("the server at {address} speaks another protocol version"),
+                "restart the server with this version of ra-multiplex, \
+                 `ra-multiplex server stop` and `ra-multiplex server`",
+            );
+            return None;
+        }
+        Ok(Err(err))
+            if is_io_error(&err, io::ErrorKind::ConnectionRefused)
+                || is_io_error(&err, io::ErrorKind::NotFound) =>
+        {
+            report.error(
+                format!("no server is running at {address}"),
+                "start it with `ra-multiplex server`, editors can't connect without it",
+            );
+            return None;
+        }
+        Ok(Err(err)) if is_io_error(&err, io::ErrorKind::PermissionDenied) => {
+            report.error(
+                format!("no permission to connect to {address}"),
+                "run editors as the user running the server",
+            );
+            return None;
+        }
+        Ok(Err(err)) => {
+            report.error(
+                format!("cannot talk to the server at {address}: {err:#}"),
+                "check `connect` points at an ra-multiplex server and not another service",
+            );
+            return None;
+        }
+        Err(_) => {
+            report.error(
+                format!("the server at {address} doesn't respond"),
+                "check `connect` points at an ra-multiplex server, restart it if it's stuck",
+            );
+            return None;
+        }
+    };
+    report.ok(format!(
+        "server at {address} is reachable, protocol version {}, {} instances running",
+        LspMuxOptions::PROTOCOL_VERSION,
+        status.instances.len(),
+    ));
+    Some(status)
+}
+
+/// Check the server finds the language servers clients ask for
+async fn check_language_servers(config: &Config, status: &StatusResponse, report: &mut Report) {
+    let default_server = env::var("RA_MUX_SERVER").unwrap_or_else(|_| DEFAULT_SERVER.into());
+    let servers = [default_server]
+        .into_iter()
+        .chain(config.server.servers.keys().cloned())
+        .chain(
+            status
+                .instances
+                .iter()
+                .map(|instance| instance.server.clone()),
+        )
+        .filter(|server| !server.starts_with("managed:"))
+        .collect::<BTreeSet<_>>();
+
+    let env = config
+        .pass_environment
+        .iter()
+        .filter_map(|key| Some((key.clone(), env::var(key).ok()?)))
+        .collect();
+    let request = ext::Request::Doctor {
+        servers: servers.iter().cloned().collect(),
+        env,
+    };
+    let res = match time::timeout(TIMEOUT, ext_request::<DoctorResponse>(config, request)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(_)) | Err(_) => {
+            report.warn(
+                "the server is too old to look up language servers",
+                "restart the server with this version of ra-multiplex",
+            );
+            return;
+        }
+    };
+    if res.version != env!("CARGO_PKG_VERSION") {
+        report.warn(
+            format!(
+                "the server runs ra-multiplex {} but this is {}",
+                res.version,
+                env!("CARGO_PKG_VERSION"),
+            ),
+            "restart the server after upgrading, `ra-multiplex server stop` and \
+             `ra-multiplex server`",
+        );
+    }
+
+    let local_path = env::var("PATH").ok();
+    let passes_path = config.pass_environment.contains("PATH");
+    let this_exe = env::current_exe().and_then(|path| path.canonicalize()).ok();
+    for lookup in res.servers {
+        let server = &lookup.server;
+        let local = find_executable(server, local_path.as_deref());
+        if local
+            .as_ref()
+            .and_then(|path| path.canonicalize().ok())
+            .is_some_and(|path| Some(path) == this_exe)
+        {
+            report.error(
+                format!("language server {server:?} is ra-multiplex itself"),
+                "point `--server-path` or RA_MUX_SERVER at the language server, the editor \
+                 runs ra-multiplex instead of it",
+            );
+            continue;
+        }
+        let Some(path) = lookup.path else {
+            let fix = match passes_path {
+                true => "install it or add its directory to PATH",
+                false => {
+                    "install it, add its directory to the PATH of `ra-multiplex server`, or add \
+                     PATH to `pass_environment` to use the PATH of editors"
+                }
+            };
+            report.error(
+                format!("the server doesn't find language server {server:?}"),
+                fix,
+            );
+            continue;
+        };
+        match local {
+            Some(local) if !passes_path && Path::new(&path) != local => report.warn(
+                format!("the server runs {path} for {server:?} but here it's {local:?}"),
+                "add PATH to `pass_environment` to run the one editors would run without \
+                 ra-multiplex",
+            ),
+            _ => report.ok(format!("language server {server:?} is {path}")),
+        }
+    }
+}
+
+fn is_io_error(err: &anyhow::Error, kind: io::ErrorKind) -> bool {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<io::Error>())
+        .any(|err| err.kind() == kind)
+}
+
+/// Format an address like it's written in the config file
+fn address(address: &Address) -> String {
+    serde_json::to_string(address).unwrap()
+}
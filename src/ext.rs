@@ -1,72 +1,18 @@
+//! Subcommands sending the server `lspMux` requests and printing the results
+
+use std::collections::BTreeMap;
 use std::env;
+use std::path::Path;
 
 use anyhow::{bail, Context, Result};
-use serde::de::{DeserializeOwned, IgnoredAny};
-use tokio::io::BufReader;
-
-use crate::config::Config;
-use crate::lsp::ext::{self, LspMuxOptions, StatusResponse};
-use crate::lsp::jsonrpc::{Message, Request, RequestId, Version};
-use crate::lsp::transport::{LspReader, LspWriter};
-use crate::lsp::{InitializationOptions, InitializeParams};
-use crate::socketwrapper::Stream;
-
-pub async fn ext_request<T>(config: &Config, method: ext::Request) -> Result<T>
-where
-    T: DeserializeOwned,
-{
-    let (reader, writer) = Stream::connect(&config.connect)
-        .await
-        .context("connect")?
-        .into_split();
-    let mut writer = LspWriter::new(writer, "lspmux");
-    let mut reader = LspReader::new(BufReader::new(reader), "lspmux");
-
-    writer
-        .write_message(
-            &Request {
-                jsonrpc: Version,
-                method: "initialize".into(),
-                params: serde_json::to_value(InitializeParams {
-                    initialization_options: Some(InitializationOptions {
-                        lsp_mux: Some(LspMuxOptions {
-                            version: LspMuxOptions::PROTOCOL_VERSION.into(),
-                            method,
-                        }),
-                        other_options: serde_json::Map::default(),
-                    }),
-                    process_id: None,
-                    client_info: None,
-                    locale: None,
-                    root_path: None,
-                    root_uri: None,
-                    capabilities: None,
-                    trace: None,
-                    workspace_folders: Vec::new(),
-                })
-                .unwrap(),
-                id: RequestId::Number(0),
-            }
-            .into(),
-        )
-        .await
-        .context("send lspmux request")?;
-
-    match reader
-        .read_message()
-        .await
-        .context("read lspmux response")?
-        .context("stream ended")?
-        .into_response()
-        .context("received message was not a response")?
-    {
-        Ok(success) => serde_json::from_value(success.result).context("parse response result"),
-        Err(error) => bail!(
-            "received error response: {msg:?}",
-            msg = Message::ResponseError(error),
-        ),
-    }
-}
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::ext::{ext_request, ext_session};
+use ra_multiplex_core::lsp::ext::{self, InstanceRole, StatusResponse};
+use ra_multiplex_core::lsp::jsonrpc::{Message, Request, RequestId, Version};
+use serde::de::IgnoredAny;
+use serde_json::Value;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::select;
 
 pub async fn config(config: &Config) -> Result<()> {
     println!("{:#?}", config);
@@ -93,8 +39,29 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
             }
         }
         println!("  path: {:?}", instance.workspace_root);
+        if instance.role != InstanceRole::Primary {
+            println!("  role: {:?}", instance.role);
+        }
+        if let Some(tag) = &instance.tag {
+            println!("  tag: {tag}");
+        }
+        if instance.pinned {
+            println!("  pinned: true");
+        }
         let now = time::OffsetDateTime::now_utc().unix_timestamp();
         println!("  last used: {}s ago", now - instance.last_used);
+        if let Some(rss) = instance.rss {
+            println!("  memory: {} MiB", rss / (1024 * 1024));
+        }
+        if let Some(memory_usage) = instance.memory_usage {
+            println!("  memory usage:");
+            for usage in memory_usage {
+                println!("    {:>10} {}", usage.size, usage.name);
+            }
+        }
+        if let Some(traffic) = instance.traffic {
+            print_traffic(&traffic);
+        }
         println!("  registered dynamic capabilities:");
         for cap in instance.registered_dyn_capabilities {
             println!("    - {}", cap);
@@ -109,15 +76,573 @@ pub async fn status(config: &Config, json: bool) -> Result<()> {
             }
         }
     }
+    if !res.duplicates.is_empty() {
+        println!("- Duplicate instances");
+        for duplicate in res.duplicates {
+            println!(
+                "  - {} ({}) is inside {} ({}), merge with `ra-multiplex merge {} {}`",
+                duplicate.workspace_root,
+                duplicate.pid,
+                duplicate.into_workspace_root,
+                duplicate.into_pid,
+                duplicate.pid,
+                duplicate.into_pid,
+            );
+        }
+    }
+    if !res.crash_reports.is_empty() {
+        println!("- Crash reports");
+        for path in res.crash_reports {
+            println!("  - {path}");
+        }
+    }
+    Ok(())
+}
+
+pub async fn workspaces(config: &Config) -> Result<()> {
+    let res = ext_request::<StatusResponse>(config, ext::Request::Status {}).await?;
+
+    let mut roots = BTreeMap::<_, Vec<_>>::new();
+    for instance in res.instances {
+        roots
+            .entry(instance.workspace_root.clone())
+            .or_default()
+            .push(instance);
+    }
+    for (root, mut instances) in roots {
+        println!("{root}");
+        instances.sort_by_key(|instance| instance.pid);
+        for (index, instance) in instances.iter().enumerate() {
+            println!(
+                "  - pid {}: {:?} {:?}",
+                instance.pid, instance.server, instance.args
+            );
+            if !instance.env.is_empty() {
+                println!("    env:");
+                for (key, val) in &instance.env {
+                    println!("      {key} = {val}");
+                }
+            }
+            if instance.role != InstanceRole::Primary {
+                println!("    role: {:?}", instance.role);
+            }
+            if let Some(group) = &instance.group {
+                println!("    group: {group}");
+            }
+            if let Some(tag) = &instance.tag {
+                println!("    tag: {tag}");
+            }
+            // Explain why the instance isn't shared with the ones listed
+            // before it.
+            for other in &instances[..index] {
+                let differences = key_differences(other, instance);
+                println!(
+                    "    not shared with pid {}: different {}",
+                    other.pid,
+                    differences.join(", "),
+                );
+            }
+            println!("    clients:");
+            for client in &instance.clients {
+                println!("      - {}", client.id);
+                for folder in &client.workspace_folders {
+                    let added = if folder.added { " (added)" } else { "" };
+                    println!("        {}{added}", folder.uri);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parts of the keys of two instances for the same workspace root which
+/// differ, those are the reason clients of one don't share the other
+fn key_differences(a: &ext::Instance, b: &ext::Instance) -> Vec<&'static str> {
+    let mut differences = Vec::new();
+    if a.server != b.server {
+        differences.push("server");
+    }
+    if a.args != b.args {
+        differences.push("args");
+    }
+    if a.env != b.env {
+        differences.push("env");
+    }
+    if a.role != b.role {
+        differences.push("role");
+    }
+    if a.group != b.group {
+        differences.push("group");
+    }
+    if a.tag != b.tag {
+        differences.push("tag");
+    }
+    differences
+}
+
+fn print_traffic(traffic: &ext::Traffic) {
+    // Rates are averages over the instance lifetime.
+    let seconds = traffic.seconds.max(1) as f64;
+    let direction = |messages: u64, bytes: u64| {
+        format!(
+            "{messages} messages ({:.1}/s), {:.1} KiB ({:.1} KiB/s)",
+            messages as f64 / seconds,
+            bytes as f64 / 1024.0,
+            bytes as f64 / 1024.0 / seconds,
+        )
+    };
+    println!("  traffic:");
+    println!(
+        "    to server: {}",
+        direction(traffic.messages_to_server, traffic.bytes_to_server)
+    );
+    println!(
+        "    from server: {}",
+        direction(traffic.messages_from_server, traffic.bytes_from_server)
+    );
+    println!("    errors: {}", traffic.errors);
+    let mut requests = traffic.requests.iter().collect::<Vec<_>>();
+    requests.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+    println!("    requests:");
+    for (method, count) in requests {
+        println!("      {method}: {count} ({:.2}/s)", *count as f64 / seconds);
+    }
+}
+
+pub async fn restart(config: &Config, instance: Option<String>) -> Result<()> {
+    let (pid, cwd) = match instance {
+        Some(instance) => select_instance(&instance)?,
+        None => (None, current_dir()?),
+    };
+    ext_request::<IgnoredAny>(config, ext::Request::Restart { pid, cwd }).await?;
+    Ok(())
+}
+
+pub async fn rollover(config: &Config, instance: Option<String>) -> Result<()> {
+    let (pid, cwd) = match instance {
+        Some(instance) => select_instance(&instance)?,
+        None => (None, current_dir()?),
+    };
+    eprintln!("waiting for the new language server to finish indexing");
+    let new_pid = ext_request::<u32>(config, ext::Request::Rollover { pid, cwd }).await?;
+    println!("switched over to language server with pid {new_pid}");
+    Ok(())
+}
+
+pub async fn merge(config: &Config, instance: String, into: String) -> Result<()> {
+    let (pid, cwd) = select_instance(&instance)?;
+    let (into_pid, into_cwd) = select_instance(&into)?;
+    let request = ext::Request::Merge {
+        pid,
+        cwd,
+        into_pid,
+        into_cwd,
+    };
+    let clients = ext_request::<usize>(config, request).await?;
+    println!("moved {clients} clients");
+    Ok(())
+}
+
+pub async fn stop(config: &Config, detach: bool) -> Result<()> {
+    let instances = ext_request::<usize>(config, ext::Request::Shutdown { detach }).await?;
+    println!("server stopped, shut down {instances} instances");
+    Ok(())
+}
+
+pub async fn reload(config: &Config, instance: Option<String>) -> Result<()> {
+    let (pid, cwd) = match instance {
+        Some(instance) => select_instance(&instance)?,
+        None => (None, current_dir()?),
+    };
+    ext_request::<IgnoredAny>(config, ext::Request::Reload { pid, cwd }).await?;
+    Ok(())
+}
+
+pub async fn pin(config: &Config, instance: Option<String>, pinned: bool) -> Result<()> {
+    let (pid, cwd) = match instance {
+        Some(instance) => select_instance(&instance)?,
+        None => (None, current_dir()?),
+    };
+    ext_request::<IgnoredAny>(config, ext::Request::Pin { pid, cwd, pinned }).await?;
+    Ok(())
+}
+
+pub async fn attach(config: &Config, instance: Option<String>, tag: Option<String>) -> Result<()> {
+    let (pid, cwd) = match instance {
+        Some(instance) => select_instance(&instance)?,
+        None => (None, current_dir()?),
+    };
+    let (_, mut reader, mut writer) =
+        ext_session(config, ext::Request::Attach { pid, cwd, tag }).await?;
+    eprintln!("attached, enter requests as `method [params]` or JSON-RPC objects, one per line");
+
+    let mut stdin = BufReader::new(io::stdin()).lines();
+    let mut stdin_closed = false;
+    let mut next_id = 0;
+    // Keep waiting for responses to requests sent before stdin was closed.
+    let mut pending = 0_usize;
+    while !stdin_closed || pending > 0 {
+        select! {
+            line = stdin.next_line(), if !stdin_closed => {
+                let Some(line) = line.context("read stdin")? else {
+                    stdin_closed = true;
+                    continue;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_request(&line, &mut next_id) {
+                    Ok(req) => {
+                        writer.write_message(&req.into()).await.context("send request")?;
+                        pending += 1;
+                    }
+                    Err(err) => eprintln!("error: {err:#}"),
+                }
+            }
+            message = reader.read_message() => {
+                let message = message.context("read response")?.context("stream ended")?;
+                println!("{}", serde_json::to_string_pretty(&message).unwrap());
+                // Adopted responses of other clients arrive as notifications.
+                if !matches!(message, Message::Notification(_)) {
+                    pending = pending.saturating_sub(1);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn request(
+    config: &Config,
+    instance: String,
+    method: String,
+    params: Option<String>,
+) -> Result<()> {
+    let params = parse_params(params)?;
+    let (pid, cwd) = select_instance(&instance)?;
+
+    let request = ext::Request::Attach {
+        pid,
+        cwd,
+        tag: None,
+    };
+    let (_, mut reader, mut writer) = ext_session(config, request).await?;
+    let req = Request {
+        jsonrpc: Version,
+        method,
+        params,
+        id: RequestId::Number(1),
+    };
+    writer
+        .write_message(&req.into())
+        .await
+        .context("send request")?;
+
+    // Skip responses the session adopted from disconnected clients.
+    let message = loop {
+        let message = reader
+            .read_message()
+            .await
+            .context("read response")?
+            .context("stream ended")?;
+        if !matches!(message, Message::Notification(_)) {
+            break message;
+        }
+    };
+    match message
+        .into_response()
+        .context("received message was not a response")?
+    {
+        Ok(success) => {
+            println!("{}", serde_json::to_string_pretty(&success.result).unwrap());
+            Ok(())
+        }
+        Err(error) => bail!(
+            "received error response: {msg:?}",
+            msg = Message::ResponseError(error),
+        ),
+    }
+}
+
+pub async fn notify(
+    config: &Config,
+    instance: String,
+    method: String,
+    params: Option<String>,
+) -> Result<()> {
+    let params = parse_params(params)?;
+    let (pid, cwd) = select_instance(&instance)?;
+    ext_request::<IgnoredAny>(
+        config,
+        ext::Request::Notify {
+            pid,
+            cwd,
+            notification: method,
+            params,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn queue(config: &Config, instance: String, cancel: Option<String>) -> Result<()> {
+    let (pid, cwd) = select_instance(&instance)?;
+    if let Some(id) = cancel {
+        let cancel = Some(id.clone());
+        ext_request::<IgnoredAny>(config, ext::Request::Queue { pid, cwd, cancel }).await?;
+        println!("cancelled request {id}");
+        return Ok(());
+    }
+
+    let requests = ext_request::<Vec<ext::PendingRequest>>(
+        config,
+        ext::Request::Queue {
+            pid,
+            cwd,
+            cancel: None,
+        },
+    )
+    .await?;
+    for req in requests {
+        let state = if req.queued { "queued" } else { "in flight" };
+        println!(
+            "{:>8.1}s  {state:<9}  client {:<4} {}  {}",
+            req.age as f64 / 1000.0,
+            req.client,
+            req.method,
+            req.id,
+        );
+    }
+    Ok(())
+}
+
+pub async fn log_level(
+    config: &Config,
+    filter: Option<String>,
+    reset: bool,
+    revert_after: Option<u32>,
+) -> Result<()> {
+    let request = ext::Request::LogFilter {
+        filter,
+        reset,
+        revert_after,
+    };
+    let res = ext_request::<ext::LogFilter>(config, request).await?;
+    if res.filter == res.previous {
+        println!("log filter: {}", res.filter);
+    } else {
+        println!("log filter changed from {} to {}", res.previous, res.filter);
+    }
+    if let Some(secs) = revert_after {
+        println!("reverting to {} in {secs}s", res.previous);
+    }
+    if res.filter != res.original {
+        println!("the server was started with {}", res.original);
+    }
+    Ok(())
+}
+
+/// Print lifecycle events as they happen until the server stops
+pub async fn events(config: &Config, json: bool) -> Result<()> {
+    let (_, mut reader, _writer) = ext_session(config, ext::Request::Subscribe {}).await?;
+    while let Some(message) = reader.read_message().await.context("read event")? {
+        let Message::Notification(notif) = message else {
+            continue;
+        };
+        if notif.method != ext::EVENT {
+            continue;
+        }
+        if json {
+            println!("{}", notif.params);
+            continue;
+        }
+        let event = serde_json::from_value::<ext::Event>(notif.params).context("parse event")?;
+        println!("{}", format_event(&event));
+    }
     Ok(())
 }
 
-pub async fn reload(config: &Config) -> Result<()> {
-    let cwd = env::current_dir()
+fn format_event(event: &ext::Event) -> String {
+    let time = time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(event.time) * 1_000_000)
+        .map_or(event.time.to_string(), |time| {
+            let (hour, minute, second, milli) = time.to_hms_milli();
+            format!("{hour:02}:{minute:02}:{second:02}.{milli:03}")
+        });
+    let kind = serde_json::to_value(event.kind).unwrap();
+    let mut line = format!("{time}  {}", kind.as_str().unwrap_or_default());
+    if let Some(pid) = event.pid {
+        line.push_str(&format!("  pid {pid}"));
+    }
+    if let Some(client_id) = event.client_id {
+        line.push_str(&format!("  client {client_id}"));
+    }
+    if let Some(exit_code) = event.exit_code {
+        line.push_str(&format!("  exit code {exit_code}"));
+    }
+    if let Some(reason) = &event.reason {
+        line.push_str(&format!("  {reason}"));
+    }
+    if let Some(missed) = event.missed {
+        line.push_str(&format!("  missed {missed} events"));
+    }
+    if let (Some(server), Some(root)) = (&event.server, &event.workspace_root) {
+        line.push_str(&format!("  {server} {root}"));
+    }
+    line
+}
+
+pub async fn statusline(config: &Config, instance: Option<String>, json: bool) -> Result<()> {
+    let (pid, cwd) = match instance {
+        Some(instance) => select_instance(&instance)?,
+        None => (None, current_dir()?),
+    };
+    let request = ext::Request::Statusline { pid, cwd };
+    let statusline = ext_request::<Option<ext::Statusline>>(config, request).await?;
+
+    if json {
+        println!("{}", serde_json::to_string(&statusline).unwrap());
+        return Ok(());
+    }
+    // Print an empty line outside of workspaces to clear the statusline.
+    let Some(statusline) = statusline else {
+        println!();
+        return Ok(());
+    };
+    let name = Path::new(&statusline.server)
+        .file_name()
+        .map_or(statusline.server.clone(), |name| {
+            name.to_string_lossy().into_owned()
+        });
+    let state = match &statusline.progress {
+        Some(ext::Progress {
+            title,
+            percentage: Some(percentage),
+        }) => format!("{title} {percentage}%"),
+        Some(ext::Progress { title, .. }) => title.clone(),
+        None if statusline.busy => "busy".into(),
+        None => match statusline.health.as_deref() {
+            Some(health) if health != "ok" => health.to_owned(),
+            _ => "ready".into(),
+        },
+    };
+    let mut line = format!("{name}: {state}");
+    if let Some(rss) = statusline.rss {
+        line.push_str(&format!(" | {} MiB", rss / (1024 * 1024)));
+    }
+    line.push_str(&format!(" | {} clients", statusline.clients));
+    println!("{line}");
+    Ok(())
+}
+
+fn parse_params(params: Option<String>) -> Result<Value> {
+    match params {
+        Some(params) => serde_json::from_str(&params).context("invalid params JSON"),
+        None => Ok(Value::Null),
+    }
+}
+
+/// Select an instance either by the language server PID or by a path in its
+/// workspace
+fn select_instance(instance: &str) -> Result<(Option<u32>, String)> {
+    match instance.parse::<u32>() {
+        Ok(pid) => Ok((Some(pid), current_dir()?)),
+        Err(_) => {
+            let path = std::path::absolute(instance).context("invalid instance path")?;
+            let cwd = path.to_str().context("instance path is not valid utf-8")?;
+            Ok((None, cwd.to_owned()))
+        }
+    }
+}
+
+/// Parse a request entered as `method [params]` or as a JSON-RPC request
+/// object, missing `jsonrpc` and `id` fields are filled in
+fn parse_request(line: &str, next_id: &mut i64) -> Result<Request> {
+    let line = line.trim();
+    let mut id = || {
+        *next_id += 1;
+        RequestId::Number(*next_id)
+    };
+    if line.starts_with('{') {
+        let mut value = serde_json::from_str::<Value>(line).context("invalid JSON")?;
+        let object = value.as_object_mut().context("expected an object")?;
+        object.entry("jsonrpc").or_insert_with(|| "2.0".into());
+        if !object.contains_key("id") {
+            object.insert("id".into(), serde_json::to_value(id()).unwrap());
+        }
+        return serde_json::from_value(value).context("invalid request");
+    }
+
+    let (method, params) = line
+        .split_once(char::is_whitespace)
+        .unwrap_or((line, "null"));
+    let params = serde_json::from_str(params).context("invalid params JSON")?;
+    Ok(Request {
+        jsonrpc: Version,
+        method: method.into(),
+        params,
+        id: id(),
+    })
+}
+
+fn current_dir() -> Result<String> {
+    Ok(env::current_dir()
         .context("unable to get current_dir")?
         .to_str()
         .context("current_dir is not valid utf-8")?
-        .to_owned();
-    ext_request::<IgnoredAny>(config, ext::Request::Reload { cwd }).await?;
-    Ok(())
+        .to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_method_and_params() {
+        let mut next_id = 0;
+        let req = parse_request("rust-analyzer/analyzerStatus", &mut next_id).unwrap();
+        assert_eq!(req.method, "rust-analyzer/analyzerStatus");
+        assert_eq!(req.params, Value::Null);
+        assert_eq!(req.id, RequestId::Number(1));
+
+        let req = parse_request(r#"workspace/symbol {"query": "Foo"}"#, &mut next_id).unwrap();
+        assert_eq!(req.params, json!({ "query": "Foo" }));
+        assert_eq!(req.id, RequestId::Number(2));
+    }
+
+    #[test]
+    fn instance_key_differences() {
+        let instance = |args: Value, role: &str| {
+            serde_json::from_value::<ext::Instance>(json!({
+                "pid": 1,
+                "server": "rust-analyzer",
+                "args": args,
+                "env": {},
+                "workspaceRoot": "/ws",
+                "role": role,
+                "registeredDynCapabilities": [],
+                "lastUsed": 0,
+                "clients": [],
+            }))
+            .unwrap()
+        };
+        let primary = instance(json!([]), "primary");
+        assert!(key_differences(&primary, &primary).is_empty());
+        assert_eq!(
+            key_differences(&primary, &instance(json!(["-v"]), "replica")),
+            ["args", "role"],
+        );
+    }
+
+    #[test]
+    fn parse_json_rpc_object() {
+        let mut next_id = 0;
+        let req = parse_request(r#"{"method": "a", "id": "x"}"#, &mut next_id).unwrap();
+        assert_eq!(req.id, RequestId::String("x".into()));
+        let req = parse_request(r#"{"method": "b", "params": [1]}"#, &mut next_id).unwrap();
+        assert_eq!(req.id, RequestId::Number(1));
+        assert_eq!(req.params, json!([1]));
+        assert!(parse_request("a {", &mut next_id).is_err());
+    }
 }
@@ -1,9 +0,0 @@
-mod client;
-mod instance;
-mod lsp;
-mod socketwrapper;
-
-pub mod config;
-pub mod ext;
-pub mod proxy;
-pub mod server;
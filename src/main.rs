@@ -1,10 +1,26 @@
 use std::env;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use ra_multiplex::config::Config;
-use ra_multiplex::{ext, proxy, server};
-use tracing::info;
+use ra_multiplex_core::chaos::Chaos;
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::log_filter::LogFilter;
+use ra_multiplex_core::proxy;
+use ra_multiplex_core::server::{self, ServerOptions};
+use tracing::{info, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+use crate::mock::MockOptions;
+
+mod doctor;
+mod ext;
+mod mock;
+mod paths;
+mod stats;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -28,13 +44,38 @@ enum Cmd {
         )]
         server: String,
 
-        /// Arguments passed to the LSP server
+        /// Arguments passed to the LSP server, taken from RA_MUX_ARGS split
+        /// on whitespace if there are none
         #[arg(name = "SERVER_ARGS")]
         args: Vec<String>,
+
+        /// Label keeping the language server instances of clients with
+        /// different tags apart, even for the same server and workspace
+        #[arg(long, env = "RA_MUX_TAG")]
+        tag: Option<String>,
+
+        /// Connect as an observer whose document changes and edits are
+        /// dropped, for watching another editor's session
+        #[arg(long)]
+        observer: bool,
+
+        /// ID of a client whose opened and closed documents are reported with
+        /// `$/lspMux/documentOpened` and `$/lspMux/documentClosed`
+        /// notifications, see `ra-multiplex status` for client IDs
+        #[arg(long, value_name = "CLIENT_ID")]
+        follow: Option<usize>,
     },
 
     /// Start a ra-mux server
-    Server {},
+    Server {
+        #[command(subcommand)]
+        command: Option<ServerCmd>,
+
+        /// Delay, reorder and drop messages to clients for testing them,
+        /// `delay=MS,reorder=P,drop=P,seed=N`
+        #[arg(long, hide = true, value_name = "SETTINGS", num_args = 0..=1, default_missing_value = "")]
+        chaos: Option<Chaos>,
+    },
 
     /// Print server status
     Status {
@@ -43,43 +84,401 @@ enum Cmd {
         json: bool,
     },
 
+    /// Print which instances serve each workspace root
+    ///
+    /// Lists the workspace folders every client asked for, including ones
+    /// added later, and why instances for the same root aren't shared.
+    Workspaces {},
+
     /// Print server configuration
     Config {},
 
-    /// Reload workspace
+    /// Print where config files are looked up and state is kept
+    ///
+    /// Lists the system, user and project config files in the order they're
+    /// layered and the directories of crash reports, logs, sockets, the
+    /// pidfile, statistics and downloads.
+    Paths {},
+
+    /// Print daily statistics recorded with `record_stats`
+    ///
+    /// Shows language server spawns and crashes, client requests and the
+    /// average time servers took to respond per day, in UTC.
+    Stats {
+        /// Number of most recent days shown
+        #[arg(long, default_value = "14")]
+        days: usize,
+
+        /// Output data as machine readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check the setup for common problems
+    ///
+    /// Checks the config file, that the server is reachable and speaks the
+    /// same protocol, that it finds the language servers editors ask for, and
+    /// prints how to fix the problems found.
+    Doctor {},
+
+    /// Reload the workspace of a language server instance
+    ///
+    /// Sends rust-analyzer the `rust-analyzer/reloadWorkspace` extension
+    /// request, other language servers the `reload_method` configured in
+    /// their server settings.
+    #[command(alias = "reload")]
+    ReloadWorkspace {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the instance of the current directory
+        instance: Option<String>,
+    },
+
+    /// Keep a language server instance running without clients
+    ///
+    /// The instance is exempt from `instance_timeout` and `keep_alive` until
+    /// it's unpinned, like instances of servers with `pinned = true` in their
+    /// server settings.
+    Pin {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the instance of the current directory
+        instance: Option<String>,
+    },
+
+    /// Let a pinned language server instance be evicted again
+    Unpin {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the instance of the current directory
+        instance: Option<String>,
+    },
+
+    /// Restart a language server instance
+    ///
+    /// Connected clients stay connected and their opened documents are opened
+    /// in the new language server again.
+    Restart {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the instance of the current directory
+        instance: Option<String>,
+    },
+
+    /// Replace a language server instance without waiting for it to index
+    ///
+    /// A new language server is started next to the running one, which keeps
+    /// serving clients until the new one finished indexing. Then clients are
+    /// switched over to it like for `restart`.
+    Rollover {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the instance of the current directory
+        instance: Option<String>,
+    },
+
+    /// Move the clients of an instance onto another running the same server
+    ///
+    /// Clients are moved onto the other instance, which gets the workspace of
+    /// the first one added as a workspace folder if it isn't inside its own.
+    /// `status` suggests instances to merge when their workspaces overlap.
+    Merge {
+        /// PID of the language server or a path in its workspace
+        instance: String,
+
+        /// PID of the language server to move the clients to or a path in its
+        /// workspace
+        into: String,
+    },
+
+    /// Send a single request to a language server instance and print the result
+    Request {
+        /// PID of the language server or a path in its workspace
+        instance: String,
+
+        /// Request method
+        method: String,
+
+        /// Request params as JSON
+        #[arg(long)]
+        params: Option<String>,
+    },
+
+    /// Send a notification to a language server instance
+    ///
+    /// For example `workspace/didChangeConfiguration` after editing project
+    /// settings, without restarting editors.
+    Notify {
+        /// PID of the language server or a path in its workspace
+        instance: String,
+
+        /// Notification method
+        method: String,
+
+        /// Notification params as JSON
+        #[arg(long)]
+        params: Option<String>,
+    },
+
+    /// List the requests a language server instance didn't respond to yet
     ///
-    /// For rust-analyzer send the `rust-analyzer/reloadWorkspace` extension request.
-    /// Do nothing for other language servers.
-    Reload {},
+    /// Shows their age, the client which sent them and whether they're in
+    /// flight or queued by `max_concurrent_requests`.
+    Queue {
+        /// PID of the language server or a path in its workspace
+        instance: String,
+
+        /// Cancel the request with this ID instead, with `$/cancelRequest` if
+        /// it was already sent to the server
+        #[arg(long, value_name = "ID")]
+        cancel: Option<String>,
+    },
+
+    /// Print a short state of a language server instance for editor statuslines
+    ///
+    /// Shows the indexing progress, memory and number of clients. Prints an
+    /// empty line if there is no instance for the directory.
+    Statusline {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the current directory
+        instance: Option<String>,
+
+        /// Output data as machine readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Attach to a language server instance and send it requests
+    ///
+    /// Requests are read from stdin one per line, either as `method [params]`
+    /// or as JSON-RPC request objects. Responses are printed to stdout.
+    Attach {
+        /// PID of the language server or a path in its workspace, defaults to
+        /// the instance of the current directory
+        instance: Option<String>,
+
+        /// Tag of the clients whose responses are adopted with
+        /// `adopt_responses`, the instance must have the same tag
+        #[arg(long, env = "RA_MUX_TAG")]
+        tag: Option<String>,
+    },
+
+    /// Print instance and client lifecycle events as they happen
+    Events {
+        /// Output events as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Change the log filter of the running server
+    ///
+    /// Replaces the filter set by `log_filters` or `RUST_LOG` without
+    /// restarting the server, for example with
+    /// `ra_multiplex_core::instance=trace`. Without a filter the filter in
+    /// effect is printed.
+    LogLevel {
+        /// Filter in `RUST_LOG` syntax
+        filter: Option<String>,
+
+        /// Go back to the filter the server was started with
+        #[arg(long, conflicts_with = "filter")]
+        reset: bool,
+
+        /// Restore the replaced filter after this many seconds
+        #[arg(long = "for", value_name = "SECONDS", requires = "filter")]
+        revert_after: Option<u32>,
+    },
+
+    /// Run a scripted language server for testing without a real one
+    ///
+    /// Answers `initialize` with fixed capabilities and other requests with
+    /// their method, params and the server's PID. `mock/sleep` with
+    /// `{"ms": N}` is answered after N milliseconds, `mock/notify` with
+    /// `{"method": M, "params": P}` sends a notification, `mock/request`
+    /// sends a request and returns the response, `mock/notifications` returns
+    /// the notifications received so far and `mock/crash` exits with status
+    /// 101.
+    MockServer {
+        /// Milliseconds to wait before answering each request
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        delay: u64,
+
+        /// Crash after answering this many requests
+        #[arg(long, value_name = "N")]
+        crash_after: Option<usize>,
+
+        /// Exit right after answering `shutdown` without waiting for `exit`
+        #[arg(long)]
+        exit_on_shutdown: bool,
+
+        /// Ask for the `mock` configuration section after `initialized`,
+        /// `mock/configuration` returns the answer
+        #[arg(long)]
+        configure: bool,
+    },
+
+    /// Run a language server for the daemon with `adopt_instances` enabled
+    #[cfg(unix)]
+    #[command(hide = true)]
+    Shim {
+        /// Unix socket the daemon connects to
+        #[arg(long)]
+        socket: PathBuf,
+
+        /// Language server command
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[derive(Subcommand, Debug)]
+enum ServerCmd {
+    /// Shut down all instances and stop the running ra-mux server
+    Stop {
+        /// Leave instances running in a shim for the next server to adopt,
+        /// see `adopt_instances`
+        #[arg(long)]
+        detach: bool,
+    },
+}
+
+fn main() -> Result<()> {
     let cli = Cli::parse();
+    let (config, log_filter) = load_config();
+
+    let runtime = config
+        .runtime
+        .build()
+        .context("building the tokio runtime")?;
+    let res = runtime.block_on(run(cli, config, log_filter));
+    // Don't wait for blocked stdin reads, the proxy can finish with the
+    // editor still holding stdin open.
+    runtime.shutdown_background();
+    res
+}
 
-    let config = match Config::try_load() {
+/// Load the config file and initialize logging, the runtime is configured by
+/// it so this happens before any tasks run
+fn load_config() -> (Config, LogFilter) {
+    match Config::try_load() {
         Ok(config) => {
-            config.init_logger();
-            config
+            let log_filter = init_logger(&config);
+            for setting in &config.ignored_settings {
+                warn!("ignoring {setting}, the project config isn't trusted with it");
+            }
+            (config, log_filter)
         }
         Err(err) => {
             let config = Config::default();
-            config.init_logger();
+            let log_filter = init_logger(&config);
             // Log only after the logger has been initialized
             info!(?err, "cannot load config file, continuing with defaults");
-            config
+            (config, log_filter)
         }
-    };
+    }
+}
+
+/// Configure tracing-subscriber with env filter set to `log_filters` (if
+/// not overriden by RUST_LOG env var), returns the filter `ra-multiplex
+/// log-level` changes
+fn init_logger(config: &Config) -> LogFilter {
+    let format = tracing_subscriber::fmt::layer()
+        .without_time()
+        .with_target(false)
+        .with_writer(std::io::stderr);
+
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.log_filters))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let (log_filter, filter) = LogFilter::new(filter);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format)
+        .init();
+    log_filter
+}
 
+async fn run(cli: Cli, config: Config, log_filter: LogFilter) -> Result<()> {
     match cli.command {
-        Some(Cmd::Server {}) => server::run(&config).await,
-        Some(Cmd::Client { server, args }) => proxy::run(&config, server, args).await,
+        Some(Cmd::Server {
+            command: None,
+            chaos,
+        }) => {
+            let options = ServerOptions {
+                log_filter: Some(log_filter),
+                chaos,
+            };
+            server::run(&config, options).await
+        }
+        Some(Cmd::Server {
+            command: Some(ServerCmd::Stop { detach }),
+            ..
+        }) => ext::stop(&config, detach).await,
+        Some(Cmd::Client {
+            server,
+            args,
+            tag,
+            observer,
+            follow,
+        }) => proxy::run(&config, server, env_args(args), observer, follow, tag).await,
         Some(Cmd::Status { json }) => ext::status(&config, json).await,
+        Some(Cmd::Workspaces {}) => ext::workspaces(&config).await,
         Some(Cmd::Config {}) => ext::config(&config).await,
-        Some(Cmd::Reload {}) => ext::reload(&config).await,
+        Some(Cmd::Paths {}) => paths::print(),
+        Some(Cmd::Stats { days, json }) => stats::print(days, json).await,
+        Some(Cmd::Doctor {}) => doctor::run(&config).await,
+        Some(Cmd::ReloadWorkspace { instance }) => ext::reload(&config, instance).await,
+        Some(Cmd::Pin { instance }) => ext::pin(&config, instance, true).await,
+        Some(Cmd::Unpin { instance }) => ext::pin(&config, instance, false).await,
+        Some(Cmd::Restart { instance }) => ext::restart(&config, instance).await,
+        Some(Cmd::Rollover { instance }) => ext::rollover(&config, instance).await,
+        Some(Cmd::Merge { instance, into }) => ext::merge(&config, instance, into).await,
+        Some(Cmd::Statusline { instance, json }) => ext::statusline(&config, instance, json).await,
+        Some(Cmd::Attach { instance, tag }) => ext::attach(&config, instance, tag).await,
+        Some(Cmd::Request {
+            instance,
+            method,
+            params,
+        }) => ext::request(&config, instance, method, params).await,
+        Some(Cmd::Notify {
+            instance,
+            method,
+            params,
+        }) => ext::notify(&config, instance, method, params).await,
+        Some(Cmd::Queue { instance, cancel }) => ext::queue(&config, instance, cancel).await,
+        Some(Cmd::Events { json }) => ext::events(&config, json).await,
+        Some(Cmd::LogLevel {
+            filter,
+            reset,
+            revert_after,
+        }) => ext::log_level(&config, filter, reset, revert_after).await,
+        Some(Cmd::MockServer {
+            delay,
+            crash_after,
+            exit_on_shutdown,
+            configure,
+        }) => {
+            let options = MockOptions {
+                delay: Duration::from_millis(delay),
+                crash_after,
+                exit_on_shutdown,
+                configure,
+            };
+            mock::run(options).await
+        }
+        #[cfg(unix)]
+        Some(Cmd::Shim { socket, command }) => ra_multiplex_core::shim::run(socket, command).await,
         None => {
             let server_path = env::var("RA_MUX_SERVER").unwrap_or_else(|_| "rust-analyzer".into());
-            proxy::run(&config, server_path, vec![]).await
+            let tag = env::var("RA_MUX_TAG").ok();
+            proxy::run(&config, server_path, env_args(vec![]), false, None, tag).await
         }
     }
 }
+
+/// Server arguments from RA_MUX_ARGS for editors which can set environment
+/// variables but not the arguments, unless some are given on the command line
+fn env_args(args: Vec<String>) -> Vec<String> {
+    if !args.is_empty() {
+        return args;
+    }
+    env::var("RA_MUX_ARGS")
+        .map(|args| args.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,393 @@
+//! Scripted language server for `ra-multiplex mock-server`
+//!
+//! Answers `initialize` with fixed capabilities and every other request with
+//! its method, params and the server's PID, so tests and users can check which
+//! instance a request was routed to without installing rust-analyzer. Requests
+//! can be delayed or make the server crash:
+//!
+//! - `mock/sleep` with `{"ms": N}` is answered after `N` milliseconds unless
+//!   it's cancelled with `$/cancelRequest` first
+//! - `mock/notify` with `{"method": M, "params": P}` sends the notification to
+//!   the client before answering
+//! - `mock/crash` exits with status 101 without answering, like a panicking
+//!   server
+//! - `mock/notifications` is answered with the notifications received so far,
+//!   to check what reached the server
+//! - `mock/request` with `{"method": M, "params": P}` sends the request to the
+//!   client and is answered with the client's response
+//!
+//! With `exit_on_shutdown` it exits right after answering `shutdown` without
+//! waiting for `exit`, like some servers do. With `configure` it asks for the
+//! `mock` configuration section after `initialized` like rust-analyzer does,
+//! `mock/configuration` is answered with the client's response.
+
+use std::collections::HashMap;
+use std::process;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::io::{self, AsyncBufRead, AsyncWrite, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::{self, AbortHandle};
+use tokio::time;
+use tracing::debug;
+
+use ra_multiplex_core::lsp::jsonrpc::{
+    self, Message, Notification, Request, RequestId, ResponseError, ResponseSuccess, Version,
+};
+use ra_multiplex_core::lsp::redact::{Logged, Redaction};
+use ra_multiplex_core::lsp::transport::{LspReader, LspWriter};
+
+/// Exit status of a crash, the same as a panicking Rust program
+const CRASH_STATUS: i32 = 101;
+
+/// ID of the `workspace/configuration` request sent with `configure`
+const CONFIGURATION_ID: &str = "mock-configuration";
+
+#[derive(Debug, Clone, Default)]
+pub struct MockOptions {
+    /// Wait before answering each request
+    pub delay: Duration,
+    /// Crash after answering this many requests
+    pub crash_after: Option<usize>,
+    /// Exit after answering `shutdown` instead of waiting for `exit`
+    pub exit_on_shutdown: bool,
+    /// Ask for the configuration after `initialized`
+    pub configure: bool,
+}
+
+pub async fn run(options: MockOptions) -> Result<()> {
+    let reader = LspReader::new(BufReader::new(io::stdin()), "client");
+    let writer = LspWriter::new(io::stdout(), "client");
+    match serve(reader, writer, options).await? {
+        0 => Ok(()),
+        status => process::exit(status),
+    }
+}
+
+/// Serve messages from `reader` until `exit`, returns the exit status
+async fn serve<R, W>(
+    mut reader: LspReader<R>,
+    writer: LspWriter<W>,
+    options: MockOptions,
+) -> Result<i32>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(16);
+    let writer = task::spawn(write_messages(rx, writer));
+    let mut pending = HashMap::<RequestId, AbortHandle>::new();
+    let mut notifications = Vec::new();
+    // IDs of `mock/request` requests by the ID of the request sent for them
+    let mut forwarded = HashMap::<RequestId, RequestId>::new();
+    let mut next_id = 0;
+    let mut answered = 0;
+    let mut configuration = Value::Null;
+
+    let status = loop {
+        let Some(message) = reader.read_message().await? else {
+            break 0;
+        };
+        let req = match message {
+            Message::Request(req) => req,
+            Message::Notification(notif) if notif.method == "exit" => break 0,
+            Message::Notification(notif) if notif.method == "$/cancelRequest" => {
+                let Ok(id) = serde_json::from_value(notif.params["id"].clone()) else {
+                    continue;
+                };
+                if let Some(handle) = pending.remove(&id) {
+                    if !handle.is_finished() {
+                        handle.abort();
+                        let _ = tx.send(cancelled(id).into()).await;
+                    }
+                }
+                continue;
+            }
+            Message::Notification(notif) => {
+                if notif.method == "initialized" && options.configure {
+                    let request = Request {
+                        jsonrpc: Version,
+                        method: "workspace/configuration".into(),
+                        params: json!({ "items": [{ "section": "mock" }] }),
+                        id: RequestId::String(CONFIGURATION_ID.into()),
+                    };
+                    let _ = tx.send(request.into()).await;
+                }
+                notifications.push(serde_json::to_value(notif)?);
+                continue;
+            }
+            Message::ResponseSuccess(res)
+                if res.id == RequestId::String(CONFIGURATION_ID.into()) =>
+            {
+                configuration = res.result;
+                continue;
+            }
+            Message::ResponseSuccess(mut res) => {
+                match forwarded.remove(&res.id) {
+                    Some(id) => {
+                        res.id = id;
+                        let _ = tx.send(res.into()).await;
+                    }
+                    None => {
+                        debug!(res = ?Logged(&res, &Redaction::NONE), "mock server ignoring response")
+                    }
+                }
+                continue;
+            }
+            Message::ResponseError(mut res) => {
+                match forwarded.remove(&res.id) {
+                    Some(id) => {
+                        res.id = id;
+                        let _ = tx.send(res.into()).await;
+                    }
+                    None => {
+                        debug!(res = ?Logged(&res, &Redaction::NONE), "mock server ignoring response")
+                    }
+                }
+                continue;
+            }
+        };
+
+        if req.method == "mock/request" {
+            next_id += 1;
+            let request = Request {
+                jsonrpc: Version,
+                method: req.params["method"].as_str().unwrap_or_default().into(),
+                params: req.params["params"].clone(),
+                id: RequestId::String(format!("mock-{next_id}")),
+            };
+            forwarded.insert(request.id.clone(), req.id);
+            let _ = tx.send(request.into()).await;
+            continue;
+        }
+
+        let shutdown = req.method == "shutdown";
+        let mut delay = options.delay;
+        let result = match req.method.as_str() {
+            "initialize" => initialize_result(),
+            "shutdown" => Value::Null,
+            "mock/crash" => break CRASH_STATUS,
+            "mock/sleep" => {
+                let ms = req.params["ms"].as_u64().unwrap_or_default();
+                delay += Duration::from_millis(ms);
+                Value::Null
+            }
+            "mock/notifications" => Value::Array(notifications.clone()),
+            "mock/configuration" => configuration.clone(),
+            "mock/notify" => {
+                let notif = Notification {
+                    jsonrpc: Version,
+                    method: req.params["method"].as_str().unwrap_or_default().into(),
+                    params: req.params["params"].clone(),
+                };
+                let _ = tx.send(notif.into()).await;
+                Value::Null
+            }
+            _ => json!({ "method": req.method, "params": req.params, "pid": process::id() }),
+        };
+        let res = ResponseSuccess {
+            jsonrpc: Version,
+            result,
+            id: req.id.clone(),
+        };
+        if delay.is_zero() {
+            let _ = tx.send(res.into()).await;
+        } else {
+            let tx = tx.clone();
+            let handle = task::spawn(async move {
+                time::sleep(delay).await;
+                let _ = tx.send(res.into()).await;
+            });
+            pending.retain(|_, handle| !handle.is_finished());
+            pending.insert(req.id, handle.abort_handle());
+        }
+
+        answered += 1;
+        if options
+            .crash_after
+            .is_some_and(|crash_after| answered >= crash_after)
+        {
+            break CRASH_STATUS;
+        }
+        if shutdown && options.exit_on_shutdown {
+            break 0;
+        }
+    };
+
+    // Requests still waiting aren't answered, the ones answered are written
+    // before exiting.
+    for handle in pending.values() {
+        handle.abort();
+    }
+    drop(tx);
+    let _ = writer.await;
+    Ok(status)
+}
+
+async fn write_messages<W>(mut rx: mpsc::Receiver<Message>, mut writer: LspWriter<W>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(message) = rx.recv().await {
+        if writer.write_message(&message).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn initialize_result() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": 1, // Full
+            "hoverProvider": true,
+            "workspace": {
+                "workspaceFolders": { "supported": true, "changeNotifications": true },
+            },
+        },
+        "serverInfo": { "name": "ra-multiplex mock-server", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+fn cancelled(id: RequestId) -> ResponseError {
+    ResponseError {
+        jsonrpc: Version,
+        error: jsonrpc::Error {
+            code: -32800, // RequestCancelled
+            message: "request cancelled".into(),
+            data: None,
+        },
+        id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(id: i64, method: &str, params: Value) -> Message {
+        Request {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+            id: RequestId::Number(id),
+        }
+        .into()
+    }
+
+    fn notification(method: &str, params: Value) -> Message {
+        Notification {
+            jsonrpc: Version,
+            method: method.into(),
+            params,
+        }
+        .into()
+    }
+
+    /// Run the mock server with `options` on `messages`, returns its exit
+    /// status and everything it wrote
+    async fn script(options: MockOptions, messages: &[Message]) -> (i32, Vec<Value>) {
+        let (client, server) = io::duplex(1 << 16);
+        let (server_read, server_write) = io::split(server);
+        let (client_read, client_write) = io::split(client);
+
+        let mut writer = LspWriter::new(client_write, "test");
+        for message in messages {
+            writer.write_message(message).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+
+        let reader = LspReader::new(BufReader::new(server_read), "test");
+        let writer = LspWriter::new(server_write, "test");
+        let status = serve(reader, writer, options).await.unwrap();
+
+        let mut reader = LspReader::new(BufReader::new(client_read), "test");
+        let mut written = Vec::new();
+        while let Ok(Some(message)) = reader.read_message().await {
+            written.push(serde_json::to_value(message).unwrap());
+        }
+        (status, written)
+    }
+
+    #[tokio::test]
+    async fn answer_requests() {
+        let messages = [
+            request(1, "initialize", json!({})),
+            request(2, "textDocument/hover", json!({ "position": 1 })),
+            request(3, "mock/notify", json!({ "method": "test", "params": [] })),
+            request(4, "mock/sleep", json!({ "ms": 60_000 })),
+            notification("$/cancelRequest", json!({ "id": 4 })),
+            notification("initialized", json!({})),
+            request(5, "mock/notifications", Value::Null),
+            notification("exit", Value::Null),
+        ];
+        let (status, written) = script(MockOptions::default(), &messages).await;
+        assert_eq!(status, 0);
+        assert_eq!(written.len(), 6);
+        assert_eq!(
+            written[0]["result"]["serverInfo"]["name"],
+            "ra-multiplex mock-server",
+        );
+        assert_eq!(written[1]["result"]["method"], "textDocument/hover");
+        assert_eq!(written[1]["result"]["params"], json!({ "position": 1 }));
+        assert_eq!(
+            written[2],
+            json!({ "jsonrpc": "2.0", "method": "test", "params": [] })
+        );
+        assert_eq!(written[3]["id"], 3);
+        assert_eq!(written[4]["error"]["code"], -32800);
+        assert_eq!(
+            written[5]["result"],
+            json!([{ "jsonrpc": "2.0", "method": "initialized", "params": {} }])
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_request() {
+        let response = ResponseSuccess {
+            jsonrpc: Version,
+            result: json!({ "applied": true }),
+            id: RequestId::String("mock-1".into()),
+        };
+        let messages = [
+            request(1, "mock/request", json!({ "method": "test", "params": [] })),
+            response.into(),
+            notification("exit", Value::Null),
+        ];
+        let (_, written) = script(MockOptions::default(), &messages).await;
+        assert_eq!(
+            written,
+            [
+                json!({ "jsonrpc": "2.0", "method": "test", "params": [], "id": "mock-1" }),
+                json!({ "jsonrpc": "2.0", "result": { "applied": true }, "id": 1 }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn crash() {
+        let messages = [request(1, "mock/crash", Value::Null)];
+        let (status, written) = script(MockOptions::default(), &messages).await;
+        assert_eq!((status, written.len()), (CRASH_STATUS, 0));
+
+        let options = MockOptions {
+            crash_after: Some(2),
+            ..MockOptions::default()
+        };
+        let messages = [1, 2, 3].map(|id| request(id, "shutdown", Value::Null));
+        let (status, written) = script(options, &messages).await;
+        assert_eq!((status, written.len()), (CRASH_STATUS, 2));
+    }
+
+    #[tokio::test]
+    async fn exit_on_shutdown() {
+        let options = MockOptions {
+            exit_on_shutdown: true,
+            ..MockOptions::default()
+        };
+        let messages = [1, 2].map(|id| request(id, "shutdown", Value::Null));
+        let (status, written) = script(options, &messages).await;
+        assert_eq!((status, written.len()), (0, 1));
+    }
+}
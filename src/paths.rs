@@ -0,0 +1,37 @@
+//! Printing where `ra-multiplex` keeps its files for `ra-multiplex paths`
+
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use ra_multiplex_core::paths::{
+    audit_log, cache_dir, crash_dir, data_dir, pidfile, project_config, runtime_dir, state_dir,
+    system_config, user_config, PROJECT_CONFIG,
+};
+
+/// Print the config files and state directories for `ra-multiplex paths`
+pub fn print() -> Result<()> {
+    let show = |path: Option<PathBuf>| match path {
+        Some(path) if path.exists() => path.display().to_string(),
+        Some(path) => format!("{} (missing)", path.display()),
+        None => "-".to_owned(),
+    };
+    let cwd = env::current_dir().context("current directory")?;
+    let project = project_config(&cwd).unwrap_or_else(|| cwd.join(PROJECT_CONFIG));
+    println!("system config   {}", show(system_config()));
+    println!("user config     {}", show(user_config().ok()));
+    println!("project config  {}", show(Some(project)));
+    println!("state           {}", show(state_dir().ok()));
+    println!("crash reports   {}", show(crash_dir().ok()));
+    println!("audit log       {}", show(audit_log().ok()));
+    println!("runtime         {}", show(runtime_dir().ok()));
+    println!("pidfile         {}", show(pidfile().ok()));
+    #[cfg(unix)]
+    println!(
+        "shim sockets    {}",
+        show(ra_multiplex_core::shim::socket_dir().ok())
+    );
+    println!("data            {}", show(data_dir().ok()));
+    println!("cache           {}", show(cache_dir().ok()));
+    Ok(())
+}
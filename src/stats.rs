@@ -0,0 +1,49 @@
+//! Printing the statistics the server records for `ra-multiplex stats`
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use ra_multiplex_core::stats::{self, DayStats};
+
+/// Print the statistics of the last `days` days for `ra-multiplex stats`
+pub async fn print(days: usize, json: bool) -> Result<()> {
+    let stored = stats::load().await?;
+    let shown = stored
+        .iter()
+        .skip(stored.len().saturating_sub(days))
+        .collect::<BTreeMap<_, _>>();
+    if json {
+        println!("{}", serde_json::to_string(&shown).unwrap());
+        return Ok(());
+    }
+    if shown.is_empty() {
+        println!("no statistics recorded, enable `record_stats` in the config");
+        return Ok(());
+    }
+
+    let mut total = DayStats::default();
+    println!("day         spawns  crashes  requests  avg server ms  avg queued ms");
+    for (day, stats) in &shown {
+        total.add(stats);
+        print_row(day, stats);
+    }
+    if shown.len() > 1 {
+        print_row("total", &total);
+    }
+    Ok(())
+}
+
+fn print_row(day: &str, stats: &DayStats) {
+    let average = |total_ms: u64| match total_ms.checked_div(stats.responses) {
+        Some(ms) => ms.to_string(),
+        None => "-".into(),
+    };
+    println!(
+        "{day:<10} {:>7} {:>8} {:>9} {:>14} {:>14}",
+        stats.spawns,
+        stats.crashes,
+        stats.requests,
+        average(stats.server_ms),
+        average(stats.queued_ms),
+    );
+}
@@ -0,0 +1,156 @@
+//! Responses to requests of a lost client go to an `attach` session of the
+//! same user with the tag of the instance
+
+#![cfg(unix)]
+
+mod common;
+
+use std::cell::Cell;
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fs, process};
+
+use ra_multiplex_core::config::{Address, Config};
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+use tokio::io;
+use tokio::net::UnixStream;
+use tokio::{task, time};
+
+use common::Client;
+
+const ADOPTED_RESPONSE: &str = "$/lspMux/adoptedResponse";
+
+fn config() -> Config {
+    Config {
+        adopt_responses: ["mock/sleep".to_owned()].into(),
+        heartbeat_timeout: 1,
+        ..Config::default()
+    }
+}
+
+/// Connect over the Unix socket the server listens on, the server knows the
+/// user of the connection
+async fn connect_unix(socket: &Path) -> Client {
+    let stream = time::timeout(common::TIMEOUT, async {
+        loop {
+            match UnixStream::connect(socket).await {
+                Ok(stream) => break stream,
+                Err(_) => time::sleep(time::Duration::from_millis(20)).await,
+            }
+        }
+    });
+    let mut stream = stream.await.expect("server isn't listening");
+    let (mut ours, theirs) = io::duplex(64 * 1024);
+    task::spawn(async move { io::copy_bidirectional(&mut stream, &mut ours).await });
+    Client::from_stream(theirs)
+}
+
+/// Send `initialize` with `lspMux` options, returns the response
+async fn initialize(client: &mut Client, lsp_mux: Value) -> Value {
+    let params = json!({ "initializationOptions": { "lspMux": lsp_mux } });
+    client.send_request(1, "initialize", params).await;
+    client
+        .receive_matching(|message| message.get("method").is_none())
+        .await
+}
+
+async fn connect_editor(client: &mut Client, tag: Option<&str>) -> Value {
+    let lsp_mux = json!({
+        "version": "1",
+        "method": "connect",
+        "server": env!("CARGO_BIN_EXE_ra-multiplex"),
+        "args": ["mock-server", "--exit-on-shutdown"],
+        "cwd": env!("CARGO_MANIFEST_DIR"),
+        "tag": tag,
+    });
+    let res = initialize(client, lsp_mux).await;
+    assert!(res["result"]["capabilities"].is_object(), "{res}");
+    client.notify("initialized", json!({})).await;
+    client.request(2, "test/pid").await["pid"].clone()
+}
+
+async fn attach(client: &mut Client, pid: &Value, tag: Option<&str>) {
+    let lsp_mux = json!({ "version": "1", "method": "attach", "pid": pid, "cwd": "/", "tag": tag });
+    let res = initialize(client, lsp_mux).await;
+    assert_eq!(res["result"], Value::Null, "{res}");
+}
+
+/// Send a request and go silent after a heartbeat, the server gives up on the
+/// client before the server responds
+async fn lose_request(editor: &mut Client) {
+    editor.notify("$/lspMux/ping", json!(null)).await;
+    editor
+        .send_request(3, "mock/sleep", json!({ "ms": 2000 }))
+        .await;
+}
+
+/// Wait for the response to a request of the session, returns whether it
+/// adopted a response before
+async fn adopted_before_response(session: &mut Client, id: i64) -> bool {
+    let adopted = Cell::new(false);
+    session
+        .receive_matching(|message| {
+            adopted.set(adopted.get() || message["method"] == ADOPTED_RESPONSE);
+            message["id"] == id && message.get("method").is_none()
+        })
+        .await;
+    adopted.get()
+}
+
+#[tokio::test]
+async fn adopt_over_unix_socket_with_tag() {
+    let socket = env::temp_dir().join(format!("ra-mux-adopt-{}.sock", process::id()));
+    let _ = fs::remove_file(&socket);
+    let config = Config {
+        listen: Address::Unix(socket.clone()),
+        ..config()
+    };
+    let server = Arc::new(Server::new(config).await.unwrap());
+    let listen = task::spawn({
+        let server = server.clone();
+        async move { server.listen().await }
+    });
+
+    let mut editor = connect_unix(&socket).await;
+    let pid = connect_editor(&mut editor, Some("a")).await;
+    let mut untagged = connect_unix(&socket).await;
+    attach(&mut untagged, &pid, None).await;
+    let mut tagged = connect_unix(&socket).await;
+    attach(&mut tagged, &pid, Some("a")).await;
+
+    // The untagged session's own response comes after the adopted one.
+    untagged
+        .send_request(1, "mock/sleep", json!({ "ms": 3000 }))
+        .await;
+    lose_request(&mut editor).await;
+
+    let notif = tagged
+        .receive_matching(|message| message["method"] == ADOPTED_RESPONSE)
+        .await;
+    assert_eq!(notif["params"]["method"], "mock/sleep", "{notif}");
+    assert!(!adopted_before_response(&mut untagged, 1).await);
+
+    server.stop(false).await;
+    listen.await.unwrap().unwrap();
+    let _ = fs::remove_file(&socket);
+}
+
+#[tokio::test]
+async fn no_adoption_without_user() {
+    let server = Server::new(config()).await.unwrap();
+    let mut editor = Client::connect_with(&server, ClientOptions::default());
+    let pid = connect_editor(&mut editor, None).await;
+    let mut session = Client::connect_with(&server, ClientOptions::default());
+    attach(&mut session, &pid, None).await;
+
+    session
+        .send_request(1, "mock/sleep", json!({ "ms": 3000 }))
+        .await;
+    lose_request(&mut editor).await;
+
+    // In-process clients have no user, nobody adopts their responses.
+    assert!(!adopted_before_response(&mut session, 1).await);
+
+    server.stop(false).await;
+}
@@ -0,0 +1,63 @@
+//! `workspace/applyEdit` requests from the server go to the clients selected
+//! by `apply_edit` or a `[routing]` entry
+
+mod common;
+
+use ra_multiplex_core::config::{Config, Route};
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+fn config(route: Route) -> Config {
+    Config {
+        routing: [("workspace/applyEdit".to_owned(), route)].into(),
+        ..Config::default()
+    }
+}
+
+/// Make the server send a `workspace/applyEdit` request
+async fn request_edit(client: &mut Client, id: i64) {
+    let params = json!({ "method": "workspace/applyEdit", "params": { "edit": {} } });
+    client.send_request(id, "mock/request", params).await;
+}
+
+async fn answer_edit(client: &mut Client) -> Value {
+    let req = client
+        .receive_matching(|message| message["method"] == "workspace/applyEdit")
+        .await;
+    let res = json!({ "jsonrpc": "2.0", "id": req["id"], "result": { "applied": true } });
+    client.send(res).await;
+    req
+}
+
+#[tokio::test]
+async fn route_to_first_client() {
+    let server = Server::new(config(Route::FirstClient)).await.unwrap();
+    let mut first = Client::connect(&server);
+    first.initialize().await;
+    let mut second = Client::connect(&server);
+    second.initialize().await;
+
+    // Without the routing entry the client which sent the last request would
+    // be asked.
+    request_edit(&mut second, 2).await;
+    answer_edit(&mut first).await;
+    let res = second.response(2).await;
+    assert_eq!(res["result"]["applied"], true, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn drop_edit() {
+    let server = Server::new(config(Route::Drop)).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    request_edit(&mut client, 2).await;
+    let res = client.response(2).await;
+    assert_eq!(res["result"]["applied"], false, "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,147 @@
+//! Editor talking to an in-process server, the language servers are
+//! `ra-multiplex mock-server`
+
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::time;
+
+/// Time a test waits for a message before it fails
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Version of the `lspMux` protocol spoken by `ext_request`
+const PROTOCOL_VERSION: &str = "1";
+
+/// Send the server an `lspMux` request like `ra-multiplex restart`, `request`
+/// has its `method` and params
+pub async fn ext_request(server: &Server, mut request: Value) -> Value {
+    request["version"] = PROTOCOL_VERSION.into();
+    let mut client = Client::connect_with(server, ClientOptions::default());
+    let params = json!({ "initializationOptions": { "lspMux": request } });
+    client.send_request(1, "initialize", params).await;
+    time::timeout(TIMEOUT, client.receive())
+        .await
+        .expect("no response")
+}
+
+/// Options connecting to a mock server started with `args`
+pub fn mock_server(args: &[&str]) -> ClientOptions {
+    let mut all = vec!["mock-server".to_owned()];
+    all.extend(args.iter().map(|arg| arg.to_string()));
+    ClientOptions {
+        args: all,
+        cwd: Some(env!("CARGO_MANIFEST_DIR").into()),
+        ..ClientOptions::new(env!("CARGO_BIN_EXE_ra-multiplex"))
+    }
+}
+
+pub struct Client {
+    stream: BufReader<DuplexStream>,
+}
+
+impl Client {
+    pub fn connect(server: &Server) -> Client {
+        Client::connect_with(server, mock_server(&["--exit-on-shutdown"]))
+    }
+
+    pub fn connect_with(server: &Server, options: ClientOptions) -> Client {
+        Client::from_stream(server.connect(options))
+    }
+
+    /// Talk LSP over `stream`, for peers other than the in-process server
+    pub fn from_stream(stream: DuplexStream) -> Client {
+        Client {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    pub async fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        let message = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+        self.stream.write_all(message.as_bytes()).await.unwrap();
+    }
+
+    pub async fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+            .await;
+    }
+
+    pub async fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            self.stream.read_line(&mut line).await.unwrap();
+            match line.trim_end().split_once(": ") {
+                Some(("Content-Length", value)) => length = value.parse().unwrap(),
+                None if line.trim_end().is_empty() => break,
+                _ => {}
+            }
+        }
+        let mut body = vec![0; length];
+        self.stream.read_exact(&mut body).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Close the sending side of the connection like a client which is done
+    /// sending requests but still waits for their responses
+    pub async fn close_output(&mut self) {
+        self.stream.get_mut().shutdown().await.unwrap();
+    }
+
+    /// Wait for the server to close the connection, skipping other messages
+    pub async fn closed(&mut self) {
+        let mut rest = Vec::new();
+        time::timeout(TIMEOUT, self.stream.read_to_end(&mut rest))
+            .await
+            .expect("connection not closed")
+            .unwrap();
+    }
+
+    /// Wait for a message matching `filter`, skipping other messages
+    pub async fn receive_matching(&mut self, filter: impl Fn(&Value) -> bool) -> Value {
+        let message = async {
+            loop {
+                let message = self.receive().await;
+                if filter(&message) {
+                    return message;
+                }
+            }
+        };
+        time::timeout(TIMEOUT, message)
+            .await
+            .expect("no matching message")
+    }
+
+    /// Wait for the response to request `id`, skipping other messages
+    pub async fn response(&mut self, id: i64) -> Value {
+        self.receive_matching(|message| message["id"] == id && message.get("method").is_none())
+            .await
+    }
+
+    /// Send a request without waiting for its response
+    pub async fn send_request(&mut self, id: i64, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await;
+    }
+
+    /// Send a request and wait for its result, skipping other messages
+    pub async fn request(&mut self, id: i64, method: &str) -> Value {
+        self.send_request(id, method, json!({})).await;
+        self.response(id).await["result"].clone()
+    }
+
+    pub async fn initialize(&mut self) {
+        self.initialize_with(json!({})).await;
+    }
+
+    pub async fn initialize_with(&mut self, params: Value) -> Value {
+        self.send_request(1, "initialize", params).await;
+        let res = self.response(1).await;
+        self.notify("initialized", json!({})).await;
+        res
+    }
+}
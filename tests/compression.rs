@@ -0,0 +1,63 @@
+//! Compression requested by a proxy starts after the `initialize` response,
+//! which tells the proxy the server accepted it
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::lsp::jsonrpc::Message;
+use ra_multiplex_core::lsp::transport::{Compression, LspReader, LspWriter};
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+use tokio::io::{self, BufReader};
+use tokio::time;
+
+fn message(value: Value) -> Message {
+    serde_json::from_value(value).unwrap()
+}
+
+async fn read(reader: &mut LspReader<BufReader<io::ReadHalf<io::DuplexStream>>>) -> Value {
+    let message = time::timeout(common::TIMEOUT, reader.read_message())
+        .await
+        .expect("no message")
+        .unwrap()
+        .expect("connection closed");
+    serde_json::to_value(message).unwrap()
+}
+
+#[tokio::test]
+async fn compress_after_response() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let (read_half, write_half) = io::split(server.connect(ClientOptions::default()));
+    let mut reader = LspReader::new(BufReader::new(read_half), "server");
+    let mut writer = LspWriter::new(write_half, "server");
+
+    let lsp_mux = json!({
+        "version": "1",
+        "method": "connect",
+        "server": env!("CARGO_BIN_EXE_ra-multiplex"),
+        "args": ["mock-server", "--exit-on-shutdown"],
+        "cwd": env!("CARGO_MANIFEST_DIR"),
+        "compression": { "zstd": 3 },
+    });
+    let params = json!({ "initializationOptions": { "lspMux": lsp_mux } });
+    let initialize = json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": params });
+    writer.write_message(&message(initialize)).await.unwrap();
+
+    // The response is readable by proxies which didn't get to know yet
+    // whether the server supports compression.
+    let res = read(&mut reader).await;
+    assert!(res["result"]["capabilities"].is_object(), "{res}");
+    assert_eq!(res["result"]["lspMux"]["compression"], json!({ "zstd": 3 }));
+
+    reader.set_compression(Compression::Zstd(3));
+    writer.set_compression(Compression::Zstd(3));
+    let initialized = json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} });
+    writer.write_message(&message(initialized)).await.unwrap();
+    let req = json!({ "jsonrpc": "2.0", "id": 2, "method": "test/pid", "params": {} });
+    writer.write_message(&message(req)).await.unwrap();
+    let res = read(&mut reader).await;
+    assert_eq!(res["id"], 2, "{res}");
+    assert!(res["result"]["pid"].is_u64(), "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,40 @@
+//! Servers embedded in one process each keep their own instances and state
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn separate_servers_in_one_process() {
+    let first = Server::new(Config::default()).await.unwrap();
+    let second = Server::new(Config::default()).await.unwrap();
+
+    let mut client = Client::connect(&first);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+    assert!(pid.is_u64(), "{pid}");
+
+    let status = common::ext_request(&first, json!({ "method": "status" })).await;
+    let instances = status["result"]["instances"].as_array().unwrap();
+    assert_eq!(instances.len(), 1, "{status}");
+
+    // The second server didn't see the first one's client spawn anything.
+    let status = common::ext_request(&second, json!({ "method": "status" })).await;
+    let instances = status["result"]["instances"].as_array().unwrap();
+    assert!(instances.is_empty(), "{status}");
+
+    let mut other = Client::connect(&second);
+    other.initialize().await;
+    let other_pid = other.request(2, "test/pid").await["pid"].clone();
+    assert_ne!(pid, other_pid);
+
+    assert_eq!(second.stop(false).await, 1);
+    // Stopping the second server left the first one's instance running.
+    assert_eq!(client.request(3, "test/pid").await["pid"], pid);
+
+    first.stop(false).await;
+}
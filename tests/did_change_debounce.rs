@@ -0,0 +1,93 @@
+//! `textDocument/didChange` notifications arriving within the debounce delay
+//! of the server are merged into one
+
+mod common;
+
+use std::num::NonZeroU32;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+use tokio::time::{self, Duration};
+
+use common::Client;
+
+const URI: &str = "file:///a.rs";
+
+fn config(millis: u32) -> Config {
+    // The mock server is the ra-multiplex binary.
+    let debounce = [("ra-multiplex".to_owned(), NonZeroU32::new(millis).unwrap())];
+    Config {
+        did_change_debounce: debounce.into(),
+        ..Config::default()
+    }
+}
+
+async fn open(client: &mut Client) {
+    let document = json!({ "uri": URI, "languageId": "rust", "version": 1, "text": "" });
+    client
+        .notify("textDocument/didOpen", json!({ "textDocument": document }))
+        .await;
+}
+
+async fn change(client: &mut Client, version: i64, text: &str) {
+    let params = json!({
+        "textDocument": { "uri": URI, "version": version },
+        "contentChanges": [{ "text": text }],
+    });
+    client.notify("textDocument/didChange", params).await;
+}
+
+/// `textDocument/didChange` notifications the server received
+async fn changes(client: &mut Client, id: i64) -> Vec<Value> {
+    let notifications = client.request(id, "mock/notifications").await;
+    let notifications = notifications.as_array().unwrap().iter();
+    notifications
+        .filter(|notif| notif["method"] == "textDocument/didChange")
+        .map(|notif| notif["params"].clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn merge_rapid_changes() {
+    let server = Server::new(config(10_000)).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    open(&mut client).await;
+
+    change(&mut client, 2, "a").await;
+    change(&mut client, 3, "ab").await;
+    change(&mut client, 4, "abc").await;
+
+    // The request flushes the held back change before it's forwarded.
+    let changes = changes(&mut client, 2).await;
+    assert_eq!(changes.len(), 1, "{changes:?}");
+    assert_eq!(changes[0]["textDocument"]["version"], 4);
+    assert_eq!(
+        changes[0]["contentChanges"],
+        json!([{ "text": "a" }, { "text": "ab" }, { "text": "abc" }])
+    );
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn forward_change_after_delay() {
+    let server = Server::new(config(50)).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    open(&mut client).await;
+
+    change(&mut client, 2, "a").await;
+    time::sleep(Duration::from_millis(500)).await;
+    change(&mut client, 3, "ab").await;
+
+    let changes = changes(&mut client, 2).await;
+    let versions = changes
+        .iter()
+        .map(|change| change["textDocument"]["version"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(versions, [2, 3]);
+
+    server.stop(false).await;
+}
@@ -0,0 +1,71 @@
+//! Clients following another client are told about the documents it opens
+//! and closes
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+
+use common::Client;
+
+async fn open(client: &mut Client, uri: &str) {
+    let document = json!({ "uri": uri, "languageId": "rust", "version": 1, "text": "" });
+    client
+        .notify("textDocument/didOpen", json!({ "textDocument": document }))
+        .await;
+}
+
+/// Wait for the next `$/lspMux/documentOpened` or `documentClosed`
+async fn followed(client: &mut Client) -> Value {
+    client
+        .receive_matching(|message| {
+            let method = message["method"].as_str().unwrap_or_default();
+            method.starts_with("$/lspMux/document")
+        })
+        .await
+}
+
+#[tokio::test]
+async fn report_followed_documents() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut editor = Client::connect(&server);
+    editor.initialize().await;
+    open(&mut editor, "file:///a.rs").await;
+
+    let status = common::ext_request(&server, json!({ "method": "status" })).await;
+    let editor_id = status["result"]["instances"][0]["clients"][0]["id"].clone();
+    let options = ClientOptions {
+        follow: Some(editor_id.as_u64().unwrap() as usize),
+        ..common::mock_server(&["--exit-on-shutdown"])
+    };
+    let mut follower = Client::connect_with(&server, options);
+    follower.initialize().await;
+
+    // Documents opened before the follower connected are reported first.
+    let opened = followed(&mut follower).await;
+    assert_eq!(opened["method"], "$/lspMux/documentOpened");
+    assert_eq!(
+        opened["params"],
+        json!({ "clientId": editor_id, "uri": "file:///a.rs" })
+    );
+
+    open(&mut editor, "file:///b.rs").await;
+    let opened = followed(&mut follower).await;
+    assert_eq!(opened["method"], "$/lspMux/documentOpened");
+    assert_eq!(opened["params"]["uri"], "file:///b.rs");
+
+    let params = json!({ "textDocument": { "uri": "file:///a.rs" } });
+    editor.notify("textDocument/didClose", params).await;
+    let closed = followed(&mut follower).await;
+    assert_eq!(closed["method"], "$/lspMux/documentClosed");
+    assert_eq!(closed["params"]["uri"], "file:///a.rs");
+
+    // The documents still open are closed when the editor disconnects.
+    drop(editor);
+    let closed = followed(&mut follower).await;
+    assert_eq!(closed["method"], "$/lspMux/documentClosed");
+    assert_eq!(closed["params"]["uri"], "file:///b.rs");
+
+    server.stop(false).await;
+}
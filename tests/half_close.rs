@@ -0,0 +1,39 @@
+//! A client which closes only its sending side still gets the responses to
+//! the requests it sent before
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn respond_to_half_closed_client() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 300 }))
+        .await;
+    client.send_request(3, "test/last", json!({})).await;
+    client.close_output().await;
+
+    let res = client.response(3).await;
+    assert_eq!(res["result"]["method"], "test/last", "{res}");
+    let res = client.response(2).await;
+    assert_eq!(res["result"], json!(null), "{res}");
+    // The connection is closed once nothing is pending anymore.
+    client.closed().await;
+
+    let status = common::ext_request(&server, json!({ "method": "status" })).await;
+    let instances = status["result"]["instances"].as_array().unwrap();
+    assert!(
+        instances[0]["clients"].as_array().unwrap().is_empty(),
+        "{status}"
+    );
+
+    server.stop(false).await;
+}
@@ -0,0 +1,140 @@
+//! `initialize` requests the server can't connect to an instance fail with an
+//! lspMux error code telling the failures apart
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+
+use common::Client;
+
+/// Options starting `server` in the crate directory
+fn options(server: &str) -> ClientOptions {
+    ClientOptions {
+        cwd: Some(env!("CARGO_MANIFEST_DIR").into()),
+        ..ClientOptions::new(server)
+    }
+}
+
+async fn initialize(server: &Server, options: ClientOptions) -> Value {
+    let mut client = Client::connect_with(server, options);
+    client.send_request(1, "initialize", json!({})).await;
+    client.response(1).await
+}
+
+#[tokio::test]
+async fn spawn_failed() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect_with(&server, options("/nonexistent/ra-mux-missing-server"));
+    client.send_request(1, "initialize", json!({})).await;
+
+    // The editor may only show a failed initialization, the user is told
+    // what went wrong first.
+    let notif = client.receive().await;
+    assert_eq!(notif["method"], "window/showMessage", "{notif}");
+    assert_eq!(notif["params"]["type"], 1);
+    let message = notif["params"]["message"].as_str().unwrap();
+    assert!(message.contains("ra-mux-missing-server"), "{message}");
+    let res = client.receive().await;
+    assert_eq!(res["id"], 1, "{res}");
+    assert_eq!(res["error"]["code"], -32052, "{res}");
+    assert_eq!(
+        res["error"]["data"]["program"],
+        "/nonexistent/ra-mux-missing-server"
+    );
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn lazy_spawn_failed() {
+    let config = Config {
+        lazy_spawn: true,
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    // The server was initialized before, the next client is answered without
+    // spawning it.
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    let mut client = Client::connect(&server);
+    let root = json!({ "rootUri": "file:///nonexistent/ra-mux-workspace" });
+    let res = client.initialize_with(root).await;
+    assert!(res["result"]["capabilities"].is_object(), "{res}");
+    client.send_request(2, "test/pid", json!({})).await;
+    let notif = client.receive().await;
+    assert_eq!(notif["method"], "window/showMessage", "{notif}");
+    assert_eq!(notif["params"]["type"], 1);
+    let res = client.receive().await;
+    assert_eq!(res["id"], 2, "{res}");
+    assert_eq!(res["error"]["code"], -32052, "{res}");
+    client.closed().await;
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn server_not_allowed() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let res = initialize(&server, options("managed:nothing")).await;
+    assert_eq!(res["error"]["code"], -32051, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn no_workspace_root() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let options = ClientOptions {
+        cwd: None,
+        ..common::mock_server(&[])
+    };
+    let res = initialize(&server, options).await;
+    assert_eq!(res["error"]["code"], -32054, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn shutting_down() {
+    let server = Server::new(Config::default()).await.unwrap();
+    server.stop(false).await;
+
+    let res = initialize(&server, common::mock_server(&[])).await;
+    assert_eq!(res["error"]["code"], -32053, "{res}");
+}
+
+#[tokio::test]
+async fn replace_evicted_instance() {
+    let config = Config {
+        instance_timeout: Some(1),
+        gc_interval: 1,
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut subscriber = Client::connect_with(&server, ClientOptions::default());
+    let request = json!({ "method": "subscribe", "version": "1" });
+    let params = json!({ "initializationOptions": { "lspMux": request } });
+    subscriber.send_request(1, "initialize", params).await;
+    subscriber.response(0).await;
+
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+    drop(client);
+    subscriber
+        .receive_matching(|message| message["params"]["kind"] == "instanceEvicted")
+        .await;
+
+    // A client connecting while the evicted instance shuts down gets a new
+    // one instead of an error.
+    let mut client = Client::connect(&server);
+    let res = client.initialize_with(json!({})).await;
+    assert!(res["result"]["capabilities"].is_object(), "{res}");
+    let new_pid = client.request(2, "test/pid").await["pid"].clone();
+    assert_ne!(pid, new_pid);
+
+    server.stop(false).await;
+}
@@ -0,0 +1,77 @@
+//! Responses are logged with the time the server took and the time the
+//! request was queued for
+
+mod common;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+use tracing::Level;
+
+use common::Client;
+
+/// Log output collected by a test
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Logs {
+    /// `queued_ms` of the response latency logged for `method`
+    fn queued_ms(&self, method: &str) -> u64 {
+        let logs = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| {
+                line.contains("response latency") && line.contains(&format!("method={method:?}"))
+            })
+            .unwrap_or_else(|| panic!("no latency of {method} logged:\n{logs}"));
+        let (_, rest) = line.split_once("queued_ms=").unwrap();
+        rest.split(' ').next().unwrap().parse().unwrap()
+    }
+}
+
+#[tokio::test]
+async fn log_queued_time() {
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // The runtime of the test runs all tasks on this thread.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let config = Config {
+        max_concurrent_requests: Some(1),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 500 }))
+        .await;
+    client.send_request(3, "test/queued", json!({})).await;
+    client.response(2).await;
+    client.response(3).await;
+
+    assert!(logs.queued_ms("mock/sleep") < 250);
+    assert!(logs.queued_ms("test/queued") >= 250);
+
+    server.stop(false).await;
+}
@@ -0,0 +1,61 @@
+//! Requests sent to all instances of a client are merged into one response
+
+mod common;
+
+use ra_multiplex_core::config::{Config, MergeStrategy};
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+/// Client with an instance for each of two workspace folders, `mock/sleep`
+/// requests are sent to both
+async fn connect() -> (Server, Client) {
+    let config = Config {
+        meta_instances: true,
+        merge_strategies: [("mock/sleep".to_owned(), MergeStrategy::Concat)].into(),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    let folder = |name: &str| {
+        let path = format!("{}/{name}", env!("CARGO_MANIFEST_DIR"));
+        json!({ "uri": format!("file://{path}"), "name": name })
+    };
+    client
+        .initialize_with(json!({ "workspaceFolders": [folder("src"), folder("tests")] }))
+        .await;
+    (server, client)
+}
+
+#[tokio::test]
+async fn cancel_merged_request() {
+    let (server, mut client) = connect().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 600_000 }))
+        .await;
+    client.notify("$/cancelRequest", json!({ "id": 2 })).await;
+    let res = client.response(2).await;
+    assert_eq!(res["error"]["code"], -32800, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn merge_with_exited_instance() {
+    let (server, mut client) = connect().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 500 }))
+        .await;
+    // Not merged, only the first instance gets it.
+    client.send_request(3, "mock/crash", json!({})).await;
+    let res = client.response(2).await;
+    assert!(
+        res.get("result").is_some() || res.get("error").is_some(),
+        "{res}"
+    );
+
+    server.stop(false).await;
+}
@@ -0,0 +1,116 @@
+//! `ra-multiplex merge` moves the clients of an instance onto another one
+//! running the same server
+
+mod common;
+
+use std::time::Duration;
+
+use ra_multiplex_core::config::{Config, MergeStrategy};
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+fn root(name: &str) -> String {
+    format!("{}/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+async fn merge(server: &Server, from: &str, into: &str) -> Value {
+    let request = json!({ "method": "merge", "cwd": root(from), "into_cwd": root(into) });
+    common::ext_request(server, request).await
+}
+
+/// Client of an instance for the workspace root `name`
+async fn connect(server: &Server, name: &str) -> (Client, Value) {
+    let mut client = Client::connect(server);
+    let root_uri = format!("file://{}", root(name));
+    client.initialize_with(json!({ "rootUri": root_uri })).await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+    (client, pid)
+}
+
+#[tokio::test]
+async fn move_clients_and_documents() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let (mut client, pid) = connect(&server, "src").await;
+    let (_other, into_pid) = connect(&server, "tests").await;
+    assert_ne!(pid, into_pid);
+    let uri = format!("file://{}/main.rs", root("src"));
+    let document = json!({ "uri": uri, "languageId": "rust", "version": 1, "text": "" });
+    client
+        .notify("textDocument/didOpen", json!({ "textDocument": document }))
+        .await;
+
+    let res = merge(&server, "src", "tests").await;
+    assert_eq!(res["result"], 1, "{res}");
+
+    // The client's requests go to the other instance, which got its document.
+    assert_eq!(client.request(3, "test/pid").await["pid"], into_pid);
+    let notifications = client.request(4, "mock/notifications").await;
+    let opened = notifications.as_array().unwrap().iter().any(|notif| {
+        notif["method"] == "textDocument/didOpen" && notif["params"]["textDocument"]["uri"] == uri
+    });
+    assert!(opened, "{notifications}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn merge_instances_of_one_client() {
+    let config = Config {
+        meta_instances: true,
+        merge_strategies: [("mock/sleep".to_owned(), MergeStrategy::Concat)].into(),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    let folder = |name: &str| json!({ "uri": format!("file://{}", root(name)), "name": name });
+    client
+        .initialize_with(json!({ "workspaceFolders": [folder("src"), folder("tests")] }))
+        .await;
+    // Sent to both instances, the merge has to answer it for the one going
+    // away.
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 500 }))
+        .await;
+
+    let res = merge(&server, "src", "tests").await;
+    assert_eq!(res["result"], 1, "{res}");
+    // The failed half is dropped from the merged response.
+    let res = client.response(2).await;
+    assert!(res.get("error").is_none(), "{res}");
+
+    // Only one instance is left to answer.
+    client
+        .send_request(3, "mock/sleep", json!({ "ms": 0 }))
+        .await;
+    let res = client.response(3).await;
+    assert!(res["result"].is_null(), "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn merge_in_opposite_directions() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let (_first, _) = connect(&server, "src").await;
+    let (_second, _) = connect(&server, "tests").await;
+
+    let both = async {
+        tokio::join!(
+            merge(&server, "src", "tests"),
+            merge(&server, "tests", "src")
+        )
+    };
+    let (a, b) = tokio::time::timeout(Duration::from_secs(30), both)
+        .await
+        .expect("merges deadlocked");
+    // The second merge finds its target merged already.
+    let moved = [&a, &b]
+        .iter()
+        .filter(|res| res.get("result").is_some())
+        .count();
+    assert_eq!(moved, 1, "{a} {b}");
+
+    server.stop(false).await;
+}
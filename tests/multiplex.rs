@@ -0,0 +1,80 @@
+//! Sessions of a multiplexed connection are served like separate connections
+
+mod common;
+
+use std::sync::Arc;
+use std::{env, fs, process};
+
+use ra_multiplex_core::channel::Multiplexer;
+use ra_multiplex_core::config::{Address, Config};
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+use tokio::{task, time};
+
+use common::Client;
+
+/// Initialize a session with a mock server
+async fn initialize(client: &mut Client) {
+    let lsp_mux = json!({
+        "version": "1",
+        "method": "connect",
+        "server": env!("CARGO_BIN_EXE_ra-multiplex"),
+        "args": ["mock-server", "--exit-on-shutdown"],
+        "cwd": env!("CARGO_MANIFEST_DIR"),
+    });
+    let params = json!({ "initializationOptions": { "lspMux": lsp_mux } });
+    client.initialize_with(params).await;
+}
+
+#[tokio::test]
+async fn serve_sessions_over_one_connection() {
+    let socket = env::temp_dir().join(format!("ra-mux-multiplex-{}.sock", process::id()));
+    let _ = fs::remove_file(&socket);
+    let config = Config {
+        listen: Address::Unix(socket.clone()),
+        connect: Address::Unix(socket.clone()),
+        ..Config::default()
+    };
+    let server = Arc::new(Server::new(config.clone()).await.unwrap());
+    let listen = task::spawn({
+        let server = server.clone();
+        async move { server.listen().await }
+    });
+
+    let mux = time::timeout(common::TIMEOUT, async {
+        loop {
+            match Multiplexer::connect(&config).await {
+                Ok(mux) => break mux,
+                Err(_) => time::sleep(time::Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .expect("server isn't listening");
+
+    let mut first = Client::from_stream(mux.open());
+    initialize(&mut first).await;
+    let mut second = Client::from_stream(mux.open());
+    initialize(&mut second).await;
+
+    // Both sessions share the instance and get their own responses.
+    second.send_request(2, "test/second", json!({})).await;
+    first.send_request(2, "test/first", json!({})).await;
+    let res = first.response(2).await;
+    assert_eq!(res["result"]["method"], "test/first", "{res}");
+    let res = second.response(2).await;
+    assert_eq!(res["result"]["method"], "test/second", "{res}");
+    assert_eq!(
+        first.request(3, "test/pid").await,
+        second.request(3, "test/pid").await
+    );
+
+    // Ending one session leaves the other one working.
+    drop(first);
+    let res = second.request(4, "test/after").await;
+    assert_eq!(res["method"], "test/after", "{res}");
+
+    server.stop(false).await;
+    listen.await.unwrap().unwrap();
+    let _ = fs::remove_file(&socket);
+}
@@ -0,0 +1,69 @@
+//! `ra-multiplex notify` sends notifications to the language server of an
+//! instance
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+fn notify(pid: &Value, notification: &str, params: Value) -> Value {
+    json!({
+        "method": "notify",
+        "pid": pid,
+        "cwd": "/",
+        "notification": notification,
+        "params": params,
+    })
+}
+
+#[tokio::test]
+async fn send_notification_to_instance() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    let params = json!({ "settings": { "check": true } });
+    let request = notify(&pid, "workspace/didChangeConfiguration", params.clone());
+    let res = common::ext_request(&server, request).await;
+    assert_eq!(res["result"], json!(null), "{res}");
+
+    let notifications = client.request(3, "mock/notifications").await;
+    let expected = json!({
+        "jsonrpc": "2.0",
+        "method": "workspace/didChangeConfiguration",
+        "params": params,
+    });
+    assert!(
+        notifications.as_array().unwrap().contains(&expected),
+        "{notifications}"
+    );
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn refuse_tracked_notifications() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    for method in ["exit", "textDocument/didOpen"] {
+        let res = common::ext_request(&server, notify(&pid, method, json!({}))).await;
+        assert_eq!(res["error"]["code"], -32600, "{res}");
+    }
+    let notifications = client.request(3, "mock/notifications").await;
+    let methods = notifications
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|notif| notif["method"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(methods, ["initialized"]);
+
+    server.stop(false).await;
+}
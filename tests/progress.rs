@@ -0,0 +1,76 @@
+//! Clients attaching while the server reports work done progress are shown
+//! the running progress if they support it
+
+mod common;
+
+use std::cell::Cell;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+async fn progress(client: &mut Client, id: i64, value: Value) {
+    let params = json!({ "method": "$/progress", "params": { "token": "index", "value": value } });
+    client.send_request(id, "mock/notify", params).await;
+    client.response(id).await;
+}
+
+#[tokio::test]
+async fn replay_progress_to_attaching_client() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let begin = json!({ "kind": "begin", "title": "Indexing", "percentage": 10 });
+    progress(&mut client, 2, begin).await;
+    let report = json!({ "kind": "report", "percentage": 40 });
+    progress(&mut client, 3, report).await;
+
+    let mut attaching = Client::connect(&server);
+    let capabilities = json!({ "capabilities": { "window": { "workDoneProgress": true } } });
+    attaching.initialize_with(capabilities).await;
+    let create = attaching
+        .receive_matching(|message| message["method"] == "window/workDoneProgress/create")
+        .await;
+    assert_eq!(create["params"]["token"], "index", "{create}");
+    let res = json!({ "jsonrpc": "2.0", "id": create["id"], "result": null });
+    attaching.send(res).await;
+    for kind in ["begin", "report"] {
+        let notif = attaching
+            .receive_matching(|message| message["method"] == "$/progress")
+            .await;
+        assert_eq!(notif["params"]["token"], "index", "{notif}");
+        assert_eq!(notif["params"]["value"]["kind"], kind, "{notif}");
+    }
+    let pid = attaching.request(4, "test/pid").await;
+    assert!(pid["pid"].is_u64(), "{pid}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn no_progress_without_capability() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let begin = json!({ "kind": "begin", "title": "Indexing" });
+    progress(&mut client, 2, begin).await;
+
+    let mut attaching = Client::connect(&server);
+    attaching.initialize().await;
+    attaching.send_request(2, "test/pid", json!({})).await;
+    // Replayed messages would come before the response.
+    let replayed = Cell::new(false);
+    attaching
+        .receive_matching(|message| {
+            let method = &message["method"];
+            let progress = method == "window/workDoneProgress/create" || method == "$/progress";
+            replayed.set(replayed.get() || progress);
+            message["id"] == 2 && message.get("method").is_none()
+        })
+        .await;
+    assert!(!replayed.get());
+
+    server.stop(false).await;
+}
@@ -0,0 +1,64 @@
+//! `ra-multiplex queue` lists the requests an instance didn't respond to yet
+//! and cancels them
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+async fn queue(server: &Server, pid: &Value, cancel: Option<&Value>) -> Value {
+    let request = json!({ "method": "queue", "pid": pid, "cwd": "/", "cancel": cancel });
+    common::ext_request(server, request).await
+}
+
+#[tokio::test]
+async fn list_and_cancel_requests() {
+    let config = Config {
+        max_concurrent_requests: Some(1),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    client
+        .send_request(3, "mock/sleep", json!({ "ms": 600_000 }))
+        .await;
+    client
+        .send_request(4, "mock/sleep", json!({ "ms": 600_000 }))
+        .await;
+    // Let the server get the first request.
+    client.notify("$/lspMux/ping", json!(null)).await;
+    client
+        .receive_matching(|message| message["method"] == "$/lspMux/pong")
+        .await;
+
+    let res = queue(&server, &pid, None).await;
+    let pending = res["result"].as_array().unwrap();
+    assert_eq!(pending.len(), 2, "{res}");
+    assert_eq!(pending[0]["method"], "mock/sleep");
+    assert_eq!(pending[0]["queued"], false, "{res}");
+    assert_eq!(pending[1]["queued"], true, "{res}");
+    assert!(pending[0]["age"].as_u64() >= pending[1]["age"].as_u64());
+
+    // The queued request is answered without reaching the server.
+    let res = queue(&server, &pid, Some(&pending[1]["id"])).await;
+    assert_eq!(res["result"], json!(null), "{res}");
+    assert_eq!(client.response(4).await["error"]["code"], -32800);
+
+    // The server is asked to cancel the request it's working on.
+    let res = queue(&server, &pid, Some(&pending[0]["id"])).await;
+    assert_eq!(res["result"], json!(null), "{res}");
+    assert_eq!(client.response(3).await["error"]["code"], -32800);
+
+    let res = queue(&server, &pid, None).await;
+    assert_eq!(res["result"], json!([]), "{res}");
+    let res = queue(&server, &pid, Some(&pending[0]["id"])).await;
+    assert!(res.get("error").is_some(), "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,38 @@
+//! Requests in `replica_methods` are answered by a replica of the requested
+//! server, its companions answer them with their primary instance
+
+mod common;
+
+use ra_multiplex_core::config::{CompanionServer, Config};
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn answer_with_replica() {
+    let companion = CompanionServer {
+        server: env!("CARGO_BIN_EXE_ra-multiplex").to_owned(),
+        args: vec!["mock-server".to_owned()],
+    };
+    let config = Config {
+        replica_methods: ["test/replica".to_owned()].into(),
+        companion_servers: [("ra-multiplex".to_owned(), vec![companion])].into(),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    // The requested server, its companion and its replica.
+    let status = common::ext_request(&server, json!({ "method": "status" })).await;
+    let instances = status["result"]["instances"].as_array().unwrap();
+    assert_eq!(instances.len(), 3, "{status}");
+
+    let primary = client.request(2, "test/primary").await["pid"].clone();
+    let replica = client.request(3, "test/replica").await["pid"].clone();
+    assert!(replica.is_u64(), "{replica}");
+    assert_ne!(primary, replica);
+
+    server.stop(false).await;
+}
@@ -0,0 +1,39 @@
+//! A request reusing the ID of a pending request is rejected, its response
+//! couldn't be told apart from the first one's
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn reject_reused_id() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 500 }))
+        .await;
+    client.send_request(2, "test/reused", json!({})).await;
+    let res = client.response(2).await;
+    assert_eq!(res["error"]["code"], -32600, "{res}");
+
+    // The pending request is answered once, by the server.
+    let res = client.response(2).await;
+    assert_eq!(res["result"], json!(null), "{res}");
+    client.send_request(3, "test/next", json!({})).await;
+    let res = client
+        .receive_matching(|message| message.get("method").is_none())
+        .await;
+    assert_eq!(res["id"], 3, "{res}");
+
+    // Its ID can be used again once it's answered.
+    let res = client.request(2, "test/reused").await;
+    assert_eq!(res["method"], "test/reused");
+
+    server.stop(false).await;
+}
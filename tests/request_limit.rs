@@ -0,0 +1,39 @@
+//! Requests over `max_concurrent_requests` are queued without holding up the
+//! client's other messages
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn queued_request_does_not_block_client() {
+    let config = Config {
+        max_concurrent_requests: Some(1),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 2000 }))
+        .await;
+    client.send_request(3, "test/queued", json!({})).await;
+    client.notify("$/lspMux/ping", json!(null)).await;
+
+    // The pong arrives while the first request holds the only permit.
+    let first = client
+        .receive_matching(|message| message["method"] == "$/lspMux/pong" || message["id"] == 2)
+        .await;
+    assert_eq!(first["method"], "$/lspMux/pong");
+
+    assert_eq!(client.response(2).await["result"], json!(null));
+    let queued = client.response(3).await;
+    assert_eq!(queued["result"]["method"], "test/queued");
+
+    server.stop(false).await;
+}
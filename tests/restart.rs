@@ -0,0 +1,47 @@
+//! Restarting an instance keeps its clients connected
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn answer_every_request_once() {
+    let config = Config {
+        max_concurrent_requests: Some(1),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    // One request the old server got and one still waiting for a permit.
+    client
+        .send_request(3, "mock/sleep", json!({ "ms": 600_000 }))
+        .await;
+    client.send_request(4, "test/queued", json!({})).await;
+    let res = common::ext_request(
+        &server,
+        json!({ "method": "restart", "pid": pid, "cwd": "/" }),
+    )
+    .await;
+    assert!(res.get("result").is_some(), "{res}");
+
+    let res = client.response(3).await;
+    assert_eq!(res["error"]["code"], -32802, "{res}");
+    let res = client.response(4).await;
+    assert_ne!(res["result"]["pid"], pid, "{res}");
+
+    // Neither request is answered a second time.
+    client.send_request(5, "test/pid", json!({})).await;
+    let res = client
+        .receive_matching(|message| message.get("method").is_none())
+        .await;
+    assert_eq!(res["id"], 5, "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,147 @@
+//! A resumable client losing its connection mid-traffic reconnects with its
+//! token and every message arrives exactly once in both directions
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+
+use common::Client;
+
+/// Requests sent before the connection is lost
+const REQUESTS: i64 = 20;
+
+/// Client end of a resumable session, numbering messages like the proxy
+struct Session {
+    client: Client,
+    token: String,
+    /// Messages sent after the `initialize` request
+    sent: Vec<Value>,
+    /// Number of messages received after the `initialize` responses
+    received: u64,
+}
+
+impl Session {
+    async fn send(&mut self, message: Value) {
+        self.client.send(message.clone()).await;
+        self.sent.push(message);
+    }
+
+    async fn receive(&mut self) -> Value {
+        let message = tokio::time::timeout(common::TIMEOUT, self.client.receive())
+            .await
+            .expect("no message");
+        self.received += 1;
+        message
+    }
+}
+
+/// Send `initialize` with `lspMux` options, returns the response
+async fn initialize(client: &mut Client, lsp_mux: Value) -> Value {
+    let mut options = json!({
+        "version": "1",
+        "method": "connect",
+        "server": env!("CARGO_BIN_EXE_ra-multiplex"),
+        "args": ["mock-server", "--exit-on-shutdown"],
+        "cwd": env!("CARGO_MANIFEST_DIR"),
+    });
+    options
+        .as_object_mut()
+        .unwrap()
+        .extend(lsp_mux.as_object().unwrap().clone());
+    let params = json!({ "initializationOptions": { "lspMux": options } });
+    client.send_request(1, "initialize", params).await;
+    client.response(1).await
+}
+
+fn sleep(id: i64, ms: u64) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "method": "mock/sleep", "params": { "ms": ms } })
+}
+
+#[tokio::test]
+async fn resend_exactly_what_was_missed() {
+    let config = Config {
+        reconnect_timeout: Some(10),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+
+    let mut client = Client::connect_with(&server, ClientOptions::default());
+    let res = initialize(&mut client, json!({ "resumable": true })).await;
+    let token = res["result"]["lspMux"]["reconnectToken"]
+        .as_str()
+        .unwrap_or_else(|| panic!("no reconnect token: {res}"))
+        .to_owned();
+    let mut session = Session {
+        client,
+        token,
+        sent: Vec::new(),
+        received: 0,
+    };
+    session
+        .send(json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }))
+        .await;
+
+    // Responses arrive before, during and after the connection is lost.
+    for id in 2..2 + REQUESTS {
+        session.send(sleep(id, 10 * id as u64)).await;
+    }
+    let mut responses = BTreeMap::new();
+    while responses.len() < 3 {
+        let message = session.receive().await;
+        if message.get("method").is_none() {
+            *responses
+                .entry(message["id"].as_i64().unwrap())
+                .or_insert(0) += 1;
+        }
+    }
+    drop(session.client);
+    // Let responses pile up for the lost connection.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = Client::connect_with(&server, ClientOptions::default());
+    let lsp_mux = json!({ "reconnectToken": session.token, "received": session.received });
+    let res = initialize(&mut client, lsp_mux).await;
+    let received = res["result"]["lspMux"]["received"]
+        .as_u64()
+        .unwrap_or_else(|| panic!("session not resumed: {res}"));
+    assert!(received <= session.sent.len() as u64, "{res}");
+    session.client = client;
+    for message in &session.sent[received as usize..] {
+        session.client.send(message.clone()).await;
+    }
+
+    while responses.len() < REQUESTS as usize {
+        let message = session.receive().await;
+        if message.get("method").is_none() {
+            *responses
+                .entry(message["id"].as_i64().unwrap())
+                .or_insert(0) += 1;
+        }
+    }
+    // Resent messages come first, any duplicate would arrive before this.
+    session
+        .send(json!({ "jsonrpc": "2.0", "id": 100, "method": "test/pid", "params": {} }))
+        .await;
+    loop {
+        let message = session.receive().await;
+        if message.get("method").is_some() {
+            continue;
+        }
+        let id = message["id"].as_i64().unwrap();
+        if id == 100 {
+            break;
+        }
+        *responses.entry(id).or_insert(0) += 1;
+    }
+    let expected = (2..2 + REQUESTS)
+        .map(|id| (id, 1))
+        .collect::<BTreeMap<_, _>>();
+    assert_eq!(responses, expected);
+
+    server.stop(false).await;
+}
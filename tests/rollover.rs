@@ -0,0 +1,74 @@
+//! Rolling over an instance switches its clients to a new server once it's
+//! ready
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+#[tokio::test]
+async fn switch_to_configured_server() {
+    let config = Config {
+        max_concurrent_requests: Some(1),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let options = common::mock_server(&["--exit-on-shutdown", "--configure"]);
+    let mut client = Client::connect_with(&server, options);
+    client.initialize().await;
+    // The request the server sends after `initialized` is dropped when it
+    // comes before the client is added, ask for the configuration again.
+    let params = json!({
+        "method": "workspace/configuration",
+        "params": { "items": [{ "section": "mock" }] },
+    });
+    client.send_request(2, "mock/request", params).await;
+    loop {
+        let message = client
+            .receive_matching(|message| {
+                message["method"] == "workspace/configuration" || message["id"] == 2
+            })
+            .await;
+        if message["id"] == 2 {
+            break;
+        }
+        let res = json!({ "jsonrpc": "2.0", "id": message["id"], "result": [{ "answer": 42 }] });
+        client.send(res).await;
+    }
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    // One request the old server got and one still waiting for a permit.
+    client
+        .send_request(3, "mock/sleep", json!({ "ms": 600_000 }))
+        .await;
+    client.send_request(4, "test/queued", json!({})).await;
+    let res = common::ext_request(
+        &server,
+        json!({ "method": "rollover", "pid": pid, "cwd": "/" }),
+    )
+    .await;
+    let new_pid = res["result"].clone();
+    assert!(new_pid.is_u64() && new_pid != pid, "{res}");
+
+    let res = client.response(3).await;
+    assert_eq!(res["error"]["code"], -32802, "{res}");
+    let res = client.response(4).await;
+    assert_eq!(res["result"]["pid"], new_pid, "{res}");
+
+    // The new server got the configuration the client gave the old one
+    // without asking the client again.
+    let configuration = client.request(5, "mock/configuration").await;
+    assert_eq!(configuration, json!([{ "answer": 42 }]));
+
+    // Neither request is answered a second time.
+    client.send_request(6, "test/pid", json!({})).await;
+    let res = client
+        .receive_matching(|message| message.get("method").is_none())
+        .await;
+    assert_eq!(res["id"], 6, "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,82 @@
+//! `ra-multiplex shim` keeps the language server running while no daemon is
+//! connected and sends the server's unanswered requests to the next daemon
+
+mod common;
+
+use std::path::Path;
+use std::process::Stdio;
+use std::{env, process};
+
+use serde_json::{json, Value};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::{io, task, time};
+
+use common::Client;
+
+fn spawn_shim(socket: &Path) -> Child {
+    let exe = env!("CARGO_BIN_EXE_ra-multiplex");
+    Command::new(exe)
+        .arg("shim")
+        .arg("--socket")
+        .arg(socket)
+        .args(["--", exe, "mock-server"])
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+/// Connect to the shim like a daemon and receive its hello
+async fn connect(socket: &Path) -> (Client, Value) {
+    let stream = time::timeout(common::TIMEOUT, async {
+        loop {
+            match UnixStream::connect(socket).await {
+                Ok(stream) => break stream,
+                Err(_) => time::sleep(time::Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .expect("shim isn't listening");
+    let (client_stream, mut relay) = io::duplex(1 << 16);
+    task::spawn(async move {
+        let mut stream = stream;
+        let _ = io::copy_bidirectional(&mut stream, &mut relay).await;
+    });
+    let mut daemon = Client::from_stream(client_stream);
+    let hello = daemon.receive().await;
+    assert_eq!(hello["method"], "$/lspMux/shimHello", "{hello}");
+    (daemon, hello)
+}
+
+#[tokio::test]
+async fn resend_unanswered_requests() {
+    let socket = env::temp_dir().join(format!("ra-mux-shim-test-{}.sock", process::id()));
+    let mut shim = spawn_shim(&socket);
+
+    let (mut first, hello) = connect(&socket).await;
+    let server_pid = hello["params"]["serverPid"].clone();
+    let params = json!({ "method": "workspace/configuration", "params": { "items": [] } });
+    first.send_request(1, "mock/request", params).await;
+    first
+        .receive_matching(|message| message["method"] == "workspace/configuration")
+        .await;
+    drop(first);
+
+    // The server kept running and asks the new daemon again.
+    let (mut second, hello) = connect(&socket).await;
+    assert_eq!(hello["params"]["serverPid"], server_pid, "{hello}");
+    let req = second
+        .receive_matching(|message| message["method"] == "workspace/configuration")
+        .await;
+    let res = json!({ "jsonrpc": "2.0", "id": req["id"], "result": ["answer"] });
+    second.send(res).await;
+    let res = second.response(1).await;
+    assert_eq!(res["result"], json!(["answer"]), "{res}");
+
+    // The shim exits with the server.
+    second.notify("exit", json!(null)).await;
+    let status = time::timeout(common::TIMEOUT, shim.wait()).await;
+    assert!(status.unwrap().unwrap().success());
+}
@@ -0,0 +1,122 @@
+//! `window/showDocument` requests from the server go to a single client and
+//! its answer goes back to the server
+
+mod common;
+
+use ra_multiplex_core::config::{Config, ShowDocumentFallback};
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+
+use common::Client;
+
+fn config(show_document: ShowDocumentFallback) -> Config {
+    Config {
+        show_document,
+        ..Config::default()
+    }
+}
+
+/// Make the server send a `window/showDocument` request
+async fn request_show(client: &mut Client, id: i64) {
+    let params = json!({
+        "method": "window/showDocument",
+        "params": { "uri": "file:///src/lib.rs", "takeFocus": true },
+    });
+    client.send_request(id, "mock/request", params).await;
+}
+
+async fn receive_show(client: &mut Client) -> Value {
+    client
+        .receive_matching(|message| message["method"] == "window/showDocument")
+        .await
+}
+
+/// Observers are never asked to show documents, requests sent by one leave
+/// the choice to the `show_document` config
+async fn observer(server: &Server) -> Client {
+    let options = ClientOptions {
+        observer: true,
+        ..common::mock_server(&["--exit-on-shutdown"])
+    };
+    let mut observer = Client::connect_with(server, options);
+    observer.initialize().await;
+    observer
+}
+
+#[tokio::test]
+async fn ask_requesting_client() {
+    let server = Server::new(config(ShowDocumentFallback::FirstClient))
+        .await
+        .unwrap();
+    let mut first = Client::connect(&server);
+    first.initialize().await;
+    let mut second = Client::connect(&server);
+    second.initialize().await;
+
+    // The first client would be the fallback.
+    request_show(&mut second, 2).await;
+    let req = receive_show(&mut second).await;
+    assert_eq!(req["params"]["uri"], "file:///src/lib.rs", "{req}");
+    let res = json!({ "jsonrpc": "2.0", "id": req["id"], "result": { "success": true } });
+    second.send(res).await;
+    let res = second.response(2).await;
+    assert_eq!(res["result"]["success"], true, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn fall_back_to_newest_client() {
+    let server = Server::new(config(ShowDocumentFallback::NewestClient))
+        .await
+        .unwrap();
+    let mut first = Client::connect(&server);
+    first.initialize().await;
+    let mut second = Client::connect(&server);
+    second.initialize().await;
+    let mut observer = observer(&server).await;
+
+    request_show(&mut observer, 2).await;
+    let req = receive_show(&mut second).await;
+    let res = json!({ "jsonrpc": "2.0", "id": req["id"], "result": { "success": true } });
+    second.send(res).await;
+    let res = observer.response(2).await;
+    assert_eq!(res["result"]["success"], true, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn drop_without_client() {
+    let server = Server::new(config(ShowDocumentFallback::Drop))
+        .await
+        .unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let mut observer = observer(&server).await;
+
+    request_show(&mut observer, 2).await;
+    let res = observer.response(2).await;
+    assert_eq!(res["result"]["success"], false, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn answer_for_disconnected_client() {
+    let server = Server::new(config(ShowDocumentFallback::FirstClient))
+        .await
+        .unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let mut observer = observer(&server).await;
+
+    request_show(&mut observer, 2).await;
+    receive_show(&mut client).await;
+    // The server still waits for the answer of the client which is gone.
+    drop(client);
+    let res = observer.response(2).await;
+    assert_eq!(res["result"]["success"], false, "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,48 @@
+//! Clients shutting down don't shut down the instance other clients share
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::Value;
+use tokio::time;
+
+use common::Client;
+
+#[tokio::test]
+async fn shutdown_is_not_forwarded() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut first = Client::connect(&server);
+    first.initialize().await;
+    let mut second = Client::connect(&server);
+    second.initialize().await;
+
+    let pid = first.request(2, "test/pid").await["pid"].clone();
+    assert!(pid.is_u64());
+    // The connection is closed after the response, `exit` can't be sent.
+    assert_eq!(first.request(3, "shutdown").await, Value::Null);
+    drop(first);
+
+    // The mock server exits on `shutdown`, it would be gone if it got one.
+    time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(second.request(2, "test/pid").await["pid"], pid);
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn stop_waits_for_shutdown_response() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect_with(&server, common::mock_server(&["--delay", "300"]));
+    client.initialize().await;
+
+    // `exit` is only sent after the delayed `shutdown` response, the server
+    // isn't killed after waiting for it to exit either.
+    let start = Instant::now();
+    assert_eq!(server.stop(false).await, 1);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+}
@@ -0,0 +1,88 @@
+//! Incremental changes made without knowing about another client's changes
+//! are refused until the client reopens the document
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+const URI: &str = "file:///a.rs";
+
+async fn open(client: &mut Client, text: &str) {
+    let document = json!({ "uri": URI, "languageId": "rust", "version": 1, "text": text });
+    client
+        .notify("textDocument/didOpen", json!({ "textDocument": document }))
+        .await;
+}
+
+/// Insert `text` at the start of the document
+async fn insert(client: &mut Client, version: i64, text: &str) {
+    let start = json!({ "line": 0, "character": 0 });
+    let params = json!({
+        "textDocument": { "uri": URI, "version": version },
+        "contentChanges": [{ "range": { "start": start, "end": start }, "text": text }],
+    });
+    client.notify("textDocument/didChange", params).await;
+}
+
+/// Texts of the `textDocument/didChange` notifications the server received
+async fn changes(client: &mut Client, id: i64) -> Vec<Value> {
+    let notifications = client.request(id, "mock/notifications").await;
+    let notifications = notifications.as_array().unwrap().iter();
+    notifications
+        .filter(|notif| notif["method"] == "textDocument/didChange")
+        .map(|notif| notif["params"]["contentChanges"][0]["text"].clone())
+        .collect()
+}
+
+/// Wait until the messages `client` sent so far were handled
+async fn sync(client: &mut Client, id: i64) {
+    client.request(id, "test/sync").await;
+}
+
+async fn show_message(client: &mut Client) -> Value {
+    client
+        .receive_matching(|message| message["method"] == "window/showMessage")
+        .await
+}
+
+#[tokio::test]
+async fn reopen_after_refused_change() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut first = Client::connect(&server);
+    first.initialize().await;
+    let mut second = Client::connect(&server);
+    second.initialize().await;
+    open(&mut first, "").await;
+    sync(&mut first, 2).await;
+    open(&mut second, "").await;
+    sync(&mut second, 2).await;
+
+    insert(&mut first, 2, "a").await;
+    sync(&mut first, 3).await;
+    insert(&mut second, 2, "b").await;
+    assert_eq!(show_message(&mut second).await["params"]["type"], 1);
+    assert_eq!(show_message(&mut first).await["params"]["type"], 2);
+
+    // Every further change of the stale client is refused.
+    insert(&mut second, 3, "c").await;
+    assert_eq!(show_message(&mut second).await["params"]["type"], 1);
+    assert_eq!(changes(&mut first, 4).await, ["a"]);
+
+    // Reopening the document makes its content the current one.
+    let params = json!({ "textDocument": { "uri": URI } });
+    second.notify("textDocument/didClose", params).await;
+    open(&mut second, "reloaded").await;
+    insert(&mut second, 2, "d").await;
+    assert_eq!(changes(&mut second, 3).await, ["a", "reloaded", "d"]);
+
+    // Now the other client is stale.
+    insert(&mut first, 3, "e").await;
+    assert_eq!(show_message(&mut first).await["params"]["type"], 1);
+    assert_eq!(changes(&mut first, 5).await, ["a", "reloaded", "d"]);
+
+    server.stop(false).await;
+}
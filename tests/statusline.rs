@@ -0,0 +1,73 @@
+//! `ra-multiplex statusline` reports the work done progress and status of the
+//! server
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+async fn statusline(server: &Server, pid: &Value) -> Value {
+    let request = json!({ "method": "statusline", "pid": pid, "cwd": "/" });
+    common::ext_request(server, request).await["result"].clone()
+}
+
+async fn progress(client: &mut Client, id: i64, token: &str, value: Value) {
+    let params = json!({ "method": "$/progress", "params": { "token": token, "value": value } });
+    client.send_request(id, "mock/notify", params).await;
+    client.response(id).await;
+}
+
+#[tokio::test]
+async fn report_progress_and_health() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    let status = statusline(&server, &pid).await;
+    assert_eq!(status["pid"], pid);
+    assert_eq!(status["clients"], 1);
+    assert_eq!(status["busy"], false, "{status}");
+    assert!(status.get("progress").is_none(), "{status}");
+
+    let begin = json!({ "kind": "begin", "title": "Indexing", "percentage": 10 });
+    progress(&mut client, 3, "index", begin).await;
+    let begin = json!({ "kind": "begin", "title": "Building", "percentage": 50 });
+    progress(&mut client, 4, "build", begin).await;
+    // The report keeps the title of the begin.
+    let report = json!({ "kind": "report", "percentage": 80 });
+    progress(&mut client, 5, "index", report).await;
+
+    // The least advanced progress is reported.
+    let status = statusline(&server, &pid).await;
+    assert_eq!(status["busy"], true, "{status}");
+    assert_eq!(
+        status["progress"],
+        json!({ "title": "Building", "percentage": 50 })
+    );
+
+    let end = json!({ "kind": "end" });
+    progress(&mut client, 6, "build", end).await;
+    let status = statusline(&server, &pid).await;
+    assert_eq!(
+        status["progress"],
+        json!({ "title": "Indexing", "percentage": 80 })
+    );
+
+    progress(&mut client, 7, "index", json!({ "kind": "end" })).await;
+    let params = json!({
+        "method": "experimental/serverStatus",
+        "params": { "health": "warning", "quiescent": false },
+    });
+    client.send_request(8, "mock/notify", params).await;
+    client.response(8).await;
+    let status = statusline(&server, &pid).await;
+    assert!(status.get("progress").is_none(), "{status}");
+    assert_eq!(status["busy"], true, "{status}");
+    assert_eq!(status["health"], "warning", "{status}");
+
+    server.stop(false).await;
+}
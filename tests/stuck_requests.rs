@@ -0,0 +1,91 @@
+//! Requests the server doesn't respond to in time are reported to the client
+//! which sent them
+
+mod common;
+
+use std::num::NonZeroU32;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+/// Wait for the stuck request warning or the response to request `id`,
+/// whichever comes first
+async fn warning_or_response(client: &mut Client, id: i64) -> Value {
+    client
+        .receive_matching(|message| {
+            message["method"] == "window/showMessage" || message["id"] == id
+        })
+        .await
+}
+
+#[tokio::test]
+async fn warn_about_stuck_request() {
+    let config = Config {
+        stuck_request_timeout: Some(1),
+        stuck_request_notify: true,
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 5000 }))
+        .await;
+    let warning = warning_or_response(&mut client, 2).await;
+    assert_eq!(warning["params"]["type"], 2, "{warning}");
+    let message = warning["params"]["message"].as_str().unwrap();
+    assert!(
+        message.contains("didn't respond to mock/sleep"),
+        "{message}"
+    );
+
+    // It's reported only once and still answered.
+    let res = warning_or_response(&mut client, 2).await;
+    assert_eq!(res["id"], 2, "{res}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn method_timeout_overrides_default() {
+    let config = Config {
+        stuck_request_timeout: None,
+        stuck_request_notify: true,
+        timeouts: [("mock/sleep".to_owned(), NonZeroU32::new(1).unwrap())].into(),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 5000 }))
+        .await;
+    let warning = warning_or_response(&mut client, 2).await;
+    assert_eq!(warning["method"], "window/showMessage", "{warning}");
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn no_warning_without_notify() {
+    let config = Config {
+        stuck_request_timeout: Some(1),
+        ..Config::default()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 2500 }))
+        .await;
+    let res = warning_or_response(&mut client, 2).await;
+    assert_eq!(res["id"], 2, "{res}");
+
+    server.stop(false).await;
+}
@@ -0,0 +1,78 @@
+//! Clients connected with the `subscribe` method receive the lifecycle events
+//! of their own server
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::{ClientOptions, Server};
+use serde_json::{json, Value};
+
+use common::Client;
+
+/// Connect a client subscribed to the events of `server`
+async fn subscribe(server: &Server) -> Client {
+    let mut subscriber = Client::connect_with(server, ClientOptions::default());
+    let request = json!({ "method": "subscribe", "version": "1" });
+    let params = json!({ "initializationOptions": { "lspMux": request } });
+    subscriber.send_request(1, "initialize", params).await;
+    // Responses to `lspMux` requests always have id 0.
+    let res = subscriber.response(0).await;
+    assert_eq!(res["result"], Value::Null, "{res}");
+    subscriber
+}
+
+async fn event(subscriber: &mut Client) -> Value {
+    let notif = subscriber
+        .receive_matching(|message| message["method"] == "$/lspMux/event")
+        .await;
+    notif["params"].clone()
+}
+
+#[tokio::test]
+async fn stream_instance_and_client_events() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut subscriber = subscribe(&server).await;
+
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+    let pid = client.request(2, "test/pid").await["pid"].clone();
+
+    let started = event(&mut subscriber).await;
+    assert_eq!(started["kind"], "instanceStarted", "{started}");
+    assert_eq!(started["pid"], pid);
+    let attached = event(&mut subscriber).await;
+    assert_eq!(attached["kind"], "clientAttached", "{attached}");
+    assert_eq!(attached["pid"], pid);
+    assert!(attached["clientId"].is_u64(), "{attached}");
+
+    drop(client);
+    let detached = event(&mut subscriber).await;
+    assert_eq!(detached["kind"], "clientDetached", "{detached}");
+    assert_eq!(detached["clientId"], attached["clientId"]);
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn events_stay_with_their_server() {
+    let first = Server::new(Config::default()).await.unwrap();
+    let second = Server::new(Config::default()).await.unwrap();
+    let mut subscriber = subscribe(&second).await;
+
+    let mut client = Client::connect(&first);
+    client.initialize().await;
+    let first_pid = client.request(2, "test/pid").await["pid"].clone();
+
+    let mut other = Client::connect(&second);
+    other.initialize().await;
+    let second_pid = other.request(2, "test/pid").await["pid"].clone();
+
+    // The first event seen is the second server's, none of the first's leaked.
+    let started = event(&mut subscriber).await;
+    assert_eq!(started["kind"], "instanceStarted", "{started}");
+    assert_eq!(started["pid"], second_pid);
+    assert_ne!(started["pid"], first_pid);
+
+    first.stop(false).await;
+    second.stop(false).await;
+}
@@ -0,0 +1,115 @@
+//! Requests listed in `supersede_requests` are cancelled when the client
+//! sends another one for the same line of the same document
+
+mod common;
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+/// Params of a `mock/sleep` request at `line` of `uri`
+fn sleep_at(uri: &str, line: u64, ms: u64) -> Value {
+    json!({
+        "textDocument": { "uri": uri },
+        "position": { "line": line, "character": 0 },
+        "ms": ms,
+    })
+}
+
+fn config() -> Config {
+    Config {
+        supersede_requests: ["mock/sleep".to_owned()].into(),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn cancel_request_for_same_line() {
+    let server = Server::new(config()).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", sleep_at("file:///a.rs", 1, 60_000))
+        .await;
+    client
+        .send_request(3, "mock/sleep", sleep_at("file:///a.rs", 5, 500))
+        .await;
+    client
+        .send_request(4, "mock/sleep", sleep_at("file:///b.rs", 1, 500))
+        .await;
+    client
+        .send_request(5, "mock/sleep", sleep_at("file:///a.rs", 1, 0))
+        .await;
+
+    for _ in 0..4 {
+        let res = client
+            .receive_matching(|message| message.get("method").is_none())
+            .await;
+        let cancelled = res["error"]["code"] == -32800;
+        assert_eq!(cancelled, res["id"] == 2, "{res}");
+    }
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn cancel_queued_request() {
+    let config = Config {
+        max_concurrent_requests: Some(1),
+        ..config()
+    };
+    let server = Server::new(config).await.unwrap();
+    let mut client = Client::connect(&server);
+    client.initialize().await;
+
+    client
+        .send_request(2, "mock/sleep", json!({ "ms": 500 }))
+        .await;
+    client
+        .send_request(3, "mock/sleep", sleep_at("file:///a.rs", 1, 0))
+        .await;
+    client
+        .send_request(4, "mock/sleep", sleep_at("file:///a.rs", 1, 0))
+        .await;
+
+    // The superseded request is answered right away and never forwarded, so
+    // it's answered only once.
+    let responses = [3, 2, 4].map(|id| json!(id));
+    for expected in responses {
+        let res = client
+            .receive_matching(|message| message.get("method").is_none())
+            .await;
+        assert_eq!(res["id"], expected, "{res}");
+        let cancelled = res["error"]["code"] == -32800;
+        assert_eq!(cancelled, expected == 3, "{res}");
+    }
+
+    server.stop(false).await;
+}
+
+#[tokio::test]
+async fn off_by_default() {
+    let server = Server::new(Config::default()).await.unwrap();
+    let options = common::mock_server(&["--delay", "300", "--exit-on-shutdown"]);
+    let mut client = Client::connect_with(&server, options);
+    client.initialize().await;
+
+    client
+        .send_request(2, "textDocument/hover", sleep_at("file:///a.rs", 1, 0))
+        .await;
+    client
+        .send_request(3, "textDocument/hover", sleep_at("file:///a.rs", 1, 0))
+        .await;
+    // The responses may come in any order.
+    for _ in 0..2 {
+        let res = client
+            .receive_matching(|message| message.get("method").is_none())
+            .await;
+        assert_eq!(res["result"]["method"], "textDocument/hover", "{res}");
+    }
+
+    server.stop(false).await;
+}
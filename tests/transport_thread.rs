@@ -0,0 +1,88 @@
+//! With `runtime.transport_thread` clients are served from a runtime of their
+//! own while the instances stay on the runtime the server was started on
+
+mod common;
+
+use ra_multiplex_core::config::{Config, RuntimeOptions};
+use ra_multiplex_core::server::Server;
+use serde_json::json;
+
+use common::Client;
+
+fn config() -> Config {
+    Config {
+        runtime: RuntimeOptions {
+            transport_thread: true,
+            ..RuntimeOptions::default()
+        },
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn serve_in_process_clients() {
+    let server = Server::new(config()).await.unwrap();
+    let mut first = Client::connect(&server);
+    first.initialize().await;
+    let pid = first.request(2, "test/pid").await["pid"].clone();
+
+    // The instance doesn't belong to the first client's connection.
+    drop(first);
+    let mut second = Client::connect(&server);
+    second.initialize().await;
+    assert_eq!(second.request(2, "test/pid").await["pid"], pid);
+
+    server.stop(false).await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serve_listening_clients() {
+    use std::sync::Arc;
+    use std::{env, fs, process};
+
+    use ra_multiplex_core::config::Address;
+    use tokio::net::UnixStream;
+    use tokio::{io, task, time};
+
+    let socket = env::temp_dir().join(format!("ra-mux-transport-{}.sock", process::id()));
+    let _ = fs::remove_file(&socket);
+    let config = Config {
+        listen: Address::Unix(socket.clone()),
+        ..config()
+    };
+    let server = Arc::new(Server::new(config).await.unwrap());
+    let listen = task::spawn({
+        let server = server.clone();
+        async move { server.listen().await }
+    });
+
+    let stream = time::timeout(common::TIMEOUT, async {
+        loop {
+            match UnixStream::connect(&socket).await {
+                Ok(stream) => break stream,
+                Err(_) => time::sleep(time::Duration::from_millis(20)).await,
+            }
+        }
+    });
+    let mut stream = stream.await.expect("server isn't listening");
+    let (mut ours, theirs) = io::duplex(64 * 1024);
+    task::spawn(async move { io::copy_bidirectional(&mut stream, &mut ours).await });
+    let mut client = Client::from_stream(theirs);
+    let lsp_mux = json!({
+        "version": "1",
+        "method": "connect",
+        "server": env!("CARGO_BIN_EXE_ra-multiplex"),
+        "args": ["mock-server", "--exit-on-shutdown"],
+        "cwd": env!("CARGO_MANIFEST_DIR"),
+    });
+    let params = json!({ "initializationOptions": { "lspMux": lsp_mux } });
+    let res = client.initialize_with(params).await;
+    assert!(res["result"]["capabilities"].is_object(), "{res}");
+    assert!(client.request(2, "test/pid").await["pid"].is_u64());
+
+    // The accept loop on the transport runtime ends with the server.
+    server.stop(false).await;
+    listen.await.unwrap().unwrap();
+    let _ = fs::remove_file(&socket);
+}
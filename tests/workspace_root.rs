@@ -0,0 +1,73 @@
+//! Clients spelling the workspace root differently share an instance which
+//! only sees the canonical spelling
+
+mod common;
+
+use std::{env, fs, process};
+
+use ra_multiplex_core::config::Config;
+use ra_multiplex_core::server::Server;
+use serde_json::{json, Value};
+
+use common::Client;
+
+async fn connect(server: &Server, root: &str) -> Client {
+    let mut client = Client::connect(server);
+    let folder = json!({ "uri": format!("file://{root}"), "name": "proj" });
+    client
+        .initialize_with(json!({ "workspaceFolders": [folder] }))
+        .await;
+    client
+}
+
+async fn pid(client: &mut Client, id: i64) -> Value {
+    client.request(id, "test/pid").await["pid"].clone()
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn rewrite_symlinked_root() {
+    let dir = env::temp_dir().join(format!("ra-mux-workspace-root-{}", process::id()));
+    fs::create_dir_all(dir.join("proj")).unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+    std::os::unix::fs::symlink(dir.join("proj"), dir.join("link")).unwrap();
+    let proj = dir.join("proj").display().to_string();
+    let link = dir.join("link").display().to_string();
+
+    let server = Server::new(Config::default()).await.unwrap();
+    let mut linked = connect(&server, &format!("{link}/")).await;
+    let mut canonical = connect(&server, &proj).await;
+    assert_eq!(pid(&mut linked, 2).await, pid(&mut canonical, 2).await);
+
+    let document = json!({
+        "uri": format!("file://{link}/a.rs"),
+        "languageId": "rust",
+        "version": 1,
+        "text": "",
+    });
+    linked
+        .notify("textDocument/didOpen", json!({ "textDocument": document }))
+        .await;
+    linked.request(3, "test/sync").await;
+    // The other client doesn't get the server's URIs rewritten.
+    let notifications = canonical.request(3, "mock/notifications").await;
+    let opened = notifications
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|notif| notif["method"] == "textDocument/didOpen")
+        .unwrap();
+    let uri = format!("file://{proj}/a.rs");
+    assert_eq!(opened["params"]["textDocument"]["uri"], uri);
+
+    // URIs from the server are spelled like the client spelled the root.
+    let params = json!({ "method": "window/showDocument", "params": { "uri": uri } });
+    linked.send_request(4, "mock/request", params).await;
+    let req = linked
+        .receive_matching(|message| message["method"] == "window/showDocument")
+        .await;
+    assert_eq!(req["params"]["uri"], format!("file://{link}/a.rs"));
+
+    server.stop(false).await;
+    fs::remove_dir_all(&dir).unwrap();
+}